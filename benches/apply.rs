@@ -0,0 +1,100 @@
+// Benchmarks of `Ledger::apply`, the path every transaction goes through, on
+// synthetic workloads with different mixes of transactions and numbers of
+// clients. Throughput is reported in transactions per second, so runs can be
+// compared with `cargo bench -- --baseline <name>` before a release.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use ledger::{AccountId, Ledger, Transaction, TransactionId};
+use rust_decimal::Decimal;
+
+// The number of transactions in every workload.
+const TRANSACTIONS: u32 = 100_000;
+
+// Workload describes the transactions of a benchmark: how many clients they're
+// spread over, and how often a deposit is followed by a dispute of it.
+struct Workload {
+    name: &'static str,
+    clients: u32,
+    // One in `dispute_every` deposits is disputed and resolved right away,
+    // or none are if it's zero. Nothing is charged back, since that would
+    // freeze the few clients of some workloads and leave nothing to apply.
+    dispute_every: u32,
+}
+
+const WORKLOADS: &[Workload] = &[
+    Workload {
+        name: "deposits/few-clients",
+        clients: 10,
+        dispute_every: 0,
+    },
+    Workload {
+        name: "deposits/many-clients",
+        clients: 60_000,
+        dispute_every: 0,
+    },
+    Workload {
+        name: "disputes/few-clients",
+        clients: 10,
+        dispute_every: 2,
+    },
+    Workload {
+        name: "disputes/many-clients",
+        clients: 60_000,
+        dispute_every: 2,
+    },
+];
+
+impl Workload {
+    // The transactions of the workload, in the order they're applied. Every
+    // client mostly deposits, with a withdrawal every so often, so most
+    // transactions succeed like they would on real input. Amounts are
+    // deterministic so runs are comparable.
+    fn transactions(&self) -> Vec<(AccountId, Transaction)> {
+        let mut transactions = Vec::with_capacity(TRANSACTIONS as usize);
+        let mut id: u32 = 0;
+        while transactions.len() < TRANSACTIONS as usize {
+            id += 1;
+            let client = (id * 7919 % self.clients) as AccountId;
+            let amount = Decimal::new(i64::from(id % 1000) * 125 + 100, 4);
+            let new_id = TransactionId::from(id);
+            if id.is_multiple_of(5) {
+                transactions.push((client, Transaction::Withdrawal { new_id, amount }));
+                continue;
+            }
+            transactions.push((client, Transaction::Deposit { new_id, amount }));
+            if self.dispute_every != 0 && id.is_multiple_of(self.dispute_every) {
+                let id = new_id;
+                transactions.push((client, Transaction::Dispute { id, amount: None }));
+                transactions.push((client, Transaction::Resolve { id }));
+            }
+        }
+        transactions.truncate(TRANSACTIONS as usize);
+        transactions
+    }
+}
+
+fn apply(c: &mut Criterion) {
+    let mut group = c.benchmark_group("apply");
+    group.throughput(Throughput::Elements(u64::from(TRANSACTIONS)));
+    group.sample_size(20);
+    for workload in WORKLOADS {
+        let transactions = workload.transactions();
+        group.bench_function(workload.name, |b| {
+            b.iter_batched(
+                || transactions.clone(),
+                |transactions| {
+                    let mut ledger = Ledger::default();
+                    for (client, tx) in transactions {
+                        // Rejected transactions are part of the workload too.
+                        let _ = ledger.apply(client, tx);
+                    }
+                    ledger
+                },
+                BatchSize::LargeInput,
+            );
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, apply);
+criterion_main!(benches);
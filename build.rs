@@ -0,0 +1,13 @@
+fn main() {
+    // The gRPC service is generated from the protobuf definition only when
+    // the feature is enabled, so the default build needs no extra tooling.
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/ledger.proto");
+        let descriptors = protox::compile(["proto/ledger.proto"], ["proto"])
+            .expect("failed to parse protobuf definition");
+        tonic_build::configure()
+            .compile_fds(descriptors)
+            .expect("failed to generate gRPC service");
+    }
+}
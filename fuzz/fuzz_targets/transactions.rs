@@ -0,0 +1,86 @@
+// Apply arbitrary sequences of transactions to a few accounts, and check the
+// invariants of every account after each one:
+// * the total is the available funds plus the held funds,
+// * held funds never go negative,
+// * only a chargeback freezes an account,
+// * a rejected transaction leaves the account as it was.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use ledger::{account::FreezeReason, AccountId, Ledger, Transaction, TransactionId};
+use libfuzzer_sys::fuzz_target;
+use rust_decimal::Decimal;
+
+// Op is a transaction of one of a few clients, referring to one of a few
+// transaction IDs, so that disputes, resolutions, and chargebacks often find
+// the transaction they refer to.
+#[derive(Arbitrary, Debug)]
+struct Op {
+    client: u8,
+    id: u8,
+    kind: Kind,
+}
+
+// Amounts are positive like they'd be in real input, and small enough that
+// the sums can't overflow.
+#[derive(Arbitrary, Debug)]
+enum Kind {
+    Deposit { amount: u32, scale: u8 },
+    Withdrawal { amount: u32, scale: u8 },
+    Dispute,
+    Resolve,
+    Chargeback,
+    Recovery,
+}
+
+const CLIENTS: u8 = 3;
+const IDS: u8 = 16;
+
+impl Op {
+    fn transaction(&self) -> (AccountId, Transaction) {
+        let client = AccountId::from(self.client % CLIENTS);
+        let id = TransactionId::from(self.id % IDS);
+        let amount = |amount: u32, scale: u8| Decimal::new(amount.into(), u32::from(scale % 5));
+        let tx = match self.kind {
+            Kind::Deposit { amount: a, scale } => Transaction::Deposit {
+                new_id: id,
+                amount: amount(a, scale),
+            },
+            Kind::Withdrawal { amount: a, scale } => Transaction::Withdrawal {
+                new_id: id,
+                amount: amount(a, scale),
+            },
+            Kind::Dispute => Transaction::Dispute { id, amount: None },
+            Kind::Resolve => Transaction::Resolve { id },
+            Kind::Chargeback => Transaction::Chargeback { id },
+            Kind::Recovery => Transaction::Recovery,
+        };
+        (client, tx)
+    }
+}
+
+fuzz_target!(|ops: Vec<Op>| {
+    let mut ledger = Ledger::default();
+    for op in &ops {
+        let (client, tx) = op.transaction();
+        let before = ledger
+            .account(client)
+            .map(|account| (account.available(), account.held(), account.is_frozen()));
+        let is_chargeback = matches!(tx, Transaction::Chargeback { .. });
+        let result = ledger.apply(client, tx.clone());
+
+        let account = ledger.account(client).expect("the account is opened");
+        assert_eq!(account.total(), Ok(account.available() + account.held()));
+        assert!(account.held() >= Decimal::ZERO, "{:?} of {}", tx, client);
+        match account.freeze_reason() {
+            None | Some(FreezeReason::Chargeback(_)) => {}
+            Some(reason) => panic!("frozen for {:?}", reason),
+        }
+        let after = (account.available(), account.held(), account.is_frozen());
+        match (result, before) {
+            (Err(_), Some(before)) => assert_eq!(after, before, "{:?} of {}", tx, client),
+            (Ok(()), Some((_, _, false))) if after.2 => assert!(is_chargeback),
+            _ => {}
+        }
+    }
+});
@@ -1,44 +1,393 @@
-use rust_decimal::Decimal;
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    funds::{self, Funds},
     ledger::{ProcessedTransaction, ProcessedTransactionState, ProcessedTxsForAccount},
-    Balance, Transaction, TransactionAmount, TransactionError,
+    policy::ParsePolicyError,
+    Balance, Currency, Timestamp, Transaction, TransactionAmount, TransactionError, TransactionId,
 };
 
-#[derive(Debug)]
-pub struct Account {
-    // if an account is frozen no transactions can be applied to it
-    frozen: bool,
+// FreezeReason records why an account was frozen.
+// * Chargeback: a chargeback of the given transaction froze the account.
+// * Manual: the account was frozen explicitly through the API, or by a
+//   `lock` record, e.g. by an administrator. The string describes why.
+// * RiskRule: the account was frozen explicitly by the given rule of a risk
+//   system, e.g. a fraud check.
+// * Opening: the account was already locked in its opening balance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FreezeReason {
+    Chargeback(TransactionId),
+    Manual(String),
+    RiskRule(String),
+    Opening,
+}
+
+// FreezeLevel is how much of an account a freeze blocks, see
+// `Policy::chargeback_freeze` and `Policy::limit_freeze`.
+// * Withdrawals: withdrawals, authorizations, fees, and refunds are
+//   rejected, but deposits are still accepted, e.g. while a velocity breach
+//   is looked into.
+// * Full: deposits are rejected as well.
+// Disputes, resolutions, and chargebacks apply at either level. Levels are
+// ordered by how much they block, and a freeze only ever gets stricter.
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub enum FreezeLevel {
+    Withdrawals,
+    #[default]
+    Full,
+}
+
+impl FromStr for FreezeLevel {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "withdrawals" => Ok(FreezeLevel::Withdrawals),
+            "full" => Ok(FreezeLevel::Full),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for FreezeLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FreezeLevel::Withdrawals => "withdrawals",
+            FreezeLevel::Full => "full",
+        })
+    }
+}
+
+// Reasons are written as their kind, followed by what it was if there's
+// more to it, e.g. `chargeback:17` or `manual:lock record`.
+impl fmt::Display for FreezeReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FreezeReason::Chargeback(id) => write!(f, "chargeback:{}", id),
+            FreezeReason::Manual(reason) => write!(f, "manual:{}", reason),
+            FreezeReason::RiskRule(rule) => write!(f, "risk_rule:{}", rule),
+            FreezeReason::Opening => f.write_str("opening"),
+        }
+    }
+}
+
+// FreezePeriod is a time an account spent frozen, see
+// `Ledger::freeze_history`: why and when it was frozen, and when and why
+// it was unfrozen, unless it's still frozen. A freeze made stricter, see
+// `FreezeLevel`, ends its period, as if it was unfrozen for the reason
+// `ESCALATED`, and starts another. Times are those of the
+// transactions that froze or unfroze the account, or the ledger's time, see
+// `Ledger::now`, for those without one and for freezes through the API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FreezePeriod {
+    pub reason: FreezeReason,
+    #[serde(default)]
+    pub level: FreezeLevel,
+    pub frozen_at: Timestamp,
+    pub unfrozen_at: Option<Timestamp>,
+    pub unfreeze_reason: Option<String>,
+}
+
+// The reason accounts frozen by a `lock` record are frozen for.
+pub const LOCK_REASON: &str = "lock record";
+
+// The reason the freeze periods of freezes that were made stricter ended.
+pub const ESCALATED: &str = "escalated";
+
+// OpeningBalance is the state an account starts from when it's carried over
+// from another system instead of starting empty. With a currency it's the
+// account's balance in that currency, see `Account::in_currency`, and a
+// closed balance closes the whole account, see `Ledger::close_account`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpeningBalance {
+    pub available: Balance,
+    pub held: Balance,
+    pub locked: bool,
+    pub closed: bool,
+    pub currency: Option<Currency>,
+}
 
-    available: Decimal,
-    held: Decimal,
+// AccountMetadata describes an account that was created explicitly, see
+// `Ledger::create_account`: the client's name or reference in other
+// systems, e.g. a customer number, the currency the account is meant for,
+// and the risk tier it was put in. They're kept as they're given, and
+// don't change how transactions are applied.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountMetadata {
+    pub reference: Option<String>,
+    pub currency: Option<Currency>,
+    pub risk_tier: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    // if an account is frozen no deposits or withdrawals can be applied to it
+    frozen: Option<FreezeReason>,
+    // How much of the account the freeze blocks, if it's frozen.
+    #[serde(default, skip_serializing_if = "is_full")]
+    freeze_level: FreezeLevel,
+    // Why the account was last unfrozen, if it ever was, for audits.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unfreeze_reason: Option<String>,
+
+    available: Funds,
+    held: Funds,
+
+    // The part of a negative balance that's still being recovered, see
+    // `Transaction::Recovery`, and how much has been recovered so far.
+    deficit: Funds,
+    recovered: Funds,
+
+    // How far withdrawals may take the available funds below zero.
+    overdraft: Funds,
+
+    // A closed account can't be deposited to or withdrawn from anymore, see
+    // `Ledger::close_account`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    closed: bool,
+
+    // The balances in every currency the account has had transactions in,
+    // see `Transaction::InCurrency`. They're accounts of their own, except
+    // that they're frozen along with this one, and share its overdraft.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    currencies: BTreeMap<Currency, Account>,
 }
 
 impl Default for Account {
     fn default() -> Self {
         Self {
-            frozen: false,
-            available: TransactionAmount::ZERO,
-            held: TransactionAmount::ZERO,
+            frozen: None,
+            freeze_level: FreezeLevel::Full,
+            unfreeze_reason: None,
+            available: Funds::ZERO,
+            held: Funds::ZERO,
+            deficit: Funds::ZERO,
+            recovered: Funds::ZERO,
+            overdraft: Funds::ZERO,
+            closed: false,
+            currencies: BTreeMap::new(),
         }
     }
 }
 
 impl Account {
+    pub fn with_opening_balance(balance: &OpeningBalance) -> Result<Account, TransactionError> {
+        let mut account = Account::default();
+        account.open_balance(balance)?;
+        Ok(account)
+    }
+
+    // Open the balance of the account in the currency of `balance`, or the
+    // account's own balance without one. Opening a locked balance freezes
+    // the whole account. It fails with `Overflow`, leaving the account as it
+    // was, if the balance, or its total, is larger than funds can hold.
+    pub(crate) fn open_balance(
+        &mut self,
+        balance: &OpeningBalance,
+    ) -> Result<(), TransactionError> {
+        let (available, held) = balances(
+            funds::rounded(balance.available)?,
+            funds::rounded(balance.held)?,
+        )?;
+        let opened = Account {
+            available,
+            held,
+            ..Account::default()
+        };
+        match balance.currency {
+            Some(currency) => {
+                self.currencies.insert(currency, opened);
+            }
+            None => {
+                self.available = opened.available;
+                self.held = opened.held;
+            }
+        }
+        if balance.locked {
+            self.freeze(FreezeReason::Opening);
+        }
+        if balance.closed {
+            self.closed = true;
+        }
+        Ok(())
+    }
+
+    // Allow withdrawals to take the available funds down to `-limit`. It
+    // fails with `Overflow` if the limit is larger than funds can hold.
+    pub fn with_overdraft(self, limit: Balance) -> Result<Account, TransactionError> {
+        Ok(Account {
+            overdraft: funds::rounded(limit)?,
+            ..self
+        })
+    }
+
     pub fn held(&self) -> Balance {
-        self.held
+        funds::to_balance(self.held)
     }
 
     pub fn available(&self) -> Balance {
-        self.available
+        funds::to_balance(self.available)
+    }
+
+    // The available and held funds together. It fails with `Overflow` if
+    // they can't be added up, which the ledger never lets happen.
+    pub fn total(&self) -> Result<Balance, TransactionError> {
+        add(self.available, self.held).map(funds::to_balance)
+    }
+
+    // Whether the totals of the account and its balances in currencies can
+    // be added up, see `total`.
+    pub(crate) fn totals_fit(&self) -> bool {
+        self.total().is_ok() && self.currencies.values().all(Account::totals_fit)
+    }
+
+    // How far the available funds are below zero, e.g. drawn on the
+    // account's overdraft.
+    pub fn overdrawn(&self) -> Balance {
+        funds::to_balance((-self.available).max(Funds::ZERO))
     }
 
-    pub fn total(&self) -> Balance {
-        self.available + self.held
+    // The amount still to be recovered from future deposits.
+    pub fn deficit(&self) -> Balance {
+        funds::to_balance(self.deficit)
+    }
+
+    // The amount recovered from deposits since recovery started.
+    pub fn recovered(&self) -> Balance {
+        funds::to_balance(self.recovered)
     }
 
     pub fn is_frozen(&self) -> bool {
-        self.frozen
+        self.frozen.is_some()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    // The account's balance in the given currency, if it has had any
+    // transactions in it. It's frozen whenever this account is, which it
+    // doesn't tell itself.
+    pub fn in_currency(&self, currency: Currency) -> Option<&Account> {
+        self.currencies.get(&currency)
+    }
+
+    // The currencies the account has balances in, in the order of their
+    // codes.
+    pub fn currencies(&self) -> impl Iterator<Item = (Currency, &Account)> {
+        self.currencies
+            .iter()
+            .map(|(currency, balance)| (*currency, balance))
+    }
+
+    pub fn freeze_reason(&self) -> Option<&FreezeReason> {
+        self.frozen.as_ref()
+    }
+
+    pub fn freeze_level(&self) -> Option<FreezeLevel> {
+        self.frozen.as_ref().map(|_| self.freeze_level)
+    }
+
+    pub fn unfreeze_reason(&self) -> Option<&str> {
+        self.unfreeze_reason.as_deref()
+    }
+
+    // Freeze the account for the given reason at the given level, see
+    // `FreezeLevel`. If the account is already frozen at least as strictly
+    // the original reason is kept, otherwise the freeze is made stricter,
+    // with the given reason. Returns whether the freeze changed.
+    pub fn freeze_at_level(&mut self, reason: FreezeReason, level: FreezeLevel) -> bool {
+        if self.freeze_level().is_some_and(|frozen| frozen >= level) {
+            return false;
+        }
+        self.frozen = Some(reason);
+        self.freeze_level = level;
+        true
+    }
+
+    // Freeze the account fully for the given reason, see `freeze_at_level`.
+    pub fn freeze(&mut self, reason: FreezeReason) {
+        self.freeze_at_level(reason, FreezeLevel::Full);
+    }
+
+    // Unfreeze the account for the given reason, returning the reason it was
+    // frozen for, if it was frozen at all. Unfreezing an account that isn't
+    // frozen keeps the reason it was last unfrozen for.
+    pub fn unfreeze(&mut self, reason: String) -> Option<FreezeReason> {
+        let frozen = self.frozen.take()?;
+        self.freeze_level = FreezeLevel::Full;
+        self.unfreeze_reason = Some(reason);
+        Some(frozen)
+    }
+
+    // What has to be swept out of the account for it to be closed: the
+    // available funds of its balance and of its balances in currencies,
+    // those that have any. It fails with `BalanceNotZero` if any of them
+    // has held funds or is below zero, which can't be swept, and with
+    // `AccountClosed` if the account is closed already.
+    pub(crate) fn residue(&self) -> Result<Vec<(Option<Currency>, Balance)>, TransactionError> {
+        if self.closed {
+            return Err(TransactionError::AccountClosed);
+        }
+        let balances = std::iter::once((None, self)).chain(
+            self.currencies()
+                .map(|(currency, balance)| (Some(currency), balance)),
+        );
+        let mut residue = vec![];
+        for (currency, balance) in balances {
+            if balance.held != Funds::ZERO || balance.available < Funds::ZERO {
+                return Err(TransactionError::BalanceNotZero);
+            }
+            if balance.available != Funds::ZERO {
+                residue.push((currency, balance.available()));
+            }
+        }
+        Ok(residue)
+    }
+
+    // Close the account, emptying every balance, see `residue`.
+    pub(crate) fn close(&mut self) {
+        self.available = Funds::ZERO;
+        for balance in self.currencies.values_mut() {
+            balance.available = Funds::ZERO;
+        }
+        self.closed = true;
+    }
+
+    // Credit a fee charged to another account, see `Transaction::Fee`, in
+    // the currency it was charged in. The fee is credited even if this
+    // account is frozen. It fails with `Overflow`, leaving the account as it
+    // was, if the balance can't hold the fee.
+    pub fn credit_fee(
+        &mut self,
+        currency: Option<Currency>,
+        amount: TransactionAmount,
+    ) -> Result<(), TransactionError> {
+        match currency {
+            Some(currency) => {
+                let mut balance = self.currencies.get(&currency).cloned().unwrap_or_default();
+                balance.credit_fee(None, amount)?;
+                self.currencies.insert(currency, balance);
+            }
+            None => {
+                let available = add(self.available, funds::exact(amount)?)?;
+                balances(available, self.held)?;
+                self.available = available;
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the available funds and the overdraft cover the funds. An
+    // overdraft so large that they can't be added up covers anything.
+    fn covers(&self, funds: Funds) -> bool {
+        self.available
+            .checked_add(self.overdraft)
+            .is_none_or(|limit| limit >= funds)
     }
 
     pub fn try_apply_transaction(
@@ -54,60 +403,97 @@ impl Account {
         // If not, they will overwrite existing transactions.
         match transaction {
             Deposit { new_id, amount } => {
-                // If an account is frozen it can't be deposited to
-                if self.frozen {
+                // If an account is frozen fully it can't be deposited to
+                if self.freeze_level() == Some(FreezeLevel::Full) {
                     return Err(TransactionError::AccountFrozen);
                 }
+                if self.closed {
+                    return Err(TransactionError::AccountClosed);
+                }
+                let funds = funds::exact(amount)?;
+                let (available, _) = balances(add(self.available, funds)?, self.held)?;
+                // Deposits go towards the deficit first, if there is one.
+                let recovered = funds.min(self.deficit);
+                let recovered_total = add(self.recovered, recovered)?;
 
                 past_txs.insert_processed(
                     new_id,
                     ProcessedTransaction {
-                        amount: amount,
+                        amount,
                         state: Settled,
+                        disputed: None,
+                        withdrawal: false,
+                        timestamp: past_txs.timestamp(),
+                        currency: past_txs.currency(),
                     },
-                );
+                )?;
 
-                self.available = self.available + amount;
+                self.deficit -= recovered;
+                self.recovered = recovered_total;
+                self.available = available;
             }
             Withdrawal { new_id, amount } => {
                 // If an account is frozen it can't be withdrawn from
-                if self.frozen {
+                if self.is_frozen() {
                     return Err(TransactionError::AccountFrozen);
                 }
+                if self.closed {
+                    return Err(TransactionError::AccountClosed);
+                }
 
-                if self.available < amount {
+                let funds = funds::exact(amount)?;
+                if !self.covers(funds) {
                     return Err(TransactionError::InsufficientFunds);
                 }
+                let (available, _) = balances(sub(self.available, funds)?, self.held)?;
+
+                // Withdrawals are only kept if they can be disputed.
+                if past_txs.keeps_withdrawals() {
+                    past_txs.insert_processed(
+                        new_id,
+                        ProcessedTransaction {
+                            amount,
+                            state: Settled,
+                            disputed: None,
+                            withdrawal: true,
+                            timestamp: past_txs.timestamp(),
+                            currency: past_txs.currency(),
+                        },
+                    )?;
+                }
 
-                past_txs.insert_processed(
-                    new_id,
-                    ProcessedTransaction {
-                        amount: amount,
-                        state: Settled,
-                    },
-                );
-
-                self.available -= amount;
+                self.available = available;
             }
-            Dispute { id } => {
-                let processed_transaction = past_txs
-                    .find(id)
-                    .ok_or(TransactionError::NonexistentTransaction)?;
+            Dispute { id, amount } => {
+                let mut processed_transaction = past_txs.find_referred(id)?;
 
                 // A transaction can only be disputed if it is currently Settled.
                 if processed_transaction.state != Settled {
                     return Err(TransactionError::NotSettled);
                 }
+                if amount.is_some_and(|amount| amount <= TransactionAmount::ZERO) {
+                    return Err(TransactionError::NonPositiveAmount);
+                }
+                if amount.is_some_and(|amount| amount > processed_transaction.amount) {
+                    return Err(TransactionError::DisputeTooLarge);
+                }
 
                 processed_transaction.state = Disputed;
-
-                self.available -= processed_transaction.amount;
-                self.held += processed_transaction.amount;
+                processed_transaction.disputed = amount;
+                let disputed = funds::exact(processed_transaction.disputed_amount())?;
+                let holds = past_txs.holds(&processed_transaction);
+                let (available, held) = if holds {
+                    balances(sub(self.available, disputed)?, add(self.held, disputed)?)?
+                } else {
+                    (self.available, self.held)
+                };
+                past_txs.insert_processed(id, processed_transaction)?;
+
+                self.available = available;
+                self.held = held;
             }
             Resolve { id } => {
-                let processed_transaction = past_txs
-                    .find(id)
-                    .ok_or(TransactionError::NonexistentTransaction)?;
+                let mut processed_transaction = past_txs.find_referred(id)?;
 
                 // A transaction can only be resolved if it's being disputed.
                 if processed_transaction.state != Disputed {
@@ -115,14 +501,20 @@ impl Account {
                 }
 
                 processed_transaction.state = Settled;
-
-                self.available += processed_transaction.amount;
-                self.held -= processed_transaction.amount;
+                let disputed = funds::exact(processed_transaction.disputed_amount())?;
+                let holds = past_txs.holds(&processed_transaction);
+                let (available, held) = if holds {
+                    balances(add(self.available, disputed)?, sub(self.held, disputed)?)?
+                } else {
+                    (self.available, self.held)
+                };
+                past_txs.insert_processed(id, processed_transaction)?;
+
+                self.available = available;
+                self.held = held;
             }
             Chargeback { id } => {
-                let processed_transaction = past_txs
-                    .find(id)
-                    .ok_or(TransactionError::NonexistentTransaction)?;
+                let mut processed_transaction = past_txs.find_referred(id)?;
 
                 // A transaction can only be chargebacked if it's being disputed.
                 if processed_transaction.state != Disputed {
@@ -130,23 +522,259 @@ impl Account {
                 }
 
                 processed_transaction.state = ChargeBacked;
+                let disputed = funds::exact(processed_transaction.disputed_amount())?;
+                // Only the disputed part is charged back. Charging back a
+                // withdrawal that wasn't held reverses it instead.
+                let (available, held) = if past_txs.holds(&processed_transaction) {
+                    balances(self.available, sub(self.held, disputed)?)?
+                } else {
+                    balances(add(self.available, disputed)?, self.held)?
+                };
+                past_txs.insert_processed(id, processed_transaction)?;
+
+                self.freeze_at_level(FreezeReason::Chargeback(id), past_txs.chargeback_freeze());
+                self.available = available;
+                self.held = held;
+            }
+            Recovery => {
+                // Only a negative balance can be recovered.
+                if self.available >= Funds::ZERO {
+                    return Err(TransactionError::NoDeficit);
+                }
+
+                self.deficit = -self.available;
+            }
+            Fee { amount, .. } => {
+                // Frozen and closed accounts can't be charged either.
+                if self.is_frozen() {
+                    return Err(TransactionError::AccountFrozen);
+                }
+                if self.closed {
+                    return Err(TransactionError::AccountClosed);
+                }
 
-                self.frozen = true;
-                self.held -= processed_transaction.amount;
+                let funds = funds::exact(amount)?;
+                if !self.covers(funds) {
+                    return Err(TransactionError::InsufficientFunds);
+                }
+                let (available, _) = balances(sub(self.available, funds)?, self.held)?;
+
+                // Fees aren't kept, since they can't be disputed.
+                self.available = available;
+            }
+            Refund { id, amount } => {
+                // Refunds return funds like withdrawals, so frozen and
+                // closed accounts can't refund either.
+                if self.is_frozen() {
+                    return Err(TransactionError::AccountFrozen);
+                }
+                if self.closed {
+                    return Err(TransactionError::AccountClosed);
+                }
+
+                let mut processed_transaction = past_txs.find_referred(id)?;
+
+                // Only settled deposits can be refunded, and only once.
+                if processed_transaction.withdrawal {
+                    return Err(TransactionError::NotRefundable);
+                }
+                if processed_transaction.state != Settled {
+                    return Err(TransactionError::NotSettled);
+                }
+                let amount = amount.unwrap_or(processed_transaction.amount);
+                if amount <= TransactionAmount::ZERO {
+                    return Err(TransactionError::NonPositiveAmount);
+                }
+                if amount > processed_transaction.amount {
+                    return Err(TransactionError::RefundTooLarge);
+                }
+                let funds = funds::exact(amount)?;
+                if !self.covers(funds) {
+                    return Err(TransactionError::InsufficientFunds);
+                }
+                let (available, _) = balances(sub(self.available, funds)?, self.held)?;
+
+                processed_transaction.state = Refunded;
+                past_txs.insert_processed(id, processed_transaction)?;
+
+                self.available = available;
+            }
+            Lock => self.freeze(FreezeReason::Manual(LOCK_REASON.to_owned())),
+            Unlock => {
+                self.unfreeze(LOCK_REASON.to_owned());
+            }
+            // Accounts are opened by the ledger, see `Ledger::create_account`,
+            // so one that's there already can't be opened again.
+            OpenAccount { .. } => return Err(TransactionError::AccountExists),
+            // On its own, an account can only be closed if it's empty, the
+            // ledger sweeps what's left elsewhere.
+            CloseAccount => {
+                if !self.residue()?.is_empty() {
+                    return Err(TransactionError::BalanceNotZero);
+                }
+                self.close();
+            }
+            // Adjustments are made by operators, so they don't check whether
+            // the account is frozen, but closed accounts are swept empty for
+            // good.
+            AdjustCredit { new_id, amount } => {
+                if self.closed {
+                    return Err(TransactionError::AccountClosed);
+                }
+                let (available, _) =
+                    balances(add(self.available, funds::exact(amount)?)?, self.held)?;
+                past_txs.insert_processed(
+                    new_id,
+                    ProcessedTransaction {
+                        amount,
+                        state: Adjusted,
+                        disputed: None,
+                        withdrawal: false,
+                        timestamp: past_txs.timestamp(),
+                        currency: past_txs.currency(),
+                    },
+                )?;
+
+                self.available = available;
+            }
+            AdjustDebit { new_id, amount } => {
+                if self.closed {
+                    return Err(TransactionError::AccountClosed);
+                }
+                let funds = funds::exact(amount)?;
+                if !self.covers(funds) {
+                    return Err(TransactionError::InsufficientFunds);
+                }
+                let (available, _) = balances(sub(self.available, funds)?, self.held)?;
+
+                past_txs.insert_processed(
+                    new_id,
+                    ProcessedTransaction {
+                        amount,
+                        state: Adjusted,
+                        disputed: None,
+                        withdrawal: false,
+                        timestamp: past_txs.timestamp(),
+                        currency: past_txs.currency(),
+                    },
+                )?;
+
+                self.available = available;
+            }
+            Authorize { new_id, amount } => {
+                // Authorizations take funds like withdrawals do.
+                if self.is_frozen() {
+                    return Err(TransactionError::AccountFrozen);
+                }
+                if self.closed {
+                    return Err(TransactionError::AccountClosed);
+                }
+
+                let funds = funds::exact(amount)?;
+                if !self.covers(funds) {
+                    return Err(TransactionError::InsufficientFunds);
+                }
+                let (available, held) =
+                    balances(sub(self.available, funds)?, add(self.held, funds)?)?;
+
+                // Once captured, an authorization is disputed like the
+                // withdrawal it turned into.
+                past_txs.insert_processed(
+                    new_id,
+                    ProcessedTransaction {
+                        amount,
+                        state: Authorized,
+                        disputed: None,
+                        withdrawal: true,
+                        timestamp: past_txs.timestamp(),
+                        currency: past_txs.currency(),
+                    },
+                )?;
+
+                self.available = available;
+                self.held = held;
+            }
+            // Captures and voids come from the payment network rather than
+            // the client, so like disputes they apply to frozen accounts.
+            Capture { id } | Void { id } => {
+                let mut processed_transaction = past_txs.find_referred(id)?;
+
+                if processed_transaction.state != Authorized {
+                    return Err(TransactionError::NotAuthorized);
+                }
+
+                let captured = matches!(transaction, Capture { .. });
+                processed_transaction.state = if captured { Settled } else { Voided };
+                let funds = funds::exact(processed_transaction.amount)?;
+                let available = if captured {
+                    self.available
+                } else {
+                    add(self.available, funds)?
+                };
+                let (available, held) = balances(available, sub(self.held, funds)?)?;
+                past_txs.insert_processed(id, processed_transaction)?;
+
+                self.available = available;
+                self.held = held;
+            }
+            // The ledger keeps scheduled transactions until they take
+            // effect and limits withdrawals by their time, an account on
+            // its own applies them right away.
+            Scheduled { transaction, .. } | Timestamped { transaction, .. } => {
+                return self.try_apply_transaction(past_txs, *transaction);
+            }
+            // The balance in the currency is frozen along with the account,
+            // and chargebacks in it freeze the account.
+            InCurrency {
+                currency,
+                transaction,
+            } => {
+                let mut balance = self.currencies.get(&currency).cloned().unwrap_or_default();
+                balance.frozen = self.frozen.take();
+                balance.freeze_level = self.freeze_level;
+                balance.overdraft = self.overdraft;
+                balance.closed = self.closed;
+                let previous = past_txs.set_currency(Some(currency));
+                let applied = balance.try_apply_transaction(past_txs, *transaction);
+                past_txs.set_currency(previous);
+                self.frozen = balance.frozen.take();
+                self.freeze_level = balance.freeze_level;
+                balance.freeze_level = FreezeLevel::Full;
+                applied?;
+                self.currencies.insert(currency, balance);
             }
         };
 
-        return Ok(());
+        Ok(())
     }
 }
 
+// Full freezes, the default, are left out of snapshots.
+fn is_full(level: &FreezeLevel) -> bool {
+    *level == FreezeLevel::Full
+}
+
+fn add(funds: Funds, other: Funds) -> Result<Funds, TransactionError> {
+    funds.checked_add(other).ok_or(TransactionError::Overflow)
+}
+
+fn sub(funds: Funds, other: Funds) -> Result<Funds, TransactionError> {
+    funds.checked_sub(other).ok_or(TransactionError::Overflow)
+}
+
+// The available and held funds, or `Overflow` if their total can't be held.
+fn balances(available: Funds, held: Funds) -> Result<(Funds, Funds), TransactionError> {
+    add(available, held)?;
+    Ok((available, held))
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
         account::TransactionError::*, ledger::ProcessedTxsForAccount, Balance, Transaction::*,
     };
 
-    use super::Account;
+    use super::{Account, FreezeReason};
 
     fn verify_account<T: Into<Balance>>(account: &Account, available: T, held: T, is_frozen: bool) {
         let available = available.into();
@@ -155,7 +783,7 @@ mod tests {
         assert_eq!(account.available(), available);
         assert_eq!(account.held(), held);
         assert_eq!(account.is_frozen(), is_frozen);
-        assert_eq!(account.total(), available + held);
+        assert_eq!(account.total(), Ok(available + held));
     }
 
     fn setup() -> (Account, ProcessedTxsForAccount<'static>) {
@@ -246,6 +874,34 @@ mod tests {
         verify_account(&account, 6, 0, false);
     }
 
+    #[test]
+    fn overdraft_within_limit() {
+        let (account, ref mut past_txs) = setup();
+        let mut account = account.with_overdraft(5.into()).unwrap();
+
+        assert!(account
+            .try_apply_transaction(
+                past_txs,
+                Withdrawal {
+                    new_id: 1,
+                    amount: 4.into()
+                }
+            )
+            .is_ok());
+        assert_eq!(
+            account.try_apply_transaction(
+                past_txs,
+                Withdrawal {
+                    new_id: 2,
+                    amount: 2.into()
+                }
+            ),
+            Err(InsufficientFunds)
+        );
+
+        verify_account(&account, -4, 0, false);
+    }
+
     #[test]
     fn frozen_account() {
         let (mut account, ref mut past_txs) = setup();
@@ -259,7 +915,7 @@ mod tests {
                 }
             )
             .is_ok());
-        account.frozen = true;
+        account.freeze(FreezeReason::Manual("test".to_owned()));
         assert_eq!(
             account.try_apply_transaction(
                 past_txs,
@@ -298,7 +954,13 @@ mod tests {
             )
             .is_ok());
         assert!(account
-            .try_apply_transaction(past_txs, Dispute { id: 1 })
+            .try_apply_transaction(
+                past_txs,
+                Dispute {
+                    id: 1,
+                    amount: None
+                }
+            )
             .is_ok());
 
         // The deposit is disputed, it should be shown as held
@@ -331,7 +993,13 @@ mod tests {
 
         // Referring to transactions that don't exist
         assert_eq!(
-            account.try_apply_transaction(past_txs, Dispute { id: 10 }),
+            account.try_apply_transaction(
+                past_txs,
+                Dispute {
+                    id: 10,
+                    amount: None
+                }
+            ),
             Err(NonexistentTransaction)
         );
         assert_eq!(
@@ -354,10 +1022,22 @@ mod tests {
             )
             .is_ok());
         assert!(account
-            .try_apply_transaction(past_txs, Dispute { id: 1 })
+            .try_apply_transaction(
+                past_txs,
+                Dispute {
+                    id: 1,
+                    amount: None
+                }
+            )
             .is_ok());
         assert_eq!(
-            account.try_apply_transaction(past_txs, Dispute { id: 1 }),
+            account.try_apply_transaction(
+                past_txs,
+                Dispute {
+                    id: 1,
+                    amount: None
+                }
+            ),
             Err(NotSettled)
         );
 
@@ -397,7 +1077,13 @@ mod tests {
         verify_account(&account, 25, 0, false);
 
         assert!(account
-            .try_apply_transaction(past_txs, Dispute { id: 1 })
+            .try_apply_transaction(
+                past_txs,
+                Dispute {
+                    id: 1,
+                    amount: None
+                }
+            )
             .is_ok());
 
         verify_account(&account, 15, 10, false);
@@ -407,6 +1093,7 @@ mod tests {
             .is_ok());
 
         verify_account(&account, 15, 0, true);
+        assert_eq!(account.freeze_reason(), Some(&FreezeReason::Chargeback(1)));
 
         // At this point no new deposits or withdrawals can be made
         assert_eq!(
@@ -434,7 +1121,13 @@ mod tests {
 
         // But existing transactions can still be disputed...
         assert!(account
-            .try_apply_transaction(past_txs, Dispute { id: 2 })
+            .try_apply_transaction(
+                past_txs,
+                Dispute {
+                    id: 2,
+                    amount: None
+                }
+            )
             .is_ok());
         verify_account(&account, 0, 15, true);
         // ... and resolved
@@ -444,6 +1137,161 @@ mod tests {
         verify_account(&account, 15, 0, true);
     }
 
+    #[test]
+    fn freeze_and_unfreeze() {
+        let (mut account, ref mut past_txs) = setup();
+
+        account.freeze(FreezeReason::Manual("suspicious activity".to_owned()));
+        // Freezing again keeps the original reason
+        account.freeze(FreezeReason::Manual("something else".to_owned()));
+        assert_eq!(
+            account.freeze_reason(),
+            Some(&FreezeReason::Manual("suspicious activity".to_owned()))
+        );
+        assert_eq!(
+            account.try_apply_transaction(
+                past_txs,
+                Deposit {
+                    new_id: 1,
+                    amount: 10.into()
+                }
+            ),
+            Err(AccountFrozen)
+        );
+
+        assert_eq!(
+            account.unfreeze("cleared".to_owned()),
+            Some(FreezeReason::Manual("suspicious activity".to_owned()))
+        );
+        assert_eq!(account.unfreeze("again".to_owned()), None);
+        assert_eq!(account.unfreeze_reason(), Some("cleared"));
+        assert!(account
+            .try_apply_transaction(
+                past_txs,
+                Deposit {
+                    new_id: 1,
+                    amount: 10.into()
+                }
+            )
+            .is_ok());
+        verify_account(&account, 10, 0, false);
+    }
+
+    #[test]
+    fn recovery() {
+        let (mut account, ref mut past_txs) = setup();
+
+        assert_eq!(
+            account.try_apply_transaction(past_txs, Recovery),
+            Err(NoDeficit)
+        );
+
+        // Withdraw everything then dispute the deposit, leaving the account
+        // with a negative balance.
+        for tx in [
+            Deposit {
+                new_id: 1,
+                amount: 10.into(),
+            },
+            Withdrawal {
+                new_id: 2,
+                amount: 10.into(),
+            },
+            Dispute {
+                id: 1,
+                amount: None,
+            },
+        ] {
+            assert!(account.try_apply_transaction(past_txs, tx).is_ok());
+        }
+        verify_account(&account, -10, 10, false);
+
+        assert!(account.try_apply_transaction(past_txs, Recovery).is_ok());
+        assert_eq!(account.deficit(), 10.into());
+        assert_eq!(account.recovered(), 0.into());
+
+        // The first deposit goes entirely towards the deficit...
+        assert!(account
+            .try_apply_transaction(
+                past_txs,
+                Deposit {
+                    new_id: 3,
+                    amount: 4.into()
+                }
+            )
+            .is_ok());
+        verify_account(&account, -6, 10, false);
+        assert_eq!(account.deficit(), 6.into());
+        assert_eq!(account.recovered(), 4.into());
+
+        // ... and the second one only partially.
+        assert!(account
+            .try_apply_transaction(
+                past_txs,
+                Deposit {
+                    new_id: 4,
+                    amount: 8.into()
+                }
+            )
+            .is_ok());
+        verify_account(&account, 2, 10, false);
+        assert_eq!(account.deficit(), 0.into());
+        assert_eq!(account.recovered(), 10.into());
+    }
+
+    #[test]
+    fn overflow() {
+        let (mut account, ref mut past_txs) = setup();
+        let max = crate::funds::max();
+
+        let deposit = |new_id, amount| Deposit { new_id, amount };
+        assert!(account
+            .try_apply_transaction(past_txs, deposit(1, max))
+            .is_ok());
+        assert_eq!(
+            account.try_apply_transaction(past_txs, deposit(2, 1.into())),
+            Err(Overflow)
+        );
+        verify_account(&account, max, 0.into(), false);
+
+        // The total can't go beyond the limit either, even if available and
+        // held funds stay within it.
+        assert!(account
+            .try_apply_transaction(
+                past_txs,
+                Dispute {
+                    id: 1,
+                    amount: None
+                }
+            )
+            .is_ok());
+        assert_eq!(
+            account.try_apply_transaction(past_txs, deposit(3, 1.into())),
+            Err(Overflow)
+        );
+        verify_account(&account, 0.into(), max, false);
+
+        // The deposits that overflowed weren't kept.
+        assert_eq!(
+            account.try_apply_transaction(
+                past_txs,
+                Dispute {
+                    id: 2,
+                    amount: None
+                }
+            ),
+            Err(NonexistentTransaction)
+        );
+        assert!(account
+            .try_apply_transaction(past_txs, Resolve { id: 1 })
+            .is_ok());
+        verify_account(&account, max, 0.into(), false);
+
+        // Credited fees are limited the same way.
+        assert_eq!(account.credit_fee(None, 1.into()), Err(Overflow));
+        verify_account(&account, max, 0.into(), false);
+    }
+
     #[test]
     fn chargebacked_transaction_is_final() {
         let (mut account, ref mut past_txs) = setup();
@@ -458,14 +1306,26 @@ mod tests {
             )
             .is_ok());
         assert!(account
-            .try_apply_transaction(past_txs, Dispute { id: 1 })
+            .try_apply_transaction(
+                past_txs,
+                Dispute {
+                    id: 1,
+                    amount: None
+                }
+            )
             .is_ok());
         assert!(account
             .try_apply_transaction(past_txs, Chargeback { id: 1 })
             .is_ok());
 
         assert_eq!(
-            account.try_apply_transaction(past_txs, Dispute { id: 1 }),
+            account.try_apply_transaction(
+                past_txs,
+                Dispute {
+                    id: 1,
+                    amount: None
+                }
+            ),
             Err(NotSettled)
         );
         assert_eq!(
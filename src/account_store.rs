@@ -0,0 +1,224 @@
+use std::{collections::HashMap, hash::BuildHasher};
+
+use crate::{account::Account, AccountId, TransactionError};
+
+// AccountStore keeps a ledger's accounts. A ledger keeps them in a `HashMap`
+// by default, which is what batch runs want. Servers can keep them somewhere
+// that outlives the process instead, e.g. an embedded key-value store or a
+// database, see `LedgerBuilder::account_store`.
+//
+// Accounts are read by reference, so a store has to keep them in memory,
+// which is cheap: there can't be more accounts than there are client IDs.
+// The ledger never changes an account in place, every change is written
+// back with `insert`, so a store can write it through to its backing
+// storage before returning. If that fails, the store fails with
+// `StoreFailed`, and the change is rejected with it.
+pub trait AccountStore: Send {
+    fn get(&self, client: AccountId) -> Option<&Account>;
+
+    // Add the account, or replace the client's account with it. If it fails,
+    // the store is left as it was.
+    fn insert(&mut self, client: AccountId, account: Account) -> Result<(), TransactionError>;
+
+    // Every account in the store with its client ID, in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (AccountId, &Account)> + '_>;
+
+    fn clear(&mut self);
+
+    // Make room for `additional` more accounts, if the store can, see
+    // `LedgerBuilder::expected_transactions`.
+    fn reserve(&mut self, _additional: usize) {}
+}
+
+impl<S: BuildHasher + Send> AccountStore for HashMap<AccountId, Account, S> {
+    fn get(&self, client: AccountId) -> Option<&Account> {
+        HashMap::get(self, &client)
+    }
+
+    fn insert(&mut self, client: AccountId, account: Account) -> Result<(), TransactionError> {
+        HashMap::insert(self, client, account);
+        Ok(())
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (AccountId, &Account)> + '_> {
+        Box::new(HashMap::iter(self).map(|(&client, account)| (client, account)))
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        HashMap::reserve(self, additional)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use super::AccountStore;
+    use crate::{
+        account::{Account, FreezeReason},
+        ledger::Ledger,
+        AccountId, Transaction, TransactionError,
+    };
+
+    // A store that writes every account through to a shared map, standing
+    // in for a database.
+    #[derive(Default)]
+    struct WriteThrough {
+        cache: HashMap<AccountId, Account>,
+        written: Arc<Mutex<Vec<AccountId>>>,
+    }
+
+    impl AccountStore for WriteThrough {
+        fn get(&self, client: AccountId) -> Option<&Account> {
+            self.cache.get(&client)
+        }
+
+        fn insert(&mut self, client: AccountId, account: Account) -> Result<(), TransactionError> {
+            self.written.lock().unwrap().push(client);
+            self.cache.insert(client, account);
+            Ok(())
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = (AccountId, &Account)> + '_> {
+            AccountStore::iter(&self.cache)
+        }
+
+        fn clear(&mut self) {
+            self.cache.clear()
+        }
+    }
+
+    // A store that can't write the accounts of one client, like a database
+    // rejecting them.
+    struct Failing {
+        accounts: HashMap<AccountId, Account>,
+        client: AccountId,
+    }
+
+    impl AccountStore for Failing {
+        fn get(&self, client: AccountId) -> Option<&Account> {
+            self.accounts.get(&client)
+        }
+
+        fn insert(&mut self, client: AccountId, account: Account) -> Result<(), TransactionError> {
+            if client == self.client {
+                return Err(TransactionError::StoreFailed);
+            }
+            self.accounts.insert(client, account);
+            Ok(())
+        }
+
+        fn iter(&self) -> Box<dyn Iterator<Item = (AccountId, &Account)> + '_> {
+            AccountStore::iter(&self.accounts)
+        }
+
+        fn clear(&mut self) {
+            self.accounts.clear()
+        }
+    }
+
+    #[test]
+    fn write_through() {
+        let store = WriteThrough::default();
+        let written = store.written.clone();
+        let mut ledger = Ledger::builder().account_store(store).build();
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,5
+withdrawal,1,3,20
+dispute,1,1,
+";
+        ledger.read_csv(input.as_bytes());
+        ledger
+            .freeze_account(2, FreezeReason::Manual("review".into()))
+            .unwrap();
+
+        // Rejected transactions aren't written.
+        assert_eq!(*written.lock().unwrap(), [1, 2, 1, 2]);
+        assert_eq!(ledger.account(1).unwrap().held(), 10.into());
+        assert!(ledger.account(2).unwrap().is_frozen());
+        assert_eq!(
+            ledger.apply(
+                2,
+                Transaction::Deposit {
+                    new_id: 4,
+                    amount: 1.into()
+                }
+            ),
+            Err(TransactionError::AccountFrozen)
+        );
+        assert_eq!(ledger.accounts().count(), 2);
+    }
+
+    #[test]
+    fn edge_cases() {
+        let store = WriteThrough::default();
+        let written = store.written.clone();
+        let mut ledger = Ledger::builder()
+            .account_store(store)
+            .sweep_account(9)
+            .build();
+
+        // Client 1's deposit too large for funds to hold, client 3's refund
+        // of a withdrawal, and the deposit into client 2's closed account
+        // leave the accounts as they were, so they aren't written, and
+        // neither are malformed lines. Closing the account writes both it and
+        // the sweep account.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,{max}
+deposit,2,3,5
+close_account,2,0,
+deposit,2,4,1
+deposit,3,5,10
+withdrawal,3,6,4
+refund,3,6,
+deposit,4,7,x
+"
+        );
+        ledger.read_csv(input.as_bytes());
+        assert_eq!(*written.lock().unwrap(), [1, 2, 2, 9, 3, 3]);
+        assert!(ledger.account(2).unwrap().is_closed());
+        assert_eq!(ledger.account(9).unwrap().total().unwrap(), 5.into());
+        assert!(ledger.account(4).is_none());
+    }
+
+    #[test]
+    fn failing_store() {
+        use crate::{ledger::Reject, scaling::AmountScale};
+
+        let store = Failing {
+            accounts: HashMap::new(),
+            client: 9,
+        };
+        let mut ledger = Ledger::builder()
+            .account_store(store)
+            .sweep_account(9)
+            .build();
+        let mut rejects = vec![];
+        ledger.read_csv_with(
+            "type,client,tx,amount\ndeposit,1,1,10\ndeposit,9,2,1\nclose_account,1,0,\n".as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+
+        // Changes that can't be written are rejected: client 9's account
+        // isn't opened, and client 1's stays open, since its balance can't
+        // be swept.
+        assert_eq!(rejects, ["store_failed", "store_failed"]);
+        assert!(ledger.account(9).is_none());
+        assert!(!ledger.account(1).unwrap().is_closed());
+        assert_eq!(ledger.account(1).unwrap().available(), 10.into());
+    }
+}
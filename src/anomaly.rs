@@ -0,0 +1,367 @@
+use std::{collections::HashMap, str::FromStr};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    ledger::{deserialize_decimal, Ledger, Precision},
+    AccountId, Balance, Transaction,
+};
+
+// Anomaly detection compares every client's behavior in this run to a
+// baseline snapshot of an earlier one, e.g. the previous period, and flags
+// the clients whose behavior changed sharply so that they can be reviewed.
+// The snapshot is written by one run and read by the next, just like the
+// closing balances are carried forward.
+
+// The factor a metric has to change by, in either direction, to be flagged
+// when none is given.
+pub const DEFAULT_FACTOR: u32 = 3;
+
+// Behavior summarises the transactions applied to a client's account in one
+// run:
+// * volume: the number of deposits and withdrawals.
+// * average_amount: their average amount.
+// * dispute_rate: the number of disputes per deposit or withdrawal.
+// Rejected transactions don't count. Averages and rates are rounded like
+// balances. Amounts too large to add up are averaged by adding up their
+// shares of the average instead, and clients whose shares don't add up
+// either are left out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Behavior {
+    pub client: AccountId,
+    pub volume: u64,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub average_amount: Balance,
+    #[serde(deserialize_with = "deserialize_decimal")]
+    pub dispute_rate: Decimal,
+}
+
+// The behavior of every client with an account in the ledger, sorted by
+// client ID. It's derived from the statements, so the ledger has to be built
+// with `keep_statements`.
+pub fn behaviors(ledger: &Ledger) -> Vec<Behavior> {
+    let precision = Precision::default();
+    let mut behaviors = ledger
+        .statements()
+        .filter_map(|(client, entries)| {
+            let amounts = entries.iter().filter_map(|entry| match &entry.transaction {
+                Some(Transaction::Deposit { amount, .. })
+                | Some(Transaction::Withdrawal { amount, .. }) => Some(*amount),
+                _ => None,
+            });
+            let volume = amounts.clone().count() as u64;
+            let disputes = entries
+                .iter()
+                .filter(|entry| matches!(entry.transaction, Some(Transaction::Dispute { .. })))
+                .count();
+            let (average_amount, dispute_rate) = match volume {
+                0 => (Balance::ZERO, Decimal::ZERO),
+                _ => {
+                    let volume = Decimal::from(volume);
+                    let average = amounts
+                        .clone()
+                        .try_fold(Balance::ZERO, Balance::checked_add)
+                        .map(|sum| sum / volume)
+                        .or_else(|| {
+                            amounts.clone().try_fold(Balance::ZERO, |sum, amount| {
+                                sum.checked_add(amount / volume)
+                            })
+                        })?;
+                    (average, Decimal::from(disputes) / volume)
+                }
+            };
+            Some(Behavior {
+                client,
+                volume,
+                average_amount: precision.apply(average_amount),
+                dispute_rate: precision.apply(dispute_rate),
+            })
+        })
+        .collect::<Vec<_>>();
+    behaviors.sort_by_key(|behavior| behavior.client);
+    behaviors
+}
+
+// Write the behaviors formatted as CSV to the given writer, in the format
+// read by `read_baseline`.
+pub fn behaviors_to_csv<W: std::io::Write>(behaviors: &[Behavior], output: W) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(true)
+        .from_writer(output);
+    for behavior in behaviors {
+        writer.serialize(behavior)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Read a baseline snapshot written by `behaviors_to_csv`, by client ID.
+pub fn read_baseline<R: std::io::Read>(reader: R) -> csv::Result<HashMap<AccountId, Behavior>> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader)
+        .deserialize::<Behavior>()
+        .map(|behavior| behavior.map(|behavior| (behavior.client, behavior)))
+        .collect()
+}
+
+#[derive(Error, Debug)]
+#[error("invalid anomaly factor {0:?}, expected a number greater than 1")]
+pub struct ParseFactorError(String);
+
+// Factor is how much a metric has to change by, in either direction, to be
+// flagged: a factor of 3 flags a client whose volume tripled, or dropped to a
+// third. Metrics that were zero in the baseline are flagged as soon as they
+// aren't anymore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Factor(Decimal);
+
+impl Default for Factor {
+    fn default() -> Self {
+        Factor(DEFAULT_FACTOR.into())
+    }
+}
+
+impl FromStr for Factor {
+    type Err = ParseFactorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Decimal::from_str(s) {
+            Ok(factor) if factor > Decimal::ONE => Ok(Factor(factor)),
+            _ => Err(ParseFactorError(s.to_owned())),
+        }
+    }
+}
+
+impl Factor {
+    // Metrics scaled beyond what decimals hold are beyond any other metric,
+    // so they can't be exceeded, nor fall short of one.
+    fn deviates(&self, baseline: Decimal, current: Decimal) -> bool {
+        let above = baseline
+            .checked_mul(self.0)
+            .is_some_and(|limit| current > limit);
+        let below = current
+            .checked_mul(self.0)
+            .is_some_and(|scaled| scaled < baseline);
+        above || below
+    }
+}
+
+// Anomaly is a metric of a client's behavior that deviates sharply from its
+// baseline.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Anomaly {
+    pub client: AccountId,
+    pub metric: &'static str,
+    pub baseline: Decimal,
+    pub current: Decimal,
+}
+
+// Compare the current behaviors to the baseline, returning every metric
+// that deviates from it by more than `factor`, in the order of `current`.
+// Clients that aren't in the baseline have nothing to compare to, and clients
+// that are only in the baseline have no behavior to flag, so neither appear.
+pub fn detect(
+    baseline: &HashMap<AccountId, Behavior>,
+    current: &[Behavior],
+    factor: Factor,
+) -> Vec<Anomaly> {
+    let mut anomalies = vec![];
+    for behavior in current {
+        let Some(baseline) = baseline.get(&behavior.client) else {
+            continue;
+        };
+        let metrics = [
+            ("volume", baseline.volume.into(), behavior.volume.into()),
+            (
+                "average_amount",
+                baseline.average_amount,
+                behavior.average_amount,
+            ),
+            ("dispute_rate", baseline.dispute_rate, behavior.dispute_rate),
+        ];
+        for (metric, baseline, current) in metrics {
+            if factor.deviates(baseline, current) {
+                anomalies.push(Anomaly {
+                    client: behavior.client,
+                    metric,
+                    baseline,
+                    current,
+                });
+            }
+        }
+    }
+    anomalies
+}
+
+// Write the anomalies formatted as CSV to the given writer, with the columns
+// client, metric, baseline, and current.
+pub fn anomalies_to_csv<W: std::io::Write>(anomalies: &[Anomaly], output: W) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(true)
+        .from_writer(output);
+    for anomaly in anomalies {
+        writer.serialize(anomaly)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::{behaviors, behaviors_to_csv, detect, read_baseline, Behavior, Factor};
+    use crate::ledger::{Ledger, Precision};
+
+    #[test]
+    fn detect_anomalies() {
+        let baseline = "\
+client,volume,average_amount,dispute_rate
+1,2,10.0000,0.0000
+2,4,5.0000,0.2500
+3,1,100.0000,0.0000
+4,1,1.0000,0.0000
+";
+        let baseline = read_baseline(baseline.as_bytes()).unwrap();
+
+        // Client 1 deposits a lot more often, client 2 starts withdrawing
+        // much larger amounts, client 3 disputes for the first time, and
+        // client 5 isn't in the baseline.
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,10
+deposit,1,3,10
+deposit,1,4,10
+deposit,1,5,10
+deposit,1,6,10
+deposit,1,7,10
+deposit,2,8,100
+withdrawal,2,9,60
+dispute,2,8,
+deposit,3,10,150
+dispute,3,10,
+deposit,4,11,2
+deposit,5,12,1000
+";
+        let mut ledger = Ledger::builder().keep_statements().build();
+        ledger.read_csv(input.as_bytes());
+        let current = behaviors(&ledger);
+
+        let mut snapshot = vec![];
+        behaviors_to_csv(&current, &mut snapshot).unwrap();
+        assert_eq!(
+            String::from_utf8(snapshot).unwrap(),
+            "\
+client,volume,average_amount,dispute_rate
+1,7,10.0000,0.0000
+2,2,80.0000,0.5000
+3,1,150.0000,1.0000
+4,1,2.0000,0.0000
+5,1,1000.0000,0.0000
+"
+        );
+
+        let anomalies = detect(&baseline, &current, Factor::default())
+            .into_iter()
+            .map(|anomaly| {
+                format!(
+                    "{},{},{},{}",
+                    anomaly.client, anomaly.metric, anomaly.baseline, anomaly.current
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            anomalies,
+            [
+                "1,volume,2,7",
+                "2,average_amount,5,80.0000",
+                "3,dispute_rate,0,1.0000",
+            ]
+        );
+
+        assert!("1".parse::<Factor>().is_err());
+        assert!("1.5".parse::<Factor>().is_ok());
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Client 1 moves amounts that may add up to more than a decimal
+        // holds, client 2 closes their account and then deposits into it,
+        // and client 3 only deposits, and disputes, zero.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+withdrawal,1,2,{max}
+deposit,1,3,{max}
+deposit,2,4,5
+withdrawal,2,5,5
+close_account,2,6,
+deposit,2,7,50
+deposit,3,8,0
+dispute,3,8,
+"
+        );
+        let mut ledger = Ledger::builder().keep_statements().build();
+        ledger.read_csv(input.as_bytes());
+        let current = behaviors(&ledger);
+        // Amounts that don't add up are still averaged exactly.
+        let expected = [
+            (3, Precision::default().apply(max)),
+            (2, 5.into()),
+            (1, 0.into()),
+        ];
+        let volumes = current
+            .iter()
+            .map(|behavior| (behavior.volume, behavior.average_amount))
+            .collect::<Vec<_>>();
+        assert_eq!(volumes, expected);
+        assert_eq!(current[2].dispute_rate, Decimal::ONE);
+
+        // The snapshot is read back exactly, however long its averages.
+        let mut snapshot = vec![];
+        behaviors_to_csv(&current, &mut snapshot).unwrap();
+        let baseline = read_baseline(snapshot.as_slice()).unwrap();
+        assert_eq!(baseline[&1], current[0]);
+
+        let malformed = "client,volume,average_amount,dispute_rate\n1,x,1,0\n";
+        assert!(read_baseline(malformed.as_bytes()).is_err());
+        let missing = "client,volume\n1,2\n";
+        assert!(read_baseline(missing.as_bytes()).is_err());
+
+        // Baselines too large to scale by the factor can't be exceeded, but
+        // current behavior can still fall short of them, and the other way
+        // around.
+        let baseline = format!(
+            "\
+client,volume,average_amount,dispute_rate
+1,1,{max},0
+2,1,{max},0
+3,1,1,0
+",
+            max = Decimal::MAX
+        );
+        let baseline = read_baseline(baseline.as_bytes()).unwrap();
+        let behavior = |client, average_amount| Behavior {
+            client,
+            volume: 1,
+            average_amount,
+            dispute_rate: Decimal::ZERO,
+        };
+        let current = [
+            behavior(1, Decimal::MAX),
+            behavior(2, Decimal::ONE),
+            behavior(3, Decimal::MAX),
+        ];
+        let anomalies = detect(&baseline, &current, Factor::default())
+            .into_iter()
+            .map(|anomaly| (anomaly.client, anomaly.metric))
+            .collect::<Vec<_>>();
+        assert_eq!(anomalies, [(2, "average_amount"), (3, "average_amount")]);
+    }
+}
@@ -0,0 +1,89 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+// The temporary file is created next to `path`, since renaming only replaces
+// a file atomically within the same filesystem. The process ID keeps
+// concurrent runs writing to the same path from sharing it.
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or(path.as_os_str()));
+    name.push(format!(".{}.tmp", std::process::id()));
+    path.with_file_name(name)
+}
+
+// Write the file at `path` so that it only ever holds either its previous
+// contents or the complete new ones: `write` writes to a temporary file,
+// which replaces `path` once it's complete and synced to disk. If `write`,
+// or replacing `path`, fails the temporary file is removed and `path` is
+// left untouched. If the process is interrupted, the temporary file may be
+// left behind instead.
+pub fn write_atomically<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut File) -> io::Result<()>,
+{
+    let temporary_path = temporary_path(path);
+    let result = File::create(&temporary_path)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temporary_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io, io::Write};
+
+    use super::{temporary_path, write_atomically};
+
+    #[test]
+    fn replaces_only_when_complete() {
+        let dir = std::env::temp_dir().join(format!("ledger-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("accounts.csv");
+
+        write_atomically(&path, |file| file.write_all(b"first")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+
+        let result = write_atomically(&path, |file| {
+            file.write_all(b"partial")?;
+            Err(io::Error::other("interrupted"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+        assert!(!temporary_path(&path).exists());
+
+        write_atomically(&path, |file| file.write_all(b"second")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edge_cases() {
+        let dir = std::env::temp_dir().join(format!("ledger-atomic-edges-{}", std::process::id()));
+        fs::create_dir_all(dir.join("accounts.csv")).unwrap();
+
+        // A directory can't be replaced by a file, and nothing is left
+        // behind trying.
+        let path = dir.join("accounts.csv");
+        assert!(write_atomically(&path, |file| file.write_all(b"first")).is_err());
+        assert!(path.is_dir());
+        assert!(!temporary_path(&path).exists());
+
+        // Nor can a file be written to a directory that doesn't exist.
+        let path = dir.join("missing").join("accounts.csv");
+        assert!(write_atomically(&path, |file| file.write_all(b"first")).is_err());
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
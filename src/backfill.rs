@@ -0,0 +1,235 @@
+use std::{
+    cell::Cell,
+    fmt,
+    io::Read,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error_sink::ErrorSink,
+    ledger::{read_csv_into, Reject},
+    policy::ErrorPolicy,
+    scaling::AmountScale,
+    shared::{SharedLedger, Submitted},
+};
+
+// Backfill reprocesses a file of historical transactions on a live server,
+// e.g. one that was missed while a feed was down, without degrading the
+// latency of live traffic: records are applied at a bounded rate, and at a
+// lower priority than every live operation, see
+// `SharedLedger::apply_background`. Progress is reported periodically while
+// it runs.
+pub struct Backfill {
+    // The most records applied per second.
+    rate: u32,
+    report_every: Duration,
+}
+
+// How often progress is reported by default.
+const REPORT_EVERY: Duration = Duration::from_secs(10);
+
+// BackfillProgress counts the records of a backfill so far. Records of
+// paused accounts are queued, see `SharedLedger::pause`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BackfillProgress {
+    pub applied: u64,
+    pub queued: u64,
+    pub rejected: u64,
+    pub elapsed: Duration,
+}
+
+impl BackfillProgress {
+    pub fn records(&self) -> u64 {
+        self.applied + self.queued + self.rejected
+    }
+}
+
+impl fmt::Display for BackfillProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} records in {}s: {} applied, {} queued, {} rejected",
+            self.records(),
+            self.elapsed.as_secs(),
+            self.applied,
+            self.queued,
+            self.rejected
+        )
+    }
+}
+
+impl Backfill {
+    // A backfill applying at most `rate` records per second.
+    pub fn new(rate: u32) -> Backfill {
+        Backfill {
+            rate: rate.max(1),
+            report_every: REPORT_EVERY,
+        }
+    }
+
+    pub fn report_every(self, report_every: Duration) -> Backfill {
+        Backfill {
+            report_every,
+            ..self
+        }
+    }
+
+    // Apply every transaction read from the given CSV reader to the ledger,
+    // like `Ledger::read_csv_with`, passing every line that's skipped to
+    // `sink`, and the progress so far to `report` every so often. Returns
+    // once the whole file is applied, with the final progress.
+    pub fn run<R, S, F>(
+        &self,
+        ledger: &SharedLedger,
+        reader: R,
+        scale: AmountScale,
+        mut sink: S,
+        mut report: F,
+    ) -> BackfillProgress
+    where
+        R: Read,
+        S: ErrorSink,
+        F: FnMut(&BackfillProgress),
+    {
+        let start = Instant::now();
+        let mut last_report = start;
+        let mut progress = BackfillProgress::default();
+        // Rejected lines are counted by the sink, which doesn't see the rest.
+        let rejected = Cell::new(0);
+
+        let sink = |reject: Reject| {
+            rejected.set(rejected.get() + 1);
+            sink.on_reject(reject);
+        };
+        let amounts = ledger.with(|ledger| ledger.policy().amounts);
+        read_csv_into(
+            reader,
+            scale,
+            sink,
+            ErrorPolicy::Skip,
+            amounts,
+            |client, tx| {
+                progress.rejected = rejected.get();
+                // Every record has its slot, so a slow record doesn't let the
+                // next ones go faster than the rate.
+                let slot = start
+                    + Duration::from_secs_f64(progress.records() as f64 / f64::from(self.rate));
+                if let Some(wait) = slot.checked_duration_since(Instant::now()) {
+                    thread::sleep(wait);
+                }
+
+                let result = ledger.apply_background(client, tx);
+                match result {
+                    Ok(Submitted::Applied) => progress.applied += 1,
+                    Ok(Submitted::Queued) => progress.queued += 1,
+                    Err(_) => {}
+                }
+
+                if last_report.elapsed() >= self.report_every {
+                    last_report = Instant::now();
+                    progress.elapsed = start.elapsed();
+                    report(&progress);
+                }
+                result.map(drop)
+            },
+        );
+
+        progress.rejected = rejected.get();
+        progress.elapsed = start.elapsed();
+        progress
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Backfill, BackfillProgress};
+    use crate::{ledger::Reject, scaling::AmountScale, shared::SharedLedger};
+
+    #[test]
+    fn throttled() {
+        let ledger = SharedLedger::default();
+        ledger.pause(2);
+        let mut input = "type,client,tx,amount\n".to_owned();
+        for tx in 0..20 {
+            input += &format!("deposit,{},{},1\n", tx % 2 + 1, tx);
+        }
+        input += "withdrawal,1,20,100\nfoo,1,21,1\n";
+
+        let mut rejects = vec![];
+        let mut reports = vec![];
+        let progress = Backfill::new(200)
+            .report_every(Duration::from_millis(20))
+            .run(
+                &ledger,
+                input.as_bytes(),
+                AmountScale::default(),
+                |reject: Reject| rejects.push(reject.reason),
+                |progress: &BackfillProgress| reports.push(*progress),
+            );
+
+        // 21 records at 200 per second take at least 100 milliseconds.
+        assert!(progress.elapsed >= Duration::from_millis(100));
+        assert_eq!(
+            (progress.applied, progress.queued, progress.rejected),
+            (10, 10, 2)
+        );
+        assert_eq!(rejects, ["insufficient_funds", "malformed"]);
+        assert!(!reports.is_empty());
+        assert!(reports.windows(2).all(|w| w[0].records() <= w[1].records()));
+        assert_eq!(ledger.account(1).unwrap().total().unwrap(), 10.into());
+        assert!(ledger.account(2).is_none());
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Client 1 deposits more than funds can hold, client 2 deposits into
+        // their closed account, and client 3 refunds a withdrawal, and then
+        // sends lines that can't be read.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,{max}
+deposit,2,3,5
+withdrawal,2,4,5
+close_account,2,0,
+deposit,2,5,1
+deposit,3,6,10
+withdrawal,3,7,4
+refund,3,7,
+deposit,3,8,x
+deposit,3,9,
+deposit,3
+"
+        );
+        let ledger = SharedLedger::default();
+        let mut rejects = vec![];
+        let progress = Backfill::new(u32::MAX).run(
+            &ledger,
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+            |_: &BackfillProgress| {},
+        );
+
+        assert_eq!(
+            rejects,
+            [
+                "overflow",
+                "account_closed",
+                "not_refundable",
+                "malformed",
+                "missing_amount",
+                "malformed",
+            ]
+        );
+        assert_eq!((progress.applied, progress.rejected), (6, 6));
+        assert_eq!(ledger.account(1).unwrap().total().unwrap(), max);
+        assert!(ledger.account(2).unwrap().is_closed());
+        assert_eq!(ledger.account(3).unwrap().total().unwrap(), 6.into());
+    }
+}
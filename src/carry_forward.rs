@@ -0,0 +1,205 @@
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::ledger::Ledger;
+
+// Carrying balances forward chains runs of consecutive periods together: the
+// closing balances of one run are written with a checksum next to them, and
+// become the opening balances of the next run, which checks that they haven't
+// been modified in between.
+
+#[derive(Error, Debug)]
+pub enum CarryForwardError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("failed to write closing balances: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("the checksum file {0:?} is malformed")]
+    MalformedChecksum(PathBuf),
+    #[error("{0:?} doesn't match its checksum, it may have been modified")]
+    ChecksumMismatch(PathBuf),
+}
+
+// The checksum of `path` is stored next to it, in `path` + ".sha256".
+fn checksum_path(path: &Path) -> PathBuf {
+    let mut checksum_path = OsString::from(path);
+    checksum_path.push(".sha256");
+    checksum_path.into()
+}
+
+fn sha256_hex(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Write the closing balances of the ledger to `path` and their checksum
+// next to it. The checksum file is in the format used by `sha256sum`, so it
+// can be checked independently with `sha256sum -c`.
+pub fn write(ledger: &Ledger, path: &Path) -> Result<(), CarryForwardError> {
+    let mut contents = vec![];
+    ledger.closing_balances_to_csv(&mut contents)?;
+    fs::write(path, &contents)?;
+
+    let filename = path.file_name().unwrap_or(path.as_os_str());
+    let checksum = format!(
+        "{}  {}\n",
+        sha256_hex(&contents),
+        Path::new(filename).display()
+    );
+    fs::write(checksum_path(path), checksum)?;
+
+    Ok(())
+}
+
+// Read the opening balances at `path`, checking them against the checksum
+// next to them. Files without a checksum, e.g. ones exported from another
+// system, are read as they are.
+pub fn read_verified(path: &Path) -> Result<Vec<u8>, CarryForwardError> {
+    let contents = fs::read(path)?;
+
+    let checksum_path = checksum_path(path);
+    let checksum = match fs::read_to_string(&checksum_path) {
+        Ok(checksum) => checksum,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(contents),
+        Err(err) => return Err(err.into()),
+    };
+
+    let expected = checksum
+        .split_whitespace()
+        .next()
+        .ok_or(CarryForwardError::MalformedChecksum(checksum_path))?;
+    if !expected.eq_ignore_ascii_case(&sha256_hex(&contents)) {
+        return Err(CarryForwardError::ChecksumMismatch(path.to_owned()));
+    }
+
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{checksum_path, read_verified, write, CarryForwardError};
+    use crate::ledger::Ledger;
+
+    #[test]
+    fn carry_forward_round_trip() {
+        let dir = std::env::temp_dir().join(format!("ledger-carry-forward-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("closing.csv");
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.12345
+deposit,2,2,5
+dispute,2,2,
+chargeback,2,2,
+deposit,3,3,7
+dispute,3,3,
+";
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+        write(&ledger, &path).expect("should write closing balances");
+
+        let checksum = fs::read_to_string(checksum_path(&path)).unwrap();
+        assert!(checksum.ends_with("  closing.csv\n"));
+
+        // The closing balances become the next period's opening balances,
+        // at full precision.
+        let contents = read_verified(&path).expect("checksum should match");
+        let mut next = Ledger::default();
+        next.read_opening_balances(contents.as_slice());
+        let mut carried = vec![];
+        next.closing_balances_to_csv(&mut carried).unwrap();
+        assert_eq!(carried, contents);
+        assert_eq!(
+            String::from_utf8(contents).unwrap(),
+            "\
+client,available,held,total,locked
+1,10.12345,0,10.12345,false
+2,0,0,0,true
+3,0,7,7,false
+"
+        );
+
+        // Any modification is detected.
+        fs::write(
+            &path,
+            "client,available,held,total,locked\n1,1000,0,1000,false\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            read_verified(&path),
+            Err(CarryForwardError::ChecksumMismatch(_))
+        ));
+
+        // Files without a checksum are accepted as they are.
+        fs::remove_file(checksum_path(&path)).unwrap();
+        assert!(read_verified(&path).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edge_cases() {
+        let dir = std::env::temp_dir().join(format!("ledger-carry-edges-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("closing.csv");
+
+        // Client 1 has as much as funds can hold, client 2 more digits than
+        // a float keeps, and client 3 closes their account.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,2,2,1234567890.12345678
+deposit,3,3,5
+withdrawal,3,4,5
+close_account,3,0,
+"
+        );
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+        write(&ledger, &path).unwrap();
+
+        // The balances are carried forward exactly, and the closed account
+        // stays closed.
+        let contents = read_verified(&path).unwrap();
+        let mut next = Ledger::default();
+        next.read_opening_balances(contents.as_slice());
+        let mut carried = vec![];
+        next.closing_balances_to_csv(&mut carried).unwrap();
+        assert_eq!(carried, contents);
+        assert_eq!(
+            String::from_utf8(contents).unwrap(),
+            format!(
+                "\
+client,available,held,total,locked,closed
+1,{max},0,{max},false,false
+2,1234567890.12345678,0,1234567890.12345678,false,false
+3,0,0,0,false,true
+"
+            )
+        );
+        next.read_csv("type,client,tx,amount\ndeposit,3,5,50\n".as_bytes());
+        assert!(next.accounts().any(|(client, account)| client == 3
+            && account.is_closed()
+            && account.available().is_zero()));
+
+        // A checksum file without a checksum is malformed.
+        fs::write(checksum_path(&path), "").unwrap();
+        assert!(matches!(
+            read_verified(&path),
+            Err(CarryForwardError::MalformedChecksum(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
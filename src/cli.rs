@@ -0,0 +1,1083 @@
+use std::{error::Error, time::Duration};
+
+use ::ledger::{
+    anomaly, client_names::ClientKey, clock, compression::Compression, daemon, filter, generate,
+    ledger, policy, scaling, stats,
+};
+
+// The options of the program, as listed by `--help`, see the README for what
+// they do.
+pub const USAGE: &str = "\
+usage: ledger [OPTIONS] FILE
+       ledger statement --client CLIENT [OPTIONS] FILE
+       ledger normalize INPUT OUTPUT
+       ledger generate [--clients N] [--transactions N] [--dispute-rate RATE]
+                       [--seed N] [--output FILE]
+       ledger diff [--snapshots] A B
+       ledger compare A B
+
+Input:
+  --input FILE                  the transactions file, also given without --input
+  --minor-units DIGITS          read amounts in minor units of DIGITS decimal places
+  --opening-balances FILE       open accounts with the balances in FILE
+  --resume FILE                 pick up the state in FILE, and save it there again
+  --as-of TIME                  the time scheduled transactions are applied up to
+  --mmap                        map the transactions file into memory
+
+Processing:
+  --zero-amounts POLICY         accept, or reject, zero amounts
+  --amounts POLICY              which amounts are accepted, e.g. positive
+  --on-error POLICY             skip, collect, or abort on lines that can't be applied
+  --strict                      stop at the first line that can't be applied
+  --overdraft AMOUNT            how far accounts may be overdrawn
+  --account-overdraft CLIENT:AMOUNT
+                                how far the account of CLIENT may be overdrawn
+  --currency-units CURRENCY:DIGITS
+                                the minor units of a currency
+  --transaction-ids POLICY      unchecked, unique, or global transaction IDs
+  --max-held-ratio RATIO        the most an account may hold of its total
+  --held-ratio-action ACTION    what happens to disputes beyond the held ratio
+  --disputes POLICY             which transactions can be disputed
+  --withdrawal-disputes POLICY  what disputes of withdrawals do
+  --early-disputes POLICY       reject, or park, disputes of unknown transactions
+  --max-parked-disputes N       park at most N disputes for every account
+  --dispute-window DAYS         how long transactions can be disputed for
+  --client-ids numeric|text     whether clients are given by ID or by name
+  --account-creation POLICY     whether accounts are created implicitly
+  --chargeback-freeze LEVEL     how far chargebacks freeze accounts
+  --limit-freeze LEVEL          how far going beyond the withdrawal limit does
+  --withdrawal-limit COUNT,AMOUNT,SECONDS
+                                the most accounts may withdraw within a window
+  --authorization-window N      how many transactions authorizations last for
+  --max-amount AMOUNT           the largest amount of a single transaction
+  --out-of-order POLICY         ignore, warn about, or reject late transactions
+  --reorder-window SECONDS      put transactions up to SECONDS late back in order
+  --fee-account CLIENT          the account fees are credited to
+  --sweep-account CLIENT        the account closed accounts are swept to
+  --simulate SETTING            report how SETTING would change the outcome
+
+Output:
+  --output FILE                 write the account summaries to FILE
+  --format FORMAT               csv, json, table, or another output format
+  --precision DIGITS            the decimal places balances are written with
+  --rounding MODE               how balances are rounded to the precision
+  --sort ORDER                  the order accounts are written in
+  --filter EXPRESSION           only write the accounts that match
+  --freeze-reasons              write why accounts are frozen
+  --compress-level LEVEL        compress --output and --journal with zstd
+  --rejects FILE                write the lines that can't be applied to FILE
+  --carry-forward FILE          write the closing balances to FILE
+  --recoveries FILE             write the recovered deficits to FILE
+  --account-metadata FILE       write the metadata of accounts to FILE
+  --journal FILE                append every transaction applied to FILE
+  --sqlite FILE                 export the ledger to an SQLite database
+  --expected-balances FILE      reconcile the balances with those in FILE
+  --discrepancies FILE          write the accounts that don't reconcile to FILE
+  --behavior FILE               write the behavior of every client to FILE
+  --baseline FILE               the behavior --anomalies compares against
+  --anomalies FILE              write the clients that behave unusually to FILE
+  --anomaly-factor FACTOR       how unusual behavior has to be to be reported
+  --stats FILE                  write the amounts of every type of transaction to FILE
+  --stats-buckets AMOUNTS       the bounds of the amount histograms of --stats
+  --manifest FILE               describe the run in FILE, for comparing it to others
+
+Scaling:
+  --tx-store DIRECTORY          keep the processed transactions on disk
+  --max-memory SIZE             spill processed transactions beyond SIZE to disk
+  --cold-after N                compress transactions N transactions old
+  --compact-transactions        pack processed transactions in memory
+  --shards N                    apply transactions on N threads
+  --expected-transactions N     make room for N transactions up front
+  --alloc-stats                 report the allocations of every phase
+
+Serving:
+  --listen ADDRESS              accept records over TCP or a Unix socket
+  --grpc ADDRESS                serve the ledger over gRPC
+  --websocket ADDRESS           push account updates over WebSocket
+  --wal FILE                    log transactions to FILE before applying them
+  --dead-letters FILE           append the records that can't be applied to FILE
+  --check-ordering              reject records out of order
+  --latency-budget DURATION     warn about records slower than DURATION
+  --backfill FILE               apply FILE in the background while serving
+  --backfill-rate N             the records per second --backfill applies
+
+  --help                        print this message
+";
+
+// The records per second `--backfill` applies by default.
+const DEFAULT_BACKFILL_RATE: u32 = 1000;
+
+// Command is what the program was asked to do.
+pub enum Command {
+    Help,
+    Normalize {
+        input: String,
+        output: String,
+    },
+    Generate {
+        generator: generate::Generator,
+        output: Option<String>,
+    },
+    Diff {
+        snapshots: bool,
+        a: String,
+        b: String,
+    },
+    Compare {
+        a: String,
+        b: String,
+    },
+    // Process the transactions file, or serve the ledger.
+    Run(Box<Options>),
+}
+
+// Mode selects what the program does with the ledger.
+// * Batch: process the transactions file and print the account summaries.
+// * Listen: accept records over a TCP or Unix socket, see `daemon`.
+// * Grpc: serve the ledger over gRPC, see `grpc`.
+#[derive(Default)]
+pub enum Mode {
+    #[default]
+    Batch,
+    Listen(daemon::ListenAddress),
+    #[cfg(feature = "grpc")]
+    Grpc(std::net::SocketAddr),
+}
+
+impl Mode {
+    pub fn is_batch(&self) -> bool {
+        matches!(self, Mode::Batch)
+    }
+}
+
+// Options are the options of a run, checked to go together.
+#[derive(Default)]
+pub struct Options {
+    pub filename: Option<String>,
+    pub output: Option<String>,
+    // The client whose statement is written instead of the account
+    // summaries, by name with `--client-ids text`.
+    pub statement: Option<ClientKey>,
+    pub opening_balances: Option<String>,
+    pub carry_forward: Option<String>,
+    pub resume: Option<String>,
+    pub recoveries: Option<String>,
+    pub account_metadata: Option<String>,
+    pub behavior: Option<String>,
+    pub baseline: Option<String>,
+    pub anomalies: Option<String>,
+    pub expected_balances: Option<String>,
+    pub discrepancies: Option<String>,
+    pub anomaly_factor: anomaly::Factor,
+    pub stats: Option<String>,
+    pub stats_buckets: stats::Buckets,
+    pub manifest: Option<String>,
+    pub journal: Option<String>,
+    pub compression: Compression,
+    pub rejects: Option<String>,
+    pub dead_letters: Option<String>,
+    pub write_ahead_log: Option<String>,
+    pub check_ordering: bool,
+    pub strict: bool,
+    pub latency_budget: Option<Duration>,
+    pub backfill: Option<String>,
+    pub backfill_rate: u32,
+    #[cfg(feature = "alloc-stats")]
+    pub alloc_stats: bool,
+    #[cfg(feature = "sqlite")]
+    pub sqlite: Option<String>,
+    #[cfg(feature = "sled")]
+    pub tx_store: Option<String>,
+    pub max_memory: Option<usize>,
+    pub cold_after: Option<u64>,
+    pub compact_transactions: bool,
+    pub as_of: Option<u64>,
+    pub shards: usize,
+    pub expected_transactions: usize,
+    #[cfg(feature = "mmap")]
+    pub mmap: bool,
+    pub format: ledger::OutputFormat,
+    pub order: ledger::AccountOrder,
+    pub filter: Option<filter::Filter>,
+    pub freeze_reasons: bool,
+    pub scale: scaling::AmountScale,
+    pub precision: ledger::Precision,
+    pub policy: policy::Policy,
+    pub simulated_settings: Vec<String>,
+    pub mode: Mode,
+    #[cfg(feature = "websocket")]
+    pub websocket: Option<std::net::SocketAddr>,
+}
+
+impl Options {
+    // Whether clients are given by name, see `ClientIdPolicy::Text`.
+    pub fn named(&self) -> bool {
+        self.policy.client_ids == policy::ClientIdPolicy::Text
+    }
+}
+
+// Parse the arguments of the program, without the program name. It may be
+// followed by the `normalize`, `generate`, `diff`, or `statement`
+// subcommand, the rest are either options or the filename.
+pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Command, Box<dyn Error>> {
+    let mut args = args.peekable();
+    if args.next_if(|arg| arg == "--help" || arg == "-h").is_some() {
+        return Ok(Command::Help);
+    }
+    if args.next_if_eq("normalize").is_some() {
+        let (Some(input), Some(output), None) = (args.next(), args.next(), args.next()) else {
+            return Err("usage: ledger normalize INPUT OUTPUT".into());
+        };
+        return Ok(Command::Normalize { input, output });
+    }
+    if args.next_if_eq("generate").is_some() {
+        return parse_generate(args);
+    }
+    if args.next_if_eq("diff").is_some() {
+        let snapshots = args.next_if_eq("--snapshots").is_some();
+        let (Some(a), Some(b), None) = (args.next(), args.next(), args.next()) else {
+            return Err("usage: ledger diff [--snapshots] A B".into());
+        };
+        return Ok(Command::Diff { snapshots, a, b });
+    }
+    if args.next_if_eq("compare").is_some() {
+        let (Some(a), Some(b), None) = (args.next(), args.next(), args.next()) else {
+            return Err("usage: ledger compare A B".into());
+        };
+        return Ok(Command::Compare { a, b });
+    }
+
+    let statement = args.next_if_eq("statement").is_some();
+    let mut statement_client: Option<String> = None;
+    let mut options = Options {
+        shards: 1,
+        backfill_rate: DEFAULT_BACKFILL_RATE,
+        ..Options::default()
+    };
+    let policy = &mut options.policy;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => return Ok(Command::Help),
+            "--client" if statement => {
+                statement_client = Some(args.next().ok_or("--client requires a value")?)
+            }
+            "--client" => return Err("--client can only be used with statement".into()),
+            "--zero-amounts" => {
+                policy.zero_amounts = args
+                    .next()
+                    .ok_or("--zero-amounts requires a value")?
+                    .parse()?
+            }
+            "--amounts" => {
+                policy.amounts = args.next().ok_or("--amounts requires a value")?.parse()?
+            }
+            "--opening-balances" => {
+                options.opening_balances = Some(
+                    args.next()
+                        .ok_or("--opening-balances requires a filename")?,
+                )
+            }
+            "--on-error" => {
+                policy.errors = args.next().ok_or("--on-error requires a value")?.parse()?
+            }
+            "--strict" => options.strict = true,
+            "--overdraft" => {
+                policy.overdraft = args
+                    .next()
+                    .ok_or("--overdraft requires an amount")?
+                    .parse()?;
+                if policy.overdraft.is_sign_negative() {
+                    return Err("--overdraft can't be negative".into());
+                }
+            }
+            "--account-overdraft" => {
+                let value = args
+                    .next()
+                    .ok_or("--account-overdraft requires a client and an amount")?;
+                policy
+                    .set(&format!("account-overdraft={}", value))
+                    .map_err(|_| "--account-overdraft takes CLIENT:AMOUNT, e.g. 7:100")?;
+            }
+            "--currency-units" => {
+                let value = args
+                    .next()
+                    .ok_or("--currency-units requires a currency and a number of digits")?;
+                policy
+                    .set(&format!("currency-units={}", value))
+                    .map_err(|_| "--currency-units takes CURRENCY:DIGITS, e.g. XAU:4")?;
+            }
+            "--transaction-ids" => {
+                policy.transaction_ids = args
+                    .next()
+                    .ok_or("--transaction-ids requires a value")?
+                    .parse()?
+            }
+            "--max-held-ratio" => {
+                let ratio: rust_decimal::Decimal = args
+                    .next()
+                    .ok_or("--max-held-ratio requires a value")?
+                    .parse()?;
+                if !ratio.is_sign_positive()
+                    || ratio.is_zero()
+                    || ratio > rust_decimal::Decimal::ONE
+                {
+                    return Err("--max-held-ratio must be positive, and at most 1".into());
+                }
+                policy.max_held_ratio = Some(ratio);
+            }
+            "--held-ratio-action" => {
+                policy.held_ratio_action = args
+                    .next()
+                    .ok_or("--held-ratio-action requires a value")?
+                    .parse()?
+            }
+            "--disputes" => {
+                policy.disputes = args.next().ok_or("--disputes requires a value")?.parse()?
+            }
+            "--withdrawal-disputes" => {
+                policy.withdrawal_disputes = args
+                    .next()
+                    .ok_or("--withdrawal-disputes requires a value")?
+                    .parse()?
+            }
+            "--client-ids" => {
+                policy.client_ids = args
+                    .next()
+                    .ok_or("--client-ids requires numeric or text")?
+                    .parse()?
+            }
+            "--account-creation" => {
+                policy.account_creation = args
+                    .next()
+                    .ok_or("--account-creation requires implicit or explicit")?
+                    .parse()?
+            }
+            "--chargeback-freeze" => {
+                policy.chargeback_freeze = args
+                    .next()
+                    .ok_or("--chargeback-freeze requires full or withdrawals")?
+                    .parse()?
+            }
+            "--limit-freeze" => {
+                policy.limit_freeze = match args
+                    .next()
+                    .ok_or("--limit-freeze requires none, withdrawals, or full")?
+                    .as_str()
+                {
+                    "none" => None,
+                    level => Some(level.parse()?),
+                }
+            }
+            "--early-disputes" => {
+                policy.early_disputes = args
+                    .next()
+                    .ok_or("--early-disputes requires a value")?
+                    .parse()?
+            }
+            "--max-parked-disputes" => {
+                let disputes: usize = args
+                    .next()
+                    .ok_or("--max-parked-disputes requires a number")?
+                    .parse()?;
+                if disputes == 0 {
+                    return Err("--max-parked-disputes must be positive".into());
+                }
+                policy.max_parked_disputes = Some(disputes);
+            }
+            "--authorization-window" => {
+                let window: u64 = args
+                    .next()
+                    .ok_or("--authorization-window requires a number of transactions")?
+                    .parse()?;
+                if window == 0 {
+                    return Err("--authorization-window must be positive".into());
+                }
+                policy.authorization_window = Some(window);
+            }
+            "--as-of" => {
+                let time = args.next().ok_or("--as-of requires a time")?;
+                options.as_of = Some(
+                    clock::parse_timestamp(&time)
+                        .ok_or_else(|| format!("invalid time {:?}", time))?,
+                )
+            }
+            "--max-amount" => {
+                let amount: rust_decimal::Decimal = args
+                    .next()
+                    .ok_or("--max-amount requires an amount")?
+                    .parse()?;
+                if !amount.is_sign_positive() || amount.is_zero() {
+                    return Err("--max-amount must be positive".into());
+                }
+                policy.max_amount = Some(amount);
+            }
+            "--dispute-window" => {
+                let days: u64 = args
+                    .next()
+                    .ok_or("--dispute-window requires a number of days")?
+                    .parse()?;
+                if days == 0 {
+                    return Err("--dispute-window must be positive".into());
+                }
+                policy.dispute_window = Some(days);
+            }
+            "--out-of-order" => {
+                policy.out_of_order = args
+                    .next()
+                    .ok_or("--out-of-order requires ignore, warn, or reject")?
+                    .parse()?
+            }
+            "--reorder-window" => {
+                let seconds: u64 = args
+                    .next()
+                    .ok_or("--reorder-window requires a number of seconds")?
+                    .parse()?;
+                if seconds == 0 {
+                    return Err("--reorder-window must be positive".into());
+                }
+                policy.reorder_window = Some(seconds);
+            }
+            "--withdrawal-limit" => {
+                policy.withdrawal_limit = Some(
+                    args.next()
+                        .ok_or("--withdrawal-limit requires COUNT,AMOUNT,SECONDS")?
+                        .parse()?,
+                )
+            }
+            "--fee-account" => {
+                policy.fee_account = Some(
+                    args.next()
+                        .ok_or("--fee-account requires a client ID")?
+                        .parse()?,
+                )
+            }
+            "--sweep-account" => {
+                policy.sweep_account = Some(
+                    args.next()
+                        .ok_or("--sweep-account requires a client ID")?
+                        .parse()?,
+                )
+            }
+            "--minor-units" => {
+                options.scale = args
+                    .next()
+                    .ok_or("--minor-units requires a value")?
+                    .parse()?
+            }
+            "--input" if options.filename.is_none() => {
+                options.filename = Some(args.next().ok_or("--input requires a filename")?)
+            }
+            "--resume" => options.resume = Some(args.next().ok_or("--resume requires a filename")?),
+            "--output" => options.output = Some(args.next().ok_or("--output requires a filename")?),
+            "--format" => {
+                options.format = args.next().ok_or("--format requires a value")?.parse()?
+            }
+            "--precision" => {
+                let decimal_places = args.next().ok_or("--precision requires a value")?.parse()?;
+                if decimal_places > ledger::MAX_DECIMAL_PLACES {
+                    return Err(format!(
+                        "--precision can be at most {}",
+                        ledger::MAX_DECIMAL_PLACES
+                    )
+                    .into());
+                }
+                options.precision.decimal_places = decimal_places;
+            }
+            "--rounding" => {
+                options.precision.rounding =
+                    args.next().ok_or("--rounding requires a value")?.parse()?
+            }
+            "--freeze-reasons" => options.freeze_reasons = true,
+            "--sort" => options.order = args.next().ok_or("--sort requires a value")?.parse()?,
+            "--filter" => {
+                options.filter = Some(
+                    args.next()
+                        .ok_or("--filter requires an expression")?
+                        .parse()?,
+                )
+            }
+            "--carry-forward" => {
+                options.carry_forward =
+                    Some(args.next().ok_or("--carry-forward requires a filename")?)
+            }
+            "--recoveries" => {
+                options.recoveries = Some(args.next().ok_or("--recoveries requires a filename")?)
+            }
+            "--account-metadata" => {
+                options.account_metadata = Some(
+                    args.next()
+                        .ok_or("--account-metadata requires a filename")?,
+                )
+            }
+            "--behavior" => {
+                options.behavior = Some(args.next().ok_or("--behavior requires a filename")?)
+            }
+            "--baseline" => {
+                options.baseline = Some(args.next().ok_or("--baseline requires a filename")?)
+            }
+            "--anomalies" => {
+                options.anomalies = Some(args.next().ok_or("--anomalies requires a filename")?)
+            }
+            "--expected-balances" => {
+                options.expected_balances = Some(
+                    args.next()
+                        .ok_or("--expected-balances requires a filename")?,
+                )
+            }
+            "--discrepancies" => {
+                options.discrepancies =
+                    Some(args.next().ok_or("--discrepancies requires a filename")?)
+            }
+            "--anomaly-factor" => {
+                options.anomaly_factor = args
+                    .next()
+                    .ok_or("--anomaly-factor requires a value")?
+                    .parse()?
+            }
+            "--stats" => options.stats = Some(args.next().ok_or("--stats requires a filename")?),
+            "--manifest" => {
+                options.manifest = Some(args.next().ok_or("--manifest requires a filename")?)
+            }
+            "--stats-buckets" => {
+                options.stats_buckets = args
+                    .next()
+                    .ok_or("--stats-buckets requires a value")?
+                    .parse()?
+            }
+            #[cfg(feature = "sqlite")]
+            "--sqlite" => options.sqlite = Some(args.next().ok_or("--sqlite requires a filename")?),
+            #[cfg(feature = "sled")]
+            "--tx-store" => {
+                options.tx_store = Some(args.next().ok_or("--tx-store requires a directory")?)
+            }
+            "--max-memory" => {
+                options.max_memory = Some(parse_size(
+                    &args.next().ok_or("--max-memory requires a size")?,
+                )?)
+            }
+            "--cold-after" => {
+                options.cold_after = Some(
+                    args.next()
+                        .ok_or("--cold-after requires a number of transactions")?
+                        .parse()?,
+                )
+            }
+            "--compact-transactions" => options.compact_transactions = true,
+            "--shards" => {
+                options.shards = args.next().ok_or("--shards requires a number")?.parse()?
+            }
+            "--expected-transactions" => {
+                options.expected_transactions = args
+                    .next()
+                    .ok_or("--expected-transactions requires a number")?
+                    .parse()?
+            }
+            #[cfg(feature = "mmap")]
+            "--mmap" => options.mmap = true,
+            "--simulate" => options
+                .simulated_settings
+                .push(args.next().ok_or("--simulate requires a setting")?),
+            "--rejects" => {
+                options.rejects = Some(args.next().ok_or("--rejects requires a filename")?)
+            }
+            "--journal" => {
+                options.journal = Some(args.next().ok_or("--journal requires a filename")?)
+            }
+            "--compress-level" => {
+                options.compression = Compression::zstd(
+                    args.next()
+                        .ok_or("--compress-level requires a value")?
+                        .parse()?,
+                )?
+            }
+            "--dead-letters" => {
+                options.dead_letters =
+                    Some(args.next().ok_or("--dead-letters requires a filename")?)
+            }
+            "--wal" => {
+                options.write_ahead_log = Some(args.next().ok_or("--wal requires a filename")?)
+            }
+            "--check-ordering" => options.check_ordering = true,
+            "--latency-budget" => {
+                options.latency_budget = Some(parse_duration(
+                    &args.next().ok_or("--latency-budget requires a duration")?,
+                )?)
+            }
+            "--backfill" => {
+                options.backfill = Some(args.next().ok_or("--backfill requires a filename")?)
+            }
+            "--backfill-rate" => {
+                options.backfill_rate = args
+                    .next()
+                    .ok_or("--backfill-rate requires a value")?
+                    .parse()?
+            }
+            #[cfg(feature = "alloc-stats")]
+            "--alloc-stats" => options.alloc_stats = true,
+            "--listen" => {
+                options.mode =
+                    Mode::Listen(args.next().ok_or("--listen requires an address")?.parse()?)
+            }
+            #[cfg(feature = "grpc")]
+            "--grpc" => {
+                options.mode = Mode::Grpc(args.next().ok_or("--grpc requires an address")?.parse()?)
+            }
+            #[cfg(feature = "websocket")]
+            "--websocket" => {
+                options.websocket = Some(
+                    args.next()
+                        .ok_or("--websocket requires an address")?
+                        .parse()?,
+                )
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option {:?}", arg).into()),
+            _ if options.filename.is_none() => options.filename = Some(arg),
+            _ => return Err(format!("unexpected argument {:?}", arg).into()),
+        }
+    }
+
+    let statement = match (statement, statement_client) {
+        (false, _) => None,
+        (true, None) => return Err("statement requires --client".into()),
+        (true, Some(_)) if !options.mode.is_batch() => {
+            return Err("statement can't be used with --listen or --grpc".into())
+        }
+        (true, Some(_))
+            if !matches!(
+                options.format,
+                ledger::OutputFormat::Csv
+                    | ledger::OutputFormat::Json
+                    | ledger::OutputFormat::Table
+            ) =>
+        {
+            return Err("statements can only be written as csv, json, or table".into())
+        }
+        (true, client) => client,
+    };
+    // Names are given account IDs in the order they're read, so the client
+    // of a statement is only looked up once the input is read.
+    options.statement = match statement {
+        Some(client) if options.named() => Some(ClientKey::Name(client)),
+        Some(client) => Some(ClientKey::Id(client.parse()?)),
+        None => None,
+    };
+    options.check()?;
+
+    // Strict runs stop at the first line that's skipped, and fail with it
+    // before anything is written.
+    if options.strict {
+        options.policy.errors = policy::ErrorPolicy::Abort;
+    }
+    Ok(Command::Run(Box::new(options)))
+}
+
+impl Options {
+    // Check that the options go together.
+    fn check(&self) -> Result<(), Box<dyn Error>> {
+        let batch = self.mode.is_batch();
+
+        // Options that refer to clients by their ID can't be used with
+        // names.
+        if self.named()
+            && (!batch
+                || self.policy.fee_account.is_some()
+                || self.policy.sweep_account.is_some()
+                || !self.policy.account_overdrafts.is_empty()
+                || self.behavior.is_some()
+                || self.baseline.is_some()
+                || self.anomalies.is_some())
+        {
+            return Err(
+                "--client-ids text can't be used with --listen, --grpc, --fee-account, \
+                 --sweep-account, --account-overdraft, --behavior, --baseline, or --anomalies"
+                    .into(),
+            );
+        }
+
+        // Summaries and statements have different fields to filter by.
+        if let Some(output_filter) = &self.filter {
+            output_filter.check(match self.statement {
+                Some(_) => filter::STATEMENT_FIELDS,
+                None if self.named() => filter::NAMED_ACCOUNT_FIELDS,
+                None => filter::ACCOUNT_FIELDS,
+            })?;
+        }
+
+        // Only files are compressed, the output written to stdout can be
+        // piped to a compressor instead.
+        if self.compression != Compression::None && self.output.is_none() && self.journal.is_none()
+        {
+            return Err("--compress-level requires --output or --journal".into());
+        }
+
+        // Runs are compared by their account summaries, read back as CSV.
+        if self.manifest.is_some()
+            && (self.output.is_none()
+                || self.format != ledger::OutputFormat::Csv
+                || self.compression != Compression::None
+                || self.statement.is_some()
+                || !self.simulated_settings.is_empty()
+                || !batch)
+        {
+            return Err(
+                "--manifest requires uncompressed csv --output, and can't be used with statement, \
+                 --simulate, --listen, or --grpc"
+                    .into(),
+            );
+        }
+
+        // Simulations replay the file under both the actual and the
+        // simulated policy and only report the differences between them.
+        if !self.simulated_settings.is_empty() {
+            if !batch
+                || self.carry_forward.is_some()
+                || self.recoveries.is_some()
+                || self.account_metadata.is_some()
+                || self.resume.is_some()
+                || self.filter.is_some()
+                || self.strict
+            {
+                return Err(
+                    "--simulate can't be used with --listen, --grpc, --carry-forward, \
+                     --recoveries, --account-metadata, --resume, --filter, or --strict"
+                        .into(),
+                );
+            }
+            if self.scale != scaling::AmountScale::default() {
+                return Err("--minor-units can't be used with --simulate".into());
+            }
+            if self.filename.is_none() {
+                return Err("no filename given".into());
+            }
+            return Ok(());
+        }
+
+        self.check_stores()?;
+
+        // Serving never ends, so there is no period to close.
+        if (self.carry_forward.is_some()
+            || self.recoveries.is_some()
+            || self.account_metadata.is_some()
+            || self.output.is_some()
+            || self.resume.is_some()
+            || self.discrepancies.is_some())
+            && !batch
+        {
+            return Err(
+                "--carry-forward, --recoveries, --account-metadata, --output, --resume, and \
+                 --discrepancies can't be used with --listen or --grpc"
+                    .into(),
+            );
+        }
+        if self.expected_balances.is_some() != self.discrepancies.is_some() {
+            return Err("--expected-balances and --discrepancies have to be used together".into());
+        }
+        if self.resume.is_some() && self.opening_balances.is_some() {
+            return Err("--opening-balances can't be used with --resume".into());
+        }
+        if (self.behavior.is_some() || self.baseline.is_some() || self.anomalies.is_some())
+            && !batch
+        {
+            return Err(
+                "--behavior, --baseline, and --anomalies can't be used with --listen or --grpc"
+                    .into(),
+            );
+        }
+        if self.baseline.is_some() != self.anomalies.is_some() {
+            return Err("--baseline and --anomalies have to be used together".into());
+        }
+        if self.stats.is_some() && !batch {
+            return Err("--stats can't be used with --listen or --grpc".into());
+        }
+        #[cfg(feature = "sqlite")]
+        if self.sqlite.is_some() && !batch {
+            return Err("--sqlite can't be used with --listen or --grpc".into());
+        }
+
+        // Options of servers.
+        if self.latency_budget.is_some() && batch {
+            return Err("--latency-budget requires --listen or --grpc".into());
+        }
+        if self.check_ordering && !matches!(self.mode, Mode::Listen(_)) {
+            return Err("--check-ordering requires --listen".into());
+        }
+        if self.backfill.is_some() && batch {
+            return Err("--backfill requires --listen or --grpc".into());
+        }
+        // Batch runs report rejected records on stderr, only servers have
+        // records that would otherwise be lost.
+        if self.dead_letters.is_some() && batch {
+            return Err("--dead-letters requires --listen or --grpc".into());
+        }
+        // Account updates are only pushed while serving, a batch run would
+        // be over before anyone could subscribe.
+        #[cfg(feature = "websocket")]
+        if self.websocket.is_some() && batch {
+            return Err("--websocket requires --listen or --grpc".into());
+        }
+        if self.write_ahead_log.is_some() && batch {
+            return Err("--wal requires --listen or --grpc".into());
+        }
+        // When serving, the file is optional and only used to seed the
+        // ledger.
+        if self.filename.is_none() && batch {
+            return Err("no filename given".into());
+        }
+        Ok(())
+    }
+
+    // Check the options of where the processed transactions are kept, and
+    // how many threads they're applied on.
+    fn check_stores(&self) -> Result<(), Box<dyn Error>> {
+        let (max_memory, cold_after) = (self.max_memory.is_some(), self.cold_after.is_some());
+        // The shards keep their transactions in memory, and would journal
+        // them out of order.
+        if self.shards > 1 {
+            if max_memory || cold_after || self.compact_transactions {
+                return Err(
+                    "--shards can't be used with --max-memory, --cold-after, or \
+                     --compact-transactions"
+                        .into(),
+                );
+            }
+            #[cfg(feature = "sled")]
+            if self.tx_store.is_some() {
+                return Err("--shards can't be used with --tx-store".into());
+            }
+            if self.journal.is_some() {
+                return Err("--shards can't be used with --journal".into());
+            }
+            if !self.mode.is_batch() {
+                return Err("--shards can't be used with --listen or --grpc".into());
+            }
+            // Names are given IDs, and transactions reordered, in the order
+            // they're read, so either is read on a single thread.
+            if self.named() || self.policy.reorder_window.is_some() {
+                return Err(
+                    "--shards can't be used with --client-ids text or --reorder-window".into(),
+                );
+            }
+        }
+        #[cfg(feature = "sled")]
+        if self.tx_store.is_some() && (max_memory || cold_after || self.compact_transactions) {
+            return Err(
+                "--max-memory, --cold-after, and --compact-transactions can't be used with \
+                 --tx-store"
+                    .into(),
+            );
+        }
+        if self.compact_transactions && (max_memory || cold_after) {
+            return Err(
+                "--compact-transactions can't be used with --max-memory or --cold-after".into(),
+            );
+        }
+        if max_memory && cold_after {
+            return Err("--cold-after can't be used with --max-memory".into());
+        }
+        Ok(())
+    }
+}
+
+// Parse the options of the `generate` subcommand.
+fn parse_generate<I: Iterator<Item = String>>(mut args: I) -> Result<Command, Box<dyn Error>> {
+    let mut generator = generate::Generator::default();
+    let mut output: Option<String> = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--clients" => {
+                generator.clients = args.next().ok_or("--clients requires a number")?.parse()?
+            }
+            "--transactions" => {
+                generator.transactions = args
+                    .next()
+                    .ok_or("--transactions requires a number")?
+                    .parse()?
+            }
+            "--dispute-rate" => {
+                generator.dispute_rate = args
+                    .next()
+                    .ok_or("--dispute-rate requires a number")?
+                    .parse()?
+            }
+            "--seed" => generator.seed = args.next().ok_or("--seed requires a number")?.parse()?,
+            "--output" => output = Some(args.next().ok_or("--output requires a filename")?),
+            _ => return Err(format!("unknown option {:?} for generate", arg).into()),
+        }
+    }
+    if generator.clients == 0 {
+        return Err("--clients must be at least 1".into());
+    }
+    if !(0.0..=1.0).contains(&generator.dispute_rate) {
+        return Err("--dispute-rate must be between 0 and 1".into());
+    }
+    Ok(Command::Generate { generator, output })
+}
+
+// Parse a number of bytes, optionally with a binary suffix, e.g. `512M` or
+// `2G`.
+fn parse_size(size: &str) -> Result<usize, Box<dyn Error>> {
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let unit: usize = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("invalid size {:?}", size).into()),
+    };
+    number
+        .parse::<usize>()?
+        .checked_mul(unit)
+        .ok_or_else(|| format!("size {:?} is too large", size).into())
+}
+
+// Parse a duration in whole microseconds, milliseconds, or seconds, e.g.
+// `250us`, `5ms`, or `2s`.
+fn parse_duration(duration: &str) -> Result<Duration, Box<dyn Error>> {
+    let (number, unit) = duration.split_at(
+        duration
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(duration.len()),
+    );
+    let number = number.parse::<u64>()?;
+    match unit {
+        "us" => Ok(Duration::from_micros(number)),
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        _ => Err(format!("invalid duration {:?}, expected e.g. 5ms", duration).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Command, Options};
+    use ::ledger::{client_names::ClientKey, policy::ErrorPolicy, AccountId};
+
+    fn run(args: &str) -> Result<Options, String> {
+        match parse(args.split_whitespace().map(str::to_owned)) {
+            Ok(Command::Run(options)) => Ok(*options),
+            Ok(_) => Err("not a run".to_owned()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    #[test]
+    fn options() {
+        let options = run("--strict --output out.csv --precision 2 statement.csv").unwrap();
+        assert_eq!(options.filename.as_deref(), Some("statement.csv"));
+        assert_eq!(options.output.as_deref(), Some("out.csv"));
+        assert_eq!(options.precision.decimal_places, 2);
+        assert_eq!(options.policy.errors, ErrorPolicy::Abort);
+        assert_eq!((options.shards, options.backfill_rate), (1, 1000));
+
+        let options = run("statement --client c-1 --client-ids text in.csv").unwrap();
+        assert_eq!(options.statement, Some(ClientKey::Name("c-1".to_owned())));
+        assert!(matches!(
+            parse(["in.csv", "--help"].map(str::to_owned).into_iter()),
+            Ok(Command::Help)
+        ));
+
+        for (args, error) in [
+            ("", "no filename given"),
+            ("a.csv b.csv", "unexpected argument \"b.csv\""),
+            ("--precision", "--precision requires a value"),
+            (
+                "--client 1 in.csv",
+                "--client can only be used with statement",
+            ),
+            ("statement in.csv", "statement requires --client"),
+            (
+                "--shards 2 --reorder-window 5 in.csv",
+                "--shards can't be used with --client-ids text or --reorder-window",
+            ),
+            (
+                "--baseline b.csv in.csv",
+                "--baseline and --anomalies have to be used together",
+            ),
+            ("--wal log in.csv", "--wal requires --listen or --grpc"),
+            (
+                "--stats s.csv --stats-buckets 100,10 in.csv",
+                "invalid buckets \"100,10\", expected increasing positive amounts, e.g. \
+                 100,1000,10000",
+            ),
+            (
+                "--manifest m.json --format json --output out.json in.csv",
+                "--manifest requires uncompressed csv --output, and can't be used with \
+                 statement, --simulate, --listen, or --grpc",
+            ),
+            ("compare a.json", "usage: ledger compare A B"),
+            (
+                "--stats s.csv --listen 127.0.0.1:0",
+                "--stats can't be used with --listen or --grpc",
+            ),
+        ] {
+            assert_eq!(run(args).err().as_deref(), Some(error), "{}", args);
+        }
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Numbers too large for their option, malformed values, and
+        // unknown options are reported rather than taken as the filename.
+        for (args, error) in [
+            ("--unknown in.csv", "unknown option \"--unknown\""),
+            (
+                "--max-memory 99999999999T in.csv",
+                "size \"99999999999T\" is too large",
+            ),
+            ("--max-memory 5X in.csv", "invalid size \"5X\""),
+            (
+                "--latency-budget 5 in.csv",
+                "invalid duration \"5\", expected e.g. 5ms",
+            ),
+            (
+                "--latency-budget 99999999999999999999s in.csv",
+                "number too large to fit in target type",
+            ),
+            (
+                "--overdraft 79228162514264337593543950336 in.csv",
+                "Invalid decimal: overflow from too many digits",
+            ),
+            ("--overdraft -1 in.csv", "--overdraft can't be negative"),
+            ("--precision 29 in.csv", "--precision can be at most 28"),
+            (
+                "--max-held-ratio 1.5 in.csv",
+                "--max-held-ratio must be positive, and at most 1",
+            ),
+            (
+                "--minor-units 29 in.csv",
+                "invalid amount scale \"29\", expected a number of digits from 0 to 28",
+            ),
+            ("--sweep-account -1 in.csv", "invalid digit found in string"),
+            (
+                "--account-overdraft 7:-1 in.csv",
+                "--account-overdraft takes CLIENT:AMOUNT, e.g. 7:100",
+            ),
+            (
+                "--withdrawal-limit 1,-1,1 in.csv",
+                "unknown policy value \"1,-1,1\"",
+            ),
+            ("generate --clients 0", "--clients must be at least 1"),
+            (
+                "generate --dispute-rate NaN",
+                "--dispute-rate must be between 0 and 1",
+            ),
+            ("generate --clients", "--clients requires a number"),
+            ("generate in.csv", "unknown option \"in.csv\" for generate"),
+        ] {
+            let result = parse(args.split_whitespace().map(str::to_owned));
+            let result = result.map(drop).map_err(|err| err.to_string());
+            assert_eq!(result.err().as_deref(), Some(error), "{}", args);
+        }
+
+        // The largest client ID is a client like any other, one more isn't.
+        let last = AccountId::MAX;
+        let options = run(&format!("--sweep-account {} in.csv", last)).unwrap();
+        assert_eq!(options.policy.sweep_account, Some(last));
+        let past = u128::from(last) + 1;
+        assert!(run(&format!("--sweep-account {} in.csv", past)).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use std::{str::FromStr, time::Duration};
+
+use thiserror::Error;
+use tonic::{transport::Channel, Code, Request, Status};
+
+use crate::{
+    clock::{IdSource, SequentialIds, SystemClock},
+    grpc::proto::{self, ledger_client::LedgerClient, transaction::Kind},
+    AccountId, Balance, Transaction,
+};
+
+// A typed client for the gRPC service, see `grpc`. Every submission carries
+// an idempotency key, so failed requests can be retried without the risk of
+// applying a transaction twice.
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("invalid URL {0:?}")]
+    InvalidUrl(String),
+    #[error("failed to connect: {0}")]
+    Connect(#[from] tonic::transport::Error),
+    // The server rejected the request, e.g. because the transaction can't
+    // be applied. The status code tells the reasons apart, see the protobuf
+    // definition. It's boxed since `Status` is large.
+    #[error("{}", .0.message())]
+    Rejected(Box<Status>),
+    #[error("{0:?} transactions can't be submitted over gRPC")]
+    Unsupported(Transaction),
+    #[error("the server sent a malformed amount {0:?}")]
+    MalformedAmount(String),
+}
+
+impl From<Status> for ClientError {
+    fn from(status: Status) -> Self {
+        ClientError::Rejected(Box::new(status))
+    }
+}
+
+// RetryPolicy decides how often requests are retried when the server can't
+// be reached, and how long to wait in between. The wait doubles after every
+// attempt.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub initial_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+// AccountState is the state of an account as reported by the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountState {
+    pub available: Balance,
+    pub held: Balance,
+    pub total: Balance,
+    pub locked: bool,
+}
+
+// Requests that never reached the ledger, or whose answer was lost, are safe
+// to retry. Anything else is the ledger's answer and would be the same again.
+fn is_retryable(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::Aborted | Code::Unknown
+    )
+}
+
+fn parse_amount(amount: String) -> Result<Balance, ClientError> {
+    Balance::from_str(&amount).map_err(|_| ClientError::MalformedAmount(amount))
+}
+
+pub struct Client {
+    inner: LedgerClient<Channel>,
+    retry: RetryPolicy,
+    // Idempotency keys are unique to this client by default.
+    keys: Box<dyn IdSource>,
+}
+
+impl Client {
+    // Connect to the gRPC service at the given URL, e.g.
+    // `http://127.0.0.1:50051`.
+    pub async fn connect(url: impl Into<String>) -> Result<Client, ClientError> {
+        let url = url.into();
+        let channel = Channel::from_shared(url.clone())
+            .map_err(|_| ClientError::InvalidUrl(url))?
+            .connect()
+            .await?;
+        Ok(Client {
+            inner: LedgerClient::new(channel),
+            retry: RetryPolicy::default(),
+            keys: Box::new(SequentialIds::unique(&SystemClock)),
+        })
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Client {
+        self.retry = retry;
+        self
+    }
+
+    // Generate idempotency keys from the given source instead, e.g. to make
+    // them predictable in tests. The keys must never repeat.
+    pub fn with_idempotency_keys(mut self, keys: impl IdSource + 'static) -> Client {
+        self.keys = Box::new(keys);
+        self
+    }
+
+    // Run `call` until it succeeds, fails with a non-retryable status, or
+    // the attempts run out.
+    async fn retry<T, F, Fut>(&self, mut call: F) -> Result<T, ClientError>
+    where
+        F: FnMut(LedgerClient<Channel>) -> Fut,
+        Fut: std::future::Future<Output = Result<T, Status>>,
+    {
+        let mut backoff = self.retry.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match call(self.inner.clone()).await {
+                Err(status) if is_retryable(&status) && attempt < self.retry.attempts => {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    // Submit a transaction, generating a new idempotency key for it.
+    pub async fn submit(&self, client: AccountId, tx: Transaction) -> Result<(), ClientError> {
+        self.submit_with_key(client, tx, self.keys.next_id()).await
+    }
+
+    // Submit a transaction with the given idempotency key, e.g. one that was
+    // persisted before a restart to retry a submission safely. The service
+    // takes IDs as u64s, which they are already with the `wide-ids` feature.
+    #[cfg_attr(feature = "wide-ids", allow(clippy::useless_conversion))]
+    pub async fn submit_with_key(
+        &self,
+        client: AccountId,
+        tx: Transaction,
+        idempotency_key: String,
+    ) -> Result<(), ClientError> {
+        let kind = match tx {
+            Transaction::Deposit { new_id, amount } => Kind::Deposit(proto::Deposit {
+                tx: new_id.into(),
+                amount: amount.to_string(),
+            }),
+            Transaction::Withdrawal { new_id, amount } => Kind::Withdrawal(proto::Withdrawal {
+                tx: new_id.into(),
+                amount: amount.to_string(),
+            }),
+            Transaction::Dispute { id, amount: None } => {
+                Kind::Dispute(proto::Dispute { tx: id.into() })
+            }
+            Transaction::Resolve { id } => Kind::Resolve(proto::Resolve { tx: id.into() }),
+            Transaction::Chargeback { id } => Kind::Chargeback(proto::Chargeback { tx: id.into() }),
+            // The service only takes disputes of whole transactions.
+            Transaction::Dispute {
+                amount: Some(_), ..
+            }
+            | Transaction::Recovery
+            | Transaction::Fee { .. }
+            | Transaction::AdjustCredit { .. }
+            | Transaction::AdjustDebit { .. }
+            | Transaction::Refund { .. }
+            | Transaction::Lock
+            | Transaction::Unlock
+            | Transaction::Authorize { .. }
+            | Transaction::Capture { .. }
+            | Transaction::Void { .. }
+            | Transaction::Scheduled { .. }
+            | Transaction::Timestamped { .. }
+            | Transaction::InCurrency { .. }
+            | Transaction::OpenAccount { .. }
+            | Transaction::CloseAccount => return Err(ClientError::Unsupported(tx)),
+        };
+        let request = proto::SubmitTransactionRequest {
+            client: client.into(),
+            transaction: Some(proto::Transaction { kind: Some(kind) }),
+            idempotency_key,
+        };
+
+        self.retry(|mut inner| {
+            let request = Request::new(request.clone());
+            async move { inner.submit_transaction(request).await }
+        })
+        .await?;
+        Ok(())
+    }
+
+    // Look up the current state of the given account.
+    #[cfg_attr(feature = "wide-ids", allow(clippy::useless_conversion))]
+    pub async fn account(&self, client: AccountId) -> Result<AccountState, ClientError> {
+        let account = self
+            .retry(|mut inner| async move {
+                let request = Request::new(proto::GetAccountRequest {
+                    client: client.into(),
+                });
+                inner.get_account(request).await
+            })
+            .await?
+            .into_inner();
+
+        Ok(AccountState {
+            available: parse_amount(account.available)?,
+            held: parse_amount(account.held)?,
+            total: parse_amount(account.total)?,
+            locked: account.locked,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::{
+        transport::{server::TcpIncoming, Server},
+        Code,
+    };
+
+    use super::{AccountState, Client, ClientError};
+    use crate::{
+        clock::IdSource,
+        grpc::{proto::ledger_server::LedgerServer, LedgerService},
+        ledger::Ledger,
+        Transaction,
+    };
+
+    #[tokio::test]
+    async fn submit_and_get_account() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(LedgerServer::new(LedgerService::new(Ledger::default())))
+                .serve_with_incoming(incoming),
+        );
+
+        let client = Client::connect(format!("http://{}", addr))
+            .await
+            .expect("should connect");
+        let deposit = Transaction::Deposit {
+            new_id: 1,
+            amount: "10.5".parse().unwrap(),
+        };
+        client.submit(1, deposit).await.expect("should deposit");
+        client
+            .submit(
+                1,
+                Transaction::Dispute {
+                    id: 1,
+                    amount: None,
+                },
+            )
+            .await
+            .expect("should dispute");
+
+        assert_eq!(
+            client.account(1).await.expect("account should exist"),
+            AccountState {
+                available: "0.0".parse().unwrap(),
+                held: "10.5".parse().unwrap(),
+                total: "10.5".parse().unwrap(),
+                locked: false,
+            }
+        );
+        assert!(matches!(
+            client.account(2).await,
+            Err(ClientError::Rejected(status)) if status.code() == Code::NotFound
+        ));
+        assert!(matches!(
+            client.submit(1, Transaction::Recovery).await,
+            Err(ClientError::Unsupported(_))
+        ));
+
+        // With a key source that always repeats itself every submission
+        // after the first is a retry, so it isn't applied again.
+        struct SameKey;
+        impl IdSource for SameKey {
+            fn next_id(&self) -> String {
+                "same".to_owned()
+            }
+        }
+        let client = client.with_idempotency_keys(SameKey);
+        for new_id in [2, 3] {
+            let deposit = Transaction::Deposit {
+                new_id,
+                amount: 1.into(),
+            };
+            client.submit(2, deposit).await.expect("should deposit");
+        }
+        let account = client.account(2).await.expect("account should exist");
+        assert_eq!(account.total, 1.into());
+    }
+}
@@ -0,0 +1,315 @@
+use std::{fmt, io::Read};
+
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    ledger::{
+        csv_headers, csv_reader, parse_csv_line, CsvHeaders, Ledger, ParsedLine, ProcessingError,
+        ProcessingErrorKind, RecordError, Reject,
+    },
+    policy::{AmountPolicy, ErrorPolicy},
+    reorder::Reorder,
+    scaling::{AmountScale, ScaleCheck},
+    AccountId,
+};
+
+// ClientNames gives the clients known by a name, e.g. a UUID, the account
+// IDs their accounts are kept under, see `ClientIdPolicy::Text`. Names are
+// given IDs in the order they're first read, starting from 0, so a ledger
+// can tell apart as many names as there are account IDs. It's serialized as
+// the names in the order of their IDs.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ClientNames {
+    names: Vec<String>,
+    ids: FxHashMap<String, AccountId>,
+}
+
+impl ClientNames {
+    // The account ID of the named client, given one if it doesn't have one
+    // yet, or `None` if every ID has been given away.
+    pub fn intern(&mut self, name: &str) -> Option<AccountId> {
+        if let Some(id) = self.ids.get(name) {
+            return Some(*id);
+        }
+        let id = AccountId::try_from(self.names.len()).ok()?;
+        self.names.push(name.to_owned());
+        self.ids.insert(name.to_owned(), id);
+        Some(id)
+    }
+
+    pub fn id(&self, name: &str) -> Option<AccountId> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: AccountId) -> Option<&str> {
+        self.names.get(id as usize).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    // Like `intern`, but a name that's empty, or can't be given an ID, makes
+    // the record it's on malformed.
+    pub(crate) fn account_for(&mut self, name: &str) -> Result<AccountId, RecordError> {
+        if name.is_empty() {
+            return Err(RecordError::Malformed("the client is empty".to_owned()));
+        }
+        self.intern(name).ok_or_else(|| {
+            RecordError::Malformed(format!("no account ID is left for client {:?}", name))
+        })
+    }
+}
+
+impl Serialize for ClientNames {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.names.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ClientNames {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut client_names = ClientNames::default();
+        for name in Vec::<String>::deserialize(deserializer)? {
+            if client_names.id(&name).is_some() || client_names.intern(&name).is_none() {
+                return Err(serde::de::Error::custom(format!(
+                    "client {:?} can't be given an account ID",
+                    name
+                )));
+            }
+        }
+        Ok(client_names)
+    }
+}
+
+// ClientKey is how outputs identify a client: by its name if it has one,
+// see `ClientNames`, or else by its account ID. It's written as the one or
+// the other, so outputs of ledgers without names stay the same.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(untagged)]
+pub enum ClientKey {
+    Id(AccountId),
+    Name(String),
+}
+
+impl fmt::Display for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientKey::Id(id) => write!(f, "{}", id),
+            ClientKey::Name(name) => f.write_str(name),
+        }
+    }
+}
+
+// The fields of a record with the client's name in the given column swapped
+// for a placeholder ID, so the rest of it can be read like any other record.
+pub(crate) fn without_name(fields: &csv::StringRecord, column: usize) -> csv::StringRecord {
+    let mut unnamed = fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| if index == column { "0" } else { field })
+        .collect::<csv::StringRecord>();
+    unnamed.set_position(fields.position().cloned());
+    unnamed
+}
+
+// Read transactions from the given CSV reader and apply them to the ledger
+// like `Ledger::read_csv_with`, with the clients given by name, see
+// `ClientIdPolicy::Text`. Names are given account IDs as they're read, so
+// lines are read and applied on a single thread, and put in order first if
+// the policy has a reorder window, like `read_csv_reordered` does.
+pub(crate) fn read_csv_named<R: Read>(
+    ledger: &mut Ledger,
+    reader: R,
+    scale: AmountScale,
+    mut sink: &mut dyn FnMut(Reject),
+) -> ScaleCheck {
+    let mut check = ScaleCheck::new(scale);
+    let amounts = ledger.policy().amounts;
+    let abort = ledger.policy().errors == ErrorPolicy::Abort;
+    let mut reader = csv_reader(reader);
+    let Some(headers) = csv_headers(&mut reader, &mut sink) else {
+        return check;
+    };
+    let column = headers.names.iter().position(|name| name == "client");
+
+    let mut reorder = ledger.policy().reorder_window.map(Reorder::new);
+    for line in reader.records() {
+        let parsed = match (line, column) {
+            (Ok(fields), Some(column)) if column < fields.len() => {
+                parse_named(ledger, fields, column, &headers, scale, amounts, &mut check)
+            }
+            // Lines without a client are rejected like any other.
+            (line, _) => parse_csv_line(line, &headers, scale, amounts, &mut check),
+        };
+        let released = match parsed {
+            Ok(line) => match &mut reorder {
+                Some(reorder) => reorder.push(line.transaction.timestamp(), line),
+                None => vec![line],
+            },
+            Err(reject) => {
+                sink(reject);
+                if abort {
+                    return check;
+                }
+                continue;
+            }
+        };
+        for line in released {
+            if let Err(reject) = apply_named(ledger, line) {
+                sink(reject);
+                if abort {
+                    return check;
+                }
+            }
+        }
+    }
+    for line in reorder.as_mut().map(Reorder::finish).unwrap_or_default() {
+        if let Err(reject) = apply_named(ledger, line) {
+            sink(reject);
+            if abort {
+                break;
+            }
+        }
+    }
+    check
+}
+
+// Read the transaction on a line like `parse_csv_line`, giving the name in
+// the client's column an account ID if it doesn't have one yet.
+fn parse_named(
+    ledger: &mut Ledger,
+    fields: csv::StringRecord,
+    column: usize,
+    headers: &CsvHeaders,
+    scale: AmountScale,
+    amounts: AmountPolicy,
+    check: &mut ScaleCheck,
+) -> Result<ParsedLine, Reject> {
+    let unnamed = without_name(&fields, column);
+    let mut line =
+        parse_csv_line(Ok(unnamed), headers, scale, amounts, check).map_err(|reject| Reject {
+            record: fields.iter().collect::<Vec<_>>().join(","),
+            ..reject
+        })?;
+    line.account = ledger
+        .client_names
+        .account_for(fields[column].trim())
+        .map_err(|err| ProcessingError::at_line(&fields, ProcessingErrorKind::Record(err)))?;
+    line.fields = fields;
+    Ok(line)
+}
+
+// Apply the transaction on a line like `ParsedLine::apply`, describing the
+// client by its name if it can't be applied.
+fn apply_named(ledger: &mut Ledger, line: ParsedLine) -> Result<(), Reject> {
+    let transaction = line.transaction.kind();
+    let result = ledger.apply(line.account, line.transaction);
+    result.map_err(|error| {
+        let kind = ProcessingErrorKind::Transaction {
+            client: ledger.client_key(line.account),
+            transaction,
+            error,
+        };
+        ProcessingError::at_line(&line.fields, kind).into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientKey, ClientNames};
+    #[cfg(not(feature = "wide-ids"))]
+    use crate::AccountId;
+    use crate::{
+        ledger::{Ledger, Reject},
+        policy::ClientIdPolicy,
+        scaling::AmountScale,
+    };
+
+    #[test]
+    fn client_names() {
+        let mut names = ClientNames::default();
+        assert_eq!(names.intern("alice"), Some(0));
+        assert_eq!(names.intern("bob"), Some(1));
+        assert_eq!(names.intern("alice"), Some(0));
+        assert_eq!(names.id("bob"), Some(1));
+        assert_eq!(names.name(1), Some("bob"));
+        assert_eq!(names.name(2), None);
+        assert!(names.account_for("").is_err());
+
+        let json = serde_json::to_string(&names).unwrap();
+        assert_eq!(json, r#"["alice","bob"]"#);
+        assert_eq!(serde_json::from_str::<ClientNames>(&json).unwrap(), names);
+        assert!(serde_json::from_str::<ClientNames>(r#"["a","a"]"#).is_err());
+
+        let key = ClientKey::Name("alice".to_owned());
+        assert_eq!(serde_json::to_string(&key).unwrap(), r#""alice""#);
+        assert_eq!(serde_json::to_string(&ClientKey::Id(7)).unwrap(), "7");
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Clients named by text are rejected like numbered ones: alice's
+        // deposit too large for funds to hold, the deposit into bob's closed
+        // account, and carol's refund of a withdrawal, and the lines without
+        // a name or with a malformed amount don't give anyone an ID.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,alice,1,{max}
+deposit,alice,2,{max}
+deposit,bob,3,5
+withdrawal,bob,4,5
+close_account,bob,0,
+deposit,bob,5,1
+deposit,\"carol, inc.\",6,10
+withdrawal,\"carol, inc.\",7,4
+refund,\"carol, inc.\",7,
+deposit,,8,1
+deposit,dave,9,x
+"
+        );
+        let mut ledger = Ledger::builder().client_ids(ClientIdPolicy::Text).build();
+        let mut rejects = vec![];
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+        assert_eq!(
+            rejects,
+            [
+                (3, "overflow"),
+                (7, "account_closed"),
+                (10, "not_refundable"),
+                (11, "malformed"),
+                (12, "malformed"),
+            ]
+        );
+        let names = &ledger.client_names;
+        assert_eq!(names.len(), 3);
+        assert_eq!(names.id("carol, inc."), Some(2));
+        assert!(ledger
+            .account(names.id("bob").unwrap())
+            .unwrap()
+            .is_closed());
+
+        // Once every account ID is given away, new names are malformed.
+        #[cfg(not(feature = "wide-ids"))]
+        {
+            let mut names = ClientNames::default();
+            for id in 0..=AccountId::MAX {
+                assert_eq!(names.intern(&id.to_string()), Some(id));
+            }
+            assert_eq!(names.intern("one too many"), None);
+            assert!(names.account_for("one too many").is_err());
+            assert_eq!(names.intern("0"), Some(0));
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::Timestamp;
+
+// Clock is where anything that depends on the current time gets it from, so
+// that tests can control time instead of waiting for it. `SystemClock` is the
+// real one, `ManualClock` only moves when it's told to.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// ManualClock starts at the given time and stays there until it's set or
+// advanced.
+pub struct ManualClock(Mutex<SystemTime>);
+
+impl ManualClock {
+    pub fn new(now: SystemTime) -> ManualClock {
+        ManualClock(Mutex::new(now))
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.0.lock().expect("clock lock poisoned") = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().expect("clock lock poisoned") += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().expect("clock lock poisoned")
+    }
+}
+
+// IdSource generates identifiers that have to be unique, e.g. idempotency
+// keys, so that tests can predict them.
+pub trait IdSource: Send + Sync {
+    fn next_id(&self) -> String;
+}
+
+// SequentialIds generates the given prefix followed by a counter in hex,
+// starting from zero: `prefix-0`, `prefix-1`, and so on.
+pub struct SequentialIds {
+    prefix: String,
+    next: AtomicU64,
+}
+
+impl SequentialIds {
+    pub fn new(prefix: impl Into<String>) -> SequentialIds {
+        SequentialIds {
+            prefix: prefix.into(),
+            next: AtomicU64::new(0),
+        }
+    }
+
+    // IDs that are unique across processes: the prefix is made from the
+    // process ID and the time on the given clock.
+    pub fn unique(clock: &dyn Clock) -> SequentialIds {
+        let started = clock.now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        SequentialIds::new(format!("{:x}-{:x}", std::process::id(), started.as_nanos()))
+    }
+}
+
+impl IdSource for SequentialIds {
+    fn next_id(&self) -> String {
+        format!(
+            "{}-{:x}",
+            self.prefix,
+            self.next.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+}
+
+// Parse a time given as seconds since the Unix epoch, as an RFC 3339 date
+// and time, e.g. `2024-01-02T03:04:05Z` or `2024-01-02T03:04:05.5+01:00`,
+// or as a date, which is taken as midnight UTC, into seconds since the Unix
+// epoch. Fractions of seconds are dropped, and times before the epoch aren't
+// supported.
+pub fn parse_timestamp(s: &str) -> Option<Timestamp> {
+    let digits = |s: &str, len: usize| {
+        (s.len() == len && s.bytes().all(|byte| byte.is_ascii_digit()))
+            .then(|| s.parse::<i64>().ok())
+            .flatten()
+    };
+    if !s.is_empty() && s.bytes().all(|byte| byte.is_ascii_digit()) {
+        return s.parse().ok();
+    }
+
+    let (date, time) = match s.find(['T', 't', ' ']) {
+        Some(at) => (&s[..at], Some(&s[at + 1..])),
+        None => (s, None),
+    };
+    let mut parts = date.split('-');
+    let (Some(year), Some(month), Some(day), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    let (year, month, day) = (digits(year, 4)?, digits(month, 2)?, digits(day, 2)?);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if !(1..=days_in_month).contains(&day) {
+        return None;
+    }
+    let mut seconds = days_from_civil(year, month, day) * 86_400;
+
+    if let Some(time) = time {
+        let (time, offset) = match time.strip_suffix(['Z', 'z']) {
+            Some(time) => (time, 0),
+            None => {
+                let at = time.rfind(['+', '-'])?;
+                let (hours, minutes) = time[at + 1..].split_once(':')?;
+                let (hours, minutes) = (digits(hours, 2)?, digits(minutes, 2)?);
+                if hours > 23 || minutes > 59 {
+                    return None;
+                }
+                let offset = hours * 3600 + minutes * 60;
+                let sign = if time.as_bytes()[at] == b'-' { -1 } else { 1 };
+                (&time[..at], sign * offset)
+            }
+        };
+        let time = match time.split_once('.') {
+            // The fraction is dropped, however many digits it has.
+            Some((time, fraction)) => {
+                if fraction.is_empty() || !fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+                    return None;
+                }
+                time
+            }
+            None => time,
+        };
+        let mut parts = time.split(':');
+        let (Some(hour), Some(minute), Some(second), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return None;
+        };
+        let (hour, minute, second) = (digits(hour, 2)?, digits(minute, 2)?, digits(second, 2)?);
+        // A leap second is taken as the first second of the next minute.
+        if hour > 23 || minute > 59 || second > 60 {
+            return None;
+        }
+        seconds += hour * 3600 + minute * 60 + second - offset;
+    }
+    seconds.try_into().ok()
+}
+
+// The number of days from the Unix epoch to the given date of the proleptic
+// Gregorian calendar, see http://howardhinnant.github.io/date_algorithms.html.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{parse_timestamp, Clock, IdSource, ManualClock, SequentialIds};
+    use crate::Timestamp;
+
+    #[test]
+    fn manual_clock_and_ids() {
+        let clock = ManualClock::new(UNIX_EPOCH + Duration::from_nanos(0x10));
+        clock.advance(Duration::from_nanos(0x20));
+        assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_nanos(0x30));
+
+        let ids = SequentialIds::unique(&clock);
+        let prefix = format!("{:x}-30", std::process::id());
+        assert_eq!(ids.next_id(), format!("{}-0", prefix));
+        assert_eq!(ids.next_id(), format!("{}-1", prefix));
+
+        let ids = SequentialIds::new("test");
+        assert_eq!(ids.next_id(), "test-0");
+    }
+
+    #[test]
+    fn timestamps() {
+        let valid = [
+            ("0", 0),
+            ("1704164645", 1_704_164_645),
+            ("1970-01-01", 0),
+            ("2024-01-02T03:04:05Z", 1_704_164_645),
+            ("2024-01-02 03:04:05.999z", 1_704_164_645),
+            ("2024-01-02T04:04:05+01:00", 1_704_164_645),
+            ("2024-01-01T23:04:05-04:00", 1_704_164_645),
+            ("2024-02-29", 1_709_164_800),
+        ];
+        for (timestamp, expected) in valid {
+            assert_eq!(parse_timestamp(timestamp), Some(expected), "{}", timestamp);
+        }
+        let invalid = [
+            "",
+            "-1",
+            "1969-12-31",
+            "2023-02-29",
+            "2024-13-01",
+            "2024-1-2",
+            "2024-01-02T03:04:05",
+            "2024-01-02T24:00:00Z",
+            "2024-01-02T03:04:05.Z",
+            "2024-01-02T03:04Z",
+        ];
+        for timestamp in invalid {
+            assert_eq!(parse_timestamp(timestamp), None, "{}", timestamp);
+        }
+    }
+
+    #[test]
+    fn edge_cases() {
+        // The largest timestamp there is, fractions however long, and leap
+        // seconds are fine, but times past the largest, or before the epoch
+        // once the offset is taken into account, aren't. Neither are
+        // offsets of a day or more.
+        let max = Timestamp::MAX;
+        assert_eq!(parse_timestamp(&max.to_string()), Some(max));
+        assert_eq!(parse_timestamp(&format!("{}0", max)), None);
+        assert_eq!(
+            parse_timestamp("2024-01-02T03:04:05.99999999999999999999999Z"),
+            Some(1_704_164_645)
+        );
+        assert_eq!(parse_timestamp("2016-12-31T23:59:60Z"), Some(1_483_228_800));
+        for timestamp in [
+            "1970-01-01T00:00:00+00:01",
+            "2024-01-02T03:04:05+24:00",
+            "2024-01-02T03:04:05+01:60",
+            "2024-01-02T03:04:05.5x5Z",
+            "2024-01-02T03:04:05+1:00",
+            "2024-01-02T03:04:05Z ",
+            "２０２４-01-02",
+        ] {
+            assert_eq!(parse_timestamp(timestamp), None, "{}", timestamp);
+        }
+
+        // IDs keep counting wherever the clock is, even before the epoch.
+        let clock = ManualClock::new(UNIX_EPOCH - Duration::from_secs(1));
+        let ids = SequentialIds::unique(&clock);
+        assert_eq!(ids.next_id(), format!("{:x}-0-0", std::process::id()));
+        clock.set(UNIX_EPOCH);
+        assert_eq!(clock.now(), UNIX_EPOCH);
+    }
+}
@@ -0,0 +1,562 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    atomic_file,
+    diff::{self, Balances, DiffError, Summaries},
+    policy::Policy,
+};
+
+// Comparing two runs, e.g. of two versions of the engine on the same
+// historical input, cross-references what they wrote and reports the records
+// they treated differently: the accounts whose balances or lock status
+// differ, the lines rejected by one run but not the other, or for different
+// reasons, and the stats that differ. Every run describes what it wrote in a
+// manifest, see `Manifest`. Rejects and stats are only compared if both runs
+// wrote them.
+
+#[derive(Error, Debug)]
+pub enum CompareError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+    #[error("{0}")]
+    Csv(#[from] csv::Error),
+    #[error("{0}")]
+    Diff(#[from] DiffError),
+    #[error("the {0} column is missing")]
+    MissingColumn(&'static str),
+    #[error("line {line}: {message}")]
+    Malformed { line: u64, message: String },
+    #[error("{}: {source}", path.display())]
+    File {
+        path: PathBuf,
+        source: Box<CompareError>,
+    },
+}
+
+// Manifest describes a run: the version of the engine, its input and
+// policy, and the files it wrote the account summaries, its rejects, and
+// its stats to. Paths are absolute, so manifests can be compared from
+// anywhere, but relative ones are read relative to the manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub input: Option<PathBuf>,
+    // The settings of the policy, see `Policy::settings`.
+    pub settings: Vec<String>,
+    pub output: PathBuf,
+    pub rejects: Option<PathBuf>,
+    pub stats: Option<PathBuf>,
+}
+
+impl Manifest {
+    // The manifest of a run of this version of the engine, for files that
+    // have been written.
+    pub fn new(
+        input: Option<&Path>,
+        policy: &Policy,
+        output: &Path,
+        rejects: Option<&Path>,
+        stats: Option<&Path>,
+    ) -> std::io::Result<Manifest> {
+        let canonical = |path: Option<&Path>| path.map(std::fs::canonicalize).transpose();
+        Ok(Manifest {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            input: canonical(input)?,
+            settings: policy.settings(),
+            output: std::fs::canonicalize(output)?,
+            rejects: canonical(rejects)?,
+            stats: canonical(stats)?,
+        })
+    }
+
+    // Write the manifest to `path` as JSON.
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        atomic_file::write_atomically(path, |file| {
+            serde_json::to_writer_pretty(&mut *file, self)?;
+            file.write_all(b"\n")
+        })
+    }
+
+    // Read the manifest written to `path`.
+    pub fn read(path: &Path) -> Result<Manifest, CompareError> {
+        let in_file = |source: CompareError| CompareError::File {
+            path: path.to_owned(),
+            source: Box::new(source),
+        };
+        let file = std::fs::File::open(path).map_err(|err| in_file(err.into()))?;
+        let mut manifest: Manifest = serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|err| in_file(err.into()))?;
+        let directory = path.parent().unwrap_or(Path::new(""));
+        for path in [
+            &mut manifest.input,
+            &mut manifest.rejects,
+            &mut manifest.stats,
+        ]
+        .into_iter()
+        .flatten()
+        .chain([&mut manifest.output])
+        {
+            *path = directory.join(&*path);
+        }
+        Ok(manifest)
+    }
+}
+
+// Section is what a difference between two runs is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Section {
+    // The input, and the settings of the policy.
+    Run,
+    Accounts,
+    Rejects,
+    Stats,
+}
+
+// Difference is a field of a record that differs between two runs, with its
+// value in either, empty if the record isn't in that run:
+// * Run: the `input`, or a setting by name, without a field.
+// * Accounts: the `available`, `held`, or `total` funds, or whether the
+//   account is `locked`, by client, and currency if it has one, e.g. `1 EUR`.
+// * Rejects: the `reason` a line was rejected for, by line number.
+// * Stats: a column of the stats of a type of transaction, by type, and
+//   currency if it has one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    pub section: Section,
+    pub key: String,
+    pub field: String,
+    pub a: String,
+    pub b: String,
+}
+
+impl Difference {
+    fn new(
+        section: Section,
+        key: String,
+        field: &str,
+        a: Option<String>,
+        b: Option<String>,
+    ) -> Self {
+        Difference {
+            section,
+            key,
+            field: field.to_owned(),
+            a: a.unwrap_or_default(),
+            b: b.unwrap_or_default(),
+        }
+    }
+}
+
+// The differences between the runs described by `a` and `b`.
+pub fn compare(a: &Manifest, b: &Manifest) -> Result<Vec<Difference>, CompareError> {
+    let mut differences = vec![];
+
+    let path = |path: &Option<PathBuf>| path.as_ref().map(|path| path.display().to_string());
+    if a.input != b.input {
+        let input = Difference::new(
+            Section::Run,
+            "input".to_owned(),
+            "",
+            path(&a.input),
+            path(&b.input),
+        );
+        differences.push(input);
+    }
+    let settings = |manifest: &Manifest| {
+        let settings = manifest
+            .settings
+            .iter()
+            .map(|setting| match setting.split_once('=') {
+                Some((name, value)) => (name.to_owned(), value.to_owned()),
+                None => (setting.clone(), String::new()),
+            });
+        settings.collect::<BTreeMap<_, _>>()
+    };
+    differences.extend(changed(Section::Run, settings(a), settings(b), |value| {
+        vec![(String::new(), value)]
+    }));
+
+    let summaries = |path: &Path| read(path, |file| Ok(Summaries::read_csv(file)?));
+    for difference in diff::diff(&summaries(&a.output)?, &summaries(&b.output)?) {
+        let key = match difference.currency {
+            Some(currency) => format!("{} {}", difference.client, currency),
+            None => difference.client.to_string(),
+        };
+        // Balances are compared as numbers, e.g. `1.5` and `1.5000`.
+        let fields = |balances: &Balances| {
+            [
+                ("available", balances.available.normalize().to_string()),
+                ("held", balances.held.normalize().to_string()),
+                ("total", balances.total.normalize().to_string()),
+                ("locked", balances.locked.to_string()),
+            ]
+        };
+        let (in_a, in_b) = (
+            difference.a.as_ref().map(fields),
+            difference.b.as_ref().map(fields),
+        );
+        for index in 0..4 {
+            let name = in_a
+                .as_ref()
+                .or(in_b.as_ref())
+                .map_or("", |fields| fields[index].0);
+            let value_a = in_a.as_ref().map(|fields| fields[index].1.clone());
+            let value_b = in_b.as_ref().map(|fields| fields[index].1.clone());
+            if value_a != value_b {
+                differences.push(Difference::new(
+                    Section::Accounts,
+                    key.clone(),
+                    name,
+                    value_a,
+                    value_b,
+                ));
+            }
+        }
+    }
+
+    if let (Some(rejects_a), Some(rejects_b)) = (&a.rejects, &b.rejects) {
+        let (rejects_a, rejects_b) = (
+            read(rejects_a, read_rejects)?,
+            read(rejects_b, read_rejects)?,
+        );
+        differences.extend(changed(Section::Rejects, rejects_a, rejects_b, |reason| {
+            vec![("reason".to_owned(), reason)]
+        }));
+    }
+
+    if let (Some(stats_a), Some(stats_b)) = (&a.stats, &b.stats) {
+        let (stats_a, stats_b) = (read(stats_a, read_stats)?, read(stats_b, read_stats)?);
+        differences.extend(changed(Section::Stats, stats_a, stats_b, |columns| columns));
+    }
+
+    Ok(differences)
+}
+
+// The differences between two sets of records by key, every record made up
+// of named fields by `fields`.
+fn changed<K, V, F>(
+    section: Section,
+    a: BTreeMap<K, V>,
+    b: BTreeMap<K, V>,
+    fields: F,
+) -> Vec<Difference>
+where
+    K: Ord + Clone + ToString,
+    F: Fn(V) -> Vec<(String, String)>,
+{
+    let keys = a.keys().chain(b.keys()).cloned().collect::<BTreeSet<_>>();
+    let (mut a, mut b) = (a, b);
+    let mut differences = vec![];
+    for key in keys {
+        let in_a = a.remove(&key).map(&fields).unwrap_or_default();
+        let in_b = b.remove(&key).map(&fields).unwrap_or_default();
+        // Fields are in the order of the columns they're read from.
+        let mut names = in_a
+            .iter()
+            .chain(&in_b)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        let mut seen = BTreeSet::new();
+        names.retain(|name| seen.insert(name.clone()));
+        for name in names {
+            let value = |fields: &[(String, String)]| {
+                fields
+                    .iter()
+                    .find(|(other, _)| *other == name)
+                    .map(|(_, value)| value.clone())
+            };
+            let (value_a, value_b) = (value(&in_a), value(&in_b));
+            if value_a != value_b {
+                differences.push(Difference::new(
+                    section,
+                    key.to_string(),
+                    &name,
+                    value_a,
+                    value_b,
+                ));
+            }
+        }
+    }
+    differences
+}
+
+// Read the file at `path` with `read`, naming it in the error if it fails.
+fn read<T, F>(path: &Path, read: F) -> Result<T, CompareError>
+where
+    F: FnOnce(std::io::BufReader<std::fs::File>) -> Result<T, CompareError>,
+{
+    std::fs::File::open(path)
+        .map_err(CompareError::from)
+        .and_then(|file| read(std::io::BufReader::new(file)))
+        .map_err(|source| CompareError::File {
+            path: path.to_owned(),
+            source: Box::new(source),
+        })
+}
+
+// The reasons lines were rejected for by line number, from a report written
+// by `--rejects`.
+fn read_rejects<R: Read>(input: R) -> Result<BTreeMap<u64, String>, CompareError> {
+    let mut reader = csv::Reader::from_reader(input);
+    let headers = reader.headers()?.clone();
+    // Reports without rejects are empty, without even a header.
+    if headers.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let column = |name: &'static str| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .ok_or(CompareError::MissingColumn(name))
+    };
+    let (line, reason) = (column("line")?, column("reason")?);
+    let mut rejects = BTreeMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let number = record.get(line).unwrap_or_default();
+        let number = number.parse().map_err(|_| CompareError::Malformed {
+            line: record.position().map_or(0, |position| position.line()),
+            message: format!("invalid line number {:?}", number),
+        })?;
+        rejects.insert(number, record.get(reason).unwrap_or_default().to_owned());
+    }
+    Ok(rejects)
+}
+
+// The columns of the stats of every type of transaction, by type and
+// currency, from a report written by `--stats`.
+fn read_stats<R: Read>(input: R) -> Result<BTreeMap<String, Vec<(String, String)>>, CompareError> {
+    let mut reader = csv::Reader::from_reader(input);
+    let headers = reader.headers()?.clone();
+    if headers.get(0) != Some("type") {
+        return Err(CompareError::MissingColumn("type"));
+    }
+    let keys = if headers.get(1) == Some("currency") {
+        2
+    } else {
+        1
+    };
+    let mut stats = BTreeMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let key = record.iter().take(keys).filter(|key| !key.is_empty());
+        let columns = headers.iter().zip(&record).skip(keys);
+        stats.insert(
+            key.collect::<Vec<_>>().join(" "),
+            columns
+                .map(|(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+        );
+    }
+    Ok(stats)
+}
+
+// Write the differences as CSV, with a row for every field that differs.
+pub fn write_csv<W: Write>(differences: &[Difference], output: W) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(true)
+        .from_writer(output);
+    for difference in differences {
+        writer.serialize(difference)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, write_csv, CompareError, Manifest};
+    use crate::policy::Policy;
+
+    #[test]
+    fn compare_runs() {
+        let dir = std::env::temp_dir().join(format!("ledger-compare-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [
+            (
+                "a.csv",
+                "client,available,held,total,locked\n1,10.0000,0,10,false\n2,5,0,5,false\n",
+            ),
+            (
+                "b.csv",
+                "client,available,held,total,locked\n1,10,0,10,false\n2,5,0,5,true\n",
+            ),
+            ("rejects-a.csv", ""),
+            (
+                "rejects-b.csv",
+                "line,record,reason,message,byte\n3,\"withdrawal,2,3,0\",zero_amount,line 3,40\n",
+            ),
+            (
+                "stats-a.csv",
+                "type,count,total,<10,>=10\ndeposit,2,15,1,1\nwithdrawal,1,0,1,0\n",
+            ),
+            (
+                "stats-b.csv",
+                "type,count,total,<10,>=10\ndeposit,2,15,1,1\n",
+            ),
+        ];
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let policy = Policy::default();
+        let a = dir.join("a.csv");
+        let a = Manifest::new(None, &policy, &a, Some(&dir.join("rejects-a.csv")), None).unwrap();
+        a.write(&dir.join("a.json")).unwrap();
+        assert_eq!(Manifest::read(&dir.join("a.json")).unwrap(), a);
+        // Paths written by hand are relative to the manifest.
+        let b = r#"{"version": "0", "input": null,
+            "settings": ["zero-amounts=reject", "on-error=skip", "overdraft=0",
+                "transaction-ids=unchecked", "held-ratio-action=block"],
+            "output": "b.csv", "rejects": "rejects-b.csv", "stats": "stats-b.csv"}"#;
+        std::fs::write(dir.join("b.json"), b).unwrap();
+        let b = Manifest::read(&dir.join("b.json")).unwrap();
+        assert_eq!(b.output, dir.join("b.csv"));
+
+        // Stats are only compared if both runs wrote them.
+        let mut output = vec![];
+        write_csv(&compare(&a, &b).unwrap(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+section,key,field,a,b
+run,zero-amounts,,accept,reject
+accounts,2,locked,false,true
+rejects,3,reason,,zero_amount
+"
+        );
+        let with_stats = Manifest {
+            stats: Some(dir.join("stats-a.csv")),
+            ..a.clone()
+        };
+        let differences = compare(&with_stats, &b).unwrap();
+        let stats = differences
+            .iter()
+            .skip(3)
+            .map(|difference| &difference.field);
+        assert_eq!(stats.collect::<Vec<_>>(), ["count", "total", "<10", ">=10"]);
+        assert!(compare(&a, &a).unwrap().is_empty());
+
+        let missing = Manifest {
+            output: dir.join("missing.csv"),
+            ..a.clone()
+        };
+        let error = compare(&a, &missing).unwrap_err().to_string();
+        assert!(error.starts_with(&dir.join("missing.csv").display().to_string()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edge_cases() {
+        let dir = std::env::temp_dir().join(format!("ledger-compare-edge-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Balances as large as a balance can be are compared as numbers,
+        // and whether an account is closed isn't compared. Run `b` rejected
+        // the refund of a withdrawal for another reason, and a deposit into
+        // a closed account that `a` didn't reject at all.
+        let max = "79228162514264337593543950335";
+        let files = [
+            (
+                "a.csv",
+                format!("client,available,held,total,locked\n1,{max},0,{max},false\n"),
+            ),
+            (
+                "b.csv",
+                format!(
+                    "client,available,held,total,locked,closed\n1,{max}.000,0,{max},false,true\n"
+                ),
+            ),
+            (
+                "rejects-a.csv",
+                "line,record,reason\n4,\"refund,1,3,\",not_refundable\n".to_owned(),
+            ),
+            (
+                "rejects-b.csv",
+                "line,record,reason\n\
+                 4,\"refund,1,3,\",nonexistent_transaction\n\
+                 6,\"deposit,1,5,1\",account_closed\n"
+                    .to_owned(),
+            ),
+            (
+                "malformed.csv",
+                format!("client,available,held,total,locked\n1,x,0,{max},false\n"),
+            ),
+            (
+                "no-reason.csv",
+                "line,record\n4,\"refund,1,3,\"\n".to_owned(),
+            ),
+            (
+                "bad-line.csv",
+                "line,record,reason\nfour,\"refund,1,3,\",not_refundable\n".to_owned(),
+            ),
+            ("no-type.csv", "count,total\n2,15\n".to_owned()),
+            ("manifest.json", "{\"version\": \"0\"".to_owned()),
+        ];
+        for (name, contents) in files {
+            std::fs::write(dir.join(name), contents).unwrap();
+        }
+
+        let policy = Policy::default();
+        let manifest = |output: &str, rejects: &str| {
+            Manifest::new(
+                None,
+                &policy,
+                &dir.join(output),
+                Some(&dir.join(rejects)),
+                None,
+            )
+            .unwrap()
+        };
+        let (a, b) = (
+            manifest("a.csv", "rejects-a.csv"),
+            manifest("b.csv", "rejects-b.csv"),
+        );
+        let mut output = vec![];
+        write_csv(&compare(&a, &b).unwrap(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+section,key,field,a,b
+rejects,4,reason,not_refundable,nonexistent_transaction
+rejects,6,reason,,account_closed
+"
+        );
+
+        // Files that can't be read are named in the error.
+        let error = |a: &Manifest, b: &Manifest| match compare(a, b).unwrap_err() {
+            CompareError::File { path, source } => (path, *source),
+            error => panic!("unexpected error {error}"),
+        };
+        let (path, source) = error(&a, &manifest("malformed.csv", "rejects-a.csv"));
+        assert_eq!(path, dir.join("malformed.csv"));
+        assert_eq!(source.to_string(), "line 2: invalid available \"x\"");
+        let (path, source) = error(&a, &manifest("a.csv", "no-reason.csv"));
+        assert_eq!(path, dir.join("no-reason.csv"));
+        assert!(matches!(source, CompareError::MissingColumn("reason")));
+        let (_, source) = error(&a, &manifest("a.csv", "bad-line.csv"));
+        assert_eq!(source.to_string(), "line 2: invalid line number \"four\"");
+        let no_type = Manifest {
+            stats: Some(dir.join("no-type.csv")),
+            ..a.clone()
+        };
+        let (_, source) = error(&no_type, &no_type);
+        assert!(matches!(source, CompareError::MissingColumn("type")));
+        let (path, source) = match Manifest::read(&dir.join("manifest.json")) {
+            Err(CompareError::File { path, source }) => (path, *source),
+            result => panic!("unexpected result {result:?}"),
+        };
+        assert_eq!(path, dir.join("manifest.json"));
+        assert!(matches!(source, CompareError::Manifest(_)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+use std::io::{self, Write};
+
+use thiserror::Error;
+
+// Compression selects whether exports, like the account summaries and the
+// journal, are compressed: not at all, or with zstd at the given level.
+// zstd compresses on a worker thread per available core, so compressing
+// doesn't slow down writing large exports more than it has to. Compressing
+// requires the `zstd` feature.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CompressionError {
+    #[error("the compression level has to be between {min} and {max}")]
+    InvalidLevel { min: i32, max: i32 },
+    #[error("compression requires the zstd feature")]
+    Unsupported,
+}
+
+impl Compression {
+    // Compress with zstd at the given level. Higher levels compress better
+    // but slower.
+    #[cfg(feature = "zstd")]
+    pub fn zstd(level: i32) -> Result<Compression, CompressionError> {
+        // Negative levels trade even more of the ratio for speed, but aren't
+        // worth the confusion.
+        let levels = 1..=*zstd::compression_level_range().end();
+        if !levels.contains(&level) {
+            return Err(CompressionError::InvalidLevel {
+                min: *levels.start(),
+                max: *levels.end(),
+            });
+        }
+        Ok(Compression::Zstd(level))
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    pub fn zstd(_level: i32) -> Result<Compression, CompressionError> {
+        Err(CompressionError::Unsupported)
+    }
+
+    // Wrap `output` so that everything written to it is compressed.
+    pub fn writer<W: Write>(self, output: W) -> io::Result<CompressedWriter<W>> {
+        let inner = match self {
+            Compression::None => Inner::Plain(output),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => {
+                let mut encoder = zstd::Encoder::new(output, level)?;
+                let workers = std::thread::available_parallelism().map_or(1, |n| n.get());
+                encoder.multithread(workers as u32)?;
+                Inner::Zstd(encoder)
+            }
+        };
+        Ok(CompressedWriter(Some(inner)))
+    }
+
+    // Compress `data` on its own, e.g. a block of a file that's read back
+    // one block at a time.
+    pub(crate) fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => zstd::bulk::compress(data, level),
+        }
+    }
+
+    // Decompress data compressed by `compress`, which decompresses to at
+    // most `capacity` bytes.
+    #[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+    pub(crate) fn decompress(self, data: &[u8], capacity: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => zstd::bulk::decompress(data, capacity),
+        }
+    }
+}
+
+// CompressedWriter compresses everything written to it before passing it on
+// to the writer it wraps. The compressed output is only complete once it's
+// finished, either by `finish`, or when the writer is dropped, in which case
+// errors are only reported on stderr.
+pub struct CompressedWriter<W: Write>(Option<Inner<W>>);
+
+enum Inner<W: Write> {
+    Plain(W),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    fn inner(&mut self) -> &mut dyn Write {
+        match self.0.as_mut().expect("writer used after finishing") {
+            Inner::Plain(output) => output,
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(encoder) => encoder,
+        }
+    }
+
+    // Complete the compressed output and return the writer it was written
+    // to.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.0.take().expect("writer used after finishing") {
+            Inner::Plain(mut output) => output.flush().map(|()| output),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+impl<W: Write> Drop for CompressedWriter<W> {
+    fn drop(&mut self) {
+        if self.0.is_some() {
+            if let Err(err) = CompressedWriter(self.0.take()).finish() {
+                eprintln!("failed to finish compressed output: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "zstd"))]
+mod tests {
+    use std::io::Write;
+
+    use super::{Compression, CompressionError};
+
+    #[test]
+    fn zstd() {
+        assert_eq!(
+            Compression::zstd(0),
+            Err(CompressionError::InvalidLevel { min: 1, max: 22 })
+        );
+
+        let contents = "client,available,held,total,locked\n".repeat(1000);
+        let mut writer = Compression::zstd(19).unwrap().writer(vec![]).unwrap();
+        writer.write_all(contents.as_bytes()).unwrap();
+        let compressed = writer.finish().unwrap();
+        assert!(compressed.len() < contents.len() / 10);
+        assert_eq!(
+            zstd::decode_all(compressed.as_slice()).unwrap(),
+            contents.as_bytes()
+        );
+
+        // Dropping the writer finishes the output as well.
+        let mut compressed = vec![];
+        let mut writer = Compression::zstd(3)
+            .unwrap()
+            .writer(&mut compressed)
+            .unwrap();
+        writer.write_all(contents.as_bytes()).unwrap();
+        drop(writer);
+        assert_eq!(
+            zstd::decode_all(compressed.as_slice()).unwrap(),
+            contents.as_bytes()
+        );
+    }
+
+    #[test]
+    fn edge_cases() {
+        assert!(Compression::zstd(23).is_err());
+        assert!(Compression::zstd(-1).is_err());
+
+        // Blocks decompress to what was compressed, even empty ones, but
+        // not into less room than they need, and malformed ones not at all.
+        let zstd = Compression::zstd(3).unwrap();
+        let block = b"deposit,1,1,10\n".repeat(100);
+        let compressed = zstd.compress(&block).unwrap();
+        assert_eq!(zstd.decompress(&compressed, block.len()).unwrap(), block);
+        assert!(zstd.decompress(&compressed, block.len() - 1).is_err());
+        assert!(zstd.decompress(&block, block.len()).is_err());
+        let empty = zstd.compress(&[]).unwrap();
+        assert_eq!(zstd.decompress(&empty, 0).unwrap(), b"");
+
+        // Without compression everything is passed through as it is.
+        let none = Compression::None;
+        assert_eq!(
+            none.decompress(&none.compress(&block).unwrap(), 0).unwrap(),
+            block
+        );
+        let mut writer = none.writer(vec![]).unwrap();
+        writer.write_all(&block).unwrap();
+        assert_eq!(writer.finish().unwrap(), block);
+
+        // An output finished without anything written to it is still a
+        // complete, empty, stream.
+        let compressed = zstd.writer(vec![]).unwrap().finish().unwrap();
+        assert!(!compressed.is_empty());
+        assert!(zstd::decode_all(compressed.as_slice()).unwrap().is_empty());
+    }
+}
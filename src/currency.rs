@@ -0,0 +1,228 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::ledger::MAX_DECIMAL_PLACES;
+
+// Currency is the ISO 4217 code of a currency, e.g. `USD`, written in
+// upper case. Codes are read in either case. It's three bytes, so it can be
+// copied around with the transactions and balances it belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("invalid currency {0:?}, expected a code of three letters")]
+pub struct ParseCurrencyError(String);
+
+impl Currency {
+    pub fn as_str(&self) -> &str {
+        // Only ASCII letters are ever stored.
+        std::str::from_utf8(&self.0).expect("currency should be ASCII")
+    }
+
+    pub(crate) fn to_bytes(self) -> [u8; 3] {
+        self.0
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; 3]) -> Option<Currency> {
+        bytes
+            .iter()
+            .all(u8::is_ascii_uppercase)
+            .then_some(Currency(bytes))
+    }
+}
+
+impl FromStr for Currency {
+    type Err = ParseCurrencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes: [u8; 3] = s
+            .as_bytes()
+            .try_into()
+            .map_err(|_| ParseCurrencyError(s.to_owned()))?;
+        Currency::from_bytes(bytes.map(|byte| byte.to_ascii_uppercase()))
+            .ok_or_else(|| ParseCurrencyError(s.to_owned()))
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// The minor units of common currencies, as the number of decimal places
+// their amounts have, by code.
+const BUILT_IN: &[(&str, u32)] = &[
+    ("AUD", 2),
+    ("BHD", 3),
+    ("BTC", 8),
+    ("CAD", 2),
+    ("CHF", 2),
+    ("CNY", 2),
+    ("EUR", 2),
+    ("GBP", 2),
+    ("HKD", 2),
+    ("INR", 2),
+    ("JPY", 0),
+    ("KRW", 0),
+    ("KWD", 3),
+    ("MXN", 2),
+    ("NOK", 2),
+    ("NZD", 2),
+    ("SEK", 2),
+    ("SGD", 2),
+    ("USD", 2),
+    ("ZAR", 2),
+];
+
+// CurrencyRegistry knows how many decimal places the amounts of every
+// currency have, e.g. 2 for USD and 0 for JPY, see `Policy::currencies`.
+// Common currencies are built in, others, or different minor units, can be
+// set on top. Currencies it doesn't know are left as they are.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CurrencyRegistry {
+    custom: BTreeMap<Currency, u32>,
+}
+
+impl CurrencyRegistry {
+    // The number of decimal places of the currency, if it's known.
+    pub fn minor_units(&self, currency: Currency) -> Option<u32> {
+        self.custom.get(&currency).copied().or_else(|| {
+            BUILT_IN
+                .iter()
+                .find(|(code, _)| *code == currency.as_str())
+                .map(|&(_, digits)| digits)
+        })
+    }
+
+    // Currencies can't have more decimal places than amounts can.
+    pub fn set(&mut self, currency: Currency, digits: u32) {
+        self.custom.insert(currency, digits.min(MAX_DECIMAL_PLACES));
+    }
+
+    // The minor units set on top of the built-in ones, in the order of
+    // their codes.
+    pub fn custom(&self) -> impl Iterator<Item = (Currency, u32)> + '_ {
+        self.custom
+            .iter()
+            .map(|(currency, digits)| (*currency, *digits))
+    }
+}
+
+// Currencies are written as their code, also as keys of JSON objects.
+impl Serialize for Currency {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        code.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Currency, CurrencyRegistry};
+    use crate::{
+        ledger::{Ledger, Reject, MAX_DECIMAL_PLACES},
+        scaling::AmountScale,
+    };
+
+    #[test]
+    fn currency() {
+        let usd: Currency = "usd".parse().unwrap();
+        assert_eq!(usd.to_string(), "USD");
+        assert_eq!(usd, "USD".parse().unwrap());
+        for invalid in ["", "US", "USDT", "U$D", "ÜSD"] {
+            assert!(invalid.parse::<Currency>().is_err(), "{}", invalid);
+        }
+        assert_eq!(serde_json::to_string(&usd).unwrap(), "\"USD\"");
+        assert_eq!(
+            serde_json::from_str::<Currency>("\"eur\"")
+                .unwrap()
+                .as_str(),
+            "EUR"
+        );
+    }
+
+    #[test]
+    fn registry() {
+        let mut registry = CurrencyRegistry::default();
+        let [usd, jpy, xau] = ["USD", "JPY", "XAU"].map(|code| code.parse().unwrap());
+        assert_eq!(registry.minor_units(usd), Some(2));
+        assert_eq!(registry.minor_units(jpy), Some(0));
+        assert_eq!(registry.minor_units(xau), None);
+        registry.set(xau, 4);
+        registry.set(usd, 3);
+        assert_eq!(registry.minor_units(xau), Some(4));
+        assert_eq!(registry.minor_units(usd), Some(3));
+        assert_eq!(registry.custom().collect::<Vec<_>>(), [(usd, 3), (xau, 4)]);
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Codes are letters only, not bytes of other characters that happen
+        // to be three long, and only upper case codes are stored.
+        for invalid in ["€", "US\0", "12D"] {
+            assert!(invalid.parse::<Currency>().is_err(), "{}", invalid);
+        }
+        assert_eq!(Currency::from_bytes(*b"usd"), None);
+        assert!(serde_json::from_str::<Currency>("\"usdt\"").is_err());
+        assert!(serde_json::from_str::<Currency>("840").is_err());
+
+        let xau = "XAU".parse().unwrap();
+        let mut registry = CurrencyRegistry::default();
+        registry.set(xau, u32::MAX);
+        assert_eq!(registry.minor_units(xau), Some(MAX_DECIMAL_PLACES));
+
+        // Client 1 deposits more gold than funds can hold, and yen finer than
+        // yen go, client 2 deposits into their closed account, client 3
+        // refunds a withdrawal, and client 4's currency isn't one.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount,currency
+deposit,1,1,{max},XAU
+deposit,1,2,{max},XAU
+deposit,1,3,0.5,JPY
+deposit,2,4,5,EUR
+withdrawal,2,5,5,EUR
+close_account,2,0,,
+deposit,2,6,1,EUR
+deposit,3,7,10,GBP
+withdrawal,3,8,4,GBP
+refund,3,8,,GBP
+deposit,4,9,1,US$
+"
+        );
+        let mut ledger = Ledger::default();
+        let mut rejects = vec![];
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+        assert_eq!(
+            rejects,
+            [
+                (3, "overflow"),
+                (4, "too_many_decimals"),
+                (8, "account_closed"),
+                (11, "not_refundable"),
+                (12, "malformed"),
+            ]
+        );
+        let balance = ledger.account(1).unwrap().in_currency(xau).unwrap();
+        assert_eq!(balance.available(), max);
+        assert!(ledger.account(2).unwrap().is_closed());
+        let gbp = "GBP".parse().unwrap();
+        let balance = ledger.account(3).unwrap().in_currency(gbp).unwrap();
+        assert_eq!(balance.available(), 6.into());
+        assert!(ledger.account(4).is_none());
+    }
+}
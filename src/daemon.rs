@@ -0,0 +1,439 @@
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{SocketAddr, TcpListener},
+    str::FromStr,
+    sync::Arc,
+    thread,
+};
+#[cfg(unix)]
+use std::{os::unix::net::UnixListener, path::PathBuf};
+
+use crate::{
+    dead_letter::DeadLetters,
+    ledger::{parse_record, RecordError},
+    shared::{SharedLedger, SourceId, Submitted},
+    AccountId,
+};
+
+// ListenAddress is where the daemon accepts connections. Unix socket paths
+// are given with a `unix:` prefix, anything else is parsed as a TCP address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(ListenAddress::Unix(path.into()));
+        }
+
+        s.parse().map(ListenAddress::Tcp)
+    }
+}
+
+// Serve the ledger on the given address until the process is stopped. Every
+// connection is handled on its own thread, so records from one connection
+// are applied in the order they're received, but records from different
+// connections may be interleaved. Records that can't be processed are
+// written to `dead_letters`, if given.
+pub fn serve(
+    address: ListenAddress,
+    ledger: impl Into<SharedLedger>,
+    dead_letters: Option<DeadLetters>,
+) -> io::Result<()> {
+    let ledger = ledger.into();
+    let dead_letters = dead_letters.map(Arc::new);
+
+    match address {
+        ListenAddress::Tcp(addr) => {
+            for stream in TcpListener::bind(addr)?.incoming() {
+                let stream = stream?;
+                spawn_handler(&ledger, &dead_letters, stream.try_clone()?, stream);
+            }
+        }
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => {
+            for stream in UnixListener::bind(path)?.incoming() {
+                let stream = stream?;
+                spawn_handler(&ledger, &dead_letters, stream.try_clone()?, stream);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn spawn_handler<R, W>(
+    ledger: &SharedLedger,
+    dead_letters: &Option<Arc<DeadLetters>>,
+    input: R,
+    output: W,
+) where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    let ledger = ledger.clone();
+    let dead_letters = dead_letters.clone();
+    thread::spawn(move || {
+        let result = handle_connection(
+            &ledger,
+            dead_letters.as_deref(),
+            BufReader::new(input),
+            BufWriter::new(output),
+        );
+        if let Err(err) = result {
+            eprintln!("connection failed: {}", err);
+        }
+    });
+}
+
+// Apply every record read from `input` to the ledger and answer each one
+// with a line on `output`: `ok` if it was applied, `queued` if its account
+// is paused, or `error: ` followed by the reason it was rejected. Records are
+// formatted like the lines of the CSV input without a header. Blank lines
+// are skipped without an answer. Rejected records are also written to
+// `dead_letters`, if given.
+//
+// The admin commands `pause,CLIENT` and `resume,CLIENT` pause and resume an
+// account, see `SharedLedger::pause`. Resuming is answered with the number
+// of queued records that were applied and rejected. `metrics` is answered
+// with the latencies of the transactions applied so far by type, as JSON,
+// see `SharedLedger::latency`.
+//
+// Every connection is an ordered source of the ledger, see `SharedLedger`,
+// so the records of a client sent over one connection are applied in the
+// order they were sent.
+pub fn handle_connection<R: BufRead, W: Write>(
+    ledger: &SharedLedger,
+    dead_letters: Option<&DeadLetters>,
+    input: R,
+    output: W,
+) -> io::Result<()> {
+    let source = ledger.next_source();
+    let result = answer_records(ledger, source, dead_letters, input, output);
+    ledger.close_source(source);
+    result
+}
+
+fn answer_records<R: BufRead, W: Write>(
+    ledger: &SharedLedger,
+    source: SourceId,
+    dead_letters: Option<&DeadLetters>,
+    input: R,
+    mut output: W,
+) -> io::Result<()> {
+    let amounts = ledger.with(|ledger| ledger.policy().amounts);
+    for (sequence, line) in (0..).zip(input.split(b'\n')) {
+        let mut line = line?;
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        // Lines that aren't UTF-8 are rejected as malformed, rather than
+        // failing the connection.
+        let (line, utf8) = match String::from_utf8(line) {
+            Ok(line) => (line, Ok(())),
+            Err(err) => (
+                String::from_utf8_lossy(err.as_bytes()).into_owned(),
+                Err(RecordError::Malformed(err.utf8_error().to_string())),
+            ),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Some(command) = utf8.is_ok().then(|| parse_command(&line)).flatten() {
+            match command {
+                Command::Pause(client) if ledger.pause(client) => writeln!(output, "ok")?,
+                Command::Pause(client) => {
+                    writeln!(output, "error: client {} is already paused", client)?
+                }
+                Command::Resume(client) => match ledger.resume(client) {
+                    Some(outcome) => writeln!(
+                        output,
+                        "ok: {} applied, {} rejected",
+                        outcome.applied,
+                        outcome.rejected.len()
+                    )?,
+                    None => writeln!(output, "error: client {} isn't paused", client)?,
+                },
+                Command::Metrics => match ledger.latency() {
+                    Some(latency) => writeln!(
+                        output,
+                        "ok: {}",
+                        serde_json::to_string(&latency).expect("latencies should serialize")
+                    )?,
+                    None => writeln!(output, "error: latencies aren't tracked")?,
+                },
+            }
+            output.flush()?;
+            continue;
+        }
+
+        // Errors start with their code, see `ErrorCode`, so clients can tell
+        // them apart without parsing the message.
+        let result = utf8
+            .and_then(|()| parse_record(&line, amounts))
+            .map_err(|err| format!("{}: {}", err.code(), err))
+            .and_then(|(client, tx)| {
+                ledger
+                    .submit_from(source, sequence, client, tx)
+                    .map_err(|err| format!("{}: {}", err.code(), err))
+            });
+
+        match result {
+            Ok(Submitted::Applied) => writeln!(output, "ok")?,
+            Ok(Submitted::Queued) => writeln!(output, "queued")?,
+            Err(err) => {
+                if let Some(dead_letters) = dead_letters {
+                    dead_letters.record(&line, &err);
+                }
+                writeln!(output, "error: {}", err)?
+            }
+        }
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+enum Command {
+    Pause(AccountId),
+    Resume(AccountId),
+    Metrics,
+}
+
+// Parse an admin command, or return `None` if the line isn't one, e.g. if
+// it's a record.
+fn parse_command(line: &str) -> Option<Command> {
+    if line.trim() == "metrics" {
+        return Some(Command::Metrics);
+    }
+    let (command, client) = line.split_once(',')?;
+    let client = client.trim().parse().ok()?;
+    match command.trim() {
+        "pause" => Some(Command::Pause(client)),
+        "resume" => Some(Command::Resume(client)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use super::{handle_connection, ListenAddress};
+    use crate::{dead_letter::DeadLetters, shared::SharedLedger};
+
+    #[test]
+    fn listen_address() {
+        assert_eq!(
+            "127.0.0.1:7000".parse(),
+            Ok(ListenAddress::Tcp(([127, 0, 0, 1], 7000).into()))
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            "unix:/tmp/ledger.sock".parse(),
+            Ok(ListenAddress::Unix("/tmp/ledger.sock".into()))
+        );
+        assert!("localhost".parse::<ListenAddress>().is_err());
+    }
+
+    #[test]
+    fn answers_every_record() {
+        let ledger = SharedLedger::default();
+        let input = "\
+deposit,1,1,10
+withdrawal,1,2,15
+
+withdraw,1,3,1
+dispute,1,1
+";
+        let path = std::env::temp_dir().join(format!("ledger-dead-letters-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let dead_letters = DeadLetters::append(&path).unwrap();
+
+        let mut output = vec![];
+        handle_connection(&ledger, Some(&dead_letters), input.as_bytes(), &mut output)
+            .expect("no I/O errors");
+
+        let output = String::from_utf8(output).expect("output should be UTF8");
+        let answers = output.lines().collect::<Vec<_>>();
+        assert_eq!(answers.len(), 4);
+        assert_eq!(answers[0], "ok");
+        assert_eq!(
+            answers[1],
+            "error: insufficient_funds: Insufficient funds to withdraw requested amount"
+        );
+        assert!(answers[2]
+            .starts_with("error: malformed: The record is malformed: unknown variant `withdraw`"));
+        assert_eq!(answers[3], "ok");
+
+        let account = ledger.account(1).expect("account should exist");
+        assert_eq!(account.held(), 10.into());
+
+        // Both rejected records are dead letters, with the error attached.
+        let dead_letters = fs::read_to_string(&path).unwrap();
+        let lines = dead_letters.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "record,error");
+        assert_eq!(
+            lines[1],
+            r#""withdrawal,1,2,15",insufficient_funds: Insufficient funds to withdraw requested amount"#
+        );
+        assert!(lines[2].starts_with(r#""withdraw,1,3,1","malformed: The record is malformed"#));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keeps_client_order_across_connections() {
+        let ledger = SharedLedger::with_ordering_checks(Default::default());
+
+        // Every connection deposits to and then withdraws everything from
+        // its own client, and deposits to a client they all share. If any
+        // withdrawal were applied before its deposit it would fail.
+        let handles = (0..4u16)
+            .map(|connection| {
+                let ledger = ledger.clone();
+                std::thread::spawn(move || {
+                    let mut input = String::new();
+                    for i in 0..50u32 {
+                        let tx = u32::from(connection) * 1000 + i * 3;
+                        input += &format!("deposit,{},{},1\n", connection + 1, tx);
+                        input += &format!("withdrawal,{},{},1\n", connection + 1, tx + 1);
+                        input += &format!("deposit,100,{},1\n", tx + 2);
+                    }
+                    let mut output = vec![];
+                    handle_connection(&ledger, None, input.as_bytes(), &mut output).unwrap();
+                    String::from_utf8(output).unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            let output = handle.join().expect("ordering should be kept");
+            assert!(output.lines().all(|answer| answer == "ok"), "{}", output);
+        }
+
+        assert_eq!(ledger.account(100).unwrap().total().unwrap(), 200.into());
+    }
+
+    #[test]
+    fn metrics() {
+        let untracked = SharedLedger::default();
+        let mut output = vec![];
+        handle_connection(&untracked, None, "metrics\n".as_bytes(), &mut output).unwrap();
+        assert_eq!(output, b"error: latencies aren't tracked\n");
+
+        let ledger = SharedLedger::default().with_latency_budget(Duration::from_secs(60));
+        let input = "deposit,1,1,10\ndeposit,1,2,5\nwithdrawal,1,3,1\nmetrics\n";
+        let mut output = vec![];
+        handle_connection(&ledger, None, input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let metrics = output.lines().nth(3).unwrap().strip_prefix("ok: ").unwrap();
+        let metrics: serde_json::Value = serde_json::from_str(metrics).unwrap();
+        assert_eq!(metrics["deposit"]["count"], 2);
+        assert_eq!(metrics["withdrawal"]["slow"], 0);
+        assert!(metrics["withdrawal"]["p99_micros"].is_u64());
+    }
+
+    #[test]
+    fn pauses_and_resumes_accounts() {
+        let ledger = SharedLedger::default();
+        let input = "\
+deposit,1,1,10
+pause,1
+pause,1
+deposit,1,2,5
+withdrawal,1,3,20
+deposit,2,4,1
+withdrawal,1,5,12
+resume,1
+resume,1
+";
+        let mut output = vec![];
+        handle_connection(&ledger, None, input.as_bytes(), &mut output).expect("no I/O errors");
+
+        let output = String::from_utf8(output).expect("output should be UTF8");
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "ok",
+                "ok",
+                "error: client 1 is already paused",
+                "queued",
+                "queued",
+                "ok",
+                "queued",
+                "ok: 2 applied, 1 rejected",
+                "error: client 1 isn't paused",
+            ]
+        );
+        // The queue was applied in order: the first withdrawal exceeded the
+        // funds, the second didn't.
+        assert_eq!(ledger.account(1).unwrap().total().unwrap(), 3.into());
+        assert!(!ledger.is_paused(1));
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Client 1 deposits more than funds can hold, client 2 deposits into
+        // their closed account, and client 3 refunds a withdrawal. Lines
+        // that aren't UTF-8, or commands for clients that can't be, are
+        // rejected as malformed without failing the connection, and lines
+        // may end in CRLF.
+        let max = crate::funds::max();
+        let mut input = format!(
+            "\
+deposit,1,1,{max}
+deposit,1,2,{max}
+deposit,2,3,5\r
+withdrawal,2,4,5
+close_account,2,0,
+deposit,2,5,1
+deposit,3,6,10
+withdrawal,3,7,4
+refund,3,7,
+pause,99999999999999999999999
+"
+        )
+        .into_bytes();
+        input.extend_from_slice(b"deposit,3,8,\xff\nresume,3\r\ndeposit,3,9,1");
+
+        let ledger = SharedLedger::default();
+        let mut output = vec![];
+        handle_connection(&ledger, None, input.as_slice(), &mut output).expect("no I/O errors");
+
+        let output = String::from_utf8(output).unwrap();
+        let answers = output
+            .lines()
+            .map(|answer| answer.split(':').take(2).collect::<Vec<_>>().join(":"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            answers,
+            [
+                "ok",
+                "error: overflow",
+                "ok",
+                "ok",
+                "ok",
+                "error: account_closed",
+                "ok",
+                "ok",
+                "error: not_refundable",
+                "error: malformed",
+                "error: malformed",
+                "error: client 3 isn't paused",
+                "ok",
+            ]
+        );
+        assert_eq!(ledger.account(1).unwrap().total().unwrap(), max);
+        assert!(ledger.account(2).unwrap().is_closed());
+        assert_eq!(ledger.account(3).unwrap().total().unwrap(), 7.into());
+    }
+}
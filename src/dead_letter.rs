@@ -0,0 +1,129 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use serde::Serialize;
+
+// DeadLetters collects the records a server couldn't process, either because
+// they're malformed or because they couldn't be applied, together with the
+// reason. The records are written the way they're read by the line protocol,
+// so once the cause is fixed they can be reprocessed by sending them again.
+//
+// Dead letters are written as CSV with the columns `record` and `error`, and
+// are flushed immediately so none are lost if the server is stopped.
+pub struct DeadLetters {
+    writer: Mutex<csv::Writer<Box<dyn Write + Send>>>,
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    record: &'a str,
+    error: &'a str,
+}
+
+impl DeadLetters {
+    // Write the dead letters to `output`, starting with a header if
+    // `headers` is set.
+    pub fn new<W: Write + Send + 'static>(output: W, headers: bool) -> DeadLetters {
+        let writer = csv::WriterBuilder::new()
+            .has_headers(headers)
+            .from_writer(Box::new(output) as Box<dyn Write + Send>);
+        DeadLetters {
+            writer: Mutex::new(writer),
+        }
+    }
+
+    // Append to the dead letters at `path`, creating the file if it doesn't
+    // exist yet. The header is only written to new or empty files.
+    pub fn append(path: &Path) -> io::Result<DeadLetters> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        Ok(DeadLetters::new(file, empty))
+    }
+
+    pub fn record(&self, record: &str, error: &str) {
+        let mut writer = self.writer.lock().expect("dead letters lock poisoned");
+        let result = writer
+            .serialize(DeadLetter { record, error })
+            .and_then(|()| Ok(writer.flush()?));
+        if let Err(err) = result {
+            eprintln!("failed to write dead letter: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io};
+
+    use super::DeadLetters;
+    use crate::{daemon::handle_connection, shared::SharedLedger};
+
+    // A writer whose every write fails, like a full disk.
+    struct FailingWriter;
+
+    impl io::Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::StorageFull.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn edge_cases() {
+        let path = std::env::temp_dir().join(format!("ledger-dead-edge-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let max = crate::funds::max();
+        let ledger = SharedLedger::default();
+
+        // A deposit too large for funds to hold, a refund of a withdrawal,
+        // a deposit into a closed account, and a malformed record, sent over
+        // two connections. Appending to the dead letters of the first one
+        // doesn't repeat the header.
+        let inputs = [
+            format!("deposit,1,1,{max}\ndeposit,1,2,1\ndeposit,2,3,5\nwithdrawal,2,4,5\n"),
+            "refund,2,4,\nclose_account,2,0,\ndeposit,2,5,1\ndeposit,\"2\",6,x\n".to_owned(),
+        ];
+        for input in &inputs {
+            let dead_letters = DeadLetters::append(&path).unwrap();
+            let mut output = vec![];
+            handle_connection(&ledger, Some(&dead_letters), input.as_bytes(), &mut output).unwrap();
+        }
+
+        let mut reader = csv::Reader::from_path(&path).unwrap();
+        let letters = reader
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                let code = record[1].split(':').next().unwrap().to_owned();
+                (record[0].to_owned(), code)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            letters,
+            [
+                ("deposit,1,2,1".to_owned(), "overflow".to_owned()),
+                ("refund,2,4,".to_owned(), "not_refundable".to_owned()),
+                ("deposit,2,5,1".to_owned(), "account_closed".to_owned()),
+                ("deposit,\"2\",6,x".to_owned(), "malformed".to_owned()),
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(&path)
+                .unwrap()
+                .matches("record,error")
+                .count(),
+            1
+        );
+        fs::remove_file(&path).unwrap();
+
+        // Dead letters that can't be written are reported, not fatal.
+        DeadLetters::new(FailingWriter, true).record("deposit,1,7,1", "overflow: too large");
+    }
+}
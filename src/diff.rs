@@ -0,0 +1,371 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{client_names::ClientKey, ledger::Ledger, AccountId, Balance, Currency};
+
+// Diffing compares two sets of account summaries, e.g. those written by two
+// versions of the engine from the same input, or ours and a vendor's report,
+// and reports the accounts whose balances or lock status differ. Summaries
+// are read as CSV with the columns `client`, `available`, `held`, `total`,
+// and `locked`, and `currency` if accounts have balances in currencies. Any
+// other column, e.g. `freeze_reason`, is ignored, and so are differences in
+// how balances are written, e.g. `1.5` and `1.5000`.
+
+#[derive(Error, Debug)]
+pub enum DiffError {
+    #[error("{0}")]
+    Csv(#[from] csv::Error),
+    #[error("failed to read snapshot: {0}")]
+    Snapshot(#[from] serde_json::Error),
+    #[error("line {line}: {message}")]
+    Malformed { line: u64, message: String },
+}
+
+// The balances of an account, or of its balance in a currency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Balances {
+    pub available: Balance,
+    pub held: Balance,
+    pub total: Balance,
+    pub locked: bool,
+}
+
+// Summaries are the balances of every account in a set of account
+// summaries, by client and currency.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Summaries {
+    accounts: BTreeMap<(ClientKey, Option<Currency>), Balances>,
+    // Whether the summaries have a currency column.
+    currencies: bool,
+}
+
+impl Summaries {
+    // Read account summaries written as CSV, e.g. by `Ledger::accounts_to_csv`.
+    pub fn read_csv<R: Read>(input: R) -> Result<Summaries, DiffError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(input);
+        let headers = reader.headers()?.clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header == name)
+                .ok_or_else(|| DiffError::Malformed {
+                    line: 1,
+                    message: format!("the {} column is missing", name),
+                })
+        };
+        let client = column("client")?;
+        let available = column("available")?;
+        let held = column("held")?;
+        let total = column("total")?;
+        let locked = column("locked")?;
+        let currency = headers.iter().position(|header| header == "currency");
+
+        let mut summaries = Summaries {
+            currencies: currency.is_some(),
+            ..Summaries::default()
+        };
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |position| position.line());
+            let malformed = |message: String| DiffError::Malformed { line, message };
+            let field = |index: usize| record.get(index).unwrap_or_default();
+            let balance = |index: usize, name: &str| {
+                field(index)
+                    .parse::<Balance>()
+                    .map_err(|_| malformed(format!("invalid {} {:?}", name, field(index))))
+            };
+            let key = client_key(field(client));
+            let currency = match currency.map(field) {
+                None | Some("") => None,
+                Some(code) => Some(code.parse().map_err(|err| malformed(format!("{}", err)))?),
+            };
+            let balances = Balances {
+                available: balance(available, "available")?,
+                held: balance(held, "held")?,
+                total: balance(total, "total")?,
+                locked: field(locked)
+                    .parse()
+                    .map_err(|_| malformed(format!("invalid locked {:?}", field(locked))))?,
+            };
+            if summaries
+                .accounts
+                .insert((key.clone(), currency), balances)
+                .is_some()
+            {
+                return Err(malformed(format!("client {} appears more than once", key)));
+            }
+        }
+        Ok(summaries)
+    }
+
+    // The summaries of the accounts in the ledger, at full precision.
+    pub fn from_ledger(ledger: &Ledger) -> Summaries {
+        let mut balances = vec![];
+        ledger
+            .closing_balances_to_csv(&mut balances)
+            .expect("writing to memory should not fail");
+        Summaries::read_csv(balances.as_slice()).expect("closing balances should be readable")
+    }
+
+    // The summaries of the accounts in a snapshot written by
+    // `Ledger::save_snapshot`, at full precision.
+    pub fn from_snapshot<R: Read>(input: R) -> Result<Summaries, DiffError> {
+        let mut ledger = Ledger::default();
+        ledger.load_snapshot(input)?;
+        Ok(Summaries::from_ledger(&ledger))
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+}
+
+// Clients are compared by ID if they have one, so they're in the same order
+// as in the summaries, and by name otherwise.
+fn client_key(client: &str) -> ClientKey {
+    client
+        .parse::<AccountId>()
+        .map_or_else(|_| ClientKey::Name(client.to_owned()), ClientKey::Id)
+}
+
+// Change is how an account differs between the two sets of summaries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    // Its balances or lock status differ.
+    Changed,
+    // It's only in the first set.
+    OnlyInA,
+    // It's only in the second set.
+    OnlyInB,
+}
+
+// Difference is an account, or its balance in a currency, that differs
+// between two sets of summaries, with its balances in either, if it's in
+// there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Difference {
+    pub client: ClientKey,
+    pub currency: Option<Currency>,
+    pub change: Change,
+    pub a: Option<Balances>,
+    pub b: Option<Balances>,
+}
+
+// The accounts that differ between `a` and `b`, in the order of their
+// clients and currencies.
+pub fn diff(a: &Summaries, b: &Summaries) -> Vec<Difference> {
+    let keys = a.accounts.keys().chain(b.accounts.keys());
+    keys.collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter_map(|key| {
+            let (in_a, in_b) = (a.accounts.get(key), b.accounts.get(key));
+            let change = match (in_a, in_b) {
+                (Some(in_a), Some(in_b)) if in_a == in_b => return None,
+                (Some(_), Some(_)) => Change::Changed,
+                (Some(_), None) => Change::OnlyInA,
+                (None, _) => Change::OnlyInB,
+            };
+            Some(Difference {
+                client: key.0.clone(),
+                currency: key.1,
+                change,
+                a: in_a.copied(),
+                b: in_b.copied(),
+            })
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct DifferenceRecord {
+    client: ClientKey,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Option<Currency>>,
+    change: Change,
+    available_a: Option<Balance>,
+    available_b: Option<Balance>,
+    held_a: Option<Balance>,
+    held_b: Option<Balance>,
+    total_a: Option<Balance>,
+    total_b: Option<Balance>,
+    locked_a: Option<bool>,
+    locked_b: Option<bool>,
+}
+
+// Write the differences as CSV: the client, its currency if either set of
+// summaries has any, how it changed, and its balances and lock status in
+// either set, empty if it isn't in there.
+pub fn write_csv<W: Write>(
+    differences: &[Difference],
+    currencies: bool,
+    output: W,
+) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(true)
+        .from_writer(output);
+    for difference in differences {
+        let (a, b) = (difference.a.as_ref(), difference.b.as_ref());
+        writer.serialize(DifferenceRecord {
+            client: difference.client.clone(),
+            currency: currencies.then_some(difference.currency),
+            change: difference.change,
+            available_a: a.map(|a| a.available),
+            available_b: b.map(|b| b.available),
+            held_a: a.map(|a| a.held),
+            held_b: b.map(|b| b.held),
+            total_a: a.map(|a| a.total),
+            total_b: b.map(|b| b.total),
+            locked_a: a.map(|a| a.locked),
+            locked_b: b.map(|b| b.locked),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Whether either set of summaries has a currency column, so the differences
+// are written with one.
+pub fn has_currencies(a: &Summaries, b: &Summaries) -> bool {
+    a.currencies || b.currencies
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, has_currencies, write_csv, Change, DiffError, Summaries};
+    use crate::{client_names::ClientKey, ledger::Ledger};
+
+    #[test]
+    fn diff_summaries() {
+        let a = Summaries::read_csv(
+            "\
+client,available,held,total,locked,freeze_reason
+1,10.0000,0.0000,10.0000,false,
+2,5.0000,0.0000,5.0000,false,
+3,1.0000,0.0000,1.0000,false,
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        let b = Summaries::read_csv(
+            "\
+client,available,held,total,locked
+1,10,0,10,false
+2,5,0,5,true
+4,0,0,0,false
+"
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let differences = diff(&a, &b);
+        let changes = differences
+            .iter()
+            .map(|difference| (difference.client.clone(), difference.change))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                (ClientKey::Id(2), Change::Changed),
+                (ClientKey::Id(3), Change::OnlyInA),
+                (ClientKey::Id(4), Change::OnlyInB),
+            ]
+        );
+        let mut output = vec![];
+        write_csv(&differences, has_currencies(&a, &b), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client,change,available_a,available_b,held_a,held_b,total_a,total_b,locked_a,locked_b
+2,changed,5.0000,5,0.0000,0,5.0000,5,false,true
+3,only_in_a,1.0000,,0.0000,,1.0000,,false,
+4,only_in_b,,0,,0,,0,,false
+"
+        );
+
+        let missing = Summaries::read_csv("client,available,total,locked\n".as_bytes());
+        assert!(missing.is_err());
+        let duplicate = "client,available,held,total,locked\n1,1,0,1,false\n1,1,0,1,false\n";
+        assert!(Summaries::read_csv(duplicate.as_bytes()).is_err());
+
+        // Snapshots are compared at full precision.
+        let mut ledger = Ledger::default();
+        ledger.read_csv("type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\n".as_bytes());
+        let mut snapshot = vec![];
+        ledger.save_snapshot(&mut snapshot).unwrap();
+        let from_snapshot = Summaries::from_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(from_snapshot, Summaries::from_ledger(&ledger));
+        assert_eq!(from_snapshot.len(), 2);
+        assert!(diff(&from_snapshot, &a)
+            .iter()
+            .all(|difference| difference.client != ClientKey::Id(1)));
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Client 1's account is closed, which isn't compared, and client 2's
+        // refund of a withdrawal is rejected, so neither differs from what
+        // was expected.
+        let input = "\
+type,client,tx,amount
+deposit,1,1,5
+withdrawal,1,2,5
+close_account,1,0,
+deposit,2,3,10
+withdrawal,2,4,4
+refund,2,4,
+";
+        let mut ledger = Ledger::default();
+        ledger.read_csv(input.as_bytes());
+        let expected = "client,available,held,total,locked\n1,0,0,0,false\n2,6,0,6,false\n";
+        let expected = Summaries::read_csv(expected.as_bytes()).unwrap();
+        assert!(diff(&Summaries::from_ledger(&ledger), &expected).is_empty());
+
+        // A balance in a currency differs from the account's own balance.
+        let currencies = "client,available,held,total,locked,currency\n1,0,0,0,false,EUR\n";
+        let currencies = Summaries::read_csv(currencies.as_bytes()).unwrap();
+        let changes = diff(&expected, &currencies)
+            .iter()
+            .map(|difference| (difference.client.clone(), difference.change))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            [
+                (ClientKey::Id(1), Change::OnlyInA),
+                (ClientKey::Id(1), Change::OnlyInB),
+                (ClientKey::Id(2), Change::OnlyInA),
+            ]
+        );
+        assert!(has_currencies(&expected, &currencies));
+
+        // Balances larger than a decimal holds, and other malformed lines,
+        // are reported with their line.
+        let header = "client,available,held,total,locked,currency\n";
+        for line in [
+            "1,79228162514264337593543950336,0,0,false,",
+            "1,1,x,1,false,",
+            "1,1,0,1,maybe,",
+            "1,1,0,1,false,EURO",
+        ] {
+            let malformed =
+                Summaries::read_csv(format!("{}1,0,0,0,false,\n{}\n", header, line).as_bytes());
+            assert!(
+                matches!(malformed, Err(DiffError::Malformed { line: 3, .. })),
+                "{:?}",
+                line
+            );
+        }
+        assert!(Summaries::from_snapshot("{".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,351 @@
+use std::fmt;
+
+use crate::{ledger::RecordError, TransactionError};
+
+// ErrorCode is the stable identity of every error a transaction or a line of
+// input can be rejected with, for integrations that act on rejections, e.g.
+// by retrying some of them. Every code has a number and a name: the name is
+// what reports like `--rejects` use, see `TransactionError::code`, and the
+// number is there for systems that prefer those.
+//
+// Codes are a public contract: once released, a code keeps its number, its
+// name, and its meaning forever. New errors get new codes, appended with the
+// next free number of their range, and codes of errors that can't happen
+// anymore are retired, never reused. Transaction errors are numbered from
+// 100, errors reading input from 200.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum ErrorCode {
+    AccountFrozen = 100,
+    InsufficientFunds = 101,
+    NonexistentTransaction = 102,
+    NotSettled = 103,
+    NotDisputed = 104,
+    NonexistentAccount = 105,
+    AccountExists = 106,
+    NoDeficit = 107,
+    ZeroAmount = 108,
+    DuplicateTransaction = 109,
+    HeldRatioExceeded = 110,
+    NotLogged = 111,
+    NoFeeAccount = 112,
+    RefundTooLarge = 113,
+    DisputeTooLarge = 114,
+    NotAuthorized = 115,
+    LimitExceeded = 116,
+    AmountTooLarge = 117,
+    ClientMismatch = 118,
+    DisputeWindowExpired = 119,
+    OutOfOrder = 120,
+    CurrencyMismatch = 121,
+    TooManyDecimals = 122,
+    Overflow = 123,
+    AccountClosed = 124,
+    BalanceNotZero = 125,
+    NotRefundable = 126,
+    NonPositiveAmount = 127,
+    StoreFailed = 128,
+    Malformed = 200,
+    MissingAmount = 201,
+    InvalidAmount = 202,
+    Unbalanced = 203,
+}
+
+impl ErrorCode {
+    // Every code, in the order of their numbers.
+    pub const ALL: &'static [ErrorCode] = &[
+        ErrorCode::AccountFrozen,
+        ErrorCode::InsufficientFunds,
+        ErrorCode::NonexistentTransaction,
+        ErrorCode::NotSettled,
+        ErrorCode::NotDisputed,
+        ErrorCode::NonexistentAccount,
+        ErrorCode::AccountExists,
+        ErrorCode::NoDeficit,
+        ErrorCode::ZeroAmount,
+        ErrorCode::DuplicateTransaction,
+        ErrorCode::HeldRatioExceeded,
+        ErrorCode::NotLogged,
+        ErrorCode::NoFeeAccount,
+        ErrorCode::RefundTooLarge,
+        ErrorCode::DisputeTooLarge,
+        ErrorCode::NotAuthorized,
+        ErrorCode::LimitExceeded,
+        ErrorCode::AmountTooLarge,
+        ErrorCode::ClientMismatch,
+        ErrorCode::DisputeWindowExpired,
+        ErrorCode::OutOfOrder,
+        ErrorCode::CurrencyMismatch,
+        ErrorCode::TooManyDecimals,
+        ErrorCode::Overflow,
+        ErrorCode::AccountClosed,
+        ErrorCode::BalanceNotZero,
+        ErrorCode::NotRefundable,
+        ErrorCode::NonPositiveAmount,
+        ErrorCode::StoreFailed,
+        ErrorCode::Malformed,
+        ErrorCode::MissingAmount,
+        ErrorCode::InvalidAmount,
+        ErrorCode::Unbalanced,
+    ];
+
+    pub fn number(self) -> u16 {
+        self as u16
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCode::AccountFrozen => "account_frozen",
+            ErrorCode::InsufficientFunds => "insufficient_funds",
+            ErrorCode::NonexistentTransaction => "nonexistent_transaction",
+            ErrorCode::NotSettled => "not_settled",
+            ErrorCode::NotDisputed => "not_disputed",
+            ErrorCode::NonexistentAccount => "nonexistent_account",
+            ErrorCode::AccountExists => "account_exists",
+            ErrorCode::NoDeficit => "no_deficit",
+            ErrorCode::ZeroAmount => "zero_amount",
+            ErrorCode::DuplicateTransaction => "duplicate_transaction",
+            ErrorCode::HeldRatioExceeded => "held_ratio_exceeded",
+            ErrorCode::NotLogged => "not_logged",
+            ErrorCode::NoFeeAccount => "no_fee_account",
+            ErrorCode::RefundTooLarge => "refund_too_large",
+            ErrorCode::DisputeTooLarge => "dispute_too_large",
+            ErrorCode::NotAuthorized => "not_authorized",
+            ErrorCode::LimitExceeded => "limit_exceeded",
+            ErrorCode::AmountTooLarge => "amount_too_large",
+            ErrorCode::ClientMismatch => "client_mismatch",
+            ErrorCode::DisputeWindowExpired => "dispute_window_expired",
+            ErrorCode::OutOfOrder => "out_of_order",
+            ErrorCode::CurrencyMismatch => "currency_mismatch",
+            ErrorCode::TooManyDecimals => "too_many_decimals",
+            ErrorCode::Overflow => "overflow",
+            ErrorCode::AccountClosed => "account_closed",
+            ErrorCode::BalanceNotZero => "balance_not_zero",
+            ErrorCode::NotRefundable => "not_refundable",
+            ErrorCode::NonPositiveAmount => "non_positive_amount",
+            ErrorCode::StoreFailed => "store_failed",
+            ErrorCode::Malformed => "malformed",
+            ErrorCode::MissingAmount => "missing_amount",
+            ErrorCode::InvalidAmount => "invalid_amount",
+            ErrorCode::Unbalanced => "unbalanced",
+        }
+    }
+
+    pub fn from_number(number: u16) -> Option<ErrorCode> {
+        ErrorCode::ALL
+            .iter()
+            .copied()
+            .find(|code| code.number() == number)
+    }
+
+    // The code with the given name, e.g. the `reason` of a `Reject`.
+    pub fn from_name(name: &str) -> Option<ErrorCode> {
+        ErrorCode::ALL
+            .iter()
+            .copied()
+            .find(|code| code.name() == name)
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name(), self.number())
+    }
+}
+
+impl From<&TransactionError> for ErrorCode {
+    fn from(err: &TransactionError) -> ErrorCode {
+        match err {
+            TransactionError::AccountFrozen => ErrorCode::AccountFrozen,
+            TransactionError::InsufficientFunds => ErrorCode::InsufficientFunds,
+            TransactionError::NonexistentTransaction => ErrorCode::NonexistentTransaction,
+            TransactionError::NotSettled => ErrorCode::NotSettled,
+            TransactionError::NotDisputed => ErrorCode::NotDisputed,
+            TransactionError::NonexistentAccount => ErrorCode::NonexistentAccount,
+            TransactionError::AccountExists => ErrorCode::AccountExists,
+            TransactionError::NoDeficit => ErrorCode::NoDeficit,
+            TransactionError::ZeroAmount => ErrorCode::ZeroAmount,
+            TransactionError::DuplicateTransaction => ErrorCode::DuplicateTransaction,
+            TransactionError::HeldRatioExceeded => ErrorCode::HeldRatioExceeded,
+            TransactionError::NotLogged => ErrorCode::NotLogged,
+            TransactionError::NoFeeAccount => ErrorCode::NoFeeAccount,
+            TransactionError::RefundTooLarge => ErrorCode::RefundTooLarge,
+            TransactionError::DisputeTooLarge => ErrorCode::DisputeTooLarge,
+            TransactionError::NotAuthorized => ErrorCode::NotAuthorized,
+            TransactionError::LimitExceeded => ErrorCode::LimitExceeded,
+            TransactionError::AmountTooLarge => ErrorCode::AmountTooLarge,
+            TransactionError::ClientMismatch => ErrorCode::ClientMismatch,
+            TransactionError::DisputeWindowExpired => ErrorCode::DisputeWindowExpired,
+            TransactionError::OutOfOrder => ErrorCode::OutOfOrder,
+            TransactionError::CurrencyMismatch => ErrorCode::CurrencyMismatch,
+            TransactionError::TooManyDecimals => ErrorCode::TooManyDecimals,
+            TransactionError::Overflow => ErrorCode::Overflow,
+            TransactionError::AccountClosed => ErrorCode::AccountClosed,
+            TransactionError::BalanceNotZero => ErrorCode::BalanceNotZero,
+            TransactionError::NotRefundable => ErrorCode::NotRefundable,
+            TransactionError::NonPositiveAmount => ErrorCode::NonPositiveAmount,
+            TransactionError::StoreFailed => ErrorCode::StoreFailed,
+        }
+    }
+}
+
+impl From<&RecordError> for ErrorCode {
+    fn from(err: &RecordError) -> ErrorCode {
+        match err {
+            RecordError::MissingAmount => ErrorCode::MissingAmount,
+            RecordError::Malformed(_) => ErrorCode::Malformed,
+            RecordError::InvalidAmount => ErrorCode::InvalidAmount,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ErrorCode;
+    use crate::{
+        ledger::{Ledger, RecordError, Reject},
+        TransactionError,
+    };
+
+    // The released codes. Entries must never be changed or removed, only
+    // appended: integrations depend on them.
+    const RELEASED: &[(u16, &str)] = &[
+        (100, "account_frozen"),
+        (101, "insufficient_funds"),
+        (102, "nonexistent_transaction"),
+        (103, "not_settled"),
+        (104, "not_disputed"),
+        (105, "nonexistent_account"),
+        (106, "account_exists"),
+        (107, "no_deficit"),
+        (108, "zero_amount"),
+        (109, "duplicate_transaction"),
+        (110, "held_ratio_exceeded"),
+        (111, "not_logged"),
+        (112, "no_fee_account"),
+        (113, "refund_too_large"),
+        (114, "dispute_too_large"),
+        (115, "not_authorized"),
+        (116, "limit_exceeded"),
+        (117, "amount_too_large"),
+        (118, "client_mismatch"),
+        (119, "dispute_window_expired"),
+        (120, "out_of_order"),
+        (121, "currency_mismatch"),
+        (122, "too_many_decimals"),
+        (123, "overflow"),
+        (124, "account_closed"),
+        (125, "balance_not_zero"),
+        (126, "not_refundable"),
+        (127, "non_positive_amount"),
+        (128, "store_failed"),
+        (200, "malformed"),
+        (201, "missing_amount"),
+        (202, "invalid_amount"),
+        (203, "unbalanced"),
+    ];
+
+    #[test]
+    fn stable_codes() {
+        for &(number, name) in RELEASED {
+            let code = ErrorCode::from_number(number).expect("released code should exist");
+            assert_eq!(code.name(), name, "code {} changed its name", number);
+            assert_eq!(ErrorCode::from_name(name), Some(code));
+        }
+        // New codes have to be added to the released ones as well.
+        assert_eq!(ErrorCode::ALL.len(), RELEASED.len());
+
+        // The errors keep their meaning.
+        let errors = [
+            (TransactionError::AccountFrozen, 100),
+            (TransactionError::InsufficientFunds, 101),
+            (TransactionError::NonexistentTransaction, 102),
+            (TransactionError::NotSettled, 103),
+            (TransactionError::NotDisputed, 104),
+            (TransactionError::NonexistentAccount, 105),
+            (TransactionError::AccountExists, 106),
+            (TransactionError::NoDeficit, 107),
+            (TransactionError::ZeroAmount, 108),
+            (TransactionError::DuplicateTransaction, 109),
+            (TransactionError::HeldRatioExceeded, 110),
+            (TransactionError::NotLogged, 111),
+            (TransactionError::NoFeeAccount, 112),
+            (TransactionError::RefundTooLarge, 113),
+            (TransactionError::DisputeTooLarge, 114),
+            (TransactionError::NotAuthorized, 115),
+            (TransactionError::LimitExceeded, 116),
+            (TransactionError::AmountTooLarge, 117),
+            (TransactionError::ClientMismatch, 118),
+            (TransactionError::DisputeWindowExpired, 119),
+            (TransactionError::OutOfOrder, 120),
+            (TransactionError::CurrencyMismatch, 121),
+            (TransactionError::TooManyDecimals, 122),
+            (TransactionError::Overflow, 123),
+            (TransactionError::AccountClosed, 124),
+            (TransactionError::BalanceNotZero, 125),
+            (TransactionError::NotRefundable, 126),
+            (TransactionError::NonPositiveAmount, 127),
+            (TransactionError::StoreFailed, 128),
+        ];
+        for (err, number) in errors {
+            assert_eq!(ErrorCode::from(&err).number(), number, "{:?}", err);
+            assert_eq!(err.code(), ErrorCode::from(&err).name());
+        }
+        assert_eq!(
+            ErrorCode::from(&RecordError::Malformed(String::new())).number(),
+            200
+        );
+        assert_eq!(ErrorCode::from(&RecordError::MissingAmount).number(), 201);
+        assert_eq!(ErrorCode::from(&RecordError::InvalidAmount).number(), 202);
+
+        // Opening balances that don't add up to their total.
+        let mut codes = vec![];
+        Ledger::default().read_opening_balances_with(
+            "client,available,held,total,locked\n1,1,0,2,false\n".as_bytes(),
+            |reject: Reject| codes.push(ErrorCode::from_name(reject.reason)),
+        );
+        assert_eq!(codes, [Some(ErrorCode::Unbalanced)]);
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Numbers and names that were never released aren't codes, and
+        // names are matched exactly.
+        for number in [0, 99, 129, 199, 204, u16::MAX] {
+            assert_eq!(ErrorCode::from_number(number), None, "{}", number);
+        }
+        for name in ["", "Overflow", " overflow", "account-closed"] {
+            assert_eq!(ErrorCode::from_name(name), None, "{:?}", name);
+        }
+        assert!(ErrorCode::ALL
+            .windows(2)
+            .all(|pair| pair[0].number() < pair[1].number()));
+        assert_eq!(ErrorCode::Overflow.to_string(), "overflow (123)");
+
+        // The reasons lines are rejected with are the names of their codes.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,{max}
+deposit,2,3,5
+withdrawal,2,4,5
+close_account,2,0,
+deposit,2,5,1
+withdrawal,1,6,1
+refund,1,6,
+deposit,1,7,x
+deposit,1,8,
+"
+        );
+        let mut codes = vec![];
+        Ledger::from_csv_reader_with(input.as_bytes(), |reject: Reject| {
+            codes.push(ErrorCode::from_name(reject.reason).map(ErrorCode::number))
+        });
+        assert_eq!(
+            codes,
+            [Some(123), Some(124), Some(126), Some(200), Some(201)]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::{collections::BTreeMap, io::Write};
+
+use crate::ledger::Reject;
+
+// An ErrorSink receives every line the ledger skips while reading CSV input,
+// see `Reject`, so that embedders can log, count, or persist them however
+// they like. The ledger itself only reports them on stderr, see `Stderr`.
+pub trait ErrorSink {
+    fn on_reject(&mut self, reject: Reject);
+
+    // Receive a warning about the input as a whole, e.g. that its amounts
+    // look mis-scaled, see `ScaleCheck::warning`. Warnings are dropped by
+    // default.
+    fn on_warning(&mut self, _warning: String) {}
+}
+
+// Closures can be used as error sinks directly.
+impl<F: FnMut(Reject)> ErrorSink for F {
+    fn on_reject(&mut self, reject: Reject) {
+        self(reject)
+    }
+}
+
+// Stderr prints the message of every rejected line, and every warning, on
+// stderr.
+pub struct Stderr;
+
+impl ErrorSink for Stderr {
+    fn on_reject(&mut self, reject: Reject) {
+        eprintln!("{}", reject.message);
+    }
+
+    fn on_warning(&mut self, warning: String) {
+        eprintln!("warning: {}", warning);
+    }
+}
+
+// ProcessingReport holds every line skipped while reading input with
+// `ErrorPolicy::Collect`, in the order of their lines, for triage once the
+// run is over, see `Ledger::take_report`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProcessingReport {
+    pub rejects: Vec<Reject>,
+}
+
+impl ProcessingReport {
+    pub fn is_empty(&self) -> bool {
+        self.rejects.is_empty()
+    }
+
+    // How many lines were skipped for every reason, see `Reject::reason`.
+    pub fn counts(&self) -> BTreeMap<&'static str, usize> {
+        let mut counts = BTreeMap::new();
+        for reject in &self.rejects {
+            *counts.entry(reject.reason).or_default() += 1;
+        }
+        counts
+    }
+
+    // Write the skipped lines as CSV, like `Ledger::read_csv_with_rejects`.
+    pub fn to_csv<W: Write>(&self, output: W) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(output);
+        for reject in &self.rejects {
+            writer.serialize(reject)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorSink, ProcessingReport};
+    use crate::{
+        ledger::{Ledger, Reject},
+        policy::ErrorPolicy,
+        scaling::AmountScale,
+    };
+
+    // A sink that counts what it receives.
+    #[derive(Default)]
+    struct Counting {
+        rejects: usize,
+        warnings: usize,
+    }
+
+    impl ErrorSink for &mut Counting {
+        fn on_reject(&mut self, _reject: Reject) {
+            self.rejects += 1;
+        }
+
+        fn on_warning(&mut self, _warning: String) {
+            self.warnings += 1;
+        }
+    }
+
+    #[test]
+    fn edge_cases() {
+        // A deposit too large for funds to hold, a refund of a withdrawal,
+        // a deposit into a closed account, and malformed lines, one of them
+        // quoted.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,1
+deposit,2,3,5
+withdrawal,2,4,5
+refund,2,4,
+close_account,2,0,
+deposit,2,5,1
+deposit,3,6,x
+\"deposit,3\",7,1
+"
+        );
+
+        let mut sink = Counting::default();
+        let mut ledger = Ledger::default();
+        ledger.read_csv_with(input.as_bytes(), AmountScale::default(), &mut sink);
+        assert_eq!((sink.rejects, sink.warnings), (5, 0));
+        assert!(ledger.take_report().is_empty());
+
+        // Collected lines are kept in the report instead of passed on.
+        let mut sink = Counting::default();
+        let mut ledger = Ledger::builder().errors(ErrorPolicy::Collect).build();
+        ledger.read_csv_with(input.as_bytes(), AmountScale::default(), &mut sink);
+        assert_eq!(sink.rejects, 0);
+        let report = ledger.take_report();
+        assert_eq!(
+            report.counts().into_iter().collect::<Vec<_>>(),
+            [
+                ("account_closed", 1),
+                ("malformed", 2),
+                ("not_refundable", 1),
+                ("overflow", 1)
+            ]
+        );
+        let lines = report.rejects.iter().map(|reject| reject.line);
+        assert_eq!(lines.collect::<Vec<_>>(), [3, 6, 8, 9, 10]);
+
+        let mut output = vec![];
+        report.to_csv(&mut output).unwrap();
+        let mut reader = csv::Reader::from_reader(output.as_slice());
+        assert_eq!(
+            reader.headers().unwrap(),
+            vec!["line", "record", "reason", "message", "byte"]
+        );
+        let records = reader.records().map(|record| record.unwrap()[1].to_owned());
+        assert_eq!(records.last().unwrap(), "\"deposit,3\",7,1");
+
+        // An empty report is an empty file, without even a header.
+        let mut output = vec![];
+        ProcessingReport::default().to_csv(&mut output).unwrap();
+        assert!(output.is_empty());
+    }
+}
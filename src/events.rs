@@ -0,0 +1,230 @@
+use crate::{
+    account::{AccountMetadata, FreezeLevel, FreezeReason, OpeningBalance},
+    ledger::ProcessedTransaction,
+    policy::PolicyVersion,
+    AccountId, Balance, Timestamp, Transaction, TransactionError, TransactionId,
+};
+
+// LedgerEvent describes a change to the ledger that observers may want to
+// know about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LedgerEvent {
+    // The account was opened with a balance carried over from another
+    // system. This is the synthetic transaction the account's history starts
+    // from.
+    AccountOpened {
+        client: AccountId,
+        balance: OpeningBalance,
+    },
+    // The account was created explicitly, empty, see `Ledger::create_account`.
+    AccountCreated {
+        client: AccountId,
+        metadata: AccountMetadata,
+    },
+    // The account was closed, see `Ledger::close_account`, and what was left
+    // in it was swept to `swept_to`, if anything was.
+    AccountClosed {
+        client: AccountId,
+        swept_to: Option<AccountId>,
+    },
+    // The account was frozen at the given time, either by a chargeback or
+    // explicitly, see `FreezePeriod`.
+    AccountFrozen {
+        client: AccountId,
+        reason: FreezeReason,
+        level: FreezeLevel,
+        at: Timestamp,
+    },
+    // The account was unfrozen explicitly at the given time. `reason`
+    // describes why.
+    AccountUnfrozen {
+        client: AccountId,
+        reason: String,
+        at: Timestamp,
+    },
+    // A deposit went towards the deficit of an account under recovery.
+    // `outstanding` is the deficit that remains after the deposit.
+    DeficitRecovered {
+        client: AccountId,
+        amount: Balance,
+        outstanding: Balance,
+    },
+    // A dispute of the given transaction would take the account's held funds
+    // beyond the maximum ratio of its total allowed by the policy. `held` is
+    // what the account would hold after the dispute. If `blocked` is set the
+    // dispute failed, otherwise it's applied right after.
+    HeldRatioExceeded {
+        client: AccountId,
+        tx: TransactionId,
+        held: Balance,
+        total: Balance,
+        blocked: bool,
+    },
+    // The transaction's time is before `latest`, the time of the latest
+    // transaction of the account, see `OutOfOrderPolicy`. If `rejected` is
+    // set the transaction failed, otherwise it's applied right after.
+    OutOfOrder {
+        client: AccountId,
+        timestamp: Timestamp,
+        latest: Timestamp,
+        rejected: bool,
+    },
+    // A dispute of a transaction the ledger hasn't seen yet was parked until
+    // the transaction comes, see `EarlyDisputePolicy`.
+    DisputeParked {
+        client: AccountId,
+        tx: TransactionId,
+    },
+    // A parked dispute failed when the transaction it refers to came, e.g.
+    // because that was a withdrawal that isn't kept. The transaction itself
+    // was applied.
+    ParkedDisputeFailed {
+        client: AccountId,
+        tx: TransactionId,
+        error: TransactionError,
+    },
+    // The transaction was applied to the account. `processed` is the state
+    // of the transaction after it was applied, or for disputes, resolutions,
+    // and chargebacks that of the transaction they refer to. Recoveries
+    // don't have one. `timestamp` is the time of the transaction, if it had
+    // one, and `policy` the version of the policy it was applied with.
+    TransactionApplied {
+        client: AccountId,
+        transaction: Transaction,
+        processed: Option<ProcessedTransaction>,
+        timestamp: Option<Timestamp>,
+        policy: PolicyVersion,
+    },
+    // The account was changed by a transaction, or by being opened, frozen,
+    // or unfrozen. The fields are the account's new state. It's emitted
+    // after any of the more specific events above.
+    AccountUpdated {
+        client: AccountId,
+        available: Balance,
+        held: Balance,
+        total: Balance,
+        locked: bool,
+    },
+}
+
+impl LedgerEvent {
+    // Whether the ledger's state is derived from the event: accounts being
+    // opened, created, or closed, transactions being applied, and accounts
+    // being frozen or unfrozen explicitly. The other events follow from
+    // these, e.g. a chargeback freezing an account, see
+    // `Ledger::rebuild_from_events`.
+    pub fn is_source(&self) -> bool {
+        match self {
+            LedgerEvent::AccountOpened { .. }
+            | LedgerEvent::AccountCreated { .. }
+            | LedgerEvent::AccountClosed { .. }
+            | LedgerEvent::TransactionApplied { .. }
+            | LedgerEvent::AccountUnfrozen { .. } => true,
+            LedgerEvent::AccountFrozen { reason, .. } => {
+                matches!(reason, FreezeReason::Manual(_) | FreezeReason::RiskRule(_))
+            }
+            LedgerEvent::DeficitRecovered { .. }
+            | LedgerEvent::HeldRatioExceeded { .. }
+            | LedgerEvent::OutOfOrder { .. }
+            | LedgerEvent::DisputeParked { .. }
+            | LedgerEvent::ParkedDisputeFailed { .. }
+            | LedgerEvent::AccountUpdated { .. } => false,
+        }
+    }
+}
+
+// An Observer is notified of every event emitted by the ledger it's
+// registered with, in the order the events happen. Observers must be `Send`
+// so that the ledger can be moved to other threads, e.g. to serve it.
+pub trait Observer: Send {
+    fn on_event(&mut self, event: &LedgerEvent);
+}
+
+// Closures can be used as observers directly.
+impl<F: FnMut(&LedgerEvent) + Send> Observer for F {
+    fn on_event(&mut self, event: &LedgerEvent) {
+        self(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::LedgerEvent;
+    use crate::{
+        ledger::{Ledger, RebuildError, Reject},
+        scaling::AmountScale,
+        TransactionError,
+    };
+
+    #[test]
+    fn edge_cases() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let observed = seen.clone();
+        let mut ledger = Ledger::builder()
+            .keep_events()
+            .sweep_account(9)
+            .observer(move |event: &LedgerEvent| observed.lock().unwrap().push(event.clone()))
+            .build();
+
+        // Rejected lines don't emit events: a deposit too large for funds to
+        // hold, a refund of a withdrawal, a deposit into a closed account,
+        // and a malformed line.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,1
+deposit,2,3,10
+withdrawal,2,4,4
+refund,2,4,
+close_account,2,0,
+deposit,2,5,1
+deposit,3,6,x
+"
+        );
+        let mut rejects = vec![];
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+        assert_eq!(
+            rejects,
+            ["overflow", "not_refundable", "account_closed", "malformed"]
+        );
+
+        let seen = seen.lock().unwrap();
+        let applied = seen
+            .iter()
+            .filter(|event| matches!(event, LedgerEvent::TransactionApplied { .. }))
+            .count();
+        assert_eq!(applied, 3);
+        let closed = LedgerEvent::AccountClosed {
+            client: 2,
+            swept_to: Some(9),
+        };
+        assert!(seen.contains(&closed));
+        // Only the events the state is derived from are kept.
+        let kept = seen.iter().filter(|event| event.is_source());
+        assert!(kept.eq(ledger.events()));
+
+        // The closed account, and the swept funds, are rebuilt from them.
+        let mut rebuilt = Ledger::default();
+        rebuilt.rebuild_from_events(ledger.events()).unwrap();
+        assert!(rebuilt.account(2).unwrap().is_closed());
+        assert_eq!(rebuilt.account(9).unwrap().total().unwrap(), 6.into());
+        assert_eq!(rebuilt.account(1).unwrap().total().unwrap(), max);
+
+        // Closing an account that doesn't exist doesn't apply cleanly.
+        assert_eq!(
+            Ledger::default().rebuild_from_events([&closed]),
+            Err(RebuildError {
+                position: 0,
+                error: TransactionError::NonexistentAccount
+            })
+        );
+    }
+}
@@ -0,0 +1,628 @@
+use std::cmp::Ordering;
+
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+use crate::{
+    client_names::ClientKey,
+    ledger::{AccountRecord, StatementEntry},
+    statement::StatementLine,
+    Transaction,
+};
+
+// A Filter selects which rows of an output are written, e.g. only the
+// locked accounts with more than 100 held:
+//
+//     locked == true && held > 100
+//
+// An expression compares a field of the row to a value with one of `==`,
+// `!=`, `<`, `<=`, `>`, or `>=`. Comparisons are combined with `&&` and `||`,
+// negated with `!`, and grouped with parentheses, up to `MAX_DEPTH` deep;
+// `&&` binds tighter than `||`. Values are numbers, `true` or `false`, or
+// text, either quoted or as a bare word, e.g. `type == deposit`. A boolean
+// field on its own is the same as comparing it to `true`. Rows that don't have the field, like the opening
+// balance of a statement that has no `tx`, never match a comparison.
+//
+// Which fields there are depends on the output, see `ACCOUNT_FIELDS`,
+// `NAMED_ACCOUNT_FIELDS`, and `STATEMENT_FIELDS`, and `check` makes sure a
+// filter only uses fields of the output it's applied to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter(Expr);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare { field: String, op: Op, value: Value },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Eq => ordering.is_eq(),
+            Op::Ne => ordering.is_ne(),
+            Op::Lt => ordering.is_lt(),
+            Op::Le => ordering.is_le(),
+            Op::Gt => ordering.is_gt(),
+            Op::Ge => ordering.is_ge(),
+        }
+    }
+}
+
+// Value is the value of a field, or one a field is compared to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Number(Decimal),
+    Bool(bool),
+    Text(String),
+}
+
+impl Value {
+    fn kind(&self) -> Kind {
+        match self {
+            Value::Number(_) => Kind::Number,
+            Value::Bool(_) => Kind::Bool,
+            Value::Text(_) => Kind::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Number,
+    Bool,
+    Text,
+}
+
+// The fields of the account summaries, including the exports.
+pub const ACCOUNT_FIELDS: &[(&str, Kind)] = &[
+    ("client", Kind::Number),
+    ("available", Kind::Number),
+    ("held", Kind::Number),
+    ("total", Kind::Number),
+    ("locked", Kind::Bool),
+    ("freeze_reason", Kind::Text),
+    ("freeze_level", Kind::Text),
+    ("closed", Kind::Bool),
+    ("overdrawn", Kind::Number),
+    ("currency", Kind::Text),
+];
+
+// The fields of the account summaries when clients are given by name, see
+// `ClientIdPolicy::Text`, whose client is text, e.g. `client == "c-17"`.
+pub const NAMED_ACCOUNT_FIELDS: &[(&str, Kind)] = &[
+    ("client", Kind::Text),
+    ("available", Kind::Number),
+    ("held", Kind::Number),
+    ("total", Kind::Number),
+    ("locked", Kind::Bool),
+    ("freeze_reason", Kind::Text),
+    ("freeze_level", Kind::Text),
+    ("closed", Kind::Bool),
+    ("overdrawn", Kind::Number),
+    ("currency", Kind::Text),
+];
+
+// The fields of the lines of a statement. The opening balance has the type
+// `opening`.
+pub const STATEMENT_FIELDS: &[(&str, Kind)] = &[
+    ("type", Kind::Text),
+    ("tx", Kind::Number),
+    ("amount", Kind::Number),
+    ("available", Kind::Number),
+    ("held", Kind::Number),
+    ("total", Kind::Number),
+    ("locked", Kind::Bool),
+];
+
+// Filterable is a row that can be filtered.
+pub trait Filterable {
+    // The value of the named field, or `None` if the row doesn't have it.
+    fn field(&self, name: &str) -> Option<Value>;
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum FilterError {
+    #[error("invalid filter: {0}")]
+    Syntax(String),
+    #[error("unknown field {0:?} in filter")]
+    UnknownField(String),
+    #[error("field {field:?} can't be compared to {value:?}")]
+    Mismatch { field: String, value: Value },
+}
+
+impl std::str::FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Filter, FilterError> {
+        let tokens = tokenize(s)?;
+        // Filters are parsed and matched recursively, so they're kept short,
+        // and shallow, enough not to run out of stack, see `Parser::unary`.
+        if tokens.len() > MAX_TOKENS {
+            return Err(FilterError::Syntax(format!(
+                "longer than {} tokens",
+                MAX_TOKENS
+            )));
+        }
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            depth: 0,
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(Filter(expr)),
+            Some(token) => Err(unexpected(token)),
+        }
+    }
+}
+
+impl Filter {
+    // Whether the row matches the filter.
+    pub fn matches<R: Filterable + ?Sized>(&self, record: &R) -> bool {
+        self.0.matches(record)
+    }
+
+    // Check that the filter only uses the given fields, and compares them to
+    // values of their kind. Booleans can only be compared with `==` and
+    // `!=`.
+    pub fn check(&self, fields: &[(&str, Kind)]) -> Result<(), FilterError> {
+        self.0.check(fields)
+    }
+}
+
+impl Expr {
+    fn matches<R: Filterable + ?Sized>(&self, record: &R) -> bool {
+        match self {
+            Expr::Or(a, b) => a.matches(record) || b.matches(record),
+            Expr::And(a, b) => a.matches(record) && b.matches(record),
+            Expr::Not(a) => !a.matches(record),
+            Expr::Compare { field, op, value } => {
+                let ordering = match (record.field(field), value) {
+                    (Some(Value::Number(a)), Value::Number(b)) => a.cmp(b),
+                    (Some(Value::Bool(a)), Value::Bool(b)) => a.cmp(b),
+                    (Some(Value::Text(a)), Value::Text(b)) => a.as_str().cmp(b),
+                    _ => return false,
+                };
+                op.holds(ordering)
+            }
+        }
+    }
+
+    fn check(&self, fields: &[(&str, Kind)]) -> Result<(), FilterError> {
+        match self {
+            Expr::Or(a, b) | Expr::And(a, b) => a.check(fields).and_then(|()| b.check(fields)),
+            Expr::Not(a) => a.check(fields),
+            Expr::Compare { field, op, value } => {
+                let (_, kind) = fields
+                    .iter()
+                    .find(|(name, _)| name == field)
+                    .ok_or_else(|| FilterError::UnknownField(field.clone()))?;
+                let ordered = !matches!(op, Op::Eq | Op::Ne);
+                if value.kind() != *kind || (ordered && *kind == Kind::Bool) {
+                    return Err(FilterError::Mismatch {
+                        field: field.clone(),
+                        value: value.clone(),
+                    });
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// The most tokens a filter can have, and how deep its negations and
+// parentheses can be nested.
+const MAX_TOKENS: usize = 1000;
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Number(Decimal),
+    Text(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn unexpected(token: &Token) -> FilterError {
+    FilterError::Syntax(format!("unexpected {:?}", token))
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, FilterError> {
+    let mut tokens = vec![];
+    let mut chars = s.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let mut next_if = |expected| chars.next_if(|&(_, c)| c == expected).is_some();
+        let token = match c {
+            _ if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '&' if next_if('&') => Token::And,
+            '|' if next_if('|') => Token::Or,
+            '=' if next_if('=') => Token::Op(Op::Eq),
+            '!' if next_if('=') => Token::Op(Op::Ne),
+            '!' => Token::Not,
+            '<' if next_if('=') => Token::Op(Op::Le),
+            '<' => Token::Op(Op::Lt),
+            '>' if next_if('=') => Token::Op(Op::Ge),
+            '>' => Token::Op(Op::Gt),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => text.push(c),
+                        None => return Err(FilterError::Syntax("unterminated text".into())),
+                    }
+                }
+                Token::Text(text)
+            }
+            _ if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|&(_, c)| c.is_ascii_digit() || c == '.') {
+                    end = i + c.len_utf8();
+                }
+                let number = &s[start..end];
+                Token::Number(
+                    number
+                        .parse()
+                        .map_err(|_| FilterError::Syntax(format!("invalid number {:?}", number)))?,
+                )
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|&(_, c)| c.is_alphanumeric() || c == '_') {
+                    end = i + c.len_utf8();
+                }
+                Token::Word(s[start..end].to_owned())
+            }
+            _ => return Err(FilterError::Syntax(format!("unexpected {:?}", c))),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// Parser is a recursive descent parser with a function for each level of
+// precedence.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    // The negations and parentheses the parser is in.
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, FilterError> {
+        let token = self
+            .tokens
+            .get(self.position)
+            .cloned()
+            .ok_or_else(|| FilterError::Syntax("unexpected end".into()))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn or(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.position += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.position += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, FilterError> {
+        let token = self.next()?;
+        if matches!(token, Token::Not | Token::Open) {
+            if self.depth == MAX_DEPTH {
+                return Err(FilterError::Syntax(format!(
+                    "nested deeper than {}",
+                    MAX_DEPTH
+                )));
+            }
+            self.depth += 1;
+        }
+        match token {
+            Token::Not => {
+                let expr = self.unary()?;
+                self.depth -= 1;
+                Ok(Expr::Not(Box::new(expr)))
+            }
+            Token::Open => {
+                let expr = self.or()?;
+                self.depth -= 1;
+                match self.next()? {
+                    Token::Close => Ok(expr),
+                    token => Err(unexpected(&token)),
+                }
+            }
+            Token::Word(field) => {
+                let Some(&Token::Op(op)) = self.peek() else {
+                    return Ok(Expr::Compare {
+                        field,
+                        op: Op::Eq,
+                        value: Value::Bool(true),
+                    });
+                };
+                self.position += 1;
+                let value = match self.next()? {
+                    Token::Number(number) => Value::Number(number),
+                    Token::Text(text) => Value::Text(text),
+                    Token::Word(word) if word == "true" => Value::Bool(true),
+                    Token::Word(word) if word == "false" => Value::Bool(false),
+                    Token::Word(word) => Value::Text(word),
+                    token => return Err(unexpected(&token)),
+                };
+                Ok(Expr::Compare { field, op, value })
+            }
+            token => Err(unexpected(&token)),
+        }
+    }
+}
+
+impl Filterable for AccountRecord {
+    fn field(&self, name: &str) -> Option<Value> {
+        match name {
+            "client" => Some(match &self.client {
+                ClientKey::Id(id) => Value::Number((*id).into()),
+                ClientKey::Name(name) => Value::Text(name.clone()),
+            }),
+            "available" => Some(Value::Number(self.available)),
+            "held" => Some(Value::Number(self.held)),
+            "total" => Some(Value::Number(self.total)),
+            "locked" => Some(Value::Bool(self.locked)),
+            "freeze_reason" => self.freeze_reason.clone().flatten().map(Value::Text),
+            "freeze_level" => self.freeze_level.clone().flatten().map(Value::Text),
+            "closed" => self.closed.map(Value::Bool),
+            "overdrawn" => self.overdrawn.map(Value::Number),
+            "currency" => self.currency.flatten().map(|c| Value::Text(c.to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl Filterable for StatementEntry {
+    fn field(&self, name: &str) -> Option<Value> {
+        let transaction = self.transaction.as_ref();
+        match name {
+            "type" => Some(Value::Text(
+                transaction.map_or("opening", Transaction::kind).to_owned(),
+            )),
+            "tx" => transaction
+                .and_then(Transaction::id)
+                .map(|id| Value::Number(id.into())),
+            "amount" => self.amount.map(Value::Number),
+            "available" => Some(Value::Number(self.available)),
+            "held" => Some(Value::Number(self.held)),
+            "total" => Some(Value::Number(self.total)),
+            "locked" => Some(Value::Bool(self.locked)),
+            _ => None,
+        }
+    }
+}
+
+impl Filterable for StatementLine {
+    fn field(&self, name: &str) -> Option<Value> {
+        match name {
+            "type" => Some(Value::Text(self.kind.to_owned())),
+            "tx" => self.tx.map(|id| Value::Number(id.into())),
+            "amount" => self.amount.map(Value::Number),
+            "available" => Some(Value::Number(self.balances.available)),
+            "held" => Some(Value::Number(self.balances.held)),
+            "total" => Some(Value::Number(self.balances.total)),
+            "locked" => Some(Value::Bool(self.balances.locked)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Filter, FilterError, Filterable, Value, ACCOUNT_FIELDS, MAX_DEPTH, MAX_TOKENS,
+        STATEMENT_FIELDS,
+    };
+    use crate::ledger::{Ledger, Reject};
+
+    struct Row(&'static [(&'static str, Value)]);
+
+    impl Filterable for Row {
+        fn field(&self, name: &str) -> Option<Value> {
+            self.0
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| value.clone())
+        }
+    }
+
+    #[test]
+    fn filter() {
+        let filter = |s: &str| s.parse::<Filter>().expect("filter should parse");
+        let row = Row(&[("held", Value::Number(rust_decimal::Decimal::ONE_HUNDRED))]);
+
+        let tests = [
+            ("held == 100.00", true),
+            ("held > 100", false),
+            ("held >= 100 && !(held != 100)", true),
+            ("held < 0 || held <= 100", true),
+            ("!held < 0 && held < 0 || held > 99", true),
+            // Comparisons with missing fields never match.
+            ("locked", false),
+            ("!locked", true),
+            ("type == deposit || type != \"deposit\"", false),
+        ];
+        for (expr, expected) in tests {
+            assert_eq!(filter(expr).matches(&row), expected, "{}", expr);
+        }
+
+        assert!(filter("locked == true && held > -1.5")
+            .check(ACCOUNT_FIELDS)
+            .is_ok());
+        assert!(filter("type == \"opening\" || tx == 1")
+            .check(STATEMENT_FIELDS)
+            .is_ok());
+        assert_eq!(
+            filter("tx == 1").check(ACCOUNT_FIELDS),
+            Err(FilterError::UnknownField("tx".into()))
+        );
+        assert!(matches!(
+            filter("held == true").check(ACCOUNT_FIELDS),
+            Err(FilterError::Mismatch { .. })
+        ));
+        assert!(matches!(
+            filter("locked > false").check(ACCOUNT_FIELDS),
+            Err(FilterError::Mismatch { .. })
+        ));
+
+        for invalid in ["", "held >", "(held > 1", "held > 1 )", "held = 1", "\"x"] {
+            assert!(invalid.parse::<Filter>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn filtered_outputs() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,200
+deposit,2,2,50
+dispute,1,1,
+";
+        let ledger = |filter: &str| {
+            let mut ledger = Ledger::builder()
+                .filter(filter.parse().unwrap())
+                .keep_statements()
+                .build();
+            ledger.read_csv(input.as_bytes());
+            ledger
+        };
+
+        let mut output = vec![];
+        ledger("held > 100").accounts_to_csv(&mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,0.0000,200.0000,200.0000,false\n"
+        );
+
+        let mut output = vec![];
+        ledger("type != opening && held == 0")
+            .statement_to_csv(1, &mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "type,tx,amount,available,held,total,locked,timestamp\ndeposit,1,200,200,0,200,false,\n"
+        );
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Numbers too large for amounts, and malformed ones, aren't values.
+        for invalid in [
+            "held > 79228162514264337593543950336",
+            "held > 1.2.3",
+            "held > -",
+            "held > ١٠٠",
+            "held > 1e3",
+        ] {
+            assert!(
+                matches!(invalid.parse::<Filter>(), Err(FilterError::Syntax(_))),
+                "{:?}",
+                invalid
+            );
+        }
+        assert_eq!(
+            "hëld > 1".parse::<Filter>().unwrap().check(ACCOUNT_FIELDS),
+            Err(FilterError::UnknownField("hëld".into()))
+        );
+
+        // Filters as long, and as deeply nested, as they can be are fine,
+        // longer or deeper ones are rejected rather than overflowing the
+        // stack.
+        let nested = |depth| format!("{}locked{}", "(".repeat(depth), ")".repeat(depth));
+        let filter = nested(MAX_DEPTH).parse::<Filter>().unwrap();
+        assert!(!filter.matches(&Row(&[])));
+        let filter = format!("{}locked", "!(".repeat(MAX_DEPTH / 2)) + &")".repeat(MAX_DEPTH / 2);
+        assert!(!filter.parse::<Filter>().unwrap().matches(&Row(&[])));
+        let chain = vec!["locked"; MAX_TOKENS / 2].join(" || ");
+        assert!(!chain.parse::<Filter>().unwrap().matches(&Row(&[])));
+        for invalid in [
+            nested(MAX_DEPTH + 1),
+            nested(100_000),
+            "!".repeat(100_000),
+            vec!["locked"; MAX_TOKENS / 2 + 1].join(" || "),
+        ] {
+            assert!(
+                matches!(invalid.parse::<Filter>(), Err(FilterError::Syntax(_))),
+                "{:.20}",
+                invalid
+            );
+        }
+
+        // Client 1 deposits more than funds can hold, client 2 closes their
+        // account and deposits into it, and client 3 refunds a withdrawal.
+        // Client 3's statement has no refund, so nothing is written for it,
+        // and only the closed account is left.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,{max}
+deposit,2,3,5
+withdrawal,2,4,5
+close_account,2,0,
+deposit,2,5,1
+deposit,3,6,10
+withdrawal,3,7,4
+refund,3,7,
+"
+        );
+        let mut ledger = Ledger::builder()
+            .filter("closed || type == refund".parse().unwrap())
+            .keep_statements()
+            .build();
+        let mut rejects = vec![];
+        ledger.read_csv_with(input.as_bytes(), Default::default(), |reject: Reject| {
+            rejects.push(reject.reason)
+        });
+        assert_eq!(rejects, ["overflow", "account_closed", "not_refundable"]);
+        let mut output = vec![];
+        ledger.statement_to_csv(3, &mut output).unwrap();
+        assert!(output.is_empty());
+        let mut output = vec![];
+        ledger.accounts_to_csv(&mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,closed\n2,0.0000,0.0000,0.0000,false,true\n"
+        );
+    }
+}
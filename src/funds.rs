@@ -0,0 +1,299 @@
+use crate::{Balance, TransactionAmount, TransactionError};
+
+// Funds is what accounts keep their balances in. By default it's `Balance`
+// itself, a `rust_decimal::Decimal`. With the `fixed-point` feature it's
+// `Fixed` instead, a whole number of hundred-millionths, so the arithmetic
+// of applying a transaction is plain integer arithmetic, which is much
+// cheaper than aligning the scales of decimals. The balances still come out
+// of the account as `Balance`, and amounts still go in as
+// `TransactionAmount`, through the functions below.
+#[cfg(not(feature = "fixed-point"))]
+pub(crate) type Funds = Balance;
+#[cfg(feature = "fixed-point")]
+pub(crate) type Funds = fixed::Fixed;
+
+// The funds of a transaction's amount. It fails if the amount has more
+// decimal places than funds can hold, or is larger than they can hold.
+#[cfg(not(feature = "fixed-point"))]
+pub(crate) fn exact(amount: TransactionAmount) -> Result<Funds, TransactionError> {
+    Ok(amount)
+}
+
+#[cfg(feature = "fixed-point")]
+pub(crate) fn exact(amount: TransactionAmount) -> Result<Funds, TransactionError> {
+    fixed::Fixed::from_decimal(amount).ok_or(
+        if amount.normalize().scale() > fixed::DECIMAL_PLACES {
+            TransactionError::TooManyDecimals
+        } else {
+            TransactionError::Overflow
+        },
+    )
+}
+
+// The funds of a balance given from outside, e.g. an opening balance,
+// rounded to the decimal places funds can hold. It fails if the balance is
+// larger than they can hold.
+#[cfg(not(feature = "fixed-point"))]
+pub(crate) fn rounded(balance: Balance) -> Result<Funds, TransactionError> {
+    Ok(balance)
+}
+
+#[cfg(feature = "fixed-point")]
+pub(crate) fn rounded(balance: Balance) -> Result<Funds, TransactionError> {
+    fixed::Fixed::round_from(balance).ok_or(TransactionError::Overflow)
+}
+
+#[cfg(not(feature = "fixed-point"))]
+pub(crate) fn to_balance(funds: Funds) -> Balance {
+    funds
+}
+
+#[cfg(feature = "fixed-point")]
+pub(crate) fn to_balance(funds: Funds) -> Balance {
+    funds.to_decimal()
+}
+
+// The largest balance funds can hold.
+#[cfg(all(test, not(feature = "fixed-point")))]
+pub(crate) fn max() -> Balance {
+    Balance::MAX
+}
+
+#[cfg(all(test, feature = "fixed-point"))]
+pub(crate) fn max() -> Balance {
+    fixed::Fixed::MAX.to_decimal()
+}
+
+#[cfg(feature = "fixed-point")]
+mod fixed {
+    use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    // The number of decimal places of `Fixed`, enough for the minor units of
+    // any common currency, including BTC.
+    pub(super) const DECIMAL_PLACES: u32 = 8;
+
+    // The largest magnitude `Fixed` holds: the largest mantissa of a
+    // `Decimal`, so every value can be turned back into one. That's about
+    // 7.9e20 in whole units.
+    const MAX: u128 = (1 << 96) - 1;
+
+    // Fixed is an amount of funds as a whole number of hundred-millionths.
+    // Like `Decimal`, its operators panic when arithmetic overflows, rather
+    // than wrapping around, and the `checked_` methods return `None`.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+    pub struct Fixed(i128);
+
+    impl Fixed {
+        pub const ZERO: Fixed = Fixed(0);
+        #[cfg(test)]
+        pub const MAX: Fixed = Fixed(MAX as i128);
+
+        fn new(units: i128) -> Option<Fixed> {
+            (units.unsigned_abs() <= MAX).then_some(Fixed(units))
+        }
+
+        // The decimal as it is, or `None` if it has more than 8 decimal
+        // places, or is too large.
+        pub fn from_decimal(decimal: Decimal) -> Option<Fixed> {
+            let (mantissa, scale) = (decimal.mantissa(), decimal.scale());
+            let units = match scale.checked_sub(DECIMAL_PLACES) {
+                None => mantissa.checked_mul(10i128.pow(DECIMAL_PLACES - scale))?,
+                Some(extra) => {
+                    let divisor = 10i128.pow(extra);
+                    if mantissa % divisor != 0 {
+                        return None;
+                    }
+                    mantissa / divisor
+                }
+            };
+            Fixed::new(units)
+        }
+
+        // The decimal rounded to 8 decimal places, or `None` if it's too
+        // large.
+        pub fn round_from(decimal: Decimal) -> Option<Fixed> {
+            Fixed::from_decimal(decimal.round_dp(DECIMAL_PLACES))
+        }
+
+        pub fn to_decimal(self) -> Decimal {
+            Decimal::from_i128_with_scale(self.0, DECIMAL_PLACES).normalize()
+        }
+
+        pub fn checked_add(self, other: Fixed) -> Option<Fixed> {
+            Fixed::new(self.0.checked_add(other.0)?)
+        }
+
+        pub fn checked_sub(self, other: Fixed) -> Option<Fixed> {
+            Fixed::new(self.0.checked_sub(other.0)?)
+        }
+    }
+
+    impl Add for Fixed {
+        type Output = Fixed;
+
+        fn add(self, other: Fixed) -> Fixed {
+            self.checked_add(other).expect("Addition overflowed")
+        }
+    }
+
+    impl Sub for Fixed {
+        type Output = Fixed;
+
+        fn sub(self, other: Fixed) -> Fixed {
+            self.checked_sub(other).expect("Subtraction overflowed")
+        }
+    }
+
+    impl AddAssign for Fixed {
+        fn add_assign(&mut self, other: Fixed) {
+            *self = *self + other;
+        }
+    }
+
+    impl SubAssign for Fixed {
+        fn sub_assign(&mut self, other: Fixed) {
+            *self = *self - other;
+        }
+    }
+
+    // The range is symmetric, so negating never overflows.
+    impl Neg for Fixed {
+        type Output = Fixed;
+
+        fn neg(self) -> Fixed {
+            Fixed(-self.0)
+        }
+    }
+
+    // Funds are written like decimals, so snapshots can be read by either
+    // backend.
+    impl Serialize for Fixed {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            Serialize::serialize(&self.to_decimal(), serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Fixed {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let decimal: Decimal = Deserialize::deserialize(deserializer)?;
+            Fixed::round_from(decimal)
+                .ok_or_else(|| serde::de::Error::custom("balance out of range"))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use rust_decimal::Decimal;
+
+        use super::Fixed;
+
+        #[test]
+        fn fixed() {
+            let parse = |amount: &str| amount.parse::<Decimal>().unwrap();
+            let a = Fixed::from_decimal(parse("10.25")).unwrap();
+            let b = Fixed::from_decimal(parse("0.00000001")).unwrap();
+            assert_eq!((a + b).to_decimal(), parse("10.25000001"));
+            assert_eq!((b - a).to_decimal(), parse("-10.24999999"));
+            assert_eq!(Fixed::from_decimal(parse("1.000000001")), None);
+            assert_eq!(
+                Fixed::from_decimal(parse("1.0000000100")),
+                Some(Fixed(100_000_001))
+            );
+            assert_eq!(
+                Fixed::round_from(parse("1.000000006")).map(Fixed::to_decimal),
+                Some(parse("1.00000001"))
+            );
+            assert_eq!(Fixed::round_from(parse("1000000000000000000000.0")), None);
+            assert_eq!(Fixed::from_decimal(Decimal::MAX), None);
+
+            let max = Fixed::from_decimal(parse("700000000000000000000")).unwrap();
+            assert_eq!(max.checked_add(max), None);
+            assert_eq!((-max).checked_sub(max), None);
+            assert_eq!(serde_json::to_string(&a).unwrap(), "\"10.25\"");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exact, max, rounded, to_balance};
+    use crate::{
+        account::{Account, OpeningBalance},
+        ledger::Ledger,
+        Balance, Transaction, TransactionError,
+    };
+
+    #[test]
+    fn edge_cases() {
+        let parse = |amount: &str| amount.parse::<Balance>().unwrap();
+        let opening = |available, held| OpeningBalance {
+            available,
+            held,
+            locked: false,
+            closed: true,
+            currency: None,
+        };
+
+        // Balances as large as funds can hold are opened as they are, even
+        // on a closed account, but not if their total is any larger.
+        let account = Account::with_opening_balance(&opening(max(), 0.into())).unwrap();
+        assert_eq!(account.total().unwrap(), max());
+        assert!(account.is_closed());
+        assert_eq!(
+            Account::with_opening_balance(&opening(max(), 1.into())).err(),
+            Some(TransactionError::Overflow)
+        );
+        assert_eq!(
+            Account::with_opening_balance(&opening(-max(), -max())).err(),
+            Some(TransactionError::Overflow)
+        );
+
+        // An overdraft as large as funds can hold can be drawn down to the
+        // end, but no further, and the withdrawal can't be refunded to make
+        // up for it.
+        let mut ledger = Ledger::builder().overdraft(max()).build();
+        let withdrawal = |new_id, amount| Transaction::Withdrawal { new_id, amount };
+        assert_eq!(ledger.apply(1, withdrawal(1, max())), Ok(()));
+        assert_eq!(
+            ledger.apply(1, withdrawal(2, parse("0.01"))),
+            Err(TransactionError::InsufficientFunds)
+        );
+        assert_eq!(
+            ledger.apply(
+                1,
+                Transaction::Refund {
+                    id: 1,
+                    amount: None
+                }
+            ),
+            Err(TransactionError::NotRefundable)
+        );
+        assert_eq!(ledger.account(1).unwrap().available(), -max());
+
+        // Amounts are taken exactly or not at all, while balances from
+        // outside are rounded to what funds can hold.
+        let tiny = parse("0.000000001");
+        #[cfg(not(feature = "fixed-point"))]
+        {
+            assert_eq!(exact(tiny).map(to_balance), Ok(tiny));
+            assert_eq!(rounded(tiny).map(to_balance), Ok(tiny));
+        }
+        #[cfg(feature = "fixed-point")]
+        {
+            assert_eq!(exact(tiny), Err(TransactionError::TooManyDecimals));
+            assert_eq!(rounded(tiny).map(to_balance), Ok(0.into()));
+            assert_eq!(
+                rounded(parse("0.000000006")).map(to_balance),
+                Ok(parse("0.00000001"))
+            );
+            assert_eq!(exact(max() + Balance::ONE), Err(TransactionError::Overflow));
+            assert_eq!(
+                rounded(-max() - Balance::ONE),
+                Err(TransactionError::Overflow)
+            );
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use std::io::Write;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::{AccountId, Transaction, TransactionAmount, TransactionId};
+
+// Generator writes synthetic transactions files, to benchmark and
+// stress-test the ledger without production data. The same settings always
+// generate the same file.
+//
+// The transactions look like real ones: clients mostly deposit, withdraw no
+// more than they have available, and dispute some of their recent deposits,
+// which are then resolved or, less often, charged back. Every transaction it
+// writes is one the ledger accepts. Chargebacks freeze the account, so once
+// half the clients are frozen, disputes are only resolved, and the rest of
+// the clients keep transacting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generator {
+    // The number of clients, whose IDs go from 1 up. There's always at
+    // least one.
+    pub clients: AccountId,
+    // The number of lines written, not counting the header.
+    pub transactions: TransactionId,
+    // The share of transactions that are disputes, and also of those that
+    // resolve or charge back one of the open disputes.
+    pub dispute_rate: f64,
+    pub seed: u64,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Generator {
+            clients: 1000,
+            transactions: 100_000,
+            dispute_rate: 0.01,
+            seed: 0,
+        }
+    }
+}
+
+// The number of a client's latest deposits that may still be disputed.
+const RECENT_DEPOSITS: usize = 4;
+
+// The share of settled disputes that are charged back.
+const CHARGEBACK_RATE: f64 = 0.1;
+
+// The largest deposit, in ten-thousandths.
+const MAX_DEPOSIT: i64 = 10_000_000;
+
+// Amounts are generated in ten-thousandths, the precision of the output.
+const SCALE: u32 = 4;
+
+#[derive(Default, Clone)]
+struct Client {
+    available: i64,
+    // The ID and amount of the latest deposits, oldest first, that haven't
+    // been disputed yet.
+    recent: Vec<(TransactionId, i64)>,
+    frozen: bool,
+}
+
+#[derive(Serialize)]
+struct GeneratedRecord {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    client: AccountId,
+    tx: TransactionId,
+    amount: Option<TransactionAmount>,
+}
+
+impl Generator {
+    // Write the transactions as CSV with the columns type, client, tx, and
+    // amount.
+    pub fn write_csv<W: Write>(&self, output: W) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(output);
+        let mut rng = Rng::new(self.seed);
+        let mut clients = vec![Client::default(); self.clients.max(1) as usize];
+        // At most this many clients are frozen.
+        let max_frozen = clients.len() / 2;
+        let mut frozen = 0;
+        // The disputes not settled yet, oldest first, with their client
+        // and amount.
+        let mut disputes: Vec<(usize, TransactionId, i64)> = vec![];
+        let mut next_id: TransactionId = 1;
+
+        for _ in 0..self.transactions {
+            // Settle a dispute.
+            if !disputes.is_empty() && rng.chance(self.dispute_rate) {
+                let (index, id, amount) = disputes.remove(rng.below(disputes.len()));
+                let client = &mut clients[index];
+                let tx = if frozen < max_frozen && rng.chance(CHARGEBACK_RATE) {
+                    if !client.frozen {
+                        client.frozen = true;
+                        frozen += 1;
+                    }
+                    Transaction::Chargeback { id }
+                } else {
+                    client.available += amount;
+                    Transaction::Resolve { id }
+                };
+                write_record(&mut writer, index, tx)?;
+                continue;
+            }
+
+            let index = loop {
+                let index = rng.below(clients.len());
+                if !clients[index].frozen {
+                    break index;
+                }
+            };
+            let client = &mut clients[index];
+
+            // Dispute one of the client's recent deposits.
+            if !client.recent.is_empty() && rng.chance(self.dispute_rate) {
+                let (id, amount) = client.recent.remove(rng.below(client.recent.len()));
+                client.available -= amount;
+                disputes.push((index, id, amount));
+                let tx = Transaction::Dispute { id, amount: None };
+                write_record(&mut writer, index, tx)?;
+                continue;
+            }
+
+            let new_id = next_id;
+            next_id += 1;
+            // Withdraw up to what's available a third of the time.
+            let tx = if client.available > 0 && rng.below(3) == 0 {
+                let amount = 1 + rng.below(client.available as usize) as i64;
+                client.available -= amount;
+                Transaction::Withdrawal {
+                    new_id,
+                    amount: Decimal::new(amount, SCALE).normalize(),
+                }
+            } else {
+                let amount = 1 + rng.below(MAX_DEPOSIT as usize) as i64;
+                client.available += amount;
+                if client.recent.len() == RECENT_DEPOSITS {
+                    client.recent.remove(0);
+                }
+                client.recent.push((new_id, amount));
+                Transaction::Deposit {
+                    new_id,
+                    amount: Decimal::new(amount, SCALE).normalize(),
+                }
+            };
+            write_record(&mut writer, index, tx)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn write_record<W: Write>(
+    writer: &mut csv::Writer<W>,
+    index: usize,
+    tx: Transaction,
+) -> csv::Result<()> {
+    writer.serialize(GeneratedRecord {
+        record_type: tx.kind(),
+        client: index as AccountId + 1,
+        tx: tx.id().unwrap_or(0),
+        amount: tx.amount(),
+    })
+}
+
+// Rng is a SplitMix64 generator: not suitable for anything but generating
+// test data, but fast, and the same seed always gives the same numbers.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // A number below `n`, which must not be zero.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    // Whether an event of the given probability happens.
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Generator;
+    use crate::{ledger::Reject, Ledger};
+
+    #[test]
+    fn generated_transactions_are_accepted() {
+        let generator = Generator {
+            clients: 20,
+            transactions: 20_000,
+            dispute_rate: 0.05,
+            seed: 42,
+        };
+        let mut output = vec![];
+        generator.write_csv(&mut output).unwrap();
+        let mut again = vec![];
+        generator.write_csv(&mut again).unwrap();
+        assert_eq!(output, again);
+
+        let output = String::from_utf8(output).expect("output should be UTF8");
+        assert_eq!(output.lines().count(), 20_001);
+        let count = |kind: &str| output.lines().filter(|line| line.starts_with(kind)).count();
+        assert!((500..1500).contains(&count("dispute,")));
+        assert!(count("resolve,") > 0 && count("chargeback,") > 0);
+
+        let mut rejects = vec![];
+        let ledger =
+            Ledger::from_csv_reader_with(output.as_bytes(), |reject: Reject| rejects.push(reject));
+        assert_eq!(rejects, []);
+        let frozen = ledger.accounts().filter(|(_, account)| account.is_frozen());
+        assert!(frozen.count() <= 10);
+    }
+
+    #[test]
+    fn edge_cases() {
+        let generate = |generator: Generator| {
+            let mut output = vec![];
+            generator.write_csv(&mut output).unwrap();
+            String::from_utf8(output).expect("output should be UTF8")
+        };
+
+        // Nothing to write, not even the header.
+        let empty = Generator {
+            transactions: 0,
+            ..Generator::default()
+        };
+        assert_eq!(generate(empty), "");
+
+        // No clients is one client, who's never frozen, since at most half
+        // the clients are, so every dispute is resolved. Disputing at every
+        // chance alternates between deposits, disputes, and resolutions.
+        let output = generate(Generator {
+            clients: 0,
+            transactions: 9,
+            dispute_rate: 1.0,
+            seed: 7,
+        });
+        let kinds = output
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                "deposit", "dispute", "resolve", "deposit", "dispute", "resolve", "deposit",
+                "dispute", "resolve",
+            ]
+        );
+        assert!(output.lines().skip(1).all(|line| line.contains(",1,")));
+
+        let mut rejects = vec![];
+        let ledger =
+            Ledger::from_csv_reader_with(output.as_bytes(), |reject: Reject| rejects.push(reject));
+        assert_eq!(rejects, []);
+        let account = ledger.account(1).unwrap();
+        assert!(!account.is_frozen());
+        assert_eq!(account.held(), 0.into());
+    }
+}
@@ -0,0 +1,459 @@
+// tonic's `Status` is large, but the service methods have to return it
+// anyway, so there's nothing to gain from boxing it in the helpers.
+#![allow(clippy::result_large_err)]
+
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Mutex,
+};
+
+use tonic::{Code, Request, Response, Status};
+
+use crate::{
+    dead_letter::DeadLetters, error_code::ErrorCode, shared::SharedLedger, AccountId, Transaction,
+    TransactionAmount, TransactionError, TransactionId,
+};
+
+pub(crate) mod proto {
+    tonic::include_proto!("ledger");
+}
+
+use proto::{
+    ledger_server::{Ledger as LedgerRpc, LedgerServer},
+    transaction::Kind,
+};
+
+// How many idempotency keys are remembered. Retries are expected to arrive
+// shortly after the original request, so only the most recent keys are kept.
+const IDEMPOTENCY_KEYS_KEPT: usize = 10_000;
+
+// IdempotencyKeys remembers the answers given to the most recent requests
+// with an idempotency key, oldest first.
+#[derive(Default)]
+struct IdempotencyKeys {
+    answers: HashMap<String, Result<(), (Code, String)>>,
+    order: VecDeque<String>,
+}
+
+impl IdempotencyKeys {
+    fn get(&self, key: &str) -> Option<Result<(), Status>> {
+        self.answers.get(key).map(|answer| {
+            answer
+                .clone()
+                .map_err(|(code, message)| Status::new(code, message))
+        })
+    }
+
+    fn insert(&mut self, key: String, answer: &Result<(), Status>) {
+        if self.order.len() == IDEMPOTENCY_KEYS_KEPT {
+            if let Some(oldest) = self.order.pop_front() {
+                self.answers.remove(&oldest);
+            }
+        }
+        let answer = answer
+            .as_ref()
+            .map(|_| ())
+            .map_err(|status| (status.code(), status.message().to_owned()));
+        self.order.push_back(key.clone());
+        self.answers.insert(key, answer);
+    }
+}
+
+// LedgerService serves a single ledger over gRPC. Requests are applied one
+// at a time in the order they acquire the lock.
+pub struct LedgerService {
+    ledger: SharedLedger,
+    idempotency_keys: Mutex<IdempotencyKeys>,
+    dead_letters: Option<DeadLetters>,
+}
+
+impl LedgerService {
+    pub fn new(ledger: impl Into<SharedLedger>) -> LedgerService {
+        LedgerService {
+            ledger: ledger.into(),
+            idempotency_keys: Mutex::default(),
+            dead_letters: None,
+        }
+    }
+
+    // Write submissions that are rejected to `dead_letters`, formatted as
+    // records of the line protocol. Repeated requests that get a remembered
+    // answer aren't written again.
+    pub fn with_dead_letters(mut self, dead_letters: DeadLetters) -> LedgerService {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    fn dead_letter(&self, request: &proto::SubmitTransactionRequest, status: &Status) {
+        if let Some(dead_letters) = &self.dead_letters {
+            dead_letters.record(&request_to_record(request), status.message());
+        }
+    }
+}
+
+// Format the request as a record of the line protocol, e.g.
+// `deposit,1,2,10.5`, as far as its fields are present. Nothing is parsed,
+// so malformed requests are kept as they were.
+fn request_to_record(request: &proto::SubmitTransactionRequest) -> String {
+    let client = request.client;
+    match request.transaction.as_ref().and_then(|tx| tx.kind.as_ref()) {
+        Some(Kind::Deposit(deposit)) => {
+            format!("deposit,{},{},{}", client, deposit.tx, deposit.amount)
+        }
+        Some(Kind::Withdrawal(withdrawal)) => {
+            format!(
+                "withdrawal,{},{},{}",
+                client, withdrawal.tx, withdrawal.amount
+            )
+        }
+        Some(Kind::Dispute(dispute)) => format!("dispute,{},{},", client, dispute.tx),
+        Some(Kind::Resolve(resolve)) => format!("resolve,{},{},", client, resolve.tx),
+        Some(Kind::Chargeback(chargeback)) => {
+            format!("chargeback,{},{},", client, chargeback.tx)
+        }
+        None => format!(",{},,", client),
+    }
+}
+
+// Serve the given ledger on the given address until the process is stopped.
+// Rejected submissions are written to `dead_letters`, if given.
+pub fn serve(
+    addr: SocketAddr,
+    ledger: impl Into<SharedLedger>,
+    dead_letters: Option<DeadLetters>,
+) -> Result<(), Box<dyn Error>> {
+    let mut service = LedgerService::new(ledger);
+    if let Some(dead_letters) = dead_letters {
+        service = service.with_dead_letters(dead_letters);
+    }
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(
+        tonic::transport::Server::builder()
+            .add_service(LedgerServer::new(service))
+            .serve(addr),
+    )?;
+    Ok(())
+}
+
+// Messages of errors start with their code, see `ErrorCode`, like the
+// answers of the line protocol.
+fn malformed(message: &str) -> Status {
+    Status::invalid_argument(format!("{}: {}", ErrorCode::Malformed.name(), message))
+}
+
+// IDs can only be out of range without the `wide-ids` feature.
+#[cfg_attr(feature = "wide-ids", allow(clippy::unnecessary_fallible_conversions))]
+fn parse_client(client: u64) -> Result<AccountId, Status> {
+    AccountId::try_from(client).map_err(|_| malformed("client ID out of range"))
+}
+
+#[cfg_attr(feature = "wide-ids", allow(clippy::unnecessary_fallible_conversions))]
+fn parse_id(tx: u64) -> Result<TransactionId, Status> {
+    TransactionId::try_from(tx).map_err(|_| malformed("transaction ID out of range"))
+}
+
+fn parse_amount(amount: &str) -> Result<TransactionAmount, Status> {
+    TransactionAmount::from_str(amount).map_err(|_| malformed("invalid amount"))
+}
+
+fn parse_transaction(tx: Option<proto::Transaction>) -> Result<Transaction, Status> {
+    let kind = tx
+        .and_then(|tx| tx.kind)
+        .ok_or_else(|| malformed("missing transaction"))?;
+
+    Ok(match kind {
+        Kind::Deposit(deposit) => Transaction::Deposit {
+            new_id: parse_id(deposit.tx)?,
+            amount: parse_amount(&deposit.amount)?,
+        },
+        Kind::Withdrawal(withdrawal) => Transaction::Withdrawal {
+            new_id: parse_id(withdrawal.tx)?,
+            amount: parse_amount(&withdrawal.amount)?,
+        },
+        Kind::Dispute(dispute) => Transaction::Dispute {
+            id: parse_id(dispute.tx)?,
+            amount: None,
+        },
+        Kind::Resolve(resolve) => Transaction::Resolve {
+            id: parse_id(resolve.tx)?,
+        },
+        Kind::Chargeback(chargeback) => Transaction::Chargeback {
+            id: parse_id(chargeback.tx)?,
+        },
+    })
+}
+
+fn transaction_error_to_status(err: TransactionError) -> Status {
+    let message = format!("{}: {}", err.code(), err);
+    match err {
+        TransactionError::NonexistentTransaction | TransactionError::NonexistentAccount => {
+            Status::not_found(message)
+        }
+        TransactionError::NotLogged | TransactionError::StoreFailed => Status::unavailable(message),
+        _ => Status::failed_precondition(message),
+    }
+}
+
+#[tonic::async_trait]
+impl LedgerRpc for LedgerService {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::SubmitTransactionRequest>,
+    ) -> Result<Response<proto::SubmitTransactionResponse>, Status> {
+        let request = request.into_inner();
+        let (client, tx) = parse_client(request.client)
+            .and_then(|client| Ok((client, parse_transaction(request.transaction.clone())?)))
+            .inspect_err(|status| self.dead_letter(&request, status))?;
+        let apply = |tx| {
+            self.ledger
+                .apply(client, tx)
+                .map_err(transaction_error_to_status)
+                .inspect_err(|status| self.dead_letter(&request, status))
+        };
+
+        let answer = if request.idempotency_key.is_empty() {
+            apply(tx)
+        } else {
+            // The keys stay locked while the transaction is applied, so a
+            // retry racing the original request waits for its answer.
+            let mut keys = self
+                .idempotency_keys
+                .lock()
+                .expect("idempotency keys lock poisoned");
+            match keys.get(&request.idempotency_key) {
+                Some(answer) => answer,
+                None => {
+                    let answer = apply(tx);
+                    keys.insert(request.idempotency_key.clone(), &answer);
+                    answer
+                }
+            }
+        };
+
+        answer.map(|()| Response::new(proto::SubmitTransactionResponse {}))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::GetAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let requested = request.into_inner().client;
+        let client = parse_client(requested)?;
+
+        let account = self
+            .ledger
+            .account(client)
+            .ok_or_else(|| transaction_error_to_status(TransactionError::NonexistentAccount))?;
+        let total = account.total().map_err(transaction_error_to_status)?;
+
+        // Fixed-point funds don't keep trailing zeros, so neither do decimal
+        // ones.
+        Ok(Response::new(proto::Account {
+            client: requested,
+            available: account.available().normalize().to_string(),
+            held: account.held().normalize().to_string(),
+            total: total.normalize().to_string(),
+            locked: account.is_frozen(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::{Code, Request};
+
+    use super::{proto, LedgerRpc, LedgerService};
+    use crate::ledger::Ledger;
+
+    fn submit(
+        client: u64,
+        kind: proto::transaction::Kind,
+    ) -> Request<proto::SubmitTransactionRequest> {
+        Request::new(proto::SubmitTransactionRequest {
+            client,
+            transaction: Some(proto::Transaction { kind: Some(kind) }),
+            idempotency_key: String::new(),
+        })
+    }
+
+    #[tokio::test]
+    async fn submit_and_get_account() {
+        use proto::transaction::Kind::*;
+
+        let service = LedgerService::new(Ledger::default());
+
+        let deposit = proto::Deposit {
+            tx: 1,
+            amount: "10.5".to_owned(),
+        };
+        assert!(service
+            .submit_transaction(submit(1, Deposit(deposit)))
+            .await
+            .is_ok());
+        assert!(service
+            .submit_transaction(submit(1, Dispute(proto::Dispute { tx: 1 })))
+            .await
+            .is_ok());
+
+        let account = service
+            .get_account(Request::new(proto::GetAccountRequest { client: 1 }))
+            .await
+            .expect("account should exist")
+            .into_inner();
+        assert_eq!(
+            account,
+            proto::Account {
+                client: 1,
+                available: "0".to_owned(),
+                held: "10.5".to_owned(),
+                total: "10.5".to_owned(),
+                locked: false,
+            }
+        );
+    }
+
+    fn code<T>(result: Result<T, tonic::Status>) -> Result<(), Code> {
+        result.map(|_| ()).map_err(|status| status.code())
+    }
+
+    #[tokio::test]
+    async fn errors() {
+        use proto::transaction::Kind::*;
+
+        let service = LedgerService::new(Ledger::default());
+
+        let withdrawal = proto::Withdrawal {
+            tx: 1,
+            amount: "10".to_owned(),
+        };
+        assert_eq!(
+            code(
+                service
+                    .submit_transaction(submit(1, Withdrawal(withdrawal)))
+                    .await
+            ),
+            Err(Code::FailedPrecondition)
+        );
+        assert_eq!(
+            code(
+                service
+                    .submit_transaction(submit(1, Dispute(proto::Dispute { tx: 1 })))
+                    .await
+            ),
+            Err(Code::NotFound)
+        );
+        let deposit = proto::Deposit {
+            tx: 2,
+            amount: "ten".to_owned(),
+        };
+        assert_eq!(
+            code(
+                service
+                    .submit_transaction(submit(1, Deposit(deposit)))
+                    .await
+            ),
+            Err(Code::InvalidArgument)
+        );
+        // Only the clients of the `wide-ids` feature go that high.
+        #[cfg(not(feature = "wide-ids"))]
+        {
+            let deposit = proto::Deposit {
+                tx: 2,
+                amount: "10".to_owned(),
+            };
+            assert_eq!(
+                code(
+                    service
+                        .submit_transaction(submit(70000, Deposit(deposit)))
+                        .await
+                ),
+                Err(Code::InvalidArgument)
+            );
+        }
+        assert_eq!(
+            code(
+                service
+                    .get_account(Request::new(proto::GetAccountRequest { client: 2 }))
+                    .await
+            ),
+            Err(Code::NotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn idempotency_keys() {
+        use proto::transaction::Kind::*;
+
+        let service = LedgerService::new(Ledger::default());
+        let submit_with_key = |tx, key: &str| {
+            let deposit = proto::Deposit {
+                tx,
+                amount: "10".to_owned(),
+            };
+            let mut request = submit(1, Deposit(deposit));
+            request.get_mut().idempotency_key = key.to_owned();
+            request
+        };
+
+        // A retry gets the original answer without depositing again, even
+        // though its transaction ID would be accepted.
+        assert!(service
+            .submit_transaction(submit_with_key(1, "a"))
+            .await
+            .is_ok());
+        assert!(service
+            .submit_transaction(submit_with_key(2, "a"))
+            .await
+            .is_ok());
+        assert!(service
+            .submit_transaction(submit_with_key(3, "b"))
+            .await
+            .is_ok());
+
+        let account = service
+            .get_account(Request::new(proto::GetAccountRequest { client: 1 }))
+            .await
+            .expect("account should exist")
+            .into_inner();
+        assert_eq!(account.total, "20");
+
+        // Failures are remembered too.
+        let dispute = |key: &str| {
+            let mut request = submit(1, Dispute(proto::Dispute { tx: 9 }));
+            request.get_mut().idempotency_key = key.to_owned();
+            request
+        };
+        assert_eq!(
+            code(service.submit_transaction(dispute("c")).await),
+            Err(Code::NotFound)
+        );
+        assert_eq!(
+            code(service.submit_transaction(dispute("c")).await),
+            Err(Code::NotFound)
+        );
+    }
+
+    #[test]
+    fn request_to_record() {
+        use proto::transaction::Kind::*;
+
+        let deposit = proto::Deposit {
+            tx: 2,
+            amount: "ten".to_owned(),
+        };
+        assert_eq!(
+            super::request_to_record(&submit(1, Deposit(deposit)).into_inner()),
+            "deposit,1,2,ten"
+        );
+        assert_eq!(
+            super::request_to_record(
+                &submit(70000, Dispute(proto::Dispute { tx: 3 })).into_inner()
+            ),
+            "dispute,70000,3,"
+        );
+    }
+}
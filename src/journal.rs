@@ -0,0 +1,296 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use serde::Serialize;
+
+use crate::{
+    compression::{CompressedWriter, Compression},
+    events::{LedgerEvent, Observer},
+    ledger::{ProcessedTransaction, ProcessedTransactionState},
+    policy::PolicyVersion,
+    AccountId, Balance, Timestamp, Transaction, TransactionAmount, TransactionId,
+};
+
+// JournalEntry is the CSV representation of an applied transaction in the
+// journal. `amount` and `state` are those of the transaction itself, or for
+// disputes, resolutions, and chargebacks, of the transaction they refer to.
+// The balances are the account's after the transaction was applied,
+// `policy` the version of the policy it was applied with, and `timestamp`
+// the time of the transaction, if it had one.
+#[derive(Serialize)]
+struct JournalEntry {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    client: AccountId,
+    tx: Option<TransactionId>,
+    amount: Option<TransactionAmount>,
+    state: Option<ProcessedTransactionState>,
+    available: Balance,
+    held: Balance,
+    total: Balance,
+    locked: bool,
+    policy: PolicyVersion,
+    timestamp: Option<Timestamp>,
+}
+
+// The transaction an entry is being written for, and the client, time, and
+// policy version it was applied with.
+type Pending = (
+    AccountId,
+    Transaction,
+    Option<ProcessedTransaction>,
+    Option<Timestamp>,
+    PolicyVersion,
+);
+
+// Journal is an observer that writes an entry for every transaction applied
+// to the ledger, formatted as CSV. Transactions that fail aren't applied and
+// don't appear in it. Every entry is flushed as soon as it's complete.
+pub struct Journal<W: Write> {
+    writer: csv::Writer<W>,
+    // The account's balances are only known from the `AccountUpdated` event
+    // that follows `TransactionApplied`, so the entry is completed then.
+    pending: Option<Pending>,
+}
+
+impl<W: Write> Journal<W> {
+    // Write the journal to `output`, starting with a header if `headers` is
+    // set.
+    pub fn new(output: W, headers: bool) -> Journal<W> {
+        let writer = csv::WriterBuilder::new()
+            .has_headers(headers)
+            .from_writer(output);
+        Journal {
+            writer,
+            pending: None,
+        }
+    }
+}
+
+impl Journal<File> {
+    // Append to the journal at `path`, creating it if it doesn't exist yet.
+    // The header is only written to new or empty journals, so consecutive
+    // runs can share one.
+    pub fn append(path: &Path) -> io::Result<Journal<File>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        Ok(Journal::new(file, empty))
+    }
+
+    // Like `append`, but compressing the entries. Every run appends its
+    // entries as a separate compressed stream, which decompress to the
+    // entries of all of them back to back.
+    pub fn append_compressed(
+        path: &Path,
+        compression: Compression,
+    ) -> io::Result<Journal<CompressedWriter<File>>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        Ok(Journal::new(compression.writer(file)?, empty))
+    }
+}
+
+impl<W: Write + Send> Observer for Journal<W> {
+    fn on_event(&mut self, event: &LedgerEvent) {
+        match event {
+            LedgerEvent::TransactionApplied {
+                client,
+                transaction,
+                processed,
+                timestamp,
+                policy,
+            } => {
+                let pending = (
+                    *client,
+                    transaction.clone(),
+                    *processed,
+                    *timestamp,
+                    *policy,
+                );
+                self.pending = Some(pending);
+            }
+            LedgerEvent::AccountUpdated {
+                client,
+                available,
+                held,
+                total,
+                locked,
+            } => {
+                let Some((_, transaction, processed, timestamp, policy)) =
+                    self.pending.take_if(|(pending, ..)| pending == client)
+                else {
+                    return;
+                };
+                let entry = JournalEntry {
+                    record_type: transaction.kind(),
+                    client: *client,
+                    tx: transaction.id(),
+                    amount: processed.map(|processed| processed.amount),
+                    state: processed.map(|processed| processed.state),
+                    available: *available,
+                    held: *held,
+                    total: *total,
+                    locked: *locked,
+                    policy,
+                    timestamp,
+                };
+                let result = self
+                    .writer
+                    .serialize(entry)
+                    .and_then(|()| Ok(self.writer.flush()?));
+                if let Err(err) = result {
+                    eprintln!("failed to write journal entry: {}", err);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::{Arc, Mutex},
+    };
+
+    use super::Journal;
+    use crate::ledger::Ledger;
+
+    // A writer whose contents can still be read after the journal was moved
+    // into the ledger.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn journal() {
+        let buffer = SharedBuffer::default();
+        let mut ledger = Ledger::default();
+        ledger.add_observer(Journal::new(buffer.clone(), true));
+
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10,2024-01-02T00:00:00Z
+withdrawal,1,2,4,1704153660
+withdrawal,1,3,100,
+dispute,1,1,,
+chargeback,1,1,,
+recovery,1,0,,
+adjust_credit,1,4,5,
+adjust_debit,1,5,0.5,
+dispute,1,4,,
+";
+        ledger.read_csv(input.as_bytes());
+
+        let journal = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            journal,
+            format!(
+                "\
+type,client,tx,amount,state,available,held,total,locked,policy,timestamp
+deposit,1,1,10,settled,10,0,10,false,{policy},1704153600
+withdrawal,1,2,4,settled,6,0,6,false,{policy},1704153660
+dispute,1,1,10,disputed,-4,10,6,false,{policy},
+chargeback,1,1,10,chargebacked,-4,0,-4,true,{policy},
+recovery,1,,,,-4,0,-4,true,{policy},
+adjust_credit,1,4,5,adjusted,1,0,1,true,{policy},
+adjust_debit,1,5,0.5,adjusted,0.5,0,0.5,true,{policy},
+",
+                policy = ledger.policy_version()
+            )
+        );
+    }
+
+    // A writer that always fails, like a full disk.
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::Error::other("disk full"))
+        }
+    }
+
+    #[test]
+    fn edge_cases() {
+        let buffer = SharedBuffer::default();
+        let mut ledger = Ledger::default();
+        ledger.add_observer(Journal::new(buffer.clone(), false));
+
+        // The deposit too large for funds to hold, the deposit into the
+        // closed account, and the refund of a withdrawal fail, so they
+        // aren't journaled. Closing an account emits `AccountClosed` rather
+        // than applying a transaction, so it isn't either.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,{max}
+deposit,2,3,5
+withdrawal,2,4,5
+close_account,2,0,
+deposit,2,5,1
+deposit,3,6,10
+withdrawal,3,7,4
+refund,3,7,
+refund,3,6,5
+"
+        );
+        ledger.read_csv(input.as_bytes());
+
+        let journal = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            journal,
+            format!(
+                "\
+deposit,1,1,{max},settled,{max},0,{max},false,{policy},
+deposit,2,3,5,settled,5,0,5,false,{policy},
+withdrawal,2,4,5,settled,0,0,0,false,{policy},
+deposit,3,6,10,settled,10,0,10,false,{policy},
+withdrawal,3,7,4,settled,6,0,6,false,{policy},
+refund,3,6,10,refunded,1,0,1,false,{policy},
+",
+                policy = ledger.policy_version()
+            )
+        );
+
+        // Entries that can't be written are reported, and the transactions
+        // are still applied.
+        let mut ledger = Ledger::default();
+        ledger.add_observer(Journal::new(FailingWriter, true));
+        ledger.read_csv("type,client,tx,amount\ndeposit,1,1,10\n".as_bytes());
+        assert_eq!(ledger.account(1).unwrap().total().unwrap(), 10.into());
+    }
+
+    #[test]
+    fn append() {
+        let path = std::env::temp_dir().join(format!("ledger-journal-{}.csv", std::process::id()));
+        for tx in 1..=2 {
+            let mut ledger = Ledger::default();
+            ledger.add_observer(Journal::append(&path).unwrap());
+            ledger.read_csv(format!("type,client,tx,amount\ndeposit,1,{tx},1\n").as_bytes());
+        }
+        let journal = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // The header is only written once.
+        assert_eq!(journal.lines().count(), 3);
+        assert!(journal.starts_with("type,"));
+    }
+}
@@ -0,0 +1,225 @@
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use serde::Serialize;
+
+use crate::AccountId;
+
+// LatencyMonitor keeps track of how long transactions take to apply, by
+// type, so tail latency regressions show up for the kind of operation they
+// affect. Transactions that take longer than the budget are reported on
+// stderr with their type and client, and counted.
+//
+// Latencies are counted in a histogram of buckets that grow by a quarter of
+// a power of two, from a microsecond up, so the percentiles it reports are
+// at most about 19% above the real ones, at a fixed cost in memory.
+pub struct LatencyMonitor {
+    budget: Duration,
+    kinds: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+// Buckets per power of two.
+const BUCKETS_PER_DOUBLING: f64 = 4.0;
+
+// Enough buckets for latencies up to about 2^48 microseconds, longer ones
+// are counted in the last bucket.
+const BUCKETS: usize = 192;
+
+struct Histogram {
+    counts: [u64; BUCKETS],
+    slow: u64,
+}
+
+// KindLatency is what a `LatencyMonitor` knows about the transactions of a
+// type: how many were applied, how many of them took longer than the
+// budget, and the latency 99% of them stayed within, in microseconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct KindLatency {
+    pub count: u64,
+    pub slow: u64,
+    pub p99_micros: u64,
+}
+
+impl LatencyMonitor {
+    pub fn new(budget: Duration) -> LatencyMonitor {
+        LatencyMonitor {
+            budget,
+            kinds: Mutex::default(),
+        }
+    }
+
+    pub fn budget(&self) -> Duration {
+        self.budget
+    }
+
+    // Record that a transaction of the given type took `latency` to apply to
+    // the client's account.
+    pub fn record(&self, kind: &'static str, client: AccountId, latency: Duration) {
+        let slow = latency > self.budget;
+        if slow {
+            eprintln!(
+                "slow {} for client {}: took {:?}, the budget is {:?}",
+                kind, client, latency, self.budget
+            );
+        }
+
+        let mut kinds = self.kinds.lock().expect("latency lock poisoned");
+        let histogram = kinds.entry(kind).or_insert_with(|| Histogram {
+            counts: [0; BUCKETS],
+            slow: 0,
+        });
+        histogram.counts[bucket(latency)] += 1;
+        histogram.slow += u64::from(slow);
+    }
+
+    // The latencies of every type of transaction applied so far, by type.
+    pub fn report(&self) -> BTreeMap<&'static str, KindLatency> {
+        let kinds = self.kinds.lock().expect("latency lock poisoned");
+        kinds
+            .iter()
+            .map(|(&kind, histogram)| (kind, histogram.summary()))
+            .collect()
+    }
+}
+
+// The bucket of a latency: the `i`th bucket has the latencies from
+// `bucket_end(i - 1)` up to `bucket_end(i)` microseconds.
+fn bucket(latency: Duration) -> usize {
+    let micros = latency.as_micros() as f64;
+    let bucket = ((micros + 1.0).log2() * BUCKETS_PER_DOUBLING).ceil() as usize;
+    bucket.min(BUCKETS - 1)
+}
+
+fn bucket_end(bucket: usize) -> u64 {
+    ((bucket as f64 / BUCKETS_PER_DOUBLING).exp2() - 1.0).round() as u64
+}
+
+impl Histogram {
+    fn summary(&self) -> KindLatency {
+        let count = self.counts.iter().sum::<u64>();
+        // The smallest bucket with at least 99% of the latencies at or
+        // below it.
+        let within = count - count / 100;
+        let mut seen = 0;
+        let p99 = self
+            .counts
+            .iter()
+            .position(|&bucket| {
+                seen += bucket;
+                seen >= within
+            })
+            .unwrap_or(0);
+        KindLatency {
+            count,
+            slow: self.slow,
+            p99_micros: bucket_end(p99),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{bucket, bucket_end, KindLatency, LatencyMonitor, BUCKETS};
+    use crate::{shared::SharedLedger, Transaction, TransactionError};
+
+    #[test]
+    fn percentiles() {
+        // Buckets cover every latency, and are at most a fifth too large.
+        for micros in [0, 1, 2, 3, 10, 999, 1000, 123_456] {
+            let end = bucket_end(bucket(Duration::from_micros(micros)));
+            assert!(end >= micros && end as f64 <= micros as f64 * 1.2 + 1.0);
+        }
+
+        let monitor = LatencyMonitor::new(Duration::from_millis(5));
+        for i in 0..990 {
+            monitor.record("deposit", 1, Duration::from_micros(100 + i % 10));
+        }
+        for _ in 0..10 {
+            monitor.record("deposit", 2, Duration::from_millis(8));
+        }
+        monitor.record("dispute", 1, Duration::from_millis(2));
+
+        let report = monitor.report();
+        let deposits = &report["deposit"];
+        assert_eq!((deposits.count, deposits.slow), (1000, 10));
+        assert!((109..=131).contains(&deposits.p99_micros));
+        assert_eq!(
+            report["dispute"],
+            KindLatency {
+                count: 1,
+                slow: 0,
+                p99_micros: bucket_end(bucket(Duration::from_millis(2))),
+            }
+        );
+        assert!(!report.contains_key("withdrawal"));
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Latencies as long as a duration can be are counted in the last
+        // bucket, and only latencies longer than the budget are slow.
+        let monitor = LatencyMonitor::new(Duration::ZERO);
+        monitor.record("deposit", 1, Duration::ZERO);
+        monitor.record("withdrawal", 1, Duration::MAX);
+        assert_eq!(bucket(Duration::MAX), BUCKETS - 1);
+        let report = monitor.report();
+        assert_eq!(
+            report["deposit"],
+            KindLatency {
+                count: 1,
+                slow: 0,
+                p99_micros: 0,
+            }
+        );
+        assert_eq!(report["withdrawal"].slow, 1);
+        assert_eq!(report["withdrawal"].p99_micros, bucket_end(BUCKETS - 1));
+
+        // Rejected transactions take time to apply too, so they're counted
+        // with the rest of their type: a deposit too large for funds to hold,
+        // one into a closed account, and a refund of a withdrawal.
+        let ledger = SharedLedger::default().with_latency_budget(Duration::from_secs(60));
+        let max = crate::funds::max();
+        let deposit = |new_id, amount| Transaction::Deposit { new_id, amount };
+        assert_eq!(ledger.apply(1, deposit(1, max)), Ok(()));
+        assert_eq!(
+            ledger.apply(1, deposit(2, max)),
+            Err(TransactionError::Overflow)
+        );
+        let withdrawal = Transaction::Withdrawal {
+            new_id: 3,
+            amount: max,
+        };
+        assert_eq!(ledger.apply(1, withdrawal), Ok(()));
+        assert_eq!(
+            ledger.apply(
+                1,
+                Transaction::Refund {
+                    id: 3,
+                    amount: None
+                }
+            ),
+            Err(TransactionError::NotRefundable)
+        );
+        assert_eq!(ledger.apply(1, Transaction::CloseAccount), Ok(()));
+        assert_eq!(
+            ledger.apply(1, deposit(4, 1.into())),
+            Err(TransactionError::AccountClosed)
+        );
+
+        let report = ledger.latency().unwrap();
+        let counts = report
+            .iter()
+            .map(|(&kind, latency)| (kind, latency.count, latency.slow))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            [
+                ("close_account", 1, 0),
+                ("deposit", 3, 0),
+                ("refund", 1, 0),
+                ("withdrawal", 1, 0),
+            ]
+        );
+    }
+}
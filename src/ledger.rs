@@ -1,394 +1,6576 @@
-use std::collections::HashMap;
+use std::{collections::VecDeque, mem, str::FromStr};
 
+use rust_decimal::{Decimal, RoundingStrategy};
+use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::{
-    account::Account, AccountId, Balance, Transaction, TransactionAmount, TransactionError,
+    account::{
+        Account, AccountMetadata, FreezeLevel, FreezePeriod, FreezeReason, OpeningBalance,
+        ESCALATED, LOCK_REASON,
+    },
+    account_store::AccountStore,
+    client_names::{without_name, ClientKey, ClientNames},
+    clock::parse_timestamp,
+    error_code::ErrorCode,
+    error_sink::{ErrorSink, ProcessingReport, Stderr},
+    events::{LedgerEvent, Observer},
+    filter::{Filter, Filterable},
+    limits::{WithdrawalLimit, Withdrawals, LIMIT_RULE},
+    policy::{
+        AccountCreationPolicy, AmountPolicy, ClientIdPolicy, DisputePolicy, EarlyDisputePolicy,
+        ErrorPolicy, HeldRatioAction, OutOfOrderPolicy, Policy, PolicyVersion, TransactionIdPolicy,
+        WithdrawalDisputePolicy, ZeroAmountPolicy,
+    },
+    reconcile::{ReconcileError, Reconciliation},
+    scaling::{AmountScale, ScaleCheck},
+    shard::shard_of,
+    tx_store::TxStore,
+    AccountId, Balance, Currency, Timestamp, Transaction, TransactionAmount, TransactionError,
     TransactionId,
 };
 
 // ProcessedTransactionState represents the state of a transaction that's been
 // successfully applied to an account.
 // * Settled: successfully applied (deposited/withdrawn)
-// * Disputed: the transaction was disputed after being settled. Its amount
-//   has been deducted from the available amount and added to the held amount.
-//   A future resolution transaction can return it to settled state, adding
-//   the amount to the available, and subtracting it from the held.
+// * Disputed: the transaction was disputed after being settled. Its disputed
+//   amount has been deducted from the available amount and added to the held
+//   amount. A future resolution transaction can return it to settled state,
+//   adding the disputed amount to the available, and subtracting it from the
+//   held.
 // * ChargeBacked: a disputed transaction can be chargebacked by the client.
 //   The transaction may not be further modified.
-#[derive(PartialEq, Eq)]
+// * Adjusted: an operator's adjustment, see `Transaction::AdjustCredit`. It
+//   can't be disputed.
+// * Refunded: a settled transaction was refunded, in full or in part, see
+//   `Transaction::Refund`. It may not be further modified.
+// * Authorized: an authorization holding its amount, see
+//   `Transaction::Authorize`. Capturing it makes it settled, like a
+//   withdrawal.
+// * Voided: an authorization that was voided, or expired, before it was
+//   captured. It may not be further modified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ProcessedTransactionState {
     Settled,
     Disputed,
     ChargeBacked,
+    Adjusted,
+    Refunded,
+    Authorized,
+    Voided,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ProcessedTransaction {
     pub amount: TransactionAmount,
     pub state: ProcessedTransactionState,
+    // The part of the amount the latest dispute held, if it didn't hold all
+    // of it. Resolving or charging back the dispute releases that part.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disputed: Option<TransactionAmount>,
+    // Whether the transaction is a withdrawal, which disputes may treat
+    // differently, see `WithdrawalDisputePolicy`.
+    #[serde(default)]
+    pub withdrawal: bool,
+    // The time of the transaction, from the `timestamp` column of the input
+    // or the time it was scheduled to take effect, if it had one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    // The currency of the transaction, if it had one. Transactions referring
+    // to it must be in the same currency.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
 }
 
-#[derive(Default)]
+impl ProcessedTransaction {
+    // The amount held by the latest dispute of the transaction.
+    pub fn disputed_amount(&self) -> TransactionAmount {
+        self.disputed.unwrap_or(self.amount)
+    }
+}
+
+// TransactionView is a read-only copy of a processed transaction, as
+// returned by `Ledger::transaction`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransactionView {
+    pub amount: TransactionAmount,
+    pub state: ProcessedTransactionState,
+    pub disputed: Option<TransactionAmount>,
+    pub timestamp: Option<Timestamp>,
+    pub currency: Option<Currency>,
+}
+
+impl From<&ProcessedTransaction> for TransactionView {
+    fn from(tx: &ProcessedTransaction) -> TransactionView {
+        TransactionView {
+            amount: tx.amount,
+            state: tx.state,
+            disputed: tx.disputed,
+            timestamp: tx.timestamp,
+            currency: tx.currency,
+        }
+    }
+}
+
+// TransactionFilter selects processed transactions in
+// `Ledger::find_transactions`. Every criterion that is set must match; an
+// empty filter matches every transaction. The amount bounds are inclusive.
+#[derive(Debug, Default, Clone)]
+pub struct TransactionFilter {
+    pub client: Option<AccountId>,
+    pub min_amount: Option<TransactionAmount>,
+    pub max_amount: Option<TransactionAmount>,
+    pub state: Option<ProcessedTransactionState>,
+}
+
+impl TransactionFilter {
+    fn matches(&self, client: AccountId, tx: &ProcessedTransaction) -> bool {
+        self.client.is_none_or(|c| c == client)
+            && self.min_amount.is_none_or(|min| tx.amount >= min)
+            && self.max_amount.is_none_or(|max| tx.amount <= max)
+            && self.state.is_none_or(|state| tx.state == state)
+    }
+}
+
+// BatchOutcome is the result of applying a batch of transactions with
+// `Ledger::apply_batch`: how many were applied, and which were rejected, by
+// their index in the batch, and why. Only a `SharedLedger` queues
+// transactions, those of paused accounts, see `SharedLedger::pause`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BatchOutcome {
+    pub applied: usize,
+    pub rejected: Vec<(usize, TransactionError)>,
+    pub queued: usize,
+}
+
+// StatementEntry is a line of an account's statement: a transaction that was
+// applied to the account, or `None` for its opening balance, and the
+// account's balances after it. `amount` is the amount of the transaction, or
+// for disputes, resolutions, and chargebacks, of the transaction they refer
+// to. `timestamp` is the time of the transaction, if it had one, and
+// `currency` its currency, in which case the balances are those in the
+// currency.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatementEntry {
+    pub transaction: Option<Transaction>,
+    pub amount: Option<TransactionAmount>,
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available: Balance,
+    pub held: Balance,
+    pub total: Balance,
+    pub locked: bool,
+}
+
+// Serializing a ledger captures its state, see `Ledger::save_snapshot`, but
+// not its configuration: the policy, precision, filter, and observers are
+// left out, and a deserialized ledger starts with the defaults.
+#[derive(Default, Serialize, Deserialize)]
 pub struct Ledger {
-    accounts: HashMap<AccountId, Account>,
+    accounts: Accounts,
+    statements: FxHashMap<AccountId, Vec<StatementEntry>>,
+    processed_txs: ProcessedTxs,
+    // The IDs of every deposit and withdrawal applied, only kept when they
+    // have to be unique.
+    used_ids: FxHashSet<TransactionId>,
+    // The client of every deposit and withdrawal applied, by their ID, kept
+    // instead of `used_ids` when IDs are unique across clients, see
+    // `TransactionIdPolicy::Global`.
+    #[serde(default)]
+    id_owners: FxHashMap<TransactionId, AccountId>,
+    #[serde(skip)]
+    policy: Policy,
+    // The lines skipped while reading input with `ErrorPolicy::Collect`.
+    #[serde(skip)]
+    pub(crate) report: ProcessingReport,
+    // The version of `policy`, kept so it isn't derived for every
+    // transaction.
+    #[serde(skip)]
+    policy_version: PolicyVersion,
+    #[serde(skip)]
+    precision: Precision,
+    #[serde(skip)]
+    filter: Option<Filter>,
+    // Whether the account summaries say why accounts are frozen, see
+    // `LedgerBuilder::freeze_reasons`.
+    #[serde(skip)]
+    freeze_reasons: bool,
+    // Whether statements are kept, see `LedgerBuilder::keep_statements`.
+    #[serde(skip)]
+    keep_statements: bool,
+    #[serde(skip)]
+    observers: Vec<Box<dyn Observer>>,
+    // The events the state is derived from, in order, if they're kept.
+    #[serde(skip)]
+    events: Option<Vec<LedgerEvent>>,
+    // The number of threads CSV input is processed on, see
+    // `LedgerBuilder::shards`.
+    #[serde(skip)]
+    shards: usize,
+    // Whether the processed transactions are kept in a store given to the
+    // builder, see `LedgerBuilder::tx_store`, which shards can't split.
+    #[serde(skip)]
+    custom_tx_store: bool,
+    // Whether the accounts are kept in a store given to the builder, see
+    // `LedgerBuilder::account_store`, which shards would bypass.
+    #[serde(skip)]
+    custom_account_store: bool,
+    // The number of transactions applied, other than voids, which is what
+    // authorizations expire by, see `Policy::authorization_window`.
+    #[serde(default)]
+    applied: u64,
+    // The authorizations that may expire, oldest first, with the number of
+    // transactions applied they expire at.
+    #[serde(default)]
+    authorizations: VecDeque<(u64, AccountId, TransactionId)>,
+    // Set while rebuilding from events, which have the voids of expired
+    // authorizations already, and were checked against the withdrawal limit
+    // and the dispute window when they were first applied.
+    #[serde(skip)]
+    rebuilding: bool,
+    // The time of the latest transaction of every account, kept when the
+    // order of transactions is checked, see `OutOfOrderPolicy`.
+    #[serde(default)]
+    latest_times: FxHashMap<AccountId, Timestamp>,
+    // The time the ledger has been advanced to, see `Ledger::advance_to`.
+    #[serde(default)]
+    now: Timestamp,
+    // The scheduled transactions that haven't taken effect yet, by the time
+    // they take effect, and those taking effect at the same time in the
+    // order they were scheduled.
+    #[serde(default)]
+    scheduled: Vec<(Timestamp, AccountId, Transaction)>,
+    // The disputes of every account waiting for the transaction they refer
+    // to, with their time, in the order they came, see
+    // `EarlyDisputePolicy`.
+    #[serde(default)]
+    parked_disputes: FxHashMap<AccountId, Vec<(Option<Timestamp>, Transaction)>>,
+    // The transactions of every account that were applied but not kept,
+    // e.g. fees, which disputes parked for them would wait for forever.
+    // Only tracked while disputes are parked.
+    #[serde(default)]
+    unkept_txs: FxHashSet<(AccountId, TransactionId)>,
+    // The recent withdrawals of every account, only kept when they're
+    // limited, see `Policy::withdrawal_limit`.
+    #[serde(default)]
+    withdrawals: Withdrawals,
+    // The account IDs of the clients known by name, see
+    // `ClientIdPolicy::Text`.
+    #[serde(default, skip_serializing_if = "ClientNames::is_empty")]
+    pub(crate) client_names: ClientNames,
+    // The metadata of the accounts that were created explicitly, see
+    // `Ledger::create_account`.
+    #[serde(default)]
+    metadata: FxHashMap<AccountId, AccountMetadata>,
+    // Every time the accounts that were ever frozen were, in order, see
+    // `Ledger::freeze_history`.
+    #[serde(default)]
+    freezes: FxHashMap<AccountId, Vec<FreezePeriod>>,
+}
+
+// RebuildError is returned by `Ledger::rebuild_from_events` when one of the
+// events can't be applied again, e.g. because the ledger's policy rejects a
+// transaction that was applied under a different one. `position` is the
+// index of the event.
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("event {position} can't be applied: {error}")]
+pub struct RebuildError {
+    pub position: usize,
+    pub error: TransactionError,
+}
+
+// LedgerBuilder configures a ledger before it's created: the policy it
+// processes transactions with, the precision its account summaries are
+// written with, the filter of its outputs, and its observers. Anything that
+// isn't set keeps the default, so `Ledger::builder().build()` is the same as
+// `Ledger::default()`.
+#[derive(Default)]
+pub struct LedgerBuilder {
+    policy: Policy,
+    precision: Precision,
+    filter: Option<Filter>,
+    freeze_reasons: bool,
+    observers: Vec<Box<dyn Observer>>,
+    keep_events: bool,
+    keep_statements: bool,
+    shards: usize,
+    expected_transactions: usize,
+    accounts: Accounts,
     processed_txs: ProcessedTxs,
+    custom_tx_store: bool,
+    custom_account_store: bool,
+}
+
+impl LedgerBuilder {
+    // Replace the whole policy, e.g. one assembled from settings.
+    pub fn policy(mut self, policy: Policy) -> LedgerBuilder {
+        self.policy = policy;
+        self
+    }
+
+    // Which amounts are accepted when reading input, see `AmountPolicy`.
+    pub fn amounts(mut self, amounts: AmountPolicy) -> LedgerBuilder {
+        self.policy.amounts = amounts;
+        self
+    }
+
+    pub fn zero_amounts(mut self, zero_amounts: ZeroAmountPolicy) -> LedgerBuilder {
+        self.policy.zero_amounts = zero_amounts;
+        self
+    }
+
+    pub fn errors(mut self, errors: ErrorPolicy) -> LedgerBuilder {
+        self.policy.errors = errors;
+        self
+    }
+
+    // Allow withdrawals to take an account's available funds down to
+    // `-limit`. Accounts opened with `open_account` get the same allowance.
+    pub fn overdraft(mut self, limit: Balance) -> LedgerBuilder {
+        self.policy.overdraft = limit;
+        self
+    }
+
+    // Give the client an overdraft of its own instead of the one above, e.g.
+    // a credit line.
+    pub fn account_overdraft(mut self, client: AccountId, limit: Balance) -> LedgerBuilder {
+        self.policy.account_overdrafts.insert(client, limit);
+        self
+    }
+
+    pub fn transaction_ids(mut self, transaction_ids: TransactionIdPolicy) -> LedgerBuilder {
+        self.policy.transaction_ids = transaction_ids;
+        self
+    }
+
+    // Limit the ratio of held to total funds disputes may take an account
+    // to, see `Policy::max_held_ratio`.
+    pub fn max_held_ratio(mut self, ratio: Decimal, action: HeldRatioAction) -> LedgerBuilder {
+        self.policy.max_held_ratio = Some(ratio);
+        self.policy.held_ratio_action = action;
+        self
+    }
+
+    pub fn disputes(mut self, disputes: DisputePolicy) -> LedgerBuilder {
+        self.policy.disputes = disputes;
+        self
+    }
+
+    pub fn withdrawal_disputes(mut self, disputes: WithdrawalDisputePolicy) -> LedgerBuilder {
+        self.policy.withdrawal_disputes = disputes;
+        self
+    }
+
+    // Park disputes of transactions that haven't come yet, see
+    // `EarlyDisputePolicy`.
+    pub fn early_disputes(mut self, disputes: EarlyDisputePolicy) -> LedgerBuilder {
+        self.policy.early_disputes = disputes;
+        self
+    }
+
+    // Park at most `disputes` disputes for every account, see
+    // `Policy::max_parked_disputes`.
+    pub fn max_parked_disputes(mut self, disputes: usize) -> LedgerBuilder {
+        self.policy.max_parked_disputes = Some(disputes);
+        self
+    }
+
+    // Credit fees to the given client's account, see `Transaction::Fee`.
+    pub fn fee_account(mut self, client: AccountId) -> LedgerBuilder {
+        self.policy.fee_account = Some(client);
+        self
+    }
+
+    // Sweep what's left in accounts to the given client's account when
+    // they're closed, see `Ledger::close_account`.
+    pub fn sweep_account(mut self, client: AccountId) -> LedgerBuilder {
+        self.policy.sweep_account = Some(client);
+        self
+    }
+
+    // Reject deposits and withdrawals of more than the given amount.
+    pub fn max_amount(mut self, amount: TransactionAmount) -> LedgerBuilder {
+        self.policy.max_amount = Some(amount);
+        self
+    }
+
+    // Reject withdrawals beyond the limit, see `WithdrawalLimit`.
+    pub fn withdrawal_limit(mut self, limit: WithdrawalLimit) -> LedgerBuilder {
+        self.policy.withdrawal_limit = Some(limit);
+        self
+    }
+
+    // Check that every account's transactions come in the order of their
+    // time, see `OutOfOrderPolicy`.
+    pub fn out_of_order(mut self, policy: OutOfOrderPolicy) -> LedgerBuilder {
+        self.policy.out_of_order = policy;
+        self
+    }
+
+    // Put the transactions read in the order of their time within the given
+    // number of seconds, see `Policy::reorder_window`.
+    pub fn reorder_window(mut self, seconds: Timestamp) -> LedgerBuilder {
+        self.policy.reorder_window = Some(seconds);
+        self
+    }
+
+    // Only apply transactions to accounts that were created first, see
+    // `AccountCreationPolicy::Explicit`.
+    pub fn account_creation(mut self, policy: AccountCreationPolicy) -> LedgerBuilder {
+        self.policy.account_creation = policy;
+        self
+    }
+
+    // Identify the clients of CSV input by name, see `ClientIdPolicy::Text`.
+    pub fn client_ids(mut self, policy: ClientIdPolicy) -> LedgerBuilder {
+        self.policy.client_ids = policy;
+        self
+    }
+
+    // Give the currency's amounts the given number of decimal places instead
+    // of its built-in minor units, see `CurrencyRegistry`.
+    pub fn minor_units(mut self, currency: Currency, digits: u32) -> LedgerBuilder {
+        self.policy.currencies.set(currency, digits);
+        self
+    }
+
+    // Reject disputes of transactions older than the given number of days,
+    // see `Policy::dispute_window`.
+    pub fn dispute_window(mut self, days: u64) -> LedgerBuilder {
+        self.policy.dispute_window = Some(days);
+        self
+    }
+
+    // Void authorizations not captured or voided within the given number of
+    // transactions, see `Policy::authorization_window`.
+    pub fn authorization_window(mut self, transactions: u64) -> LedgerBuilder {
+        self.policy.authorization_window = Some(transactions);
+        self
+    }
+
+    pub fn precision(mut self, precision: Precision) -> LedgerBuilder {
+        self.precision = precision;
+        self
+    }
+
+    // Only write the account summaries and statement lines that match
+    // `filter`, see `Filter`. Closing balances and carried forward balances
+    // are never filtered.
+    pub fn filter(mut self, filter: Filter) -> LedgerBuilder {
+        self.filter = Some(filter);
+        self
+    }
+
+    // Add `freeze_reason` and `freeze_level` columns to the account
+    // summaries, saying why the frozen accounts are frozen, see
+    // `FreezeReason`, and how strictly, see `FreezeLevel`.
+    pub fn freeze_reasons(mut self) -> LedgerBuilder {
+        self.freeze_reasons = true;
+        self
+    }
+
+    pub fn observer<O: Observer + 'static>(mut self, observer: O) -> LedgerBuilder {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    // Keep the accounts in `store` instead of the default map, see
+    // `AccountStore`.
+    pub fn account_store<S: AccountStore + 'static>(mut self, store: S) -> LedgerBuilder {
+        self.accounts = Accounts(Box::new(store));
+        self.custom_account_store = true;
+        self
+    }
+
+    // Keep the processed transactions in `store` instead of in memory, see
+    // `TxStore`.
+    pub fn tx_store<S: TxStore + 'static>(mut self, store: S) -> LedgerBuilder {
+        self.processed_txs = ProcessedTxs(Box::new(store));
+        self.custom_tx_store = true;
+        self
+    }
+
+    // Keep the events the ledger's state is derived from, see
+    // `Ledger::events`. This costs memory for every transaction applied.
+    pub fn keep_events(mut self) -> LedgerBuilder {
+        self.keep_events = true;
+        self
+    }
+
+    // Keep the statement of every account, see `Ledger::statement_for`.
+    // This costs memory for every transaction applied, and the statements
+    // are part of snapshots.
+    pub fn keep_statements(mut self) -> LedgerBuilder {
+        self.keep_statements = true;
+        self
+    }
+
+    // Process CSV input on `shards` threads, see `shard::read_csv_sharded`.
+    // Input is still processed on a single thread when the order of
+    // transactions across clients matters: with observers, kept events,
+    // unique transaction IDs, or when aborting on errors. So it is with
+    // clients identified by name, a reorder window, or a `tx_store`, whose
+    // transactions the shards can't split between them.
+    pub fn shards(mut self, shards: usize) -> LedgerBuilder {
+        self.shards = shards;
+        self
+    }
+
+    // Make room for about `transactions` transactions up front, e.g. the
+    // number of lines of the input, so the maps of the ledger don't have to
+    // grow, and rehash everything, again and again while it's read. Stores
+    // that don't keep everything in memory ignore this.
+    pub fn expected_transactions(mut self, transactions: usize) -> LedgerBuilder {
+        self.expected_transactions = transactions;
+        self
+    }
+
+    pub fn build(self) -> Ledger {
+        let expected_transactions = self.expected_transactions;
+        let mut ledger = Ledger {
+            events: self.keep_events.then(Vec::new),
+            policy_version: self.policy.version(),
+            policy: self.policy,
+            precision: self.precision,
+            filter: self.filter,
+            freeze_reasons: self.freeze_reasons,
+            keep_statements: self.keep_statements,
+            observers: self.observers,
+            shards: self.shards,
+            custom_tx_store: self.custom_tx_store,
+            custom_account_store: self.custom_account_store,
+            accounts: self.accounts,
+            processed_txs: self.processed_txs,
+            ..Ledger::default()
+        };
+        if expected_transactions > 0 {
+            ledger.reserve(expected_transactions);
+        }
+        ledger
+    }
 }
 
 impl Ledger {
-    // Attempt to apply the given transaction to the given account.
-    // If the transaction can't be applied an error is returned and no change
-    // is made.
-    fn apply_for_account(
+    pub fn builder() -> LedgerBuilder {
+        LedgerBuilder::default()
+    }
+
+    // Create an empty ledger that processes transactions according to the
+    // given policy.
+    pub fn with_policy(policy: Policy) -> Ledger {
+        Ledger::builder().policy(policy).build()
+    }
+
+    // Make room for `transactions` more transactions, and the accounts and
+    // statements of as many clients, as far as there can be that many.
+    fn reserve(&mut self, transactions: usize) {
+        // The largest ID of `wide-ids` is cut down to the largest usize.
+        let clients = transactions.min((AccountId::MAX as usize).saturating_add(1));
+        self.processed_txs.0.reserve(transactions);
+        self.accounts.0.reserve(clients);
+        self.statements.reserve(clients);
+        match self.policy.transaction_ids {
+            TransactionIdPolicy::Unchecked => {}
+            TransactionIdPolicy::Unique => self.used_ids.reserve(transactions),
+            TransactionIdPolicy::Global => self.id_owners.reserve(transactions),
+        }
+    }
+
+    // The policy transactions are applied with.
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    // The names of the clients known by one, and the account IDs they were
+    // given, see `ClientIdPolicy::Text`.
+    pub fn client_names(&self) -> &ClientNames {
+        &self.client_names
+    }
+
+    // How outputs identify the client of the given account: by its name if
+    // it has one.
+    pub(crate) fn client_key(&self, client: AccountId) -> ClientKey {
+        match self.client_names.name(client) {
+            Some(name) => ClientKey::Name(name.to_owned()),
+            None => ClientKey::Id(client),
+        }
+    }
+
+    // The version of the policy transactions are applied with.
+    pub fn policy_version(&self) -> PolicyVersion {
+        self.policy_version
+    }
+
+    // The precision the account summaries are written with.
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    // The filter of the account summaries and statements, if any.
+    pub fn filter(&self) -> Option<&Filter> {
+        self.filter.as_ref()
+    }
+
+    // Open an account for the given client starting from the given balance,
+    // e.g. when migrating from another system. This has to happen before any
+    // transaction is applied to the account. Funds held in the opening
+    // balance aren't tied to any transaction, so they can't be resolved or
+    // chargebacked later. Balances in a currency are opened the same way,
+    // before any transaction in that currency, and an account may be opened
+    // with a balance in several currencies.
+    pub fn open_account(
         &mut self,
-        account: AccountId,
-        tx: Transaction,
+        client: AccountId,
+        balance: OpeningBalance,
     ) -> Result<(), TransactionError> {
-        let mut txs_for_account =
-            ProcessedTxsForAccount::for_account(&mut self.processed_txs, account);
-        let account = self.accounts.entry(account).or_default();
+        let account = match (self.accounts.0.get(client), balance.currency) {
+            (Some(account), Some(currency)) if account.in_currency(currency).is_none() => {
+                let mut account = account.clone();
+                account.open_balance(&balance)?;
+                account
+            }
+            (Some(_), _) => return Err(TransactionError::AccountExists),
+            (None, _) => Account::with_opening_balance(&balance)?
+                .with_overdraft(self.policy.overdraft_for(client))?,
+        };
+        let was_frozen = self.accounts.0.get(client).is_some_and(Account::is_frozen);
+        let frozen = account.is_frozen();
+        self.accounts.0.insert(client, account)?;
+        if !was_frozen && frozen {
+            self.record_freeze(client, FreezeReason::Opening, FreezeLevel::Full, self.now);
+        }
+        self.record_statement(client, None, None, None, balance.currency)?;
+        self.emit(LedgerEvent::AccountOpened { client, balance });
+        self.emit_account_updated(client)?;
+        Ok(())
+    }
 
-        account.try_apply_transaction(&mut txs_for_account, tx)
+    // An empty account for the given client, with its overdraft. It fails
+    // with `Overflow` if the overdraft is larger than funds can hold.
+    fn new_account(&self, client: AccountId) -> Result<Account, TransactionError> {
+        Account::default().with_overdraft(self.policy.overdraft_for(client))
     }
 
-    // Write the account summaries in this ledger formatted as CSV to the
-    // given writer. This consumes the ledger to prevent modification
-    // after writing.
-    pub fn accounts_to_csv<W: std::io::Write>(self, output: &mut W) {
-        let mut writer = csv::WriterBuilder::new()
-            .has_headers(true)
-            .from_writer(output);
+    // Create an empty account for the given client along with its metadata,
+    // e.g. ahead of its first deposit when accounts aren't created by
+    // transactions, see `AccountCreationPolicy::Explicit`. It fails if the
+    // client has an account already.
+    pub fn create_account(
+        &mut self,
+        client: AccountId,
+        metadata: AccountMetadata,
+    ) -> Result<(), TransactionError> {
+        if self.accounts.0.get(client).is_some() {
+            return Err(TransactionError::AccountExists);
+        }
+        let account = self.new_account(client)?;
+        self.accounts.0.insert(client, account)?;
+        self.metadata.insert(client, metadata.clone());
+        self.record_statement(client, None, None, None, None)?;
+        self.emit(LedgerEvent::AccountCreated { client, metadata });
+        self.emit_account_updated(client)?;
+        Ok(())
+    }
 
-        #[derive(Serialize)]
-        struct OutputRecord {
-            client: AccountId,
-            available: Balance,
-            held: Balance,
-            total: Balance,
-            locked: bool,
+    // Close the given client's account, so it can't be deposited to or
+    // withdrawn from anymore. What's left in it is swept to the ledger's
+    // sweep account, see `Policy::sweep_account`, which is opened if needed,
+    // like the fee account. It fails with `BalanceNotZero` if the account
+    // has held funds, is below zero, or has funds left without a sweep
+    // account to take them, leaving it as it was.
+    pub fn close_account(&mut self, client: AccountId) -> Result<(), TransactionError> {
+        let sweep_to = self
+            .policy
+            .sweep_account
+            .filter(|&sweep_to| sweep_to != client);
+        self.close_account_into(client, sweep_to)
+    }
+
+    // Close the account like `close_account`, sweeping what's left to the
+    // given account. Replayed closings sweep to the account they swept to
+    // when they were applied, whatever the policy says now.
+    fn close_account_into(
+        &mut self,
+        client: AccountId,
+        sweep_to: Option<AccountId>,
+    ) -> Result<(), TransactionError> {
+        let mut account = self
+            .accounts
+            .0
+            .get(client)
+            .cloned()
+            .ok_or(TransactionError::NonexistentAccount)?;
+        let residue = account.residue()?;
+        let sweep = match (residue.is_empty(), sweep_to) {
+            (true, _) => None,
+            (false, None) => return Err(TransactionError::BalanceNotZero),
+            (false, Some(sweep_to)) => {
+                let explicit = self.policy.account_creation == AccountCreationPolicy::Explicit;
+                let mut sweep_account = match self.accounts.0.get(sweep_to) {
+                    Some(account) if account.is_closed() => {
+                        return Err(TransactionError::AccountClosed)
+                    }
+                    Some(account) => account.clone(),
+                    None if explicit => return Err(TransactionError::NonexistentAccount),
+                    None => self.new_account(sweep_to)?,
+                };
+                for &(currency, amount) in &residue {
+                    sweep_account.credit_fee(currency, amount)?;
+                }
+                Some((sweep_to, sweep_account))
+            }
+        };
+
+        // If the sweep account can't be written, the closing is undone.
+        let open = account.clone();
+        account.close();
+        self.accounts.0.insert(client, account)?;
+        if let Some((sweep_to, sweep_account)) = &sweep {
+            if let Err(err) = self.accounts.0.insert(*sweep_to, sweep_account.clone()) {
+                let _ = self.accounts.0.insert(client, open);
+                return Err(err);
+            }
+        }
+        let swept = residue
+            .iter()
+            .find(|(currency, _)| currency.is_none())
+            .map(|&(_, amount)| amount);
+        self.record_statement(client, Some(Transaction::CloseAccount), swept, None, None)?;
+        let swept_to = sweep.as_ref().map(|&(sweep_to, _)| sweep_to);
+        self.emit(LedgerEvent::AccountClosed { client, swept_to });
+        self.emit_account_updated(client)?;
+        if let Some((sweep_to, _)) = sweep {
+            for (currency, amount) in residue {
+                let closing = Some(Transaction::CloseAccount);
+                self.record_statement(sweep_to, closing, Some(amount), None, currency)?;
+            }
+            self.emit_account_updated(sweep_to)?;
         }
+        Ok(())
+    }
 
-        // NOTE: This is not necessary but it makes testing easier.
-        // It could be removed at the cost of making tests more complicated.
-        let mut sorted_accounts = self.accounts.keys().collect::<Vec<_>>();
-        sorted_accounts.sort();
+    // The metadata of the given client's account, if it was created
+    // explicitly.
+    pub fn account_metadata(&self, client: AccountId) -> Option<&AccountMetadata> {
+        self.metadata.get(&client)
+    }
 
-        for account_id in sorted_accounts {
-            // This unwrap is okay, we know the key must exist because
-            // this method takes self by value, so no one can have access
-            // to the accounts map during this iteration.
-            let account = self
-                .accounts
-                .get(account_id)
-                .expect("accounts modified during iteration");
-            let (mut available, mut held, mut total) =
-                (account.available(), account.held(), account.total());
-
-            // Output at most 4 decimal places of precision.
-            available.rescale(4);
-            held.rescale(4);
-            total.rescale(4);
-
-            writer
-                .serialize(OutputRecord {
-                    client: *account_id,
-                    available: available,
-                    held: held,
-                    total: total,
-                    locked: account.is_frozen(),
-                })
-                .expect("failed to write CSV output");
+    // Write the state of this ledger to the given writer as JSON, so that a
+    // long-running process can checkpoint it and resume from it with
+    // `load_snapshot` after a restart.
+    pub fn save_snapshot<W: std::io::Write>(&self, output: W) -> serde_json::Result<()> {
+        serde_json::to_writer(output, self)
+    }
+
+    // Replace the state of this ledger with a snapshot written by
+    // `save_snapshot`. The ledger keeps its own policy, precision, and
+    // observers, and no events are emitted for the restored state. If the
+    // snapshot can't be read the ledger is left unchanged.
+    pub fn load_snapshot<R: std::io::Read>(&mut self, input: R) -> serde_json::Result<()> {
+        let snapshot: Ledger = serde_json::from_reader(input)?;
+        if let Some((client, _)) = snapshot
+            .accounts
+            .0
+            .iter()
+            .find(|(_, account)| !account.totals_fit())
+        {
+            return Err(serde::de::Error::custom(format!(
+                "the funds of client {} are out of range",
+                client
+            )));
+        }
+        self.accounts.0.clear();
+        for (client, account) in snapshot.accounts.0.iter() {
+            self.accounts
+                .0
+                .insert(client, account.clone())
+                .map_err(serde::de::Error::custom)?;
+        }
+        self.statements = snapshot.statements;
+        self.processed_txs.0.clear();
+        for found in snapshot.processed_txs.0.iter() {
+            let (client, id, tx) = found.map_err(serde::de::Error::custom)?;
+            self.processed_txs
+                .0
+                .insert(client, id, tx)
+                .map_err(serde::de::Error::custom)?;
         }
+        self.used_ids = snapshot.used_ids;
+        self.id_owners = snapshot.id_owners;
+        self.applied = snapshot.applied;
+        self.authorizations = snapshot.authorizations;
+        self.now = snapshot.now;
+        self.scheduled = snapshot.scheduled;
+        self.withdrawals = snapshot.withdrawals;
+        self.latest_times = snapshot.latest_times;
+        self.parked_disputes = snapshot.parked_disputes;
+        self.unkept_txs = snapshot.unkept_txs;
+        self.client_names = snapshot.client_names;
+        self.metadata = snapshot.metadata;
+        self.freezes = snapshot.freezes;
+        Ok(())
     }
 
-    pub fn from_csv_reader<R: std::io::Read>(reader: R) -> Ledger {
-        let mut reader = csv::ReaderBuilder::new()
-            .flexible(true)
-            .has_headers(true)
-            .trim(csv::Trim::All)
-            .from_reader(reader);
+    // The events this ledger's state is derived from, in the order they
+    // happened, see `LedgerEvent::is_source`. They're only kept by ledgers
+    // built with `keep_events`, and aren't part of snapshots.
+    pub fn events(&self) -> &[LedgerEvent] {
+        self.events.as_deref().unwrap_or_default()
+    }
 
-        let mut ledger = Ledger::default();
+    // Derive the state from the given events, e.g. those of another
+    // ledger's `events`, by applying them to this ledger in order. Events
+    // that don't change the state are skipped. Applying a prefix of the
+    // events gives the state as it was at that point, and the observers of
+    // this ledger see the events again, e.g. for auditing them.
+    //
+    // The events are applied under this ledger's policy, and must apply
+    // cleanly on top of its state, normally an empty ledger. Rebuilding
+    // stops at the first one that doesn't. Authorizations don't expire while
+    // rebuilding, since the events have the voids of those that did.
+    pub fn rebuild_from_events<'a, I>(&mut self, events: I) -> Result<(), RebuildError>
+    where
+        I: IntoIterator<Item = &'a LedgerEvent>,
+    {
+        self.rebuilding = true;
+        let result = self.replay_events(events);
+        self.rebuilding = false;
+        result
+    }
 
-        for line in reader.deserialize::<Record>() {
-            let record = match line {
-                Ok(record) => record,
-                Err(err) => {
-                    eprintln!("invalid line in CSV: {}", err.to_string());
-                    continue;
+    fn replay_events<'a, I>(&mut self, events: I) -> Result<(), RebuildError>
+    where
+        I: IntoIterator<Item = &'a LedgerEvent>,
+    {
+        for (position, event) in events.into_iter().enumerate() {
+            let result = match event {
+                LedgerEvent::AccountOpened { client, balance } => {
+                    self.open_account(*client, balance.clone())
                 }
-            };
-            let (account, transaction) = match record_to_transaction(&record) {
-                Ok((account, transaction)) => (account, transaction),
-                Err(err) => {
-                    eprintln!("invalid record encountered {}", err);
-                    continue;
+                LedgerEvent::AccountCreated { client, metadata } => {
+                    self.create_account(*client, metadata.clone())
+                }
+                LedgerEvent::AccountClosed { client, swept_to } => {
+                    self.close_account_into(*client, *swept_to)
                 }
+                LedgerEvent::TransactionApplied {
+                    client,
+                    transaction,
+                    timestamp,
+                    ..
+                } => self.apply_at(*client, transaction.clone(), *timestamp),
+                LedgerEvent::AccountFrozen {
+                    client,
+                    reason,
+                    level,
+                    at,
+                } if event.is_source() => {
+                    self.freeze_account_at(*client, reason.clone(), *level, *at)
+                }
+                LedgerEvent::AccountUnfrozen { client, reason, at } => {
+                    self.unfreeze_account_at(*client, reason.clone(), *at)
+                }
+                _ => Ok(()),
             };
+            result.map_err(|error| RebuildError { position, error })?;
+        }
+        Ok(())
+    }
 
-            if let Err(e) = ledger.apply_for_account(account, transaction) {
-                eprintln!("{}", e);
+    // Register an observer that will be notified of every event emitted by
+    // this ledger from now on.
+    pub fn add_observer<O: Observer + 'static>(&mut self, observer: O) {
+        self.observers.push(Box::new(observer));
+    }
+
+    fn record_statement(
+        &mut self,
+        client: AccountId,
+        transaction: Option<Transaction>,
+        amount: Option<TransactionAmount>,
+        timestamp: Option<Timestamp>,
+        currency: Option<Currency>,
+    ) -> Result<(), TransactionError> {
+        if !self.keep_statements {
+            return Ok(());
+        }
+        let account = &self.accounts[&client];
+        let balance = currency
+            .and_then(|currency| account.in_currency(currency))
+            .unwrap_or(account);
+        let entry = StatementEntry {
+            transaction,
+            amount,
+            timestamp,
+            currency,
+            available: balance.available(),
+            held: balance.held(),
+            total: balance.total()?,
+            locked: account.is_frozen(),
+        };
+        self.statements.entry(client).or_default().push(entry);
+        Ok(())
+    }
+
+    fn emit(&mut self, event: LedgerEvent) {
+        if let Some(events) = &mut self.events {
+            if event.is_source() {
+                events.push(event.clone());
             }
         }
+        for observer in self.observers.iter_mut() {
+            observer.on_event(&event);
+        }
+    }
 
-        ledger
+    fn emit_account_updated(&mut self, client: AccountId) -> Result<(), TransactionError> {
+        let account = &self.accounts[&client];
+        let event = LedgerEvent::AccountUpdated {
+            client,
+            available: account.available(),
+            held: account.held(),
+            total: account.total()?,
+            locked: account.is_frozen(),
+        };
+        self.emit(event);
+        Ok(())
     }
-}
 
-#[derive(Default)]
-pub struct ProcessedTxs(HashMap<(AccountId, TransactionId), ProcessedTransaction>);
+    // Freeze the given account fully, e.g. as a manual risk action. Freezing
+    // an account that's already frozen keeps the original reason and emits
+    // no event. It's frozen at the ledger's time, see `Ledger::now`.
+    pub fn freeze_account(
+        &mut self,
+        client: AccountId,
+        reason: FreezeReason,
+    ) -> Result<(), TransactionError> {
+        self.freeze_account_with(client, reason, FreezeLevel::Full)
+    }
 
-// ProcessedTxsForAccount is a reference into all processed transactions,
-// with the added restriction that it only allows lookups and insertions
-// for the specified account number.
-pub struct ProcessedTxsForAccount<'a> {
-    // `processed` is a reference to all processed transactions.
-    processed: &'a mut ProcessedTxs,
-    // Only transactions belonging to this account may be accessed through
-    // this struct.
-    account: AccountId,
-}
+    // Like `freeze_account`, but only as strictly as the given level. An
+    // account frozen less strictly already is escalated to the level, with
+    // the new reason, see `FreezePeriod`.
+    pub fn freeze_account_with(
+        &mut self,
+        client: AccountId,
+        reason: FreezeReason,
+        level: FreezeLevel,
+    ) -> Result<(), TransactionError> {
+        self.freeze_account_at(client, reason, level, self.now)
+    }
 
-impl<'a> ProcessedTxsForAccount<'a> {
-    pub(crate) fn for_account(
-        processed: &'a mut ProcessedTxs,
-        id: AccountId,
-    ) -> ProcessedTxsForAccount {
-        ProcessedTxsForAccount {
-            processed: processed,
-            account: id,
+    fn freeze_account_at(
+        &mut self,
+        client: AccountId,
+        reason: FreezeReason,
+        level: FreezeLevel,
+        at: Timestamp,
+    ) -> Result<(), TransactionError> {
+        let mut account = self
+            .accounts
+            .0
+            .get(client)
+            .cloned()
+            .ok_or(TransactionError::NonexistentAccount)?;
+        if !account.freeze_at_level(reason.clone(), level) {
+            return Ok(());
         }
+
+        self.accounts.0.insert(client, account)?;
+        self.record_freeze(client, reason.clone(), level, at);
+        self.emit(LedgerEvent::AccountFrozen {
+            client,
+            reason,
+            level,
+            at,
+        });
+        self.emit_account_updated(client)?;
+        Ok(())
     }
 
-    // Find a transaction by transaction ID. If the given transaction ID does
-    // not belong to the account associated with this object then it won't be
-    // returned.
-    pub fn find<'b>(self: &'b mut Self, tx: TransactionId) -> Option<&'b mut ProcessedTransaction> {
-        self.processed.0.get_mut(&(self.account, tx))
+    // Unfreeze the given account, allowing deposits and withdrawals again.
+    // Unfreezing an account that isn't frozen emits no event. It's unfrozen
+    // at the ledger's time, see `Ledger::now`.
+    pub fn unfreeze_account(
+        &mut self,
+        client: AccountId,
+        reason: String,
+    ) -> Result<(), TransactionError> {
+        self.unfreeze_account_at(client, reason, self.now)
     }
 
-    // Insert a new transaction as processed and associate it with the account
-    // referenced by this object.
-    pub fn insert_processed(self: &mut Self, id: TransactionId, tx: ProcessedTransaction) {
-        self.processed.0.insert((self.account, id), tx);
+    fn unfreeze_account_at(
+        &mut self,
+        client: AccountId,
+        reason: String,
+        at: Timestamp,
+    ) -> Result<(), TransactionError> {
+        let mut account = self
+            .accounts
+            .0
+            .get(client)
+            .cloned()
+            .ok_or(TransactionError::NonexistentAccount)?;
+        if account.unfreeze(reason.clone()).is_some() {
+            self.accounts.0.insert(client, account)?;
+            if let Some(period) = self
+                .freezes
+                .get_mut(&client)
+                .and_then(|freezes| freezes.last_mut())
+            {
+                period.unfrozen_at = Some(at);
+                period.unfreeze_reason = Some(reason.clone());
+            }
+            self.emit(LedgerEvent::AccountUnfrozen { client, reason, at });
+            self.emit_account_updated(client)?;
+        }
+        Ok(())
     }
-}
 
-// NOTE: Due to the CSV crate's shortcomings the records can't
-// be directly deserialized as an enum. Therefore they're
-// first read as a simple record type then transformed into
-// an enum.
-// https://github.com/BurntSushi/rust-csv/issues/211
-#[derive(Deserialize)]
-#[serde(rename_all = "snake_case")]
-struct Record {
-    #[serde(rename = "type")]
-    record_type: RecordType,
-    client: AccountId,
-    tx: TransactionId,
-    amount: Option<TransactionAmount>,
-}
+    // Start a freeze period, ending the one going on first if the freeze is
+    // being escalated.
+    fn record_freeze(
+        &mut self,
+        client: AccountId,
+        reason: FreezeReason,
+        level: FreezeLevel,
+        frozen_at: Timestamp,
+    ) {
+        let freezes = self.freezes.entry(client).or_default();
+        if let Some(period) = freezes
+            .last_mut()
+            .filter(|period| period.unfrozen_at.is_none())
+        {
+            period.unfrozen_at = Some(frozen_at);
+            period.unfreeze_reason = Some(ESCALATED.to_owned());
+        }
+        freezes.push(FreezePeriod {
+            reason,
+            level,
+            frozen_at,
+            unfrozen_at: None,
+            unfreeze_reason: None,
+        });
+    }
+
+    // Every time the given client's account was frozen, in order, with why
+    // and when, and when and why it was unfrozen again. The last one is
+    // still going on if the account is frozen.
+    pub fn freeze_history(&self, client: AccountId) -> &[FreezePeriod] {
+        self.freezes.get(&client).map_or(&[], Vec::as_slice)
+    }
+
+    // Attempt to apply the given transaction to the given account, opening
+    // it if the client doesn't have one yet. This is what every other way of
+    // feeding the ledger goes through, so embedders can use it to feed
+    // transactions one at a time from any source. If the transaction can't
+    // be applied an error is returned and no change is made.
+    pub fn apply(&mut self, client: AccountId, tx: Transaction) -> Result<(), TransactionError> {
+        self.apply_at(client, tx, None)
+    }
+
+    // Apply the transaction as one that happened at `time`, which is kept
+    // with it, and which withdrawal limits go by. Transactions without a time
+    // happen at the ledger's.
+    fn apply_at(
+        &mut self,
+        client: AccountId,
+        tx: Transaction,
+        time: Option<Timestamp>,
+    ) -> Result<(), TransactionError> {
+        match tx {
+            // Transactions are checked when they take effect, not when
+            // they're scheduled, and happen at the time they take effect.
+            Transaction::Scheduled {
+                effective_at,
+                transaction,
+            } => {
+                if effective_at <= self.now {
+                    return self.apply_at(client, *transaction, Some(effective_at));
+                }
+                let position = self
+                    .scheduled
+                    .partition_point(|&(at, ..)| at <= effective_at);
+                self.scheduled
+                    .insert(position, (effective_at, client, *transaction));
+                return Ok(());
+            }
+            Transaction::Timestamped {
+                timestamp,
+                transaction,
+            } => return self.apply_at(client, *transaction, Some(timestamp)),
+            _ => {}
+        }
+        // Transactions are expected in the order of their time, see
+        // `OutOfOrderPolicy`.
+        let ordering = self.policy.out_of_order;
+        if let (Some(timestamp), false) = (time, ordering == OutOfOrderPolicy::Ignore) {
+            match self.latest_times.get(&client) {
+                Some(&latest) if timestamp < latest => {
+                    let rejected = ordering == OutOfOrderPolicy::Reject;
+                    if !self.rebuilding {
+                        self.emit(LedgerEvent::OutOfOrder {
+                            client,
+                            timestamp,
+                            latest,
+                            rejected,
+                        });
+                        if rejected {
+                            return Err(TransactionError::OutOfOrder);
+                        }
+                    }
+                }
+                _ => {
+                    self.latest_times.insert(client, timestamp);
+                }
+            }
+        }
+        // Disputes may come before the transaction they refer to, which
+        // isn't known yet then, see `EarlyDisputePolicy`. Events only have
+        // the disputes that were applied, so there's nothing to park while
+        // rebuilding.
+        let early = self.policy.early_disputes;
+        let inner = match &tx {
+            Transaction::InCurrency { transaction, .. } => transaction.as_ref(),
+            tx => tx,
+        };
+        if let (Transaction::Dispute { id, .. }, EarlyDisputePolicy::Park, false) =
+            (inner, early, self.rebuilding)
+        {
+            // Transactions of other clients fail as mismatched instead, and
+            // those that came already, but weren't kept, fail right away.
+            let parked = self.parked_disputes.get(&client).map_or(0, Vec::len);
+            if self.processed_txs.0.get(client, *id)?.is_none()
+                && !self.id_owners.contains_key(id)
+                && !self.unkept_txs.contains(&(client, *id))
+                && self
+                    .policy
+                    .max_parked_disputes
+                    .is_none_or(|max| parked < max)
+            {
+                let id = *id;
+                let parked = self.parked_disputes.entry(client).or_default();
+                parked.push((time, tx));
+                self.emit(LedgerEvent::DisputeParked { client, tx: id });
+                return Ok(());
+            }
+        }
+        self.apply_in_order(client, tx, time)
+    }
+
+    // Apply a transaction as of the given time, once it's been checked to be
+    // in order, and not parked, see `apply_at`.
+    fn apply_in_order(
+        &mut self,
+        client: AccountId,
+        tx: Transaction,
+        time: Option<Timestamp>,
+    ) -> Result<(), TransactionError> {
+        // Transactions referring to one in a currency are in that currency
+        // too, see `Transaction::InCurrency`. The account checks that
+        // those given a currency refer to one in the same currency.
+        let (currency, tx) = match tx {
+            Transaction::InCurrency {
+                currency,
+                transaction,
+            } => (Some(currency), *transaction),
+            tx => (None, tx),
+        };
+        let currency = match currency {
+            Some(currency) => Some(currency),
+            None => self.referred_currency(client, &tx)?,
+        };
+        let applied_tx = match currency {
+            Some(currency) => Transaction::InCurrency {
+                currency,
+                transaction: Box::new(tx.clone()),
+            },
+            None => tx.clone(),
+        };
+        if self.policy.zero_amounts == ZeroAmountPolicy::Reject
+            && tx.amount().is_some_and(|amount| amount.is_zero())
+        {
+            return Err(TransactionError::ZeroAmount);
+        }
+        // Amounts can't be finer than their currency's minor units, e.g.
+        // cents of JPY.
+        let minor_units =
+            currency.and_then(|currency| self.policy.currencies.minor_units(currency));
+        if let (Some(amount), Some(digits)) = (tx.amount(), minor_units) {
+            if amount.normalize().scale() > digits {
+                return Err(TransactionError::TooManyDecimals);
+            }
+        }
+        if let (
+            Transaction::Deposit { amount, .. } | Transaction::Withdrawal { amount, .. },
+            Some(max),
+        ) = (&tx, self.policy.max_amount)
+        {
+            if *amount > max {
+                return Err(TransactionError::AmountTooLarge);
+            }
+        }
+        // Locks and unlocks only apply to existing accounts, and are reported
+        // like explicit freezes. Accounts are opened and closed by the
+        // ledger, see `create_account` and `close_account`.
+        match tx {
+            Transaction::Lock => {
+                let reason = FreezeReason::Manual(LOCK_REASON.to_owned());
+                let at = time.unwrap_or(self.now);
+                return self.freeze_account_at(client, reason, FreezeLevel::Full, at);
+            }
+            Transaction::Unlock => {
+                let reason = LOCK_REASON.to_owned();
+                return self.unfreeze_account_at(client, reason, time.unwrap_or(self.now));
+            }
+            // Accounts opened in a currency are meant for it, unless their
+            // metadata says otherwise.
+            Transaction::OpenAccount { metadata } => {
+                let metadata = AccountMetadata {
+                    currency: metadata.currency.or(currency),
+                    ..*metadata
+                };
+                return self.create_account(client, metadata);
+            }
+            Transaction::CloseAccount => return self.close_account(client),
+            _ => {}
+        }
+        let explicit = self.policy.account_creation == AccountCreationPolicy::Explicit;
+        let fee_account = self.policy.fee_account;
+        if let Transaction::Fee { amount, .. } = tx {
+            let Some(fee_account) = fee_account else {
+                return Err(TransactionError::NoFeeAccount);
+            };
+            // The fee is only charged if the fee account can hold it, and
+            // exists if accounts have to be created explicitly.
+            match self.accounts.0.get(fee_account) {
+                Some(account) if account.is_closed() => {
+                    return Err(TransactionError::AccountClosed)
+                }
+                Some(account) => account.clone().credit_fee(currency, amount)?,
+                None if explicit => return Err(TransactionError::NonexistentAccount),
+                None => self
+                    .new_account(fee_account)?
+                    .credit_fee(currency, amount)?,
+            }
+        }
+        let new_id = match tx {
+            Transaction::Deposit { new_id, .. }
+            | Transaction::Withdrawal { new_id, .. }
+            | Transaction::Fee { new_id, .. }
+            | Transaction::AdjustCredit { new_id, .. }
+            | Transaction::AdjustDebit { new_id, .. }
+            | Transaction::Authorize { new_id, .. } => Some(new_id),
+            _ => None,
+        };
+        let ids = self.policy.transaction_ids;
+        if let Some(new_id) = new_id {
+            let used = match ids {
+                TransactionIdPolicy::Unchecked => false,
+                TransactionIdPolicy::Unique => self.used_ids.contains(&new_id),
+                TransactionIdPolicy::Global => self.id_owners.contains_key(&new_id),
+            };
+            if used {
+                return Err(TransactionError::DuplicateTransaction);
+            }
+        } else if let (TransactionIdPolicy::Global, Some(id)) = (ids, tx.id()) {
+            // Transactions of unknown IDs fail as nonexistent below.
+            if self
+                .id_owners
+                .get(&id)
+                .is_some_and(|&owner| owner != client)
+            {
+                return Err(TransactionError::ClientMismatch);
+            }
+        }
+        // Transactions may only be disputed within the dispute window of
+        // their time.
+        let window = self.policy.dispute_window.filter(|_| !self.rebuilding);
+        if let (Transaction::Dispute { id, .. }, Some(days)) = (&tx, window) {
+            let disputed = self.processed_txs.0.get(client, *id)?;
+            let now = time.unwrap_or(self.now);
+            if disputed
+                .and_then(|disputed| disputed.timestamp)
+                .is_some_and(|at| now.saturating_sub(at) > days.saturating_mul(86_400))
+            {
+                return Err(TransactionError::DisputeWindowExpired);
+            }
+        }
+        if let Transaction::Dispute { id, amount } = tx {
+            self.check_held_ratio(client, id, amount)?;
+        }
+        let limit = self.policy.withdrawal_limit.filter(|_| !self.rebuilding);
+        if let (Transaction::Withdrawal { amount, .. }, Some(limit)) = (&tx, &limit) {
+            let time = time.unwrap_or(self.now);
+            let checked = self.withdrawals.check(limit, client, time, *amount);
+            // Going over the limit may freeze the account too.
+            if let (Err(_), Some(level)) = (&checked, self.policy.limit_freeze) {
+                let reason = FreezeReason::RiskRule(LIMIT_RULE.to_owned());
+                match self.freeze_account_at(client, reason, level, time) {
+                    Ok(()) | Err(TransactionError::NonexistentAccount) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            checked?;
+        }
+
+        let existing = self.accounts.0.get(client);
+        let is_new = existing.is_none();
+        if is_new && explicit {
+            return Err(TransactionError::NonexistentAccount);
+        }
+        let mut account = match existing {
+            Some(account) => account.clone(),
+            None => self.new_account(client)?,
+        };
+        let mut txs_for_account =
+            ProcessedTxsForAccount::for_account(&mut self.processed_txs, client)
+                .with_disputes(self.policy.disputes)
+                .with_withdrawal_disputes(self.policy.withdrawal_disputes)
+                .with_chargeback_freeze(self.policy.chargeback_freeze)
+                .with_timestamp(time);
+
+        let level_before = account.freeze_level();
+        let recovered_before = account.recovered();
+        let id = tx.id();
+        let applied = account.try_apply_transaction(&mut txs_for_account, applied_tx.clone());
+        // The account is opened by its first transaction, even if that's
+        // rejected, but rejected transactions leave existing accounts as
+        // they were.
+        let stored = if applied.is_ok() || is_new {
+            self.accounts.0.insert(client, account)
+        } else {
+            Ok(())
+        };
+        applied?;
+        stored?;
+        if let Some(new_id) = new_id {
+            match ids {
+                TransactionIdPolicy::Unchecked => {}
+                TransactionIdPolicy::Unique => {
+                    self.used_ids.insert(new_id);
+                }
+                TransactionIdPolicy::Global => {
+                    self.id_owners.insert(new_id, client);
+                }
+            }
+        }
+        // Withdrawals may not have been kept, see `DisputePolicy`.
+        let processed = match tx {
+            Transaction::Deposit { amount, .. } | Transaction::Withdrawal { amount, .. } => {
+                Some(ProcessedTransaction {
+                    amount,
+                    state: ProcessedTransactionState::Settled,
+                    disputed: None,
+                    withdrawal: matches!(tx, Transaction::Withdrawal { .. }),
+                    timestamp: time,
+                    currency,
+                })
+            }
+            Transaction::AdjustCredit { amount, .. } | Transaction::AdjustDebit { amount, .. } => {
+                Some(ProcessedTransaction {
+                    amount,
+                    state: ProcessedTransactionState::Adjusted,
+                    disputed: None,
+                    withdrawal: false,
+                    timestamp: time,
+                    currency,
+                })
+            }
+            // Fees aren't kept.
+            Transaction::Fee { .. } => None,
+            _ => id.and_then(|id| txs_for_account.inserted(id)),
+        };
+        let unkept = new_id.filter(|&new_id| txs_for_account.inserted(new_id).is_none());
+        // Partial refunds move less than the refunded transaction's amount,
+        // and partial disputes, and their resolutions and chargebacks, hold
+        // or release less of it.
+        let amount = match tx {
+            Transaction::Refund {
+                amount: Some(amount),
+                ..
+            } => Some(amount),
+            Transaction::Dispute { .. }
+            | Transaction::Resolve { .. }
+            | Transaction::Chargeback { .. } => {
+                processed.map(|processed| processed.disputed_amount())
+            }
+            _ => processed.map(|processed| processed.amount).or(tx.amount()),
+        };
+        self.record_statement(client, Some(tx.clone()), amount, time, currency)?;
+        let account = &self.accounts[&client];
+
+        // Chargebacks may freeze the account, or escalate its freeze.
+        if let (Some(reason), Some(level)) = (account.freeze_reason(), account.freeze_level()) {
+            if level_before < Some(level) {
+                let (reason, at) = (reason.clone(), time.unwrap_or(self.now));
+                self.record_freeze(client, reason.clone(), level, at);
+                self.emit(LedgerEvent::AccountFrozen {
+                    client,
+                    reason,
+                    level,
+                    at,
+                });
+            }
+        }
+        // Deposits may go towards a deficit.
+        let account = &self.accounts[&client];
+        if account.recovered() != recovered_before {
+            let event = LedgerEvent::DeficitRecovered {
+                client,
+                amount: account.recovered() - recovered_before,
+                outstanding: account.deficit(),
+            };
+            self.emit(event);
+        }
+        let fee = match (&tx, fee_account) {
+            (Transaction::Fee { amount, .. }, Some(fee_account)) => Some((*amount, fee_account)),
+            _ => None,
+        };
+        self.emit(LedgerEvent::TransactionApplied {
+            client,
+            transaction: applied_tx,
+            processed,
+            timestamp: time,
+            policy: self.policy_version,
+        });
+        self.emit_account_updated(client)?;
+
+        // Credit the fee. There's no event of its own for the credit, the
+        // fee's `TransactionApplied` stands for both sides of it, so
+        // rebuilding from events credits it again.
+        if let Some((amount, fee_account)) = fee {
+            let mut account = match self.accounts.0.get(fee_account) {
+                Some(account) => account.clone(),
+                None => self.new_account(fee_account)?,
+            };
+            account
+                .credit_fee(currency, amount)
+                .expect("the fee account should have been checked");
+            self.accounts.0.insert(fee_account, account)?;
+            self.record_statement(fee_account, Some(tx.clone()), Some(amount), time, currency)?;
+            self.emit_account_updated(fee_account)?;
+        }
+
+        if !matches!(tx, Transaction::Void { .. }) {
+            self.applied += 1;
+        }
+        if let (Transaction::Withdrawal { amount, .. }, Some(_)) = (&tx, limit) {
+            self.withdrawals
+                .record(client, time.unwrap_or(self.now), *amount);
+        }
+        if let (Transaction::Authorize { new_id, .. }, Some(window)) =
+            (&tx, self.policy.authorization_window)
+        {
+            // Windows too long to count to never run out.
+            let expires = self.applied.saturating_add(window);
+            self.authorizations.push_back((expires, client, *new_id));
+        }
+        self.expire_authorizations();
+        if let (Some(new_id), EarlyDisputePolicy::Park) = (unkept, self.policy.early_disputes) {
+            self.unkept_txs.insert((client, new_id));
+        }
+        if let Some(new_id) = new_id {
+            self.apply_parked_disputes(client, new_id);
+        }
+
+        Ok(())
+    }
+
+    // Apply the disputes parked for the given transaction, which was just
+    // applied, in the order they came. Those that fail are reported with
+    // `LedgerEvent::ParkedDisputeFailed`.
+    fn apply_parked_disputes(&mut self, client: AccountId, id: TransactionId) {
+        let Some(parked) = self.parked_disputes.get_mut(&client) else {
+            return;
+        };
+        let (due, waiting): (Vec<_>, Vec<_>) = mem::take(parked)
+            .into_iter()
+            .partition(|(_, dispute)| dispute.id() == Some(id));
+        if waiting.is_empty() {
+            self.parked_disputes.remove(&client);
+        } else {
+            *parked = waiting;
+        }
+        for (time, dispute) in due {
+            if let Err(error) = self.apply_in_order(client, dispute, time) {
+                self.emit(LedgerEvent::ParkedDisputeFailed {
+                    client,
+                    tx: id,
+                    error,
+                });
+            }
+        }
+    }
+
+    // The disputes waiting for the transaction they refer to, with their
+    // client, see `EarlyDisputePolicy`.
+    pub fn parked_disputes(&self) -> impl Iterator<Item = (AccountId, &Transaction)> + '_ {
+        self.parked_disputes
+            .iter()
+            .flat_map(|(&client, parked)| parked.iter().map(move |(_, tx)| (client, tx)))
+    }
+
+    // The currency of the transaction the given one refers to, if it has
+    // one.
+    fn referred_currency(
+        &self,
+        client: AccountId,
+        tx: &Transaction,
+    ) -> Result<Option<Currency>, TransactionError> {
+        match *tx {
+            Transaction::Dispute { id, .. }
+            | Transaction::Resolve { id }
+            | Transaction::Chargeback { id }
+            | Transaction::Refund { id, .. }
+            | Transaction::Capture { id }
+            | Transaction::Void { id } => {
+                let referred = self.processed_txs.0.get(client, id)?;
+                Ok(referred.and_then(|referred| referred.currency))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Move the ledger's time forward to `now`, applying the scheduled
+    // transactions that take effect by then, in order. The transactions that
+    // are rejected are returned with their client and the error. The time
+    // never goes back, so advancing to an earlier time does nothing.
+    pub fn advance_to(
+        &mut self,
+        now: Timestamp,
+    ) -> Vec<(AccountId, Transaction, TransactionError)> {
+        if now <= self.now {
+            return vec![];
+        }
+        self.now = now;
+        let due = self.scheduled.partition_point(|&(at, ..)| at <= now);
+        let mut rejected = vec![];
+        for (at, client, tx) in self.scheduled.drain(..due).collect::<Vec<_>>() {
+            if let Err(error) = self.apply_at(client, tx.clone(), Some(at)) {
+                rejected.push((client, tx, error));
+            }
+        }
+        rejected
+    }
+
+    // The time the ledger has been advanced to.
+    pub fn now(&self) -> Timestamp {
+        self.now
+    }
+
+    // The scheduled transactions that haven't taken effect yet, in the
+    // order they will, with the time they take effect and their client.
+    pub fn scheduled(&self) -> &[(Timestamp, AccountId, Transaction)] {
+        &self.scheduled
+    }
+
+    // Void the authorizations whose window is over, unless they were
+    // captured or voided already. Voids don't count towards the windows, so
+    // this doesn't make any more authorizations expire. If the transaction
+    // store fails, the rest are tried again after the next transaction.
+    fn expire_authorizations(&mut self) {
+        if self.rebuilding {
+            return;
+        }
+        while let Some(&(expires, client, id)) = self.authorizations.front() {
+            if expires > self.applied {
+                break;
+            }
+            let Ok(authorized) = self.processed_txs.0.get(client, id) else {
+                break;
+            };
+            self.authorizations.pop_front();
+            let authorized =
+                authorized.map(|tx| tx.state) == Some(ProcessedTransactionState::Authorized);
+            if authorized {
+                if let Err(err) = self.apply(client, Transaction::Void { id }) {
+                    debug_assert_eq!(err, TransactionError::StoreFailed);
+                    self.authorizations.push_front((expires, client, id));
+                    break;
+                }
+            }
+        }
+    }
+
+    // Alert if disputing the given transaction would take the account's held
+    // funds beyond the maximum ratio of its total, and fail if the policy
+    // blocks such disputes. Disputes that fail for other reasons are left to
+    // fail as usual.
+    fn check_held_ratio(
+        &mut self,
+        client: AccountId,
+        id: TransactionId,
+        amount: Option<TransactionAmount>,
+    ) -> Result<(), TransactionError> {
+        let Some(max_ratio) = self.policy.max_held_ratio else {
+            return Ok(());
+        };
+        let (Some(account), Some(disputed)) = (
+            self.accounts.0.get(client),
+            self.processed_txs.0.get(client, id)?,
+        ) else {
+            return Ok(());
+        };
+        if account.is_frozen() || disputed.state != ProcessedTransactionState::Settled {
+            return Ok(());
+        }
+        // Disputes of withdrawals that chargebacks reverse hold nothing.
+        let reversed = self.policy.withdrawal_disputes == WithdrawalDisputePolicy::Reverse;
+        if disputed.withdrawal && reversed {
+            return Ok(());
+        }
+
+        // The ratio is that of the balance in the transaction's currency.
+        let balance = disputed
+            .currency
+            .and_then(|currency| account.in_currency(currency))
+            .unwrap_or(account);
+        // Held funds beyond what funds can hold couldn't be held anyway, and
+        // a limit beyond that doesn't limit anything.
+        let held = balance
+            .held()
+            .checked_add(amount.unwrap_or(disputed.amount))
+            .ok_or(TransactionError::Overflow)?;
+        let total = balance.total()?;
+        if total
+            .checked_mul(max_ratio)
+            .is_none_or(|limit| held <= limit)
+        {
+            return Ok(());
+        }
+        let blocked = self.policy.held_ratio_action == HeldRatioAction::Block;
+        self.emit(LedgerEvent::HeldRatioExceeded {
+            client,
+            tx: id,
+            held,
+            total,
+            blocked,
+        });
+        match blocked {
+            true => Err(TransactionError::HeldRatioExceeded),
+            false => Ok(()),
+        }
+    }
+
+    // Apply a batch of transactions in order, e.g. one received from a queue,
+    // and call `acknowledge` with the outcome once all of them are applied
+    // and every observer has been notified of them. Rejected transactions
+    // don't stop the rest of the batch. The outcome is also returned.
+    //
+    // Upstream systems should only mark the batch as consumed from
+    // `acknowledge`. Behind a `SharedLedger` it's called before any other
+    // operation can start, so acknowledgments are delivered in the order the
+    // batches were applied.
+    pub fn apply_batch<I, F>(&mut self, batch: I, acknowledge: F) -> BatchOutcome
+    where
+        I: IntoIterator<Item = (AccountId, Transaction)>,
+        F: FnOnce(&BatchOutcome),
+    {
+        let mut outcome = BatchOutcome::default();
+        for (index, (client, tx)) in batch.into_iter().enumerate() {
+            match self.apply(client, tx) {
+                Ok(()) => outcome.applied += 1,
+                Err(err) => outcome.rejected.push((index, err)),
+            }
+        }
+
+        acknowledge(&outcome);
+        outcome
+    }
+
+    // The statement of the given client's account: every transaction applied
+    // to it in the order they were applied, with the running balances.
+    // Transactions that were rejected don't appear in it. Clients without an
+    // account have no statement, and neither has anyone unless the ledger
+    // was built with `keep_statements`.
+    pub fn statement_for(&self, client: AccountId) -> Option<&[StatementEntry]> {
+        self.statements.get(&client).map(Vec::as_slice)
+    }
+
+    // The statements of every account, in no particular order.
+    pub(crate) fn statements(&self) -> impl Iterator<Item = (AccountId, &[StatementEntry])> {
+        self.statements
+            .iter()
+            .map(|(client, entries)| (*client, entries.as_slice()))
+    }
+
+    // Write the statement of the given client's account formatted as CSV to
+    // the given writer, with the columns type, tx, amount, available, held,
+    // total, locked, and timestamp, and currency if any line has one. The
+    // opening balance has the type `opening`. Only the lines that match the
+    // ledger's filter are written.
+    pub fn statement_to_csv<W: std::io::Write>(
+        &self,
+        client: AccountId,
+        output: W,
+    ) -> csv::Result<()> {
+        #[derive(Serialize)]
+        struct StatementRecord {
+            #[serde(rename = "type")]
+            record_type: &'static str,
+            tx: Option<TransactionId>,
+            amount: Option<TransactionAmount>,
+            available: Balance,
+            held: Balance,
+            total: Balance,
+            locked: bool,
+            timestamp: Option<Timestamp>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            currency: Option<Option<Currency>>,
+        }
+
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(true)
+            .from_writer(output);
+
+        let entries = self.statement_for(client).unwrap_or_default();
+        let currencies = entries.iter().any(|entry| entry.currency.is_some());
+        for entry in entries.iter().filter(|entry| self.includes(*entry)) {
+            let transaction = entry.transaction.as_ref();
+            writer.serialize(StatementRecord {
+                record_type: transaction.map_or("opening", Transaction::kind),
+                tx: transaction.and_then(Transaction::id),
+                amount: entry.amount,
+                available: entry.available,
+                held: entry.held,
+                total: entry.total,
+                locked: entry.locked,
+                timestamp: entry.timestamp,
+                currency: currencies.then_some(entry.currency),
+            })?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Look up the account of the given client, if it has one.
+    pub fn account(&self, client: AccountId) -> Option<&Account> {
+        self.accounts.0.get(client)
+    }
+
+    // Every account in the ledger with its client ID, in no particular order.
+    pub fn accounts(&self) -> impl Iterator<Item = (AccountId, &Account)> {
+        self.accounts.0.iter()
+    }
+
+    // Look up the deposit or withdrawal with the given ID applied to the
+    // given client's account, e.g. to check that a dispute has something to
+    // refer to before submitting it.
+    // It fails with `StoreFailed` if the transaction store can't be read.
+    pub fn transaction(
+        &self,
+        client: AccountId,
+        tx: TransactionId,
+    ) -> Result<Option<TransactionView>, TransactionError> {
+        let found = self.processed_txs.0.get(client, tx)?;
+        Ok(found.map(|tx| TransactionView::from(&tx)))
+    }
+
+    // Find all processed transactions matching the given filter. The
+    // transactions are yielded as (client, transaction ID, transaction) in no
+    // particular order, with `StoreFailed` in place of those the transaction
+    // store fails to read.
+    pub fn find_transactions<'a>(
+        &'a self,
+        filter: &'a TransactionFilter,
+    ) -> impl Iterator<
+        Item = Result<(AccountId, TransactionId, ProcessedTransaction), TransactionError>,
+    > + 'a {
+        self.processed_txs
+            .0
+            .iter()
+            .filter(move |found| match found {
+                Ok((client, _, tx)) => filter.matches(*client, tx),
+                Err(_) => true,
+            })
+    }
+
+    // The account summaries in this ledger in the given order, with the
+    // balances rounded to the given precision, or those in a currency to its
+    // minor units, see `Policy::currencies`. Summaries that don't match the
+    // ledger's filter are left out.
+    pub(crate) fn account_summaries(
+        &self,
+        order: AccountOrder,
+        precision: Precision,
+    ) -> Vec<AccountRecord> {
+        // NOTE: Sorting by client ID is not necessary but it makes testing
+        // easier. It could be removed at the cost of making tests more
+        // complicated.
+        let mut rows = self.balance_rows();
+        if order == AccountOrder::TotalDescending {
+            // The sort is stable, so accounts with equal totals stay sorted
+            // by client ID.
+            rows.sort_by_key(|(_, _, balance, _)| std::cmp::Reverse(balance.total().ok()));
+        }
+
+        // How far accounts are overdrawn is only written when they can be,
+        // and whether they're closed once any of them is.
+        let overdrafts = self.policy.allows_overdrafts();
+        let closings = self.accounts().any(|(_, account)| account.is_closed());
+        rows.into_iter()
+            .map(|(account_id, currency, balance, locked)| {
+                let precision = self.precision_in(currency.flatten(), precision);
+                AccountRecord {
+                    client: self.client_key(account_id),
+                    currency,
+                    available: precision.apply(balance.available()),
+                    held: precision.apply(balance.held()),
+                    // Totals are checked whenever the funds change, and
+                    // when snapshots are loaded, so they always fit.
+                    total: precision.apply(balance.total().expect("total should fit")),
+                    locked,
+                    freeze_reason: self.freeze_reasons.then(|| {
+                        let account = &self.accounts[&account_id];
+                        account.freeze_reason().map(FreezeReason::to_string)
+                    }),
+                    freeze_level: self.freeze_reasons.then(|| {
+                        let account = &self.accounts[&account_id];
+                        account.freeze_level().map(|level| level.to_string())
+                    }),
+                    closed: closings.then(|| self.accounts[&account_id].is_closed()),
+                    overdrawn: overdrafts.then(|| precision.apply(balance.overdrawn())),
+                }
+            })
+            .filter(|record| self.includes(record))
+            .collect()
+    }
+
+    // The given precision, or for balances in a currency, its minor units.
+    pub(crate) fn precision_in(
+        &self,
+        currency: Option<Currency>,
+        precision: Precision,
+    ) -> Precision {
+        let decimal_places =
+            currency.and_then(|currency| self.policy.currencies.minor_units(currency));
+        Precision {
+            decimal_places: decimal_places.unwrap_or(precision.decimal_places),
+            ..precision
+        }
+    }
+
+    // The balances of every account sorted by client ID, with their currency
+    // and whether the account is locked. Accounts with balances in currencies
+    // have a row for each of them, after the one without a currency, which
+    // is left out if it's empty. If no account has any, the currency is left
+    // out altogether, so the outputs of single currency ledgers stay the
+    // same.
+    pub(crate) fn balance_rows(
+        &self,
+    ) -> Vec<(AccountId, Option<Option<Currency>>, &Account, bool)> {
+        let mut sorted_accounts = self.accounts().collect::<Vec<_>>();
+        sorted_accounts.sort_by_key(|(account_id, _)| *account_id);
+        let currencies = sorted_accounts
+            .iter()
+            .any(|(_, account)| account.currencies().next().is_some());
+        if !currencies {
+            return sorted_accounts
+                .into_iter()
+                .map(|(account_id, account)| (account_id, None, account, account.is_frozen()))
+                .collect();
+        }
+
+        let mut rows = vec![];
+        for (account_id, account) in sorted_accounts {
+            let locked = account.is_frozen();
+            let empty = account.available().is_zero() && account.held().is_zero();
+            if !empty || account.currencies().next().is_none() {
+                rows.push((account_id, Some(None), account, locked));
+            }
+            for (currency, balance) in account.currencies() {
+                rows.push((account_id, Some(Some(currency)), balance, locked));
+            }
+        }
+        rows
+    }
+
+    // Whether the ledger's filter lets the record through.
+    pub(crate) fn includes<R: Filterable>(&self, record: &R) -> bool {
+        self.filter
+            .as_ref()
+            .is_none_or(|filter| filter.matches(record))
+    }
+
+    // Write the account summaries in this ledger in the given format and
+    // order, with the ledger's precision, to the given writer. This consumes
+    // the ledger to prevent modification after writing.
+    pub fn write_accounts<W: std::io::Write + Send>(
+        self,
+        format: OutputFormat,
+        order: AccountOrder,
+        output: &mut W,
+    ) {
+        let precision = self.precision;
+        let summaries = self.account_summaries(order, precision);
+        match format {
+            OutputFormat::Csv => write_csv(&summaries, output),
+            OutputFormat::Json => write_json(&summaries, output, false),
+            OutputFormat::JsonLines => write_json(&summaries, output, true),
+            OutputFormat::Table => write_table(&summaries, output),
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet => {
+                crate::parquet_output::write_parquet(&summaries, precision, output)
+                    .expect("failed to write Parquet output")
+            }
+        }
+    }
+
+    // Write the account summaries in this ledger formatted as CSV, with the
+    // ledger's precision, to the given writer. This consumes the ledger to
+    // prevent modification after writing.
+    pub fn accounts_to_csv<W: std::io::Write>(self, output: &mut W) {
+        write_csv(
+            &self.account_summaries(AccountOrder::Client, self.precision),
+            output,
+        );
+    }
+
+    // Write the account summaries in this ledger formatted as JSON to the
+    // given writer, with the same fields as the CSV output. The summaries are
+    // either written as a single array, or if `lines` is set, as one object
+    // per line (JSONL). Balances are written as strings to avoid any loss of
+    // precision. This consumes the ledger to prevent modification after
+    // writing.
+    pub fn accounts_to_json<W: std::io::Write>(self, output: &mut W, lines: bool) {
+        let summaries = self.account_summaries(AccountOrder::Client, self.precision);
+        write_json(&summaries, output, lines);
+    }
+
+    // Write the account summaries in this ledger as an aligned table meant
+    // for humans to the given writer, in the given order. This consumes the
+    // ledger to prevent modification after writing.
+    pub fn accounts_to_table<W: std::io::Write>(self, output: &mut W, order: AccountOrder) {
+        write_table(&self.account_summaries(order, self.precision), output);
+    }
+
+    // Write the closing balances of the accounts in this ledger formatted as
+    // CSV to the given writer, in the format read by `read_opening_balances`.
+    // Unlike the account summaries the balances are written at full
+    // precision, so that nothing is lost when they're carried forward, and
+    // without the freeze reasons and overdrafts, which aren't.
+    pub fn closing_balances_to_csv<W: std::io::Write>(&self, output: W) -> csv::Result<()> {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(true)
+            .from_writer(output);
+
+        // Closed accounts are carried forward as closed, see
+        // `account_summaries`.
+        let closings = self.accounts().any(|(_, account)| account.is_closed());
+        for (account_id, currency, balance, locked) in self.balance_rows() {
+            writer.serialize(AccountRecord {
+                client: self.client_key(account_id),
+                currency,
+                available: balance.available(),
+                held: balance.held(),
+                total: balance.total().map_err(std::io::Error::other)?,
+                locked,
+                freeze_reason: None,
+                freeze_level: None,
+                closed: closings.then(|| self.accounts[&account_id].is_closed()),
+                overdrawn: None,
+            })?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Compare the balances of the accounts in this ledger to those another
+    // system expects, read as CSV from `expected`, and report the accounts
+    // missing from either side and those whose available or held funds
+    // differ, see `Reconciliation`.
+    pub fn reconcile<R: std::io::Read>(
+        &self,
+        expected: R,
+    ) -> Result<Reconciliation, ReconcileError> {
+        crate::reconcile::reconcile(self, expected)
+    }
+
+    // Write a report of the accounts whose negative balance is being
+    // recovered, formatted as CSV, to the given writer. For each account it
+    // includes the amount recovered so far and the amount still outstanding.
+    pub fn recoveries_to_csv<W: std::io::Write>(&self, output: W) -> csv::Result<()> {
+        #[derive(Serialize)]
+        struct RecoveryRecord {
+            client: ClientKey,
+            recovered: Balance,
+            outstanding: Balance,
+        }
+
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(true)
+            .from_writer(output);
+
+        let mut recoveries = self
+            .accounts()
+            .filter(|(_, account)| !account.recovered().is_zero() || !account.deficit().is_zero())
+            .collect::<Vec<_>>();
+        recoveries.sort_by_key(|(account_id, _)| *account_id);
+
+        for (account_id, account) in recoveries {
+            writer.serialize(RecoveryRecord {
+                client: self.client_key(account_id),
+                recovered: account.recovered(),
+                outstanding: account.deficit(),
+            })?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    // Write the metadata of the accounts that were created explicitly, see
+    // `Ledger::create_account`, as CSV to the given output, one row per
+    // account. Metadata that wasn't given is left empty.
+    pub fn account_metadata_to_csv<W: std::io::Write>(&self, output: W) -> csv::Result<()> {
+        #[derive(Serialize)]
+        struct MetadataRecord<'a> {
+            client: ClientKey,
+            reference: Option<&'a str>,
+            currency: Option<Currency>,
+            risk_tier: Option<&'a str>,
+        }
+
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(true)
+            .from_writer(output);
+
+        let mut metadata = self.metadata.iter().collect::<Vec<_>>();
+        metadata.sort_by_key(|(account_id, _)| **account_id);
+
+        for (account_id, metadata) in metadata {
+            writer.serialize(MetadataRecord {
+                client: self.client_key(*account_id),
+                reference: metadata.reference.as_deref(),
+                currency: metadata.currency,
+                risk_tier: metadata.risk_tier.as_deref(),
+            })?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn from_csv_reader<R: std::io::Read>(reader: R) -> Ledger {
+        let mut ledger = Ledger::default();
+        ledger.read_csv(reader);
+        ledger
+    }
+
+    // Like `from_csv_reader`, but stop at the first line that has to be
+    // skipped and fail with it, for input that has to be processed in full.
+    // Warnings about the input are passed to `Stderr`.
+    pub fn try_from_csv_reader<R: std::io::Read>(reader: R) -> Result<Ledger, Reject> {
+        let mut ledger = Ledger::builder().errors(ErrorPolicy::Abort).build();
+        let mut first = None;
+        let check = ledger.read_csv_with(reader, AmountScale::default(), |reject: Reject| {
+            first.get_or_insert(reject);
+        });
+        if let Some(warning) = check.warning() {
+            Stderr.on_warning(warning);
+        }
+        match first {
+            Some(reject) => Err(reject),
+            None => Ok(ledger),
+        }
+    }
+
+    // Like `from_csv_reader`, but pass every line that's skipped to `sink`
+    // instead of printing it.
+    pub fn from_csv_reader_with<R: std::io::Read, S: ErrorSink>(reader: R, sink: S) -> Ledger {
+        let mut ledger = Ledger::default();
+        ledger.read_csv_with(reader, AmountScale::default(), sink);
+        ledger
+    }
+
+    // Apply every transaction read from the given CSV reader to this ledger.
+    // Invalid lines and transactions that can't be applied, and warnings
+    // about the input, are passed to `Stderr`, and the lines skipped.
+    pub fn read_csv<R: std::io::Read>(&mut self, reader: R) {
+        let check = self.read_csv_with(reader, AmountScale::default(), Stderr);
+        if let Some(warning) = check.warning() {
+            Stderr.on_warning(warning);
+        }
+    }
+
+    // Like `read_csv_with`, but also write a report of the skipped lines,
+    // formatted as CSV, to `rejects`. See `Reject` for the columns. Warnings
+    // about the scale are left to the caller.
+    pub fn read_csv_with_rejects<R, W, S>(
+        &mut self,
+        reader: R,
+        scale: AmountScale,
+        rejects: W,
+        sink: S,
+    ) -> csv::Result<ScaleCheck>
+    where
+        R: std::io::Read,
+        W: std::io::Write,
+        S: ErrorSink,
+    {
+        write_rejects(rejects, sink, |sink| {
+            self.read_csv_with(reader, scale, sink)
+        })
+    }
+
+    // Apply every transaction read from the given CSV reader to this ledger,
+    // passing every line that's skipped to `sink`. Amounts are read with
+    // the given scale, see `AmountScale`. The returned check tells whether
+    // the amounts look like they have a different one.
+    pub fn read_csv_with<R, S>(&mut self, reader: R, scale: AmountScale, sink: S) -> ScaleCheck
+    where
+        R: std::io::Read,
+        S: ErrorSink,
+    {
+        self.with_reject_sink(sink, |ledger, sink| {
+            ledger.read_csv_to_sink(reader, scale, sink)
+        })
+    }
+
+    fn read_csv_to_sink<R: std::io::Read>(
+        &mut self,
+        reader: R,
+        scale: AmountScale,
+        sink: &mut dyn FnMut(Reject),
+    ) -> ScaleCheck {
+        if self.policy.client_ids == ClientIdPolicy::Text {
+            return crate::client_names::read_csv_named(self, reader, scale, sink);
+        }
+        if let Some(window) = self.policy.reorder_window {
+            return crate::reorder::read_csv_reordered(self, reader, scale, window, sink);
+        }
+        let shards = self.parallel_shards();
+        if shards > 1 {
+            return crate::shard::read_csv_sharded(self, reader, scale, shards, sink);
+        }
+        let errors = self.policy.errors;
+        if errors != ErrorPolicy::Abort {
+            return crate::pipeline::read_csv_pipelined(self, reader, scale, sink);
+        }
+        let amounts = self.policy.amounts;
+        read_csv_into(reader, scale, sink, errors, amounts, |client, tx| {
+            self.apply(client, tx)
+        })
+    }
+
+    // Read input with `read`, passing the lines it skips to `sink`, or
+    // keeping them in the ledger's report with `ErrorPolicy::Collect`.
+    pub(crate) fn with_reject_sink<S, T, F>(&mut self, mut sink: S, read: F) -> T
+    where
+        S: ErrorSink,
+        F: FnOnce(&mut Ledger, &mut dyn FnMut(Reject)) -> T,
+    {
+        let collect = self.policy.errors == ErrorPolicy::Collect;
+        let mut report = std::mem::take(&mut self.report);
+        let result = read(self, &mut |reject| {
+            if collect {
+                report.rejects.push(reject);
+            } else {
+                sink.on_reject(reject);
+            }
+        });
+        self.report = report;
+        result
+    }
+
+    // The lines skipped since the report was last taken, see
+    // `ErrorPolicy::Collect`.
+    pub fn take_report(&mut self) -> ProcessingReport {
+        std::mem::take(&mut self.report)
+    }
+
+    // The number of shards CSV input is applied on, see
+    // `LedgerBuilder::shards`.
+    pub(crate) fn parallel_shards(&self) -> usize {
+        if self.shardable() {
+            self.shards.max(1)
+        } else {
+            1
+        }
+    }
+
+    // Whether transactions can be applied to the accounts of different
+    // clients independently of each other.
+    fn shardable(&self) -> bool {
+        self.observers.is_empty()
+            && self.events.is_none()
+            && self.policy.transaction_ids == TransactionIdPolicy::Unchecked
+            && self.policy.errors != ErrorPolicy::Abort
+            // Fees and closings credit an account of another client.
+            && self.policy.fee_account.is_none()
+            && self.policy.sweep_account.is_none()
+            // Authorizations expire by the transactions of every client.
+            && self.policy.authorization_window.is_none()
+            // Names are given IDs, and transactions reordered, in the order
+            // they're read.
+            && self.policy.client_ids != ClientIdPolicy::Text
+            && self.policy.reorder_window.is_none()
+            // The shards keep their transactions and accounts in memory.
+            && !self.custom_tx_store
+            && !self.custom_account_store
+    }
+
+    // Move the state of this ledger into `shards` empty ledgers with the
+    // same policy, each with the clients whose ID is its index modulo
+    // `shards`.
+    pub(crate) fn split_into_shards(&mut self, shards: usize) -> Vec<Ledger> {
+        let mut ledgers = (0..shards)
+            .map(|_| Ledger {
+                now: self.now,
+                keep_statements: self.keep_statements,
+                ..Ledger::with_policy(self.policy.clone())
+            })
+            .collect::<Vec<_>>();
+        let shard = |client: AccountId| shard_of(client, shards);
+        for (at, client, tx) in self.scheduled.drain(..) {
+            ledgers[shard(client)].scheduled.push((at, client, tx));
+        }
+        for (client, recent) in self.withdrawals.0.drain() {
+            ledgers[shard(client)].withdrawals.0.insert(client, recent);
+        }
+        for (client, latest) in self.latest_times.drain() {
+            ledgers[shard(client)].latest_times.insert(client, latest);
+        }
+        for (client, parked) in self.parked_disputes.drain() {
+            ledgers[shard(client)]
+                .parked_disputes
+                .insert(client, parked);
+        }
+        for (client, id) in self.unkept_txs.drain() {
+            ledgers[shard(client)].unkept_txs.insert((client, id));
+        }
+        for (client, metadata) in self.metadata.drain() {
+            ledgers[shard(client)].metadata.insert(client, metadata);
+        }
+        for (client, freezes) in self.freezes.drain() {
+            ledgers[shard(client)].freezes.insert(client, freezes);
+        }
+        for (client, account) in self.accounts.0.iter() {
+            ledgers[shard(client)]
+                .accounts
+                .0
+                .insert(client, account.clone())
+                .expect("shards keep their accounts in memory");
+        }
+        self.accounts.0.clear();
+        for (client, statement) in self.statements.drain() {
+            ledgers[shard(client)].statements.insert(client, statement);
+        }
+        for found in self.processed_txs.0.iter() {
+            let (client, id, tx) = found.expect("shards keep their transactions in memory");
+            ledgers[shard(client)]
+                .processed_txs
+                .0
+                .insert(client, id, tx)
+                .expect("shards keep their transactions in memory");
+        }
+        self.processed_txs.0.clear();
+        ledgers
+    }
+
+    // Move the state of a ledger from `split_into_shards` back into this
+    // one.
+    pub(crate) fn merge_shard(&mut self, shard: Ledger) {
+        for (client, account) in shard.accounts.0.iter() {
+            self.accounts
+                .0
+                .insert(client, account.clone())
+                .expect("sharded ledgers keep their accounts in memory");
+        }
+        self.statements.extend(shard.statements);
+        for found in shard.processed_txs.0.iter() {
+            let (client, id, tx) =
+                found.expect("sharded ledgers keep their transactions in memory");
+            self.processed_txs
+                .0
+                .insert(client, id, tx)
+                .expect("sharded ledgers keep their transactions in memory");
+        }
+        // The sort is stable, so each client's transactions stay in order.
+        self.scheduled.extend(shard.scheduled);
+        self.scheduled.sort_by_key(|&(at, ..)| at);
+        self.withdrawals.0.extend(shard.withdrawals.0);
+        self.latest_times.extend(shard.latest_times);
+        self.parked_disputes.extend(shard.parked_disputes);
+        self.unkept_txs.extend(shard.unkept_txs);
+        self.metadata.extend(shard.metadata);
+        self.freezes.extend(shard.freezes);
+    }
+
+    // Open accounts with the opening balances read from the given CSV
+    // reader. The expected columns are the same as in the account summaries
+    // written by `accounts_to_csv`, with `total`, `closed`, and `currency`
+    // being optional. If `total` is present it must equal the sum of the
+    // available and held funds. Invalid lines and accounts that can't be
+    // opened are reported on stderr and skipped.
+    pub fn read_opening_balances<R: std::io::Read>(&mut self, reader: R) {
+        self.read_opening_balances_with(reader, Stderr);
+    }
+
+    // Like `read_opening_balances`, but pass every line that's skipped to
+    // `sink` instead of printing it. Lines whose total doesn't add up are
+    // rejected as `unbalanced`, and those too large to add up as `overflow`.
+    pub fn read_opening_balances_with<R, S>(&mut self, reader: R, mut sink: S)
+    where
+        R: std::io::Read,
+        S: ErrorSink,
+    {
+        #[derive(Deserialize)]
+        struct BalanceRecord {
+            client: AccountId,
+            #[serde(deserialize_with = "deserialize_decimal")]
+            available: Balance,
+            #[serde(deserialize_with = "deserialize_decimal")]
+            held: Balance,
+            #[serde(default, deserialize_with = "deserialize_amount")]
+            total: Option<Balance>,
+            locked: bool,
+            #[serde(default)]
+            closed: bool,
+            #[serde(default)]
+            currency: Option<Currency>,
+        }
+
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let headers = match reader.headers() {
+            Ok(headers) => headers.clone(),
+            Err(err) => {
+                sink.on_reject(Reject {
+                    line: 1,
+                    record: String::new(),
+                    reason: ErrorCode::Malformed.name(),
+                    message: format!("line 1: invalid header in opening balances: {}", err),
+                    byte: 0,
+                });
+                return;
+            }
+        };
+        // With text client IDs clients are given by name, see
+        // `ClientIdPolicy::Text`.
+        let name_column = match self.policy.client_ids {
+            ClientIdPolicy::Numeric => None,
+            ClientIdPolicy::Text => headers.iter().position(|name| name == "client"),
+        };
+
+        for line in reader.records() {
+            let fields = match line {
+                Ok(fields) => fields,
+                Err(err) => {
+                    let position = err.position().map(Position::from).unwrap_or_default();
+                    sink.on_reject(Reject {
+                        line: position.line,
+                        record: String::new(),
+                        reason: ErrorCode::Malformed.name(),
+                        message: format!("invalid line in opening balances: {}", err),
+                        byte: position.byte,
+                    });
+                    continue;
+                }
+            };
+            let position = fields.position().map(Position::from).unwrap_or_default();
+            let reject = |reason, message| Reject {
+                line: position.line,
+                record: fields.iter().collect::<Vec<_>>().join(","),
+                reason,
+                message: format!("line {}: {}", position.line, message),
+                byte: position.byte,
+            };
+
+            let name = name_column.and_then(|column| Some((column, fields.get(column)?)));
+            let unnamed = name.map(|(column, _)| without_name(&fields, column));
+            let record = match unnamed
+                .as_ref()
+                .unwrap_or(&fields)
+                .deserialize::<BalanceRecord>(Some(&headers))
+            {
+                Ok(record) => record,
+                Err(err) => {
+                    let message = format!("invalid line in opening balances: {}", err);
+                    sink.on_reject(reject(ErrorCode::Malformed.name(), message));
+                    continue;
+                }
+            };
+            let client = match name {
+                Some((_, name)) => match self.client_names.account_for(name) {
+                    Ok(client) => client,
+                    Err(err) => {
+                        let message = format!("invalid line in opening balances: {}", err);
+                        sink.on_reject(reject(err.code(), message));
+                        continue;
+                    }
+                },
+                None => record.client,
+            };
+            if let Some(total) = record.total {
+                let Some(sum) = record.available.checked_add(record.held) else {
+                    let message = format!(
+                        "opening balance of client {} is too large to add up",
+                        self.client_key(client)
+                    );
+                    sink.on_reject(reject(ErrorCode::Overflow.name(), message));
+                    continue;
+                };
+                if total != sum {
+                    let message = format!(
+                        "opening balance of client {} doesn't add up to its total",
+                        self.client_key(client)
+                    );
+                    sink.on_reject(reject(ErrorCode::Unbalanced.name(), message));
+                    continue;
+                }
+            }
+
+            let balance = OpeningBalance {
+                available: record.available,
+                held: record.held,
+                locked: record.locked,
+                closed: record.closed,
+                currency: record.currency,
+            };
+            if let Err(err) = self.open_account(client, balance) {
+                sink.on_reject(reject(err.code(), err.to_string()));
+            }
+        }
+    }
+}
+
+// OutputFormat selects how account summaries are written.
+// * Csv: a CSV file with a header.
+// * Json: a single JSON array of objects.
+// * JsonLines: one JSON object per line.
+// * Table: an aligned table meant for humans.
+// * Parquet: a Parquet file, with balances as DECIMAL columns.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+    JsonLines,
+    Table,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("unknown output option {0:?}")]
+pub struct ParseOutputOptionError(String);
+
+impl FromStr for OutputFormat {
+    type Err = ParseOutputOptionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "table" => Ok(OutputFormat::Table),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(OutputFormat::Parquet),
+            _ => Err(ParseOutputOptionError(s.to_owned())),
+        }
+    }
+}
+
+// Rounding selects how balances are rounded to the output precision.
+// * Truncate: extra digits are dropped, rounding toward zero.
+// * HalfEven: to the nearest value, with ties going to the even digit
+//   (banker's rounding).
+// * HalfUp: to the nearest value, with ties going away from zero.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    Truncate,
+    HalfEven,
+    #[default]
+    HalfUp,
+}
+
+impl FromStr for Rounding {
+    type Err = ParseOutputOptionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(Rounding::Truncate),
+            "half-even" => Ok(Rounding::HalfEven),
+            "half-up" => Ok(Rounding::HalfUp),
+            _ => Err(ParseOutputOptionError(s.to_owned())),
+        }
+    }
+}
+
+// The most decimal places a balance can have.
+pub const MAX_DECIMAL_PLACES: u32 = 28;
+
+// Precision is the number of decimal places balances are written with, and
+// how they're rounded to it. Balances with fewer decimal places are padded
+// with zeros. The default is 4 decimal places, rounding half up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Precision {
+    pub decimal_places: u32,
+    pub rounding: Rounding,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Precision {
+            decimal_places: 4,
+            rounding: Rounding::default(),
+        }
+    }
+}
+
+impl Precision {
+    pub fn apply(&self, balance: Balance) -> Balance {
+        let strategy = match self.rounding {
+            Rounding::Truncate => RoundingStrategy::ToZero,
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+        };
+        let mut balance = balance.round_dp_with_strategy(self.decimal_places, strategy);
+        balance.rescale(self.decimal_places);
+        balance
+    }
+}
+
+// AccountOrder selects the order account summaries are written in.
+// * Client: by client ID.
+// * TotalDescending: by total funds, largest first.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccountOrder {
+    #[default]
+    Client,
+    TotalDescending,
+}
+
+impl FromStr for AccountOrder {
+    type Err = ParseOutputOptionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(AccountOrder::Client),
+            "total" => Ok(AccountOrder::TotalDescending),
+            _ => Err(ParseOutputOptionError(s.to_owned())),
+        }
+    }
+}
+
+// AccountRecord is the CSV and JSON representation of an account in the account
+// summaries and closing balances, or of its balance in a currency.
+#[derive(Serialize)]
+pub(crate) struct AccountRecord {
+    pub(crate) client: ClientKey,
+    // The currency of the balance, empty for the balance without one, if
+    // any account has balances in currencies, see `Ledger::balance_rows`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) currency: Option<Option<Currency>>,
+    pub(crate) available: Balance,
+    pub(crate) held: Balance,
+    pub(crate) total: Balance,
+    pub(crate) locked: bool,
+    // Why the account is frozen, empty if it isn't, if the ledger writes
+    // the reasons, see `LedgerBuilder::freeze_reasons`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) freeze_reason: Option<Option<String>>,
+    // How strictly the account is frozen, likewise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) freeze_level: Option<Option<String>>,
+    // Whether the account is closed, if any account is, see
+    // `Ledger::close_account`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) closed: Option<bool>,
+    // The part of the available funds below zero, if the policy allows
+    // overdrafts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) overdrawn: Option<Balance>,
+}
+
+fn write_csv<W: std::io::Write>(summaries: &[AccountRecord], output: &mut W) {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(true)
+        .from_writer(output);
+
+    for record in summaries {
+        writer
+            .serialize(record)
+            .expect("failed to write CSV output");
+    }
+}
+
+fn write_json<W: std::io::Write>(summaries: &[AccountRecord], output: &mut W, lines: bool) {
+    let result = if lines {
+        summaries.iter().try_for_each(|record| {
+            serde_json::to_writer(&mut *output, record)?;
+            writeln!(output).map_err(serde_json::Error::io)
+        })
+    } else {
+        serde_json::to_writer(&mut *output, summaries)
+            .and_then(|_| writeln!(output).map_err(serde_json::Error::io))
+    };
+    result.expect("failed to write JSON output");
+}
+
+fn write_table<W: std::io::Write>(summaries: &[AccountRecord], output: &mut W) {
+    let mut header = vec!["client"];
+    if summaries.iter().any(|record| record.currency.is_some()) {
+        header.push("currency");
+    }
+    header.extend(["available", "held", "total", "locked"]);
+    if summaries
+        .iter()
+        .any(|record| record.freeze_reason.is_some())
+    {
+        header.extend(["freeze_reason", "freeze_level"]);
+    }
+    if summaries.iter().any(|record| record.closed.is_some()) {
+        header.push("closed");
+    }
+    if summaries.iter().any(|record| record.overdrawn.is_some()) {
+        header.push("overdrawn");
+    }
+    let rows = summaries
+        .iter()
+        .map(|record| {
+            let mut row = vec![record.client.to_string()];
+            row.extend(
+                record
+                    .currency
+                    .map(|currency| currency.map_or_else(String::new, |c| c.to_string())),
+            );
+            row.extend([
+                record.available.to_string(),
+                record.held.to_string(),
+                record.total.to_string(),
+                record.locked.to_string(),
+            ]);
+            row.extend(record.freeze_reason.clone().map(Option::unwrap_or_default));
+            row.extend(record.freeze_level.clone().map(Option::unwrap_or_default));
+            row.extend(record.closed.map(|closed| closed.to_string()));
+            row.extend(record.overdrawn.map(|overdrawn| overdrawn.to_string()));
+            row
+        })
+        .collect::<Vec<_>>();
+    write_aligned(&header, &rows, output).expect("failed to write table output");
+}
+
+// Write the rows as an aligned table with the given header, where every
+// column is as wide as its widest cell.
+pub(crate) fn write_aligned<W: std::io::Write>(
+    header: &[&str],
+    rows: &[Vec<String>],
+    output: &mut W,
+) -> std::io::Result<()> {
+    let mut widths = header.iter().map(|cell| cell.len()).collect::<Vec<_>>();
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    // Everything is right-aligned so that the decimal points line up.
+    let mut write_row = |cells: &mut dyn Iterator<Item = &str>| {
+        let line = cells
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:>width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(output, "{}", line.trim_end())
+    };
+
+    write_row(&mut header.iter().copied())?;
+    let separator = widths
+        .iter()
+        .map(|width| "-".repeat(*width))
+        .collect::<Vec<_>>();
+    write_row(&mut separator.iter().map(String::as_str))?;
+    for row in rows.iter() {
+        write_row(&mut row.iter().map(String::as_str))?;
+    }
+    Ok(())
+}
+
+// Accounts holds every account, in the ledger's store, see `AccountStore`.
+struct Accounts(Box<dyn AccountStore>);
+
+impl Default for Accounts {
+    fn default() -> Accounts {
+        Accounts(Box::new(FxHashMap::default()))
+    }
+}
+
+// Every account of a ledger is expected to be in its store.
+impl std::ops::Index<&AccountId> for Accounts {
+    type Output = Account;
+
+    fn index(&self, client: &AccountId) -> &Account {
+        self.0.get(*client).expect("account should exist")
+    }
+}
+
+// The accounts are serialized as a map from client ID, and always
+// deserialized into memory.
+impl Serialize for Accounts {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for Accounts {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let accounts = FxHashMap::<AccountId, Account>::deserialize(deserializer)?;
+        Ok(Accounts(Box::new(accounts)))
+    }
+}
+
+// ProcessedTxs holds every processed transaction, in the ledger's store, see
+// `TxStore`.
+pub struct ProcessedTxs(Box<dyn TxStore>);
+
+impl Default for ProcessedTxs {
+    fn default() -> ProcessedTxs {
+        ProcessedTxs(Box::new(FxHashMap::default()))
+    }
+}
+
+// The transactions are serialized as a list of (client, ID, transaction)
+// since formats like JSON don't allow compound map keys. They're always
+// deserialized into memory. Failing to read the store fails serializing.
+impl Serialize for ProcessedTxs {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{Error, SerializeSeq};
+
+        let mut seq = serializer.serialize_seq(None)?;
+        for found in self.0.iter() {
+            seq.serialize_element(&found.map_err(S::Error::custom)?)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for ProcessedTxs {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let txs =
+            Vec::<(AccountId, TransactionId, ProcessedTransaction)>::deserialize(deserializer)?;
+        let txs = txs
+            .into_iter()
+            .map(|(client, id, tx)| ((client, id), tx))
+            .collect::<FxHashMap<_, _>>();
+        Ok(ProcessedTxs(Box::new(txs)))
+    }
+}
+
+// ProcessedTxsForAccount is a reference into all processed transactions,
+// with the added restriction that it only allows lookups and insertions
+// for the specified account number.
+pub struct ProcessedTxsForAccount<'a> {
+    // `processed` is a reference to all processed transactions.
+    processed: &'a mut ProcessedTxs,
+    // Only transactions belonging to this account may be accessed through
+    // this struct.
+    account: AccountId,
+    disputes: DisputePolicy,
+    withdrawal_disputes: WithdrawalDisputePolicy,
+    chargeback_freeze: FreezeLevel,
+    // The time of the transaction being applied, kept with the transactions
+    // it inserts.
+    timestamp: Option<Timestamp>,
+    // The currency of the transaction being applied, likewise.
+    currency: Option<Currency>,
+    // The last transaction inserted, with its ID, so it doesn't have to be
+    // read back from the store.
+    inserted: Option<(TransactionId, ProcessedTransaction)>,
+}
+
+impl<'a> ProcessedTxsForAccount<'a> {
+    pub(crate) fn for_account(
+        processed: &'a mut ProcessedTxs,
+        id: AccountId,
+    ) -> ProcessedTxsForAccount<'a> {
+        ProcessedTxsForAccount {
+            processed,
+            account: id,
+            disputes: DisputePolicy::All,
+            withdrawal_disputes: WithdrawalDisputePolicy::Hold,
+            chargeback_freeze: FreezeLevel::Full,
+            timestamp: None,
+            currency: None,
+            inserted: None,
+        }
+    }
+
+    // Only keep the transactions that can be disputed under the given
+    // policy.
+    pub(crate) fn with_disputes(mut self, disputes: DisputePolicy) -> Self {
+        self.disputes = disputes;
+        self
+    }
+
+    // Dispute withdrawals according to the given policy.
+    pub(crate) fn with_withdrawal_disputes(mut self, disputes: WithdrawalDisputePolicy) -> Self {
+        self.withdrawal_disputes = disputes;
+        self
+    }
+
+    // Freeze the account at the given level when a chargeback freezes it.
+    pub(crate) fn with_chargeback_freeze(mut self, level: FreezeLevel) -> Self {
+        self.chargeback_freeze = level;
+        self
+    }
+
+    // How strictly a chargeback freezes the account, see
+    // `Policy::chargeback_freeze`.
+    pub fn chargeback_freeze(&self) -> FreezeLevel {
+        self.chargeback_freeze
+    }
+
+    // Keep the given time with the transactions inserted.
+    pub(crate) fn with_timestamp(mut self, timestamp: Option<Timestamp>) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    // The time of the transaction being applied, if it has one.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+
+    // The currency of the transaction being applied, if it has one.
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    // Apply the transactions that follow in the given currency, returning
+    // the one before.
+    pub(crate) fn set_currency(&mut self, currency: Option<Currency>) -> Option<Currency> {
+        mem::replace(&mut self.currency, currency)
+    }
+
+    // Whether withdrawals should be inserted, i.e. whether they can be
+    // disputed.
+    pub fn keeps_withdrawals(&self) -> bool {
+        self.disputes == DisputePolicy::All
+    }
+
+    // Whether disputing the transaction holds its disputed amount, which is
+    // the case unless it's a withdrawal that chargebacks reverse instead, see
+    // `WithdrawalDisputePolicy`.
+    pub fn holds(&self, tx: &ProcessedTransaction) -> bool {
+        !tx.withdrawal || self.withdrawal_disputes == WithdrawalDisputePolicy::Hold
+    }
+
+    // Find a transaction by transaction ID. If the given transaction ID does
+    // not belong to the account associated with this object then it won't be
+    // returned. The transaction is a copy, changes to it have to be written
+    // back with `insert_processed`.
+    pub fn find(
+        &self,
+        tx: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, TransactionError> {
+        self.processed.0.get(self.account, tx)
+    }
+
+    // Find the transaction the one being applied refers to, e.g. the one it
+    // disputes, which must be in the same currency.
+    pub fn find_referred(
+        &self,
+        tx: TransactionId,
+    ) -> Result<ProcessedTransaction, TransactionError> {
+        let found = self
+            .find(tx)?
+            .ok_or(TransactionError::NonexistentTransaction)?;
+        if found.currency != self.currency {
+            return Err(TransactionError::CurrencyMismatch);
+        }
+        Ok(found)
+    }
+
+    // Insert a new transaction as processed and associate it with the account
+    // referenced by this object, replacing any with the same ID.
+    pub fn insert_processed(
+        &mut self,
+        id: TransactionId,
+        tx: ProcessedTransaction,
+    ) -> Result<(), TransactionError> {
+        self.processed.0.insert(self.account, id, tx)?;
+        self.inserted = Some((id, tx));
+        Ok(())
+    }
+
+    // The transaction with the given ID, if it was the last one inserted.
+    pub(crate) fn inserted(&self, id: TransactionId) -> Option<ProcessedTransaction> {
+        self.inserted
+            .filter(|&(inserted, _)| inserted == id)
+            .map(|(_, tx)| tx)
+    }
+}
+
+// NOTE: Due to the CSV crate's shortcomings the records can't
+// be directly deserialized as an enum. Therefore they're
+// first read as a simple record type then transformed into
+// an enum.
+// https://github.com/BurntSushi/rust-csv/issues/211
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+struct Record {
+    #[serde(rename = "type")]
+    record_type: RecordType,
+    client: AccountId,
+    tx: TransactionId,
+    #[serde(default, deserialize_with = "deserialize_amount")]
+    amount: Option<TransactionAmount>,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    effective_at: Option<Timestamp>,
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    timestamp: Option<Timestamp>,
+    #[serde(default, deserialize_with = "deserialize_currency")]
+    currency: Option<Currency>,
+    // The metadata of `open_account` records, see `AccountMetadata`.
+    #[serde(default, deserialize_with = "deserialize_text")]
+    reference: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_text")]
+    risk_tier: Option<String>,
+}
+
+// Amounts are read from their text, rather than as whatever number they look
+// like, which would round those with more than 15 significant digits and
+// fail for whole ones beyond `u64::MAX`. Trailing zeros are dropped, and
+// zero has no sign.
+fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<TransactionAmount>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)?.as_deref() {
+        None | Some("") => Ok(None),
+        Some(amount) => TransactionAmount::from_str(amount)
+            .or_else(|_| TransactionAmount::from_scientific(amount))
+            .map(|amount| Some(amount.normalize()))
+            .map_err(|_| serde::de::Error::custom(format!("invalid amount {:?}", amount))),
+    }
+}
+
+// Balances, and other decimals written by the ledger, are read from their
+// text like amounts, see `deserialize_amount`.
+pub(crate) fn deserialize_decimal<'de, D>(deserializer: D) -> Result<Decimal, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_amount(deserializer)?.ok_or_else(|| serde::de::Error::custom("missing number"))
+}
+
+// Times are read like `parse_timestamp` parses them.
+fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<Option<Timestamp>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)?.as_deref() {
+        None | Some("") => Ok(None),
+        Some(time) => parse_timestamp(time)
+            .map(Some)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid time {:?}", time))),
+    }
+}
+
+// Transactions without a currency are left with an empty field.
+fn deserialize_currency<'de, D>(deserializer: D) -> Result<Option<Currency>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)?.as_deref() {
+        None | Some("") => Ok(None),
+        Some(currency) => currency.parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+// Fields that are left empty aren't given.
+fn deserialize_text<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let text = Option::<String>::deserialize(deserializer)?;
+    Ok(text.filter(|text| !text.is_empty()))
+}
+
+// The fields of a `Record` can also be parsed without serde, which is much
+// faster, see `Record::from_fields`. Only lines that are certainly parsed
+// the same way are parsed like that, everything else, including every line
+// that doesn't parse, still goes through serde, so the results and errors
+// stay the same.
+impl Record {
+    // Parse the already split, but not trimmed, fields of a line, or return
+    // `None` if they have to be parsed by serde instead.
+    // With the `wide-ids` feature, IDs are u64s already.
+    #[cfg_attr(feature = "wide-ids", allow(clippy::useless_conversion))]
+    fn from_fields(fields: &csv::ByteRecord, columns: &Columns) -> Option<Record> {
+        if fields.len() > columns.len {
+            return None;
+        }
+        let field = |column| fields.get(column).map(<[u8]>::trim_ascii);
+
+        let record_type = match field(columns.record_type)? {
+            b"deposit" => RecordType::Deposit,
+            b"withdrawal" => RecordType::Withdrawal,
+            b"dispute" => RecordType::Dispute,
+            b"resolve" => RecordType::Resolve,
+            b"chargeback" => RecordType::Chargeback,
+            b"recovery" => RecordType::Recovery,
+            b"fee" => RecordType::Fee,
+            b"adjust_credit" => RecordType::AdjustCredit,
+            b"adjust_debit" => RecordType::AdjustDebit,
+            b"lock" => RecordType::Lock,
+            b"unlock" => RecordType::Unlock,
+            b"refund" => RecordType::Refund,
+            b"authorize" => RecordType::Authorize,
+            b"capture" => RecordType::Capture,
+            b"void" => RecordType::Void,
+            _ => return None,
+        };
+        let client = parse_digits(field(columns.client)?)?.try_into().ok()?;
+        let tx = parse_digits(field(columns.tx)?)?.try_into().ok()?;
+        let amount = match columns.amount.and_then(field) {
+            None | Some(b"") => None,
+            Some(amount) => Some(parse_amount(amount)?),
+        };
+        let time = |column: Option<usize>| match column.and_then(field) {
+            None | Some(b"") => Some(None),
+            Some(time) => std::str::from_utf8(time)
+                .ok()
+                .and_then(parse_timestamp)
+                .map(Some),
+        };
+        let effective_at = time(columns.effective_at)?;
+        let timestamp = time(columns.timestamp)?;
+        let currency = match columns.currency.and_then(field) {
+            None | Some(b"") => None,
+            Some(currency) => Some(std::str::from_utf8(currency).ok()?.parse().ok()?),
+        };
+        // Only `open_account` records have metadata, and they're left to
+        // serde.
+        Some(Record {
+            record_type,
+            client,
+            tx,
+            amount,
+            effective_at,
+            timestamp,
+            currency,
+            reference: None,
+            risk_tier: None,
+        })
+    }
+}
+
+// Parse a non-empty number of at most 19 decimal digits, or return `None`.
+fn parse_digits(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 19 {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |number, &digit| {
+        digit
+            .is_ascii_digit()
+            .then(|| number * 10 + u64::from(digit - b'0'))
+    })
+}
+
+// Parse an amount like `-12.340` straight to a fixed-point decimal without
+// its trailing zeros, like `deserialize_amount` does, or return `None`. Only
+// amounts with up to 15 digits are parsed here, so they fit a `u64`, and the
+// rest are left to serde.
+fn parse_amount(amount: &[u8]) -> Option<TransactionAmount> {
+    let (negative, digits) = match amount.split_first() {
+        Some((b'-', digits)) => (true, digits),
+        _ => (false, amount),
+    };
+    let (whole, fraction) = match digits.iter().position(|&byte| byte == b'.') {
+        Some(dot) if dot + 1 < digits.len() => (&digits[..dot], &digits[dot + 1..]),
+        Some(_) => return None,
+        None => (digits, &[][..]),
+    };
+    if whole.is_empty() || whole.len() + fraction.len() > 15 {
+        return None;
+    }
+    let fraction_digits = match fraction {
+        [] => 0,
+        fraction => parse_digits(fraction)?,
+    };
+    let mantissa = parse_digits(whole)? * 10u64.pow(fraction.len() as u32) + fraction_digits;
+    // Serde reads `-0` as zero, but `-0.0` as negative zero.
+    if negative && mantissa == 0 {
+        return None;
+    }
+    let mantissa = if negative {
+        -(mantissa as i64)
+    } else {
+        mantissa as i64
+    };
+    Some(Decimal::new(mantissa, fraction.len() as u32).normalize())
+}
+
+// CsvHeaders are the trimmed headers of CSV input, along with the columns
+// of the fields of a `Record`, so they're only looked up once.
+pub(crate) struct CsvHeaders {
+    pub(crate) names: csv::StringRecord,
+    columns: Option<Columns>,
+}
+
+// The columns of the fields of a `Record`, and the number of columns.
+struct Columns {
+    record_type: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    effective_at: Option<usize>,
+    timestamp: Option<usize>,
+    currency: Option<usize>,
+    len: usize,
+}
+
+impl CsvHeaders {
+    pub(crate) fn new(names: csv::StringRecord) -> CsvHeaders {
+        let column = |name| names.iter().position(|header| header == name);
+        // Serde rejects fields given more than once, leave those to it.
+        let unique = [
+            "type",
+            "client",
+            "tx",
+            "amount",
+            "effective_at",
+            "timestamp",
+            "currency",
+            "reference",
+            "risk_tier",
+        ]
+        .into_iter()
+        .all(|name| names.iter().filter(|&header| header == name).count() <= 1);
+        let columns = unique
+            .then(|| {
+                Some(Columns {
+                    record_type: column("type")?,
+                    client: column("client")?,
+                    tx: column("tx")?,
+                    amount: column("amount"),
+                    effective_at: column("effective_at"),
+                    timestamp: column("timestamp"),
+                    currency: column("currency"),
+                    len: names.len(),
+                })
+            })
+            .flatten();
+        CsvHeaders { names, columns }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RecordType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Recovery,
+    Fee,
+    AdjustCredit,
+    AdjustDebit,
+    Lock,
+    Unlock,
+    Refund,
+    Authorize,
+    Capture,
+    Void,
+    OpenAccount,
+    CloseAccount,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RecordError {
+    #[error("The amount is missing for a transaction type that requires it")]
+    MissingAmount,
+    #[error("The record is malformed: {0}")]
+    Malformed(String),
+    #[error("The amount isn't allowed by the amount policy")]
+    InvalidAmount,
+}
+
+impl RecordError {
+    // A stable, machine-readable code for the error, like
+    // `TransactionError::code`.
+    pub fn code(&self) -> &'static str {
+        ErrorCode::from(self).name()
+    }
+}
+
+// Read CSV input with `read`, writing a report of every skipped line to
+// `rejects` and passing it on to `sink`, see `Ledger::read_csv_with_rejects`.
+pub(crate) fn write_rejects<W, S, F>(rejects: W, mut sink: S, read: F) -> csv::Result<ScaleCheck>
+where
+    W: std::io::Write,
+    S: ErrorSink,
+    F: FnOnce(&mut dyn FnMut(Reject)) -> ScaleCheck,
+{
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(true)
+        .from_writer(rejects);
+
+    let mut result = Ok(());
+    let check = read(&mut |reject: Reject| {
+        if result.is_ok() {
+            result = writer.serialize(&reject);
+        }
+        sink.on_reject(reject);
+    });
+
+    result?;
+    writer.flush()?;
+    Ok(check)
+}
+
+// Read transactions from the given CSV reader like `Ledger::read_csv_with`,
+// but pass them to `apply`, e.g. to apply them to a `SharedLedger`, and stop
+// at the first line that's skipped if `errors` says so.
+pub(crate) fn read_csv_into<R, S, A>(
+    reader: R,
+    scale: AmountScale,
+    mut sink: S,
+    errors: ErrorPolicy,
+    amounts: AmountPolicy,
+    mut apply: A,
+) -> ScaleCheck
+where
+    R: std::io::Read,
+    S: ErrorSink,
+    A: FnMut(AccountId, Transaction) -> Result<(), TransactionError>,
+{
+    let mut reader = csv_reader(reader);
+    let mut check = ScaleCheck::new(scale);
+    let Some(headers) = csv_headers(&mut reader, &mut sink) else {
+        return check;
+    };
+
+    for line in reader.records() {
+        if let Err(reject) = apply_csv_line(line, &headers, scale, amounts, &mut check, &mut apply)
+        {
+            sink.on_reject(reject);
+            if errors == ErrorPolicy::Abort {
+                break;
+            }
+        }
+    }
+    check
+}
+
+// A reader of transactions in CSV. Fields are trimmed separately, see
+// `apply_csv_line`, so the rejected lines can be reported as they were read.
+pub(crate) fn csv_reader<R: std::io::Read>(reader: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(true)
+        .trim(csv::Trim::None)
+        .from_reader(reader)
+}
+
+// The fields joined back into a line of CSV, quoted where they have to be,
+// so it reads back into the same fields.
+pub(crate) fn csv_line(fields: &csv::StringRecord) -> String {
+    let mut writer = csv::WriterBuilder::new()
+        .flexible(true)
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(vec![]);
+    // Writing to memory doesn't fail, and the fields were read as text.
+    writer
+        .write_record(fields)
+        .expect("writing to memory can't fail");
+    let line = writer.into_inner().expect("writing to memory can't fail");
+    let line = String::from_utf8(line).expect("fields should be UTF8");
+    line.trim_end_matches('\n').to_owned()
+}
+
+// Move the position of a record read from a part of the input that starts
+// at `start` to count from the start of the input instead.
+pub(crate) fn offset_position(record: &mut csv::StringRecord, start: &csv::Position) {
+    let position = record.position().map(|position| {
+        let mut moved = csv::Position::new();
+        moved
+            .set_byte(start.byte() + position.byte())
+            .set_line(start.line() + position.line() - 1)
+            .set_record(start.record() + position.record());
+        moved
+    });
+    record.set_position(position);
+}
+
+// The trimmed headers of a reader from `csv_reader`, or `None` if they
+// can't be read, which is reported to `sink`.
+pub(crate) fn csv_headers<R: std::io::Read, S: ErrorSink>(
+    reader: &mut csv::Reader<R>,
+    sink: &mut S,
+) -> Option<CsvHeaders> {
+    match reader.headers() {
+        Ok(headers) => {
+            let mut headers = headers.clone();
+            headers.trim();
+            Some(CsvHeaders::new(headers))
+        }
+        Err(err) => {
+            let position = Position { line: 1, byte: 0 };
+            let message = format!("invalid header: {}", err);
+            sink.on_reject(ProcessingError::malformed(position, message).into());
+            None
+        }
+    }
+}
+
+// Apply the transaction on a line read by `read_csv_into`, or describe why
+// the line had to be skipped.
+pub(crate) fn apply_csv_line<A>(
+    line: csv::Result<csv::StringRecord>,
+    headers: &CsvHeaders,
+    scale: AmountScale,
+    amounts: AmountPolicy,
+    check: &mut ScaleCheck,
+    apply: &mut A,
+) -> Result<(), Reject>
+where
+    A: FnMut(AccountId, Transaction) -> Result<(), TransactionError>,
+{
+    parse_csv_line(line, headers, scale, amounts, check)?.apply(apply)
+}
+
+// ParsedLine is the transaction on a line of CSV input, along with the
+// line's fields as they were read, to report it if it can't be applied.
+pub(crate) struct ParsedLine {
+    pub(crate) fields: csv::StringRecord,
+    pub(crate) account: AccountId,
+    pub(crate) transaction: Transaction,
+}
+
+impl ParsedLine {
+    pub(crate) fn apply<A>(self, apply: &mut A) -> Result<(), Reject>
+    where
+        A: FnMut(AccountId, Transaction) -> Result<(), TransactionError>,
+    {
+        let transaction = self.transaction.kind();
+        apply(self.account, self.transaction).map_err(|error| {
+            let kind = ProcessingErrorKind::Transaction {
+                client: ClientKey::Id(self.account),
+                transaction,
+                error,
+            };
+            ProcessingError::at_line(&self.fields, kind).into()
+        })
+    }
+}
+
+// Read the transaction on a line of CSV input, the first half of
+// `apply_csv_line`.
+pub(crate) fn parse_csv_line(
+    line: csv::Result<csv::StringRecord>,
+    headers: &CsvHeaders,
+    scale: AmountScale,
+    amounts: AmountPolicy,
+    check: &mut ScaleCheck,
+) -> Result<ParsedLine, Reject> {
+    let fields = line.map_err(|err| {
+        let position = err.position().map(Position::from).unwrap_or_default();
+        ProcessingError::malformed(position, err.to_string())
+    })?;
+
+    let fast = headers
+        .columns
+        .as_ref()
+        .and_then(|columns| Record::from_fields(fields.as_byte_record(), columns));
+    let mut record = match fast {
+        Some(record) => record,
+        None => {
+            let mut trimmed = fields.clone();
+            trimmed.trim();
+            match trimmed.deserialize::<Record>(Some(&headers.names)) {
+                Ok(record) => record,
+                Err(err) => {
+                    let kind = ProcessingErrorKind::Record(malformed(err));
+                    return Err(ProcessingError::at_line(&fields, kind).into());
+                }
+            }
+        }
+    };
+    if let Some(amount) = record.amount {
+        check.observe(amount);
+        record.amount = Some(scale.apply(amount).ok_or_else(|| {
+            let message = format!("amount {amount} has too many decimal places to scale");
+            let kind = ProcessingErrorKind::Record(RecordError::Malformed(message));
+            ProcessingError::at_line(&fields, kind)
+        })?);
+    }
+    match record_to_transaction(&record, amounts) {
+        Ok((account, transaction)) => Ok(ParsedLine {
+            fields,
+            account,
+            transaction,
+        }),
+        Err(err) => {
+            let kind = ProcessingErrorKind::Record(err);
+            Err(ProcessingError::at_line(&fields, kind).into())
+        }
+    }
+}
+
+// Reject describes a line of the CSV input that was skipped, either because
+// it's malformed or because its transaction couldn't be applied, as reported
+// to an `ErrorSink` and written by `--rejects`. `record` is the line's fields
+// as they were read, quoted where they have to be, `reason` the code of the error, see
+// `TransactionError::code` and `RecordError::code`, `message` the
+// description of a `ProcessingError`, and `byte` the offset the line starts
+// at, after the rest so the columns of earlier reports stay in place.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Reject {
+    pub line: u64,
+    pub record: String,
+    pub reason: &'static str,
+    pub message: String,
+    pub byte: u64,
+}
+
+impl std::fmt::Display for Reject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+// Position is where a line of input starts: its number, counting the header
+// as the first line, and its offset in bytes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: u64,
+    pub byte: u64,
+}
+
+impl From<&csv::Position> for Position {
+    fn from(position: &csv::Position) -> Position {
+        Position {
+            line: position.line(),
+            byte: position.byte(),
+        }
+    }
+}
+
+// ProcessingError is why a line of input had to be skipped, along with
+// where it was read and its fields as they were read, if they could be. It's
+// described like `line 48213: withdrawal for client 7 rejected: ...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessingError {
+    pub position: Position,
+    pub record: String,
+    pub kind: ProcessingErrorKind,
+}
+
+// ProcessingErrorKind is what went wrong with a line of input.
+// * Record: the line couldn't be read as a transaction, e.g. because it's
+//   malformed.
+// * Transaction: the line's transaction couldn't be applied to the client's
+//   account. `transaction` is its type, see `Transaction::kind`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessingErrorKind {
+    Record(RecordError),
+    Transaction {
+        client: ClientKey,
+        transaction: &'static str,
+        error: TransactionError,
+    },
+}
+
+impl ProcessingError {
+    // The error of a line with the given fields, read with their position.
+    pub(crate) fn at_line(fields: &csv::StringRecord, kind: ProcessingErrorKind) -> Self {
+        ProcessingError {
+            position: fields.position().map(Position::from).unwrap_or_default(),
+            record: csv_line(fields),
+            kind,
+        }
+    }
+
+    // The error of a line that isn't valid CSV, so it has no fields.
+    pub(crate) fn malformed(position: Position, message: String) -> Self {
+        ProcessingError {
+            position,
+            record: String::new(),
+            kind: ProcessingErrorKind::Record(RecordError::Malformed(message)),
+        }
+    }
+
+    // The stable code of the error, see `ErrorCode`.
+    pub fn code(&self) -> &'static str {
+        match &self.kind {
+            ProcessingErrorKind::Record(err) => err.code(),
+            ProcessingErrorKind::Transaction { error, .. } => error.code(),
+        }
+    }
+}
+
+impl std::fmt::Display for ProcessingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: ", self.position.line)?;
+        match &self.kind {
+            ProcessingErrorKind::Record(err) => write!(f, "{}", err),
+            ProcessingErrorKind::Transaction {
+                client,
+                transaction,
+                error,
+            } => write!(
+                f,
+                "{} for client {} rejected: {}",
+                transaction, client, error
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProcessingError {}
+
+impl From<ProcessingError> for Reject {
+    fn from(err: ProcessingError) -> Reject {
+        Reject {
+            line: err.position.line,
+            reason: err.code(),
+            message: err.to_string(),
+            record: err.record,
+            byte: err.position.byte,
+        }
+    }
+}
+
+impl std::error::Error for Reject {}
+
+// Parse a single record formatted like a line of the CSV input, but without
+// a header, so the fields must be in the order type, client, tx, amount. The
+// amount may be omitted entirely for transactions that don't need one.
+pub(crate) fn parse_record(
+    line: &str,
+    amounts: AmountPolicy,
+) -> Result<(AccountId, Transaction), RecordError> {
+    let mut fields = csv::StringRecord::new();
+    csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .trim(csv::Trim::All)
+        .from_reader(line.as_bytes())
+        .read_record(&mut fields)
+        .map_err(malformed)?;
+
+    let headers = csv::StringRecord::from(vec!["type", "client", "tx", "amount"]);
+    parse_fields(&fields, &headers, amounts)
+}
+
+// Parse the already trimmed fields of a line of CSV input with the given
+// headers, the same way the transactions file is read.
+pub(crate) fn parse_fields(
+    fields: &csv::StringRecord,
+    headers: &csv::StringRecord,
+    amounts: AmountPolicy,
+) -> Result<(AccountId, Transaction), RecordError> {
+    let record: Record = fields.deserialize(Some(headers)).map_err(malformed)?;
+    record_to_transaction(&record, amounts)
+}
+
+fn malformed(err: csv::Error) -> RecordError {
+    match err.kind() {
+        // The position of the error is meaningless for a single record.
+        csv::ErrorKind::Deserialize { err, .. } => RecordError::Malformed(err.to_string()),
+        _ => RecordError::Malformed(err.to_string()),
+    }
+}
+
+fn record_to_transaction(
+    record: &Record,
+    amounts: AmountPolicy,
+) -> Result<(AccountId, Transaction), RecordError> {
+    use RecordError::*;
+    use Transaction::*;
+
+    let tx = match record.record_type {
+        RecordType::Deposit => record
+            .amount
+            .map(|amount| Deposit {
+                new_id: record.tx,
+                amount,
+            })
+            .ok_or(MissingAmount),
+        RecordType::Withdrawal => record
+            .amount
+            .map(|amount| Withdrawal {
+                new_id: record.tx,
+                amount,
+            })
+            .ok_or(MissingAmount),
+        RecordType::Dispute => Ok(Dispute {
+            id: record.tx,
+            amount: record.amount,
+        }),
+        RecordType::Resolve => Ok(Resolve { id: record.tx }),
+        RecordType::Chargeback => Ok(Chargeback { id: record.tx }),
+        RecordType::Refund => Ok(Refund {
+            id: record.tx,
+            amount: record.amount,
+        }),
+        // The transaction ID isn't used for anything
+        RecordType::Recovery => Ok(Recovery),
+        RecordType::Lock => Ok(Lock),
+        RecordType::Unlock => Ok(Unlock),
+        RecordType::Fee => record
+            .amount
+            .map(|amount| Fee {
+                new_id: record.tx,
+                amount,
+            })
+            .ok_or(MissingAmount),
+        RecordType::AdjustCredit => record
+            .amount
+            .map(|amount| AdjustCredit {
+                new_id: record.tx,
+                amount,
+            })
+            .ok_or(MissingAmount),
+        RecordType::AdjustDebit => record
+            .amount
+            .map(|amount| AdjustDebit {
+                new_id: record.tx,
+                amount,
+            })
+            .ok_or(MissingAmount),
+        RecordType::Authorize => record
+            .amount
+            .map(|amount| Authorize {
+                new_id: record.tx,
+                amount,
+            })
+            .ok_or(MissingAmount),
+        RecordType::Capture => Ok(Capture { id: record.tx }),
+        RecordType::Void => Ok(Void { id: record.tx }),
+        // The currency of an account is part of its metadata.
+        RecordType::OpenAccount => Ok(OpenAccount {
+            metadata: Box::new(AccountMetadata {
+                reference: record.reference.clone(),
+                currency: record.currency,
+                risk_tier: record.risk_tier.clone(),
+            }),
+        }),
+        RecordType::CloseAccount => Ok(CloseAccount),
+    };
+    // Only the amounts that are used are checked, those of resolutions, for
+    // example, are ignored anyway.
+    let tx = tx.and_then(|tx| match tx.amount() {
+        Some(amount) if !amounts.allows(amount) => Err(InvalidAmount),
+        _ => Ok(tx),
+    });
+
+    // The currency goes innermost, see `Transaction::InCurrency`.
+    let tx = match record.currency {
+        Some(_) if matches!(record.record_type, RecordType::OpenAccount) => tx,
+        Some(currency) => tx.map(|tx| InCurrency {
+            currency,
+            transaction: Box::new(tx),
+        }),
+        None => tx,
+    };
+    let tx = match record.timestamp {
+        Some(timestamp) => tx.map(|tx| Timestamped {
+            timestamp,
+            transaction: Box::new(tx),
+        }),
+        None => tx,
+    };
+    // Dated transactions are scheduled, even if their time has come
+    // already, the ledger applies them right away then.
+    let tx = match record.effective_at {
+        Some(effective_at) => tx.map(|tx| Scheduled {
+            effective_at,
+            transaction: Box::new(tx),
+        }),
+        None => tx,
+    };
+    tx.map(|tx| (record.client, tx))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{Ledger, Precision, ProcessedTransaction, ProcessedTransactionState, Reject};
+    use crate::{
+        account::Account, events::LedgerEvent, scaling::AmountScale, Transaction, TransactionError,
+    };
+
+    #[test]
+    fn record_to_transaction() {
+        use super::RecordError;
+        use super::RecordType::*;
+        use super::{record_to_transaction as f, Record};
+        use crate::policy::AmountPolicy;
+
+        let tests = [
+            // Withdrawals
+            (
+                Record {
+                    record_type: Withdrawal,
+                    client: 1,
+                    tx: 2,
+                    amount: Some(10.into()),
+                    effective_at: None,
+                    timestamp: None,
+                    currency: None,
+                    reference: None,
+                    risk_tier: None,
+                },
+                Ok((
+                    1,
+                    Transaction::Withdrawal {
+                        new_id: 2,
+                        amount: 10.into(),
+                    },
+                )),
+            ),
+            (
+                Record {
+                    record_type: Withdrawal,
+                    client: 16,
+                    tx: 32,
+                    amount: None,
+                    effective_at: None,
+                    timestamp: None,
+                    currency: None,
+                    reference: None,
+                    risk_tier: None,
+                },
+                Err(RecordError::MissingAmount),
+            ),
+            // Deposits
+            (
+                Record {
+                    record_type: Deposit,
+                    client: 5,
+                    tx: 4,
+                    amount: Some(90.into()),
+                    effective_at: None,
+                    timestamp: None,
+                    currency: None,
+                    reference: None,
+                    risk_tier: None,
+                },
+                Ok((
+                    5,
+                    Transaction::Deposit {
+                        new_id: 4,
+                        amount: 90.into(),
+                    },
+                )),
+            ),
+            (
+                Record {
+                    record_type: Deposit,
+                    client: 7,
+                    tx: 6,
+                    amount: None,
+                    effective_at: None,
+                    timestamp: None,
+                    currency: None,
+                    reference: None,
+                    risk_tier: None,
+                },
+                Err(RecordError::MissingAmount),
+            ),
+            // Disputes
+            (
+                Record {
+                    record_type: Dispute,
+                    client: 7,
+                    tx: 6,
+                    amount: None,
+                    effective_at: None,
+                    timestamp: None,
+                    currency: None,
+                    reference: None,
+                    risk_tier: None,
+                },
+                Ok((
+                    7,
+                    Transaction::Dispute {
+                        id: 6,
+                        amount: None,
+                    },
+                )),
+            ),
+            (
+                Record {
+                    record_type: Dispute,
+                    client: 7,
+                    tx: 6,
+                    // An amount only disputes that much of the transaction
+                    amount: Some(10.into()),
+                    effective_at: None,
+                    timestamp: None,
+                    currency: None,
+                    reference: None,
+                    risk_tier: None,
+                },
+                Ok((
+                    7,
+                    Transaction::Dispute {
+                        id: 6,
+                        amount: Some(10.into()),
+                    },
+                )),
+            ),
+            // Resolve
+            (
+                Record {
+                    record_type: Resolve,
+                    client: 5,
+                    tx: 2,
+                    amount: None,
+                    effective_at: None,
+                    timestamp: None,
+                    currency: None,
+                    reference: None,
+                    risk_tier: None,
+                },
+                Ok((5, Transaction::Resolve { id: 2 })),
+            ),
+            (
+                Record {
+                    record_type: Resolve,
+                    client: 2,
+                    tx: 5,
+                    // Amount on a resolve is ok, it's simply ignored
+                    amount: Some(10.into()),
+                    effective_at: None,
+                    timestamp: None,
+                    currency: None,
+                    reference: None,
+                    risk_tier: None,
+                },
+                Ok((2, Transaction::Resolve { id: 5 })),
+            ),
+            // Chargeback
+            (
+                Record {
+                    record_type: Chargeback,
+                    client: 5,
+                    tx: 2,
+                    amount: None,
+                    effective_at: None,
+                    timestamp: None,
+                    currency: None,
+                    reference: None,
+                    risk_tier: None,
+                },
+                Ok((5, Transaction::Chargeback { id: 2 })),
+            ),
+            (
+                Record {
+                    record_type: Chargeback,
+                    client: 2,
+                    tx: 5,
+                    // Amount on a chargeback is ok, it's simply ignored
+                    amount: Some(10.into()),
+                    effective_at: None,
+                    timestamp: None,
+                    currency: None,
+                    reference: None,
+                    risk_tier: None,
+                },
+                Ok((2, Transaction::Chargeback { id: 5 })),
+            ),
+            // Scheduled
+            (
+                Record {
+                    record_type: Deposit,
+                    client: 3,
+                    tx: 8,
+                    amount: Some(5.into()),
+                    effective_at: Some(100),
+                    timestamp: None,
+                    currency: None,
+                    reference: None,
+                    risk_tier: None,
+                },
+                Ok((
+                    3,
+                    Transaction::Scheduled {
+                        effective_at: 100,
+                        transaction: Box::new(Transaction::Deposit {
+                            new_id: 8,
+                            amount: 5.into(),
+                        }),
+                    },
+                )),
+            ),
+            // In a currency
+            (
+                Record {
+                    record_type: Deposit,
+                    client: 3,
+                    tx: 9,
+                    amount: Some(5.into()),
+                    effective_at: None,
+                    timestamp: Some(100),
+                    currency: Some("EUR".parse().unwrap()),
+                    reference: None,
+                    risk_tier: None,
+                },
+                Ok((
+                    3,
+                    Transaction::Timestamped {
+                        timestamp: 100,
+                        transaction: Box::new(Transaction::InCurrency {
+                            currency: "EUR".parse().unwrap(),
+                            transaction: Box::new(Transaction::Deposit {
+                                new_id: 9,
+                                amount: 5.into(),
+                            }),
+                        }),
+                    },
+                )),
+            ),
+        ];
+
+        for (left, right) in tests.into_iter() {
+            assert_eq!(f(&left, AmountPolicy::default()), right);
+        }
+    }
+
+    #[test]
+    fn parse_record() {
+        use super::RecordError;
+        use crate::policy::AmountPolicy;
+        let f = |line| super::parse_record(line, AmountPolicy::default());
+
+        assert_eq!(
+            f("deposit, 1, 2, 3.5"),
+            Ok((
+                1,
+                Transaction::Deposit {
+                    new_id: 2,
+                    amount: "3.5".parse().unwrap()
+                }
+            ))
+        );
+        assert_eq!(
+            f("dispute,1,2,"),
+            Ok((
+                1,
+                Transaction::Dispute {
+                    id: 2,
+                    amount: None
+                }
+            ))
+        );
+        assert_eq!(
+            f("dispute,1,2"),
+            Ok((
+                1,
+                Transaction::Dispute {
+                    id: 2,
+                    amount: None
+                }
+            ))
+        );
+        assert_eq!(f("withdrawal,1,2"), Err(RecordError::MissingAmount));
+        assert!(matches!(
+            f("withdraw,1,2,3"),
+            Err(RecordError::Malformed(_))
+        ));
+        assert!(matches!(f("deposit,1"), Err(RecordError::Malformed(_))));
+        assert!(matches!(f(""), Err(RecordError::Malformed(_))));
+
+        // Negative amounts are rejected unless the policy allows them, zero
+        // ones only if it says so, and ignored ones never.
+        assert_eq!(f("deposit,1,2,-3"), Err(RecordError::InvalidAmount));
+        assert_eq!(f("dispute,1,2,-3"), Err(RecordError::InvalidAmount));
+        assert!(f("deposit,1,2,0").is_ok());
+        assert!(f("resolve,1,2,-3").is_ok());
+        let positive = |line| super::parse_record(line, AmountPolicy::Positive);
+        assert_eq!(
+            positive("withdrawal,1,2,0"),
+            Err(RecordError::InvalidAmount)
+        );
+        assert!(positive("withdrawal,1,2,0.01").is_ok());
+        assert!(super::parse_record("deposit,1,2,-3", AmountPolicy::Any).is_ok());
+
+        // Amounts are read exactly, however many digits they have.
+        let amount = |line: &str| match super::parse_record(line, AmountPolicy::default()) {
+            Ok((_, transaction)) => transaction.amount().map(|amount| amount.to_string()),
+            Err(_) => None,
+        };
+        for exact in [
+            "18446744073709551616",
+            "1234567890.123456789",
+            "0.0000000000000000001",
+        ] {
+            let line = format!("deposit,1,2,{}", exact);
+            assert_eq!(amount(&line).as_deref(), Some(exact));
+        }
+        assert_eq!(amount("deposit,1,2,1.50").as_deref(), Some("1.5"));
+        assert_eq!(amount("deposit,1,2,1e3").as_deref(), Some("1000"));
+        assert_eq!(amount("deposit,1,2,-0.0").as_deref(), Some("0"));
+        assert!(matches!(
+            f("deposit,1,2,1.2.3"),
+            Err(RecordError::Malformed(_))
+        ));
+        assert!(matches!(
+            f("deposit,1,2,1e99"),
+            Err(RecordError::Malformed(_))
+        ));
+    }
+
+    #[cfg(feature = "wide-ids")]
+    #[test]
+    fn wide_ids() {
+        let input = "\
+type,client,tx,amount
+deposit,70000,5000000000,10
+dispute,70000,5000000000,
+";
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+        assert_eq!(ledger.account(70000).map(Account::held), Some(10.into()));
+    }
+
+    #[test]
+    fn fields_parsed_like_serde() {
+        use super::{CsvHeaders, Record};
+        use crate::policy::AmountPolicy;
+
+        let headers = CsvHeaders::new(csv::StringRecord::from(vec![
+            "client", "type", "tx", "amount",
+        ]));
+        let columns = headers.columns.as_ref().unwrap();
+        let lines = [
+            ("1,deposit,2,3.5", true),
+            (" 1 , withdrawal , 2 , 0.1000 ", true),
+            ("65535,deposit,4294967295,-12.340", true),
+            ("1,dispute,2,", true),
+            ("1,resolve,2", true),
+            ("1,chargeback,007,10", true),
+            ("1,deposit,2,999999999999999", true),
+            ("1,deposit,2,123456789.123456", true),
+            ("1,deposit,2,-0", false),
+            ("1,deposit,2,-0.0", false),
+            ("1,deposit,2,1234567890.1234567", false),
+            ("1,deposit,2,.5", false),
+            ("1,deposit,2,5.", false),
+            ("1,deposit,2,+5", false),
+            ("1,deposit,2,1e3", false),
+            ("65536,deposit,2,1", cfg!(feature = "wide-ids")),
+            ("1,Deposit,2,1", false),
+            ("1,deposit,x,1", false),
+            ("1,deposit,2,1,extra", false),
+        ];
+        for (line, fast) in lines {
+            let fields = csv::StringRecord::from(line.split(',').collect::<Vec<_>>());
+            let parsed = Record::from_fields(fields.as_byte_record(), columns);
+            assert_eq!(parsed.is_some(), fast, "{}", line);
+
+            let mut trimmed = fields.clone();
+            trimmed.trim();
+            let expected = trimmed.deserialize::<Record>(Some(&headers.names));
+            if let Some(parsed) = parsed {
+                let expected = expected.unwrap();
+                assert_eq!(
+                    super::record_to_transaction(&parsed, AmountPolicy::Any),
+                    super::record_to_transaction(&expected, AmountPolicy::Any),
+                    "{}",
+                    line
+                );
+                // The amounts keep the same scale.
+                assert_eq!(
+                    parsed.amount.map(|amount| amount.to_string()),
+                    expected.amount.map(|amount| amount.to_string()),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn header_ordering_is_permissive() {
+        let input = "\
+client,amount,type,tx
+5,10,deposit,1
+";
+
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+        assert_eq!(ledger.accounts().count(), 1);
+        assert!(ledger.account(5).is_some());
+    }
+
+    #[test]
+    fn bad_records_are_ignored() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+foo,1,2,10
+withdraw,1,3,
+dispute,1,,
+";
+
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+        assert_eq!(ledger.accounts().count(), 1);
+        assert_eq!(ledger.account(1).map(Account::available), Some(10.into()));
+    }
+
+    #[test]
+    fn rejects() {
+        let input = "\
+type, client, tx, amount
+deposit, 1, 1, 10
+foo,1,2,10
+withdrawal,1,3,
+withdrawal,1,4,20
+deposit,2,5,1
+";
+
+        let mut ledger = Ledger::default();
+        let mut rejects = vec![];
+        let mut forwarded = vec![];
+        let sink = |reject: Reject| forwarded.push(reject.line);
+        ledger
+            .read_csv_with_rejects(input.as_bytes(), AmountScale::default(), &mut rejects, sink)
+            .expect("should write rejects");
+        assert_eq!(ledger.accounts().count(), 2);
+        assert_eq!(forwarded, [3, 4, 5]);
+
+        let rejects = String::from_utf8(rejects).unwrap();
+        let lines = rejects.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "line,record,reason,message,byte");
+        assert!(lines[1].starts_with(r#"3,"foo,1,2,10",malformed,"line 3: "#));
+        assert!(lines[1].ends_with(",43"));
+        assert_eq!(
+            lines[2],
+            r#"4,"withdrawal,1,3,",missing_amount,line 4: The amount is missing for a transaction type that requires it,54"#
+        );
+        assert_eq!(
+            lines[3],
+            r#"5,"withdrawal,1,4,20",insufficient_funds,line 5: withdrawal for client 1 rejected: Insufficient funds to withdraw requested amount,70"#
+        );
+    }
+
+    #[test]
+    fn max_amount() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,100
+deposit,1,2,100000
+withdrawal,1,3,100.0001
+adjust_credit,1,4,1000
+";
+        let mut ledger = Ledger::builder().max_amount(100.into()).build();
+        let mut rejects = vec![];
+        ledger
+            .read_csv_with_rejects(
+                input.as_bytes(),
+                AmountScale::default(),
+                &mut rejects,
+                |_| {},
+            )
+            .expect("should write rejects");
+        assert_eq!(ledger.account(1).map(Account::available), Some(1100.into()));
+
+        let reasons = csv::Reader::from_reader(rejects.as_slice())
+            .records()
+            .map(|record| record.unwrap()[2].to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(reasons, ["amount_too_large", "amount_too_large"]);
+    }
+
+    #[test]
+    fn apply_batch() {
+        use super::BatchOutcome;
+
+        let mut ledger = Ledger::default();
+        let events = observe(&mut ledger, true);
+
+        let batch = vec![
+            (
+                1,
+                Transaction::Deposit {
+                    new_id: 1,
+                    amount: 10.into(),
+                },
+            ),
+            (
+                1,
+                Transaction::Withdrawal {
+                    new_id: 2,
+                    amount: 20.into(),
+                },
+            ),
+            (
+                1,
+                Transaction::Dispute {
+                    id: 1,
+                    amount: None,
+                },
+            ),
+        ];
+        let mut acknowledged = None;
+        let outcome = ledger.apply_batch(batch, |outcome| {
+            // Every transaction has been applied and observed already.
+            assert_eq!(events.lock().unwrap().len(), 4);
+            acknowledged = Some(outcome.clone());
+        });
+
+        assert_eq!(
+            outcome,
+            BatchOutcome {
+                applied: 2,
+                rejected: vec![(1, TransactionError::InsufficientFunds)],
+                queued: 0,
+            }
+        );
+        assert_eq!(acknowledged, Some(outcome));
+    }
+
+    #[test]
+    fn statement() {
+        let mut ledger = Ledger::builder().keep_statements().build();
+        ledger.read_opening_balances("client,available,held,locked\n1,5,0,false\n".as_bytes());
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10,
+deposit,2,2,7,
+withdrawal,1,3,100,
+withdrawal,1,4,3,60
+dispute,1,1,,1970-01-01T00:02:00Z
+";
+        ledger.read_csv(input.as_bytes());
+
+        let statement = ledger.statement_for(1).expect("account should exist");
+        assert_eq!(statement.len(), 4);
+        assert_eq!(statement[0].transaction, None);
+        assert_eq!(
+            statement[3].transaction,
+            Some(Transaction::Dispute {
+                id: 1,
+                amount: None
+            })
+        );
+        assert!(ledger.statement_for(3).is_none());
+
+        let mut output = vec![];
+        ledger.statement_to_csv(1, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+type,tx,amount,available,held,total,locked,timestamp
+opening,,,5,0,5,false,
+deposit,1,10,15,0,15,false,
+withdrawal,4,3,12,0,12,false,60
+dispute,1,10,2,10,12,false,120
+"
+        );
+
+        // Statements aren't kept by default, nor saved in snapshots then.
+        let mut ledger = Ledger::default();
+        ledger.read_csv(input.as_bytes());
+        assert!(ledger.statement_for(1).is_none());
+        let mut snapshot = vec![];
+        ledger.save_snapshot(&mut snapshot).unwrap();
+        assert!(String::from_utf8(snapshot)
+            .unwrap()
+            .contains("\"statements\":{}"));
+    }
+
+    #[test]
+    fn precision() {
+        use super::Rounding::*;
+
+        let precision = |decimal_places, rounding| Precision {
+            decimal_places,
+            rounding,
+        };
+        let tests = [
+            (precision(2, Truncate), "1.005", "1.00"),
+            (precision(2, Truncate), "-1.009", "-1.00"),
+            (precision(2, HalfEven), "1.005", "1.00"),
+            (precision(2, HalfEven), "1.015", "1.02"),
+            (precision(2, HalfUp), "1.005", "1.01"),
+            (precision(2, HalfUp), "-1.005", "-1.01"),
+            (precision(8, HalfUp), "1.5", "1.50000000"),
+            (precision(0, HalfUp), "2.5", "3"),
+        ];
+        for (precision, balance, expected) in tests {
+            let rounded = precision.apply(balance.parse().unwrap());
+            assert_eq!(
+                rounded.to_string(),
+                expected,
+                "{:?} of {}",
+                precision,
+                balance
+            );
+        }
+
+        let mut output = vec![];
+        let mut ledger = Ledger::builder().precision(precision(2, HalfEven)).build();
+        ledger.read_csv("type,client,tx,amount\ndeposit,1,1,2.345\n".as_bytes());
+        ledger.accounts_to_csv(&mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,2.34,0.00,2.34,false\n"
+        );
+    }
+
+    #[test]
+    fn zero_amount_policy() {
+        use crate::policy::{Policy, ZeroAmountPolicy};
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,0
+withdrawal,1,3,0
+dispute,1,2,
+";
+
+        // Accepted zero-amount transactions are recorded, so they can be
+        // disputed, even though they have no effect on the balances.
+        let mut ledger = Ledger::with_policy(Policy {
+            zero_amounts: ZeroAmountPolicy::Accept,
+            ..Policy::default()
+        });
+        ledger.read_csv(input.as_bytes());
+        let past_txs = &ledger.processed_txs.0;
+        assert_eq!(past_txs.iter().count(), 3);
+        assert!(past_txs.get(1, 2).unwrap().unwrap().state == ProcessedTransactionState::Disputed);
+        assert_eq!(ledger.account(1).map(Account::available), Some(10.into()));
+
+        // Rejected ones are never recorded.
+        let mut ledger = Ledger::with_policy(Policy {
+            zero_amounts: ZeroAmountPolicy::Reject,
+            ..Policy::default()
+        });
+        ledger.read_csv(input.as_bytes());
+        assert_eq!(ledger.processed_txs.0.iter().count(), 1);
+        assert_eq!(
+            ledger.apply(
+                1,
+                Transaction::Deposit {
+                    new_id: 4,
+                    amount: 0.into()
+                }
+            ),
+            Err(TransactionError::ZeroAmount)
+        );
+        assert_eq!(ledger.account(1).map(Account::available), Some(10.into()));
+    }
+
+    #[test]
+    fn amount_policies_combined() {
+        use crate::policy::{AmountPolicy, ZeroAmountPolicy};
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,0
+dispute,1,1,0
+refund,1,1,0
+fee,1,3,0
+";
+        let reasons = |amounts, zero_amounts| {
+            let mut ledger = Ledger::builder()
+                .amounts(amounts)
+                .zero_amounts(zero_amounts)
+                .fee_account(9)
+                .build();
+            let mut rejects = vec![];
+            ledger.read_csv_with(
+                input.as_bytes(),
+                AmountScale::default(),
+                |reject: Reject| rejects.push(reject.reason),
+            );
+            rejects
+        };
+
+        // Zero amounts are checked when lines are read first, so they never
+        // get as far as the zero amount policy.
+        let invalid = ["invalid_amount"; 4];
+        assert_eq!(
+            reasons(AmountPolicy::Positive, ZeroAmountPolicy::Reject),
+            invalid
+        );
+        assert_eq!(
+            reasons(AmountPolicy::Positive, ZeroAmountPolicy::Accept),
+            invalid
+        );
+        // Otherwise the zero amount policy rejects every transaction with a
+        // zero amount before it's applied.
+        assert_eq!(
+            reasons(AmountPolicy::NonNegative, ZeroAmountPolicy::Reject),
+            ["zero_amount"; 4]
+        );
+        // And if it accepts them, only partial disputes and refunds fail.
+        assert_eq!(
+            reasons(AmountPolicy::NonNegative, ZeroAmountPolicy::Accept),
+            ["non_positive_amount"; 2]
+        );
+    }
+
+    #[test]
+    fn builder() {
+        use crate::policy::{ErrorPolicy, TransactionIdPolicy};
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,12
+deposit,2,1,5
+withdrawal,1,3,3
+deposit,2,4,1
+";
+
+        // The second withdrawal exceeds the overdraft, and client 2 reuses
+        // the ID of client 1's deposit. Processing stops at the first error.
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .overdraft(4.into())
+            .transaction_ids(TransactionIdPolicy::Unique)
+            .errors(ErrorPolicy::Abort)
+            .build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+        assert_eq!(rejects, ["duplicate_transaction"]);
+        assert_eq!(ledger.account(1).map(Account::available), Some((-2).into()));
+        assert!(ledger.account(2).is_none());
+
+        // Skipping goes on after the errors.
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .overdraft(4.into())
+            .transaction_ids(TransactionIdPolicy::Unique)
+            .build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+        assert_eq!(rejects, ["duplicate_transaction", "insufficient_funds"]);
+        assert_eq!(ledger.account(2).map(Account::available), Some(1.into()));
+    }
+
+    #[test]
+    fn strict_reading() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,12
+deposit,1,3,5
+";
+        let Err(reject) = Ledger::try_from_csv_reader(input.as_bytes()) else {
+            panic!("the withdrawal should be rejected");
+        };
+        assert_eq!((reject.line, reject.reason), (3, "insufficient_funds"));
+        assert_eq!(reject.record, "withdrawal,1,2,12");
+
+        let input = "type,client,tx,amount\ndeposit,1,1,10\n";
+        let ledger = Ledger::try_from_csv_reader(input.as_bytes()).ok();
+        let account = ledger.as_ref().and_then(|ledger| ledger.account(1));
+        assert_eq!(account.map(Account::available), Some(10.into()));
+    }
+
+    #[test]
+    fn collected_errors() {
+        use crate::policy::ErrorPolicy;
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,2,2,12
+deposit,3,x,5
+withdrawal,1,3,12
+deposit,2,4,1
+";
+        for shards in [1, 2] {
+            let mut ledger = Ledger::builder()
+                .errors(ErrorPolicy::Collect)
+                .shards(shards)
+                .build();
+            let mut reported = vec![];
+            ledger.read_csv_with(
+                input.as_bytes(),
+                AmountScale::default(),
+                |reject: Reject| reported.push(reject),
+            );
+            assert_eq!(reported, []);
+            assert_eq!(ledger.account(2).map(Account::available), Some(1.into()));
+
+            let report = ledger.take_report();
+            let lines = report.rejects.iter().map(|reject| reject.line);
+            assert_eq!(lines.collect::<Vec<_>>(), [3, 4, 5], "{} shards", shards);
+            let counts = report.counts().into_iter().collect::<Vec<_>>();
+            assert_eq!(counts, [("insufficient_funds", 2), ("malformed", 1)]);
+            assert!(ledger.take_report().is_empty());
+        }
+    }
+
+    #[test]
+    fn unshardable() {
+        use crate::{policy::ClientIdPolicy, tx_store::CompactTxStore};
+
+        assert_eq!(Ledger::builder().shards(4).build().parallel_shards(), 4);
+        let unshardable = [
+            Ledger::builder().tx_store(CompactTxStore::default()),
+            Ledger::builder().client_ids(ClientIdPolicy::Text),
+            Ledger::builder().reorder_window(10),
+        ];
+        for builder in unshardable {
+            let mut ledger = builder.shards(4).build();
+            assert_eq!(ledger.parallel_shards(), 1);
+            let input = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\n";
+            ledger.read_csv_with(
+                input.as_bytes(),
+                AmountScale::default(),
+                |reject: Reject| panic!("{:?}", reject),
+            );
+            assert_eq!(ledger.accounts().count(), 2);
+        }
+    }
+
+    #[test]
+    fn global_transaction_ids() {
+        use crate::policy::TransactionIdPolicy;
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,10
+dispute,2,1,
+dispute,1,1,
+deposit,2,1,5
+resolve,3,1,
+dispute,2,3,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .transaction_ids(TransactionIdPolicy::Global)
+            .build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+        assert_eq!(
+            rejects,
+            [
+                (4, "client_mismatch"),
+                (6, "duplicate_transaction"),
+                (7, "client_mismatch"),
+                (8, "nonexistent_transaction"),
+            ]
+        );
+        assert_eq!(ledger.account(1).map(Account::held), Some(10.into()));
+
+        // The owners of the IDs are part of snapshots.
+        let mut snapshot = vec![];
+        ledger.save_snapshot(&mut snapshot).unwrap();
+        let mut restored = Ledger::builder()
+            .transaction_ids(TransactionIdPolicy::Global)
+            .build();
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(
+            restored.apply(2, Transaction::Resolve { id: 1 }),
+            Err(TransactionError::ClientMismatch)
+        );
+        assert_eq!(restored.apply(1, Transaction::Resolve { id: 1 }), Ok(()));
+    }
+
+    #[test]
+    fn account_overdrafts() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,15
+deposit,2,3,10
+withdrawal,2,4,15
+withdrawal,2,5,100
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .overdraft(1.into())
+            .account_overdraft(2, 100.into())
+            .build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+        assert_eq!(rejects, ["insufficient_funds", "insufficient_funds"]);
+        assert_eq!(ledger.account(2).map(Account::available), Some((-5).into()));
+
+        // The summaries have how far each account is overdrawn.
+        let mut output = vec![];
+        ledger.accounts_to_csv(&mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client,available,held,total,locked,overdrawn
+1,10.0000,0.0000,10.0000,false,0.0000
+2,-5.0000,0.0000,-5.0000,false,5.0000
+"
+        );
+    }
+
+    #[test]
+    fn deposits_only_disputes() {
+        use crate::policy::DisputePolicy;
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,4
+dispute,1,2,
+dispute,1,1,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .disputes(DisputePolicy::DepositsOnly)
+            .keep_statements()
+            .build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+
+        // The withdrawal was applied but not kept, so it can't be disputed.
+        assert_eq!(rejects, ["nonexistent_transaction"]);
+        assert!(ledger.transaction(1, 2).unwrap().is_none());
+        assert_eq!(ledger.find_transactions(&Default::default()).count(), 1);
+        let account = ledger.account(1).unwrap();
+        assert_eq!(account.available(), (-4).into());
+        assert_eq!(account.held(), 10.into());
+        // Statements still have the withdrawn amount.
+        assert_eq!(ledger.statements[&1][1].amount, Some(4.into()));
+    }
+
+    #[test]
+    fn reversed_withdrawal_disputes() {
+        use crate::{policy::WithdrawalDisputePolicy, tx_store::CompactTxStore};
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,4
+dispute,1,2,
+resolve,1,2,
+dispute,1,2,3
+chargeback,1,2,
+deposit,2,3,10
+dispute,2,3,
+";
+        let mut ledger = Ledger::builder()
+            .withdrawal_disputes(WithdrawalDisputePolicy::Reverse)
+            .tx_store(CompactTxStore::default())
+            .build();
+        ledger.read_csv(input.as_bytes());
+
+        // The disputes of the withdrawal held nothing, and the chargeback
+        // credited the disputed part back.
+        let account = ledger.account(1).unwrap();
+        assert_eq!(account.available(), 9.into());
+        assert_eq!(account.held(), 0.into());
+        assert!(account.is_frozen());
+        // Disputes of deposits still hold their amount.
+        let account = ledger.account(2).unwrap();
+        assert_eq!(account.available(), 0.into());
+        assert_eq!(account.held(), 10.into());
+    }
+
+    #[test]
+    fn refunds() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,20
+refund,1,1,
+refund,1,2,25
+refund,1,2,5.5
+dispute,1,2,
+refund,1,2,1
+refund,1,3,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder().keep_statements().build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+
+        assert_eq!(
+            rejects,
+            [
+                "refund_too_large",
+                "not_settled",
+                "not_settled",
+                "nonexistent_transaction"
+            ]
+        );
+        assert_eq!(
+            ledger.account(1).map(Account::available),
+            Some("14.5".parse().unwrap())
+        );
+        let refunded = ledger.transaction(1, 2).unwrap().unwrap();
+        assert_eq!(refunded.state, ProcessedTransactionState::Refunded);
+        assert_eq!(refunded.amount, 20.into());
+        // Statements have the amount refunded.
+        let amounts = ledger.statements[&1]
+            .iter()
+            .map(|entry| entry.amount.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            amounts,
+            [10.into(), 20.into(), 10.into(), "5.5".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn refund_checks() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,5
+refund,1,2,
+refund,1,1,0
+deposit,1,3,0
+refund,1,3,
+deposit,2,4,10
+close_account,2,5,
+refund,2,4,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder().sweep_account(9).build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+        assert_eq!(
+            rejects,
+            [
+                "not_refundable",
+                "non_positive_amount",
+                "non_positive_amount",
+                "account_closed"
+            ]
+        );
+        assert_eq!(ledger.account(1).map(Account::available), Some(5.into()));
+
+        // Negative refunds don't get past the account either.
+        let refund = Transaction::Refund {
+            id: 1,
+            amount: Some((-1).into()),
+        };
+        assert_eq!(
+            ledger.apply(1, refund),
+            Err(TransactionError::NonPositiveAmount)
+        );
+    }
+
+    #[test]
+    fn partial_disputes() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,100
+dispute,1,1,120
+dispute,1,1,0
+dispute,1,1,20
+resolve,1,1,
+dispute,1,1,30
+chargeback,1,1,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder().keep_statements().build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+
+        assert_eq!(rejects, ["dispute_too_large", "non_positive_amount"]);
+        // Only the disputed part was charged back.
+        let account = ledger.account(1).unwrap();
+        assert_eq!(account.available(), 70.into());
+        assert_eq!(account.held(), 0.into());
+        assert!(account.is_frozen());
+        let disputed = ledger.transaction(1, 1).unwrap().unwrap();
+        assert_eq!(disputed.state, ProcessedTransactionState::ChargeBacked);
+        assert_eq!(disputed.amount, 100.into());
+        assert_eq!(disputed.disputed, Some(30.into()));
+        // Statements have the amount held or released.
+        let amounts = ledger.statements[&1]
+            .iter()
+            .map(|entry| entry.amount.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            amounts,
+            [100.into(), 20.into(), 20.into(), 30.into(), 30.into()]
+        );
+
+        // Negative amounts don't get past the account either.
+        let deposit = Transaction::Deposit {
+            new_id: 2,
+            amount: 10.into(),
+        };
+        ledger.apply(2, deposit).unwrap();
+        let dispute = Transaction::Dispute {
+            id: 2,
+            amount: Some((-1).into()),
+        };
+        assert_eq!(
+            ledger.apply(2, dispute),
+            Err(TransactionError::NonPositiveAmount)
+        );
+        assert_eq!(ledger.account(2).unwrap().held(), 0.into());
+    }
+
+    #[test]
+    fn lock_records() {
+        use crate::{account::FreezeReason, events::LedgerEvent};
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+dispute,1,1,
+chargeback,1,1,
+unlock,1,0,
+deposit,1,2,5
+lock,1,0,
+deposit,1,3,5
+unlock,2,0,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder().keep_events().build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+
+        assert_eq!(rejects, [(8, "account_frozen"), (9, "nonexistent_account")]);
+        let account = ledger.account(1).unwrap();
+        assert_eq!(account.available(), 5.into());
+        assert_eq!(
+            account.freeze_reason(),
+            Some(&FreezeReason::Manual("lock record".to_owned()))
+        );
+        let unfrozen = ledger
+            .events()
+            .iter()
+            .filter(|event| matches!(event, LedgerEvent::AccountUnfrozen { client: 1, .. }));
+        assert_eq!(unfrozen.count(), 1);
+
+        // Locks and unlocks are rebuilt from their events.
+        let mut rebuilt = Ledger::default();
+        rebuilt.rebuild_from_events(ledger.events()).unwrap();
+        let account = rebuilt.account(1).unwrap();
+        assert_eq!(account.available(), 5.into());
+        assert!(account.is_frozen());
+    }
+
+    #[test]
+    fn explicit_accounts() {
+        use crate::{account::AccountMetadata, policy::AccountCreationPolicy};
+
+        let input = "\
+type,client,tx,amount,currency,reference,risk_tier
+open_account,1,0,,usd,CUST-0042,low
+deposit,1,1,10,usd,,
+deposit,2,2,10,,,
+open_account,1,0,,,,
+open_account,3,0,,,,
+deposit,3,3,5,,,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .account_creation(AccountCreationPolicy::Explicit)
+            .keep_events()
+            .build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+
+        assert_eq!(rejects, [(4, "nonexistent_account"), (5, "account_exists")]);
+        assert!(ledger.account(2).is_none());
+        assert_eq!(ledger.account(3).unwrap().available(), 5.into());
+        let usd = "USD".parse().unwrap();
+        assert_eq!(
+            ledger.account_metadata(1),
+            Some(&AccountMetadata {
+                reference: Some("CUST-0042".to_owned()),
+                currency: Some(usd),
+                risk_tier: Some("low".to_owned()),
+            })
+        );
+        assert_eq!(
+            ledger.account_metadata(3),
+            Some(&AccountMetadata::default())
+        );
+
+        let mut output = vec![];
+        ledger.account_metadata_to_csv(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,reference,currency,risk_tier\n1,CUST-0042,USD,low\n3,,,\n"
+        );
+
+        // Accounts are created again when the events are replayed.
+        let mut rebuilt = Ledger::default();
+        rebuilt.rebuild_from_events(ledger.events()).unwrap();
+        assert_eq!(rebuilt.account_metadata(1), ledger.account_metadata(1));
+        assert_eq!(rebuilt.account(3).unwrap().available(), 5.into());
+    }
+
+    #[test]
+    fn closed_accounts_charged_or_adjusted() {
+        let input = "\
+type,client,tx,amount,currency
+deposit,1,1,10,
+deposit,1,2,3,eur
+close_account,1,0,,
+fee,1,3,1,
+fee,1,4,1,eur
+adjust_credit,1,5,5,
+adjust_debit,1,6,5,
+adjust_credit,1,7,5,eur
+fee,2,8,1,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder().fee_account(8).sweep_account(9).build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+
+        assert_eq!(
+            rejects,
+            [
+                (5, "account_closed"),
+                (6, "account_closed"),
+                (7, "account_closed"),
+                (8, "account_closed"),
+                (9, "account_closed"),
+                (10, "insufficient_funds"),
+            ]
+        );
+        let account = ledger.account(1).unwrap();
+        assert_eq!(account.total().unwrap(), 0.into());
+        assert!(ledger.account(8).is_none());
+    }
+
+    #[test]
+    fn closed_accounts() {
+        let input = "\
+type,client,tx,amount,currency
+deposit,1,1,10,
+deposit,1,2,3,eur
+deposit,2,3,5,
+dispute,2,3,,
+close_account,1,0,,
+close_account,2,0,,
+deposit,1,4,1,
+withdrawal,1,5,1,
+close_account,1,0,,
+deposit,3,6,1,
+withdrawal,3,7,1,
+close_account,3,0,,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder().sweep_account(9).keep_events().build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+
+        assert_eq!(
+            rejects,
+            [
+                (7, "balance_not_zero"),
+                (8, "account_closed"),
+                (9, "account_closed"),
+                (10, "account_closed"),
+            ]
+        );
+        let account = ledger.account(1).unwrap();
+        assert!(account.is_closed());
+        assert_eq!(account.total().unwrap(), 0.into());
+        let eur = "EUR".parse().unwrap();
+        let sweep = ledger.account(9).unwrap();
+        assert_eq!(sweep.available(), 10.into());
+        assert_eq!(sweep.in_currency(eur).unwrap().available(), 3.into());
+        assert!(!ledger.account(2).unwrap().is_closed());
+
+        // Without a sweep account only empty accounts can be closed.
+        let mut rebuilt = Ledger::default();
+        assert_eq!(
+            rebuilt.apply(
+                4,
+                Transaction::Deposit {
+                    new_id: 1,
+                    amount: 1.into(),
+                }
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            rebuilt.close_account(4),
+            Err(TransactionError::BalanceNotZero)
+        );
+
+        // Closings sweep to the same account when they're replayed.
+        let mut rebuilt = Ledger::default();
+        rebuilt.rebuild_from_events(ledger.events()).unwrap();
+        assert_eq!(rebuilt.account(9).unwrap().available(), 10.into());
+
+        let mut output = vec![];
+        ledger.accounts_to_csv(&mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client,currency,available,held,total,locked,closed
+1,EUR,0.00,0.00,0.00,false,true
+2,,0.0000,5.0000,5.0000,false,false
+3,,0.0000,0.0000,0.0000,false,true
+9,,10.0000,0.0000,10.0000,false,false
+9,EUR,3.00,0.00,3.00,false,false
+"
+        );
+    }
+
+    #[test]
+    fn authorizations() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,100
+authorize,1,2,30
+authorize,1,3,500
+authorize,1,4,20
+capture,1,2,
+capture,1,2,
+deposit,2,5,1
+deposit,2,6,1
+void,1,4,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .authorization_window(3)
+            .keep_events()
+            .keep_statements()
+            .build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+
+        assert_eq!(
+            rejects,
+            ["insufficient_funds", "not_authorized", "not_authorized"]
+        );
+        // The capture took the held funds, and the other authorization
+        // expired three transactions later, releasing them.
+        let account = ledger.account(1).unwrap();
+        assert_eq!(account.available(), 70.into());
+        assert_eq!(account.held(), 0.into());
+        let captured = ledger.transaction(1, 2).unwrap().unwrap();
+        assert_eq!(captured.state, ProcessedTransactionState::Settled);
+        let voided = ledger.transaction(1, 4).unwrap().unwrap();
+        assert_eq!(voided.state, ProcessedTransactionState::Voided);
+        let last = ledger.statements[&1].last().unwrap();
+        assert_eq!(last.transaction, Some(Transaction::Void { id: 4 }));
+        assert_eq!(last.amount, Some(20.into()));
+
+        // Rebuilding voids the expired authorization once.
+        let mut rebuilt = Ledger::builder().authorization_window(3).build();
+        rebuilt.rebuild_from_events(ledger.events()).unwrap();
+        assert_eq!(rebuilt.account(1).unwrap().available(), 70.into());
+        assert_eq!(
+            rebuilt.transaction(1, 4).unwrap(),
+            ledger.transaction(1, 4).unwrap()
+        );
+
+        // The longest window keeps authorizations until they're captured or
+        // voided.
+        let mut ledger = Ledger::builder().authorization_window(u64::MAX).build();
+        ledger.read_csv(input.trim_end_matches("void,1,4,\n").as_bytes());
+        let account = ledger.account(1).unwrap();
+        assert_eq!(
+            (account.available(), account.held()),
+            (50.into(), 20.into())
+        );
+        let authorized = ledger.transaction(1, 4).unwrap().unwrap();
+        assert_eq!(authorized.state, ProcessedTransactionState::Authorized);
+    }
+
+    #[test]
+    fn scheduled_transactions() {
+        let input = "\
+type,client,tx,amount,effective_at
+deposit,1,1,10,
+withdrawal,1,2,5,200
+deposit,2,3,7,100
+withdrawal,1,4,20,150
+deposit,1,5,1,50
+";
+        let mut ledger = Ledger::builder().shards(2).build();
+        assert_eq!(ledger.advance_to(60), []);
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| panic!("{:?}", reject),
+        );
+
+        // Only the transactions dated by now were applied.
+        assert_eq!(ledger.account(1).unwrap().available(), 11.into());
+        assert!(ledger.account(2).is_none());
+        let times = ledger
+            .scheduled()
+            .iter()
+            .map(|&(at, client, _)| (at, client));
+        assert_eq!(times.collect::<Vec<_>>(), [(100, 2), (150, 1), (200, 1)]);
+
+        // Scheduled transactions are kept in snapshots.
+        let mut snapshot = vec![];
+        ledger.save_snapshot(&mut snapshot).unwrap();
+        let mut ledger = Ledger::default();
+        ledger.load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(ledger.now(), 60);
+        assert_eq!(ledger.scheduled().len(), 3);
+
+        let withdrawal = Transaction::Withdrawal {
+            new_id: 4,
+            amount: 20.into(),
+        };
+        assert_eq!(
+            ledger.advance_to(150),
+            [(1, withdrawal, TransactionError::InsufficientFunds)]
+        );
+        assert_eq!(ledger.account(2).unwrap().available(), 7.into());
+        assert_eq!(ledger.advance_to(100), []);
+        assert_eq!(ledger.scheduled().len(), 1);
+        assert_eq!(ledger.advance_to(200), []);
+        assert_eq!(ledger.account(1).unwrap().available(), 6.into());
+    }
+
+    #[test]
+    fn withdrawal_limits() {
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,100,
+withdrawal,1,2,30,1000
+withdrawal,1,3,30,2000
+withdrawal,2,4,1,2000
+withdrawal,1,4,30,3000
+withdrawal,1,5,30,4000
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .withdrawal_limit("2,50,2500".parse().unwrap())
+            .build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+
+        // The second and third withdrawals go beyond the amount, and the
+        // fourth comes after the window of the first. Client 2's withdrawal
+        // counts towards a limit of its own.
+        assert_eq!(
+            rejects,
+            [
+                (4, "limit_exceeded"),
+                (5, "insufficient_funds"),
+                (6, "limit_exceeded")
+            ]
+        );
+        assert_eq!(ledger.account(1).unwrap().available(), 40.into());
+    }
+
+    #[test]
+    fn dispute_window() {
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10,2024-01-01
+deposit,1,2,10,2024-03-01
+deposit,1,3,10,
+dispute,1,1,,2024-05-01
+dispute,1,2,,2024-05-01
+dispute,1,3,,2024-05-01
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder().dispute_window(90).build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+
+        // The first deposit is 121 days old when it's disputed, the second
+        // 61 days, and the third has no time to go by.
+        assert_eq!(rejects, [(5, "dispute_window_expired")]);
+        assert_eq!(ledger.account(1).unwrap().held(), 20.into());
+    }
+
+    #[test]
+    fn fees() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+fee,1,2,0.25
+fee,2,3,1
+dispute,1,2,
+deposit,2,4,5
+fee,2,5,1.5
+";
+        let mut rejects = vec![];
+        Ledger::default().read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+        assert_eq!(
+            rejects,
+            [
+                "no_fee_account",
+                "no_fee_account",
+                "nonexistent_transaction",
+                "no_fee_account"
+            ]
+        );
+
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder().fee_account(9).keep_statements().build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+        assert_eq!(rejects, ["insufficient_funds", "nonexistent_transaction"]);
+        assert_eq!(
+            ledger.account(1).map(Account::available),
+            Some("9.75".parse().unwrap())
+        );
+        assert_eq!(
+            ledger.account(2).map(Account::available),
+            Some("3.5".parse().unwrap())
+        );
+        assert_eq!(
+            ledger.account(9).map(Account::available),
+            Some("1.75".parse().unwrap())
+        );
+        let fees = ledger.statements[&9]
+            .iter()
+            .map(|entry| (entry.amount, entry.available))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fees,
+            [
+                (Some("0.25".parse().unwrap()), "0.25".parse().unwrap()),
+                (Some("1.5".parse().unwrap()), "1.75".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn minor_units() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,1050
+withdrawal,1,2,25
+dispute,1,1,
+";
+        let mut ledger = Ledger::default();
+        let check = ledger.read_csv_with(input.as_bytes(), "2".parse().unwrap(), |_: Reject| {});
+        assert!(check.warning().is_none());
+        let account = ledger.account(1).unwrap();
+        assert_eq!(account.held(), "10.50".parse().unwrap());
+        assert_eq!(account.total().unwrap(), "10.25".parse().unwrap());
+
+        // Read as decimals the amounts aren't all large, so they don't look
+        // like minor units.
+        let check = Ledger::default().read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |_: Reject| {},
+        );
+        assert!(check.warning().is_none());
+    }
+
+    #[test]
+    fn accounts() {
+        let ledger = Ledger::from_csv_reader(
+            "type,client,tx,amount\ndeposit,2,1,10\ndeposit,1,2,5\n".as_bytes(),
+        );
+
+        let mut accounts = ledger
+            .accounts()
+            .map(|(client, account)| (client, account.total().unwrap()))
+            .collect::<Vec<_>>();
+        accounts.sort();
+        assert_eq!(accounts, [(1, 5.into()), (2, 10.into())]);
+        assert_eq!(ledger.account(2).map(Account::available), Some(10.into()));
+    }
+
+    #[test]
+    fn transaction() {
+        use super::TransactionView;
+
+        let ledger = Ledger::from_csv_reader(
+            "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\ndispute,2,2,\n".as_bytes(),
+        );
+        assert_eq!(
+            ledger.transaction(2, 2).unwrap(),
+            Some(TransactionView {
+                amount: 5.into(),
+                state: ProcessedTransactionState::Disputed,
+                disputed: None,
+                timestamp: None,
+                currency: None,
+            })
+        );
+        assert_eq!(
+            ledger.transaction(1, 1).unwrap().map(|tx| tx.state),
+            Some(ProcessedTransactionState::Settled)
+        );
+        // Transactions are only found through the account they belong to.
+        assert_eq!(ledger.transaction(1, 2).unwrap(), None);
+        assert_eq!(ledger.transaction(3, 3).unwrap(), None);
+    }
+
+    #[test]
+    fn timestamps() {
+        let mut ledger = Ledger::builder().keep_events().keep_statements().build();
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10,2024-01-02T00:00:00Z
+deposit,1,2,5,
+dispute,1,1,,1704160800
+";
+        ledger.read_csv(input.as_bytes());
+        // Disputes don't change the time of the transaction they refer to.
+        let time = |ledger: &Ledger, tx| {
+            ledger
+                .transaction(1, tx)
+                .unwrap()
+                .and_then(|tx| tx.timestamp)
+        };
+        assert_eq!(time(&ledger, 1), Some(1704153600));
+        assert_eq!(time(&ledger, 2), None);
+        let times = ledger
+            .statement_for(1)
+            .unwrap()
+            .iter()
+            .map(|entry| entry.timestamp);
+        assert_eq!(
+            times.collect::<Vec<_>>(),
+            [Some(1704153600), None, Some(1704160800)]
+        );
+
+        let mut rebuilt = Ledger::builder().keep_statements().build();
+        rebuilt.rebuild_from_events(ledger.events()).unwrap();
+        assert_eq!(time(&rebuilt, 1), Some(1704153600));
+        assert_eq!(rebuilt.statement_for(1), ledger.statement_for(1));
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut ledger = Ledger::from_csv_reader(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,5
+withdrawal,2,3,1
+dispute,2,3,
+chargeback,2,3,
+"
+            .as_bytes(),
+        );
+        let mut snapshot = vec![];
+        ledger.save_snapshot(&mut snapshot).unwrap();
+
+        let mut restored = Ledger::default();
+        assert!(restored.load_snapshot("{".as_bytes()).is_err());
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(
+            restored.statement_for(2),
+            ledger.statement_for(2),
+            "the statements should be restored"
+        );
+
+        // Both ledgers carry on from where the snapshot was taken.
+        let input = "type,client,tx,amount\ndispute,1,1,\ndeposit,1,4,1\n";
+        ledger.read_csv(input.as_bytes());
+        restored.read_csv(input.as_bytes());
+        let (mut expected, mut output) = (vec![], vec![]);
+        ledger.accounts_to_csv(&mut expected);
+        restored.accounts_to_csv(&mut output);
+        assert_eq!(
+            String::from_utf8(output).expect("output should be UTF8"),
+            String::from_utf8(expected).expect("output should be UTF8")
+        );
+    }
+
+    #[test]
+    fn max_held_ratio() {
+        use crate::policy::HeldRatioAction;
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,6
+deposit,1,2,4
+dispute,1,1,
+dispute,1,2,
+";
+
+        // Disputing the second deposit would hold all the funds.
+        for (action, blocked, held) in [
+            (HeldRatioAction::Block, true, 6),
+            (HeldRatioAction::Flag, false, 10),
+        ] {
+            let mut rejects = vec![];
+            let mut ledger = Ledger::builder()
+                .max_held_ratio("0.6".parse().unwrap(), action)
+                .build();
+            let events = observe(&mut ledger, false);
+            ledger.read_csv_with(
+                input.as_bytes(),
+                AmountScale::default(),
+                |reject: Reject| rejects.push(reject.reason),
+            );
+            assert_eq!(
+                *events.lock().unwrap(),
+                vec![LedgerEvent::HeldRatioExceeded {
+                    client: 1,
+                    tx: 2,
+                    held: 10.into(),
+                    total: 10.into(),
+                    blocked,
+                }]
+            );
+            let expected: &[&str] = if blocked {
+                &["held_ratio_exceeded"]
+            } else {
+                &[]
+            };
+            assert_eq!(rejects, expected);
+            assert_eq!(ledger.account(1).map(Account::held), Some(held.into()));
+        }
+
+        // Near the largest amount, disputes holding more than funds can hold
+        // fail with `Overflow` instead of panicking, also with ratios above
+        // 1, which only the builder allows, whose limits can be beyond what
+        // decimals hold.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+withdrawal,1,2,1
+deposit,1,3,1
+dispute,1,1,
+dispute,1,3,
+dispute,1,2,
+"
+        );
+        for ratio in ["1", "2"] {
+            let mut rejects = vec![];
+            let mut ledger = Ledger::builder()
+                .max_held_ratio(ratio.parse().unwrap(), HeldRatioAction::Block)
+                .build();
+            ledger.read_csv_with(
+                input.as_bytes(),
+                AmountScale::default(),
+                |reject: Reject| rejects.push(reject.reason),
+            );
+            // Fixed-point funds hold less than decimals, so the ratio is
+            // checked, and it's exceeded first.
+            let expected = match (cfg!(feature = "fixed-point"), ratio) {
+                (true, "1") => "held_ratio_exceeded",
+                _ => "overflow",
+            };
+            assert_eq!(rejects, [expected, expected], "{}", ratio);
+            assert_eq!(ledger.account(1).map(Account::held), Some(max));
+        }
+    }
+
+    #[test]
+    fn out_of_order() {
+        use crate::policy::OutOfOrderPolicy;
+
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10,100
+withdrawal,1,2,5,120
+deposit,1,3,1,110
+deposit,1,4,1,60
+deposit,2,5,1,50
+";
+        let out_of_order = |timestamp, latest, rejected| LedgerEvent::OutOfOrder {
+            client: 1,
+            timestamp,
+            latest,
+            rejected,
+        };
+
+        for (policy, rejected, available) in [
+            (OutOfOrderPolicy::Reject, true, 5),
+            (OutOfOrderPolicy::Warn, false, 7),
+        ] {
+            let mut rejects = vec![];
+            let mut ledger = Ledger::builder().out_of_order(policy).build();
+            let events = observe(&mut ledger, false);
+            ledger.read_csv_with(
+                input.as_bytes(),
+                AmountScale::default(),
+                |reject: Reject| rejects.push(reject.line),
+            );
+            assert_eq!(
+                *events.lock().unwrap(),
+                [
+                    out_of_order(110, 120, rejected),
+                    out_of_order(60, 120, rejected)
+                ]
+            );
+            let expected: &[u64] = if rejected { &[4, 5] } else { &[] };
+            assert_eq!(rejects, expected);
+            assert_eq!(ledger.account(1).unwrap().available(), available.into());
+        }
+
+        // Within the window the deposit at 110 is put before the withdrawal,
+        // but the one at 60 is too late for that.
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .out_of_order(OutOfOrderPolicy::Reject)
+            .reorder_window(20)
+            .keep_statements()
+            .build();
+        let events = observe(&mut ledger, false);
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.line),
+        );
+        assert_eq!(*events.lock().unwrap(), [out_of_order(60, 100, true)]);
+        assert_eq!(rejects, [5]);
+        let applied = ledger.statement_for(1).unwrap().iter();
+        let ids = applied.filter_map(|entry| entry.transaction.as_ref()?.id());
+        assert_eq!(ids.collect::<Vec<_>>(), [1, 3, 2]);
+        assert_eq!(ledger.account(2).unwrap().available(), 1.into());
+    }
+
+    #[test]
+    fn early_disputes() {
+        use crate::policy::{DisputePolicy, EarlyDisputePolicy};
+
+        let input = "\
+type,client,tx,amount
+dispute,1,1,
+dispute,2,9,
+deposit,1,1,10
+deposit,1,2,5
+dispute,1,3,
+withdrawal,1,3,1
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .disputes(DisputePolicy::DepositsOnly)
+            .build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.line),
+        );
+        assert_eq!(rejects, [2, 3, 6]);
+
+        // Withdrawals aren't kept, so the parked dispute of one fails once
+        // it comes.
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .disputes(DisputePolicy::DepositsOnly)
+            .early_disputes(EarlyDisputePolicy::Park)
+            .keep_statements()
+            .build();
+        let events = observe(&mut ledger, false);
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.line),
+        );
+        assert_eq!(rejects, [] as [u64; 0]);
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                LedgerEvent::DisputeParked { client: 1, tx: 1 },
+                LedgerEvent::DisputeParked { client: 2, tx: 9 },
+                LedgerEvent::DisputeParked { client: 1, tx: 3 },
+                LedgerEvent::ParkedDisputeFailed {
+                    client: 1,
+                    tx: 3,
+                    error: TransactionError::NonexistentTransaction
+                },
+            ]
+        );
+        let account = ledger.account(1).unwrap();
+        assert_eq!((account.available(), account.held()), (4.into(), 10.into()));
+        let applied = ledger.statement_for(1).unwrap().iter();
+        let kinds = applied.filter_map(|entry| Some(entry.transaction.as_ref()?.kind()));
+        assert_eq!(
+            kinds.collect::<Vec<_>>(),
+            ["deposit", "dispute", "deposit", "withdrawal"]
+        );
+        let dispute = Transaction::Dispute {
+            id: 9,
+            amount: None,
+        };
+        assert_eq!(
+            ledger.parked_disputes().collect::<Vec<_>>(),
+            [(2, &dispute)]
+        );
+    }
+
+    #[test]
+    fn unkept_early_disputes() {
+        use crate::policy::{DisputePolicy, EarlyDisputePolicy};
+
+        // Withdrawals aren't kept, so disputes of those that came already
+        // would never be applied, and neither would disputes of fees.
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,1
+fee,1,3,1
+dispute,1,2,
+dispute,1,3,
+dispute,1,4,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .disputes(DisputePolicy::DepositsOnly)
+            .early_disputes(EarlyDisputePolicy::Park)
+            .fee_account(9)
+            .build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+        assert_eq!(
+            rejects,
+            [
+                (5, "nonexistent_transaction"),
+                (6, "nonexistent_transaction")
+            ]
+        );
+        let parked = ledger
+            .parked_disputes()
+            .map(|(client, tx)| (client, tx.id()));
+        assert_eq!(parked.collect::<Vec<_>>(), [(1, Some(4))]);
+
+        // Snapshots remember them.
+        let mut snapshot = vec![];
+        ledger.save_snapshot(&mut snapshot).unwrap();
+        let mut restored = Ledger::builder()
+            .disputes(DisputePolicy::DepositsOnly)
+            .early_disputes(EarlyDisputePolicy::Park)
+            .build();
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+        let dispute = Transaction::Dispute {
+            id: 2,
+            amount: None,
+        };
+        assert_eq!(
+            restored.apply(1, dispute),
+            Err(TransactionError::NonexistentTransaction)
+        );
+    }
+
+    #[test]
+    fn max_parked_disputes() {
+        use crate::policy::EarlyDisputePolicy;
+
+        let input = "\
+type,client,tx,amount
+dispute,1,1,
+dispute,1,2,
+dispute,1,3,
+dispute,2,4,
+deposit,1,1,5
+dispute,1,5,
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder()
+            .early_disputes(EarlyDisputePolicy::Park)
+            .max_parked_disputes(2)
+            .build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+        // The limit is by account, and applying a parked dispute makes room
+        // for another.
+        assert_eq!(rejects, [(4, "nonexistent_transaction")]);
+        let parked = ledger
+            .parked_disputes()
+            .map(|(client, tx)| (client, tx.id()));
+        let mut parked = parked.collect::<Vec<_>>();
+        parked.sort();
+        assert_eq!(parked, [(1, Some(2)), (1, Some(5)), (2, Some(4))]);
+        assert_eq!(ledger.account(1).unwrap().held(), 5.into());
+    }
+
+    #[test]
+    fn currencies() {
+        let input = "\
+type,client,tx,amount,currency
+deposit,1,1,10,usd
+deposit,1,2,5,EUR
+withdrawal,1,3,6,EUR
+withdrawal,1,3,2,EUR
+dispute,1,1,,
+dispute,1,2,,USD
+deposit,2,4,3,
+deposit,2,5,1.5,JPY
+";
+        let mut rejects = vec![];
+        let mut ledger = Ledger::builder().keep_statements().build();
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+        // Balances in different currencies don't make up for each other, and
+        // transactions are only referred to in their own currency.
+        assert_eq!(
+            rejects,
+            [
+                (4, "insufficient_funds"),
+                (7, "currency_mismatch"),
+                (9, "too_many_decimals")
+            ]
+        );
+        let usd = "USD".parse().unwrap();
+        let account = ledger.account(1).unwrap();
+        assert_eq!((account.available(), account.held()), (0.into(), 0.into()));
+        let balance = account.in_currency(usd).unwrap();
+        assert_eq!((balance.available(), balance.held()), (0.into(), 10.into()));
+        let dispute = ledger.statement_for(1).unwrap().last().unwrap();
+        assert_eq!(dispute.currency, Some(usd));
+
+        // Accounts only have a row without a currency if there's anything
+        // in it. Balances in currencies have their minor units.
+        let expected = "\
+client,currency,available,held,total,locked
+1,EUR,3.00,0.00,3.00,false
+1,USD,0.00,10.00,10.00,false
+2,,3.0000,0.0000,3.0000,false
+";
+        let mut closing = vec![];
+        ledger.closing_balances_to_csv(&mut closing).unwrap();
+        let mut output = vec![];
+        ledger.accounts_to_csv(&mut output);
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+
+        let mut carried = Ledger::default();
+        carried.read_opening_balances(closing.as_slice());
+        let mut output = vec![];
+        carried.accounts_to_csv(&mut output);
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    // Record the events emitted by the ledger, optionally including the
+    // `TransactionApplied` and `AccountUpdated` events that follow every
+    // transaction.
+    fn observe(ledger: &mut Ledger, updates: bool) -> Arc<Mutex<Vec<LedgerEvent>>> {
+        let events = Arc::new(Mutex::new(vec![]));
+        let observed = events.clone();
+        ledger.add_observer(move |event: &LedgerEvent| {
+            let update = matches!(
+                event,
+                LedgerEvent::TransactionApplied { .. } | LedgerEvent::AccountUpdated { .. }
+            );
+            if updates || !update {
+                observed.lock().unwrap().push(event.clone())
+            }
+        });
+        events
+    }
+
+    #[test]
+    fn freeze_events() {
+        use crate::account::{FreezeLevel, FreezeReason};
+
+        let mut ledger = Ledger::default();
+        let events = observe(&mut ledger, false);
+
+        let manual = FreezeReason::Manual("investigation".to_owned());
+        assert_eq!(
+            ledger.freeze_account(1, manual.clone()),
+            Err(TransactionError::NonexistentAccount)
+        );
+
+        ledger.read_csv(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,10
+dispute,2,2,
+chargeback,2,2,
+"
+            .as_bytes(),
+        );
+        assert!(ledger.freeze_account(1, manual.clone()).is_ok());
+        // Already frozen, nothing happens
+        assert!(ledger.freeze_account(1, manual.clone()).is_ok());
+        assert!(ledger.unfreeze_account(1, "cleared".to_owned()).is_ok());
+        // Not frozen, nothing happens
+        assert!(ledger.unfreeze_account(1, "cleared".to_owned()).is_ok());
+        assert!(!ledger.accounts[&1].is_frozen());
+        assert_eq!(ledger.accounts[&1].unfreeze_reason(), Some("cleared"));
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                LedgerEvent::AccountFrozen {
+                    client: 2,
+                    reason: FreezeReason::Chargeback(2),
+                    level: FreezeLevel::Full,
+                    at: 0,
+                },
+                LedgerEvent::AccountFrozen {
+                    client: 1,
+                    reason: manual,
+                    level: FreezeLevel::Full,
+                    at: 0,
+                },
+                LedgerEvent::AccountUnfrozen {
+                    client: 1,
+                    reason: "cleared".to_owned(),
+                    at: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn freeze_history() {
+        use crate::account::{FreezeLevel, FreezePeriod, FreezeReason};
+
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10,100
+dispute,1,1,,150
+chargeback,1,1,,200
+unlock,1,0,,300
+lock,1,0,,400
+deposit,2,2,10,500
+";
+        let mut ledger = Ledger::builder().freeze_reasons().keep_events().build();
+        ledger.read_csv(input.as_bytes());
+        let rule = FreezeReason::RiskRule("velocity".to_owned());
+        ledger.advance_to(600);
+        ledger.freeze_account(2, rule.clone()).unwrap();
+
+        let lock = FreezeReason::Manual("lock record".to_owned());
+        assert_eq!(
+            ledger.freeze_history(1),
+            [
+                FreezePeriod {
+                    reason: FreezeReason::Chargeback(1),
+                    level: FreezeLevel::Full,
+                    frozen_at: 200,
+                    unfrozen_at: Some(300),
+                    unfreeze_reason: Some("lock record".to_owned()),
+                },
+                FreezePeriod {
+                    reason: lock,
+                    level: FreezeLevel::Full,
+                    frozen_at: 400,
+                    unfrozen_at: None,
+                    unfreeze_reason: None,
+                },
+            ]
+        );
+        assert_eq!(ledger.freeze_history(2)[0].frozen_at, 600);
+        assert!(ledger.freeze_history(3).is_empty());
+
+        // The history is rebuilt with the same times, and kept in snapshots.
+        let mut rebuilt = Ledger::default();
+        rebuilt.rebuild_from_events(ledger.events()).unwrap();
+        assert_eq!(rebuilt.freeze_history(2), ledger.freeze_history(2));
+        let mut snapshot = vec![];
+        ledger.save_snapshot(&mut snapshot).unwrap();
+        let mut restored = Ledger::default();
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.freeze_history(1), ledger.freeze_history(1));
+
+        let mut output = vec![];
+        ledger.accounts_to_csv(&mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client,available,held,total,locked,freeze_reason,freeze_level
+1,0.0000,0.0000,0.0000,true,manual:lock record,full
+2,10.0000,0.0000,10.0000,true,risk_rule:velocity,full
+"
+        );
+    }
+
+    #[test]
+    fn freeze_levels() {
+        use crate::{
+            account::{FreezeLevel, FreezeReason, ESCALATED},
+            limits::{WithdrawalLimit, LIMIT_RULE},
+            policy::Policy,
+        };
+
+        let mut policy = Policy {
+            chargeback_freeze: FreezeLevel::Withdrawals,
+            limit_freeze: Some(FreezeLevel::Withdrawals),
+            ..Policy::default()
+        };
+        policy.withdrawal_limit = Some(WithdrawalLimit {
+            count: Some(1),
+            amount: None,
+            window: 100,
+        });
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10,100
+deposit,1,2,10,100
+dispute,1,1,,150
+chargeback,1,1,,200
+deposit,1,3,5,250
+withdrawal,1,4,1,260
+deposit,2,5,10,300
+withdrawal,2,6,1,300
+withdrawal,2,7,1,310
+";
+        let mut ledger = Ledger::builder()
+            .policy(policy)
+            .freeze_reasons()
+            .keep_events()
+            .build();
+        ledger.read_csv(input.as_bytes());
+        // Frozen for withdrawals only, client 1 can still deposit.
+        assert_eq!(ledger.accounts[&1].total().unwrap(), 15.into());
+        assert_eq!(
+            ledger.accounts[&1].freeze_level(),
+            Some(FreezeLevel::Withdrawals)
+        );
+        // Client 2 went over the withdrawal limit.
+        assert_eq!(
+            ledger.accounts[&2].freeze_reason(),
+            Some(&FreezeReason::RiskRule(LIMIT_RULE.to_owned()))
+        );
+        assert_eq!(ledger.freeze_history(2)[0].frozen_at, 310);
+
+        // A full freeze escalates the withdrawal-only one.
+        let manual = FreezeReason::Manual("investigation".to_owned());
+        ledger.advance_to(400);
+        ledger.freeze_account(1, manual.clone()).unwrap();
+        let history = ledger.freeze_history(1);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].unfrozen_at, Some(400));
+        assert_eq!(history[0].unfreeze_reason.as_deref(), Some(ESCALATED));
+        assert_eq!(
+            (&history[1].reason, history[1].level),
+            (&manual, FreezeLevel::Full)
+        );
+        assert_eq!(
+            ledger.apply(
+                1,
+                Transaction::Deposit {
+                    new_id: 8,
+                    amount: 1.into()
+                }
+            ),
+            Err(TransactionError::AccountFrozen)
+        );
+        // But freezing less strictly leaves it as it is.
+        ledger
+            .freeze_account_with(1, manual, FreezeLevel::Withdrawals)
+            .unwrap();
+        assert_eq!(ledger.freeze_history(1).len(), 2);
+
+        let mut rebuilt = Ledger::builder().policy(ledger.policy().clone()).build();
+        rebuilt.rebuild_from_events(ledger.events()).unwrap();
+        assert_eq!(rebuilt.freeze_history(1), ledger.freeze_history(1));
+        assert_eq!(rebuilt.freeze_history(2), ledger.freeze_history(2));
+
+        let mut output = vec![];
+        ledger.accounts_to_csv(&mut output);
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client,available,held,total,locked,freeze_reason,freeze_level
+1,15.0000,0.0000,15.0000,true,manual:investigation,full
+2,9.0000,0.0000,9.0000,true,risk_rule:withdrawal limit,withdrawals
+"
+        );
+    }
+
+    #[test]
+    fn rebuild_from_events() {
+        use super::RebuildError;
+        use crate::account::{FreezeReason, OpeningBalance};
+
+        let mut ledger = Ledger::builder().keep_events().build();
+        let opening = OpeningBalance {
+            available: 5.into(),
+            held: 0.into(),
+            locked: false,
+            closed: false,
+            currency: None,
+        };
+        ledger.open_account(3, opening).unwrap();
+        ledger.read_csv(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,10
+dispute,2,2,
+chargeback,2,2,
+withdrawal,3,3,2
+"
+            .as_bytes(),
+        );
+        let reason = FreezeReason::Manual("investigation".to_owned());
+        ledger.freeze_account(1, reason).unwrap();
+        ledger.unfreeze_account(2, "cleared".to_owned()).unwrap();
+        assert_eq!(ledger.events().len(), 8);
+        assert!(Ledger::default().events().is_empty());
+
+        let summaries = |ledger: Ledger| {
+            let mut output = vec![];
+            ledger.accounts_to_csv(&mut output);
+            String::from_utf8(output).expect("output should be UTF8")
+        };
+        let mut rebuilt = Ledger::builder().keep_events().build();
+        rebuilt.rebuild_from_events(ledger.events()).unwrap();
+        assert_eq!(rebuilt.events(), ledger.events());
+        assert_eq!(summaries(rebuilt), summaries(ledger));
+
+        // A prefix of the events gives an earlier state: before the
+        // chargeback client 2's funds were still held.
+        let mut ledger = Ledger::builder().keep_events().build();
+        ledger.read_csv("type,client,tx,amount\ndeposit,2,2,10\ndispute,2,2,\n".as_bytes());
+        ledger
+            .apply(2, crate::Transaction::Chargeback { id: 2 })
+            .unwrap();
+        let mut earlier = Ledger::default();
+        earlier.rebuild_from_events(&ledger.events()[..2]).unwrap();
+        assert_eq!(earlier.account(2).map(Account::held), Some(10.into()));
+        assert!(!earlier.account(2).unwrap().is_frozen());
+
+        // The events have to apply cleanly.
+        assert_eq!(
+            Ledger::default().rebuild_from_events(&ledger.events()[1..]),
+            Err(RebuildError {
+                position: 0,
+                error: TransactionError::NonexistentTransaction
+            })
+        );
+    }
+
+    #[test]
+    fn find_transactions() {
+        use super::{ProcessedTransactionState::*, TransactionFilter};
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,500
+deposit,1,2,1500
+deposit,1,3,2500
+deposit,2,4,3000
+withdrawal,2,5,1000
+dispute,1,2,
+dispute,1,3,
+dispute,2,5,
+";
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+
+        let find = |filter: TransactionFilter| {
+            let mut found = ledger
+                .find_transactions(&filter)
+                .map(|found| found.map(|(client, id, _)| (client, id)).unwrap())
+                .collect::<Vec<_>>();
+            found.sort();
+            found
+        };
+
+        assert_eq!(find(TransactionFilter::default()).len(), 5);
+        assert_eq!(
+            find(TransactionFilter {
+                state: Some(Disputed),
+                min_amount: Some(1000.into()),
+                ..Default::default()
+            }),
+            vec![(1, 2), (1, 3), (2, 5)]
+        );
+        assert_eq!(
+            find(TransactionFilter {
+                client: Some(1),
+                max_amount: Some(1500.into()),
+                ..Default::default()
+            }),
+            vec![(1, 1), (1, 2)]
+        );
+        assert_eq!(
+            find(TransactionFilter {
+                client: Some(2),
+                state: Some(Settled),
+                ..Default::default()
+            }),
+            vec![(2, 4)]
+        );
+        assert!(find(TransactionFilter {
+            state: Some(ChargeBacked),
+            ..Default::default()
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn opening_balances() {
+        use crate::account::OpeningBalance;
+
+        let mut ledger = Ledger::default();
+        let events = observe(&mut ledger, false);
+
+        ledger.read_opening_balances(
+            "\
+client,available,held,total,locked
+1,10.5,2,12.5,false
+2,5,0,5,true
+3,1,1,3,false
+1,1,0,1,false
+"
+            .as_bytes(),
+        );
+        // Totals are optional
+        ledger.read_opening_balances(
+            "\
+client,available,held,locked
+4,1,0,false
+"
+            .as_bytes(),
+        );
+
+        let mut accounts = ledger
+            .accounts()
+            .map(|(client, _)| client)
+            .collect::<Vec<_>>();
+        accounts.sort();
+        assert_eq!(accounts, vec![1, 2, 4]);
+        assert_eq!(events.lock().unwrap().len(), 3);
+        assert_eq!(
+            events.lock().unwrap()[0],
+            LedgerEvent::AccountOpened {
+                client: 1,
+                balance: OpeningBalance {
+                    available: "10.5".parse().unwrap(),
+                    held: 2.into(),
+                    locked: false,
+                    closed: false,
+                    currency: None,
+                }
+            }
+        );
+
+        ledger.read_csv(
+            "\
+type,client,tx,amount
+withdrawal,1,1,10
+deposit,2,2,10
+"
+            .as_bytes(),
+        );
+        let mut output = vec![];
+        ledger.accounts_to_csv(&mut output);
+        assert_eq!(
+            String::from_utf8(output).expect("output should be UTF8"),
+            "\
+client,available,held,total,locked
+1,0.5000,2.0000,2.5000,false
+2,5.0000,0.0000,5.0000,true
+4,1.0000,0.0000,1.0000,false
+"
+        );
+    }
+
+    #[test]
+    fn near_max_opening_balances() {
+        use crate::account::OpeningBalance;
+
+        let near_max: crate::Balance = "50000000000000000000000000000".parse().unwrap();
+        let balance = |currency| OpeningBalance {
+            available: near_max,
+            held: near_max,
+            locked: false,
+            closed: false,
+            currency,
+        };
+        let mut ledger = Ledger::default();
+        assert_eq!(
+            ledger.open_account(1, balance(None)),
+            Err(TransactionError::Overflow)
+        );
+        assert!(ledger.account(1).is_none());
+
+        let opening = OpeningBalance {
+            available: 1.into(),
+            held: 0.into(),
+            locked: false,
+            closed: false,
+            currency: None,
+        };
+        ledger.open_account(1, opening).unwrap();
+        let eur = "EUR".parse().unwrap();
+        assert_eq!(
+            ledger.open_account(1, balance(Some(eur))),
+            Err(TransactionError::Overflow)
+        );
+        let account = ledger.account(1).unwrap();
+        assert!(account.in_currency(eur).is_none());
+        assert_eq!(account.available(), 1.into());
+    }
+
+    #[cfg(feature = "fixed-point")]
+    #[test]
+    fn out_of_range_opening_balances() {
+        use crate::account::OpeningBalance;
+
+        let too_large: crate::Balance = "1000000000000000000000".parse().unwrap();
+        let mut ledger = Ledger::default();
+        let balance = OpeningBalance {
+            available: too_large,
+            held: 0.into(),
+            locked: false,
+            closed: false,
+            currency: None,
+        };
+        assert_eq!(
+            ledger.open_account(1, balance),
+            Err(TransactionError::Overflow)
+        );
+        assert!(ledger.account(1).is_none());
 
-#[derive(Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum RecordType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
-}
+        let mut ledger = Ledger::builder().overdraft(too_large).build();
+        let deposit = Transaction::Deposit {
+            new_id: 1,
+            amount: 1.into(),
+        };
+        assert_eq!(ledger.apply(1, deposit), Err(TransactionError::Overflow));
+        assert!(ledger.account(1).is_none());
 
-#[derive(Error, Debug, PartialEq, Eq)]
-enum RecordError {
-    #[error("The amount is missing for a transaction type that requires it")]
-    MissingAmount,
-}
+        // Funds that don't add up to a total, since their sum is beyond
+        // what decimals can hold, are rejected rather than checked.
+        let mut ledger = Ledger::default();
+        let mut rejects = vec![];
+        let max = crate::Balance::MAX;
+        ledger.read_opening_balances_with(
+            format!("client,available,held,total,locked\n1,{max},{max},{max},false\n").as_bytes(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+        assert_eq!(rejects, [(2, "overflow")]);
+        assert!(ledger.account(1).is_none());
+    }
 
-fn record_to_transaction(record: &Record) -> Result<(AccountId, Transaction), RecordError> {
-    use RecordError::*;
-    use Transaction::*;
+    #[test]
+    fn error_sink() {
+        use crate::error_sink::ErrorSink;
 
-    let tx = match record.record_type {
-        RecordType::Deposit => record
-            .amount
-            .map(|amount| Deposit {
-                new_id: record.tx,
-                amount: amount,
-            })
-            .ok_or(MissingAmount),
-        RecordType::Withdrawal => record
-            .amount
-            .map(|amount| Withdrawal {
-                new_id: record.tx,
-                amount: amount,
-            })
-            .ok_or(MissingAmount),
-        RecordType::Dispute => Ok(Dispute { id: record.tx }),
-        RecordType::Resolve => Ok(Resolve { id: record.tx }),
-        RecordType::Chargeback => Ok(Chargeback { id: record.tx }),
-    };
+        // A sink that only counts the rejects by reason.
+        #[derive(Default)]
+        struct Counts(std::collections::BTreeMap<&'static str, usize>);
 
-    tx.map(|tx| (record.client, tx))
-}
+        impl ErrorSink for &mut Counts {
+            fn on_reject(&mut self, reject: Reject) {
+                *self.0.entry(reject.reason).or_default() += 1;
+            }
+        }
 
-#[cfg(test)]
-mod tests {
-    use super::Ledger;
-    use crate::{account::Account, Transaction};
+        let mut counts = Counts::default();
+        let mut ledger = Ledger::from_csv_reader_with(
+            "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,20\nfoo,1,3,1\n".as_bytes(),
+            &mut counts,
+        );
+        ledger.read_opening_balances_with(
+            "\
+client,available,held,total,locked
+1,1,0,1,false
+2,1,0,2,false
+3,x,0,1,false
+"
+            .as_bytes(),
+            &mut counts,
+        );
+        assert_eq!(
+            counts.0.into_iter().collect::<Vec<_>>(),
+            [
+                ("account_exists", 1),
+                ("insufficient_funds", 1),
+                ("malformed", 2),
+                ("unbalanced", 1)
+            ]
+        );
+    }
 
     #[test]
-    fn record_to_transaction() {
-        use super::RecordError;
-        use super::RecordType::*;
-        use super::{record_to_transaction as f, Record};
+    fn account_updated_events() {
+        let mut ledger = Ledger::default();
+        let events = observe(&mut ledger, true);
 
-        let tests = [
-            // Withdrawals
-            (
-                Record {
-                    record_type: Withdrawal,
+        ledger.read_csv(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,20
+dispute,1,1,
+"
+            .as_bytes(),
+        );
+
+        // The failed withdrawal doesn't change the account
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                LedgerEvent::TransactionApplied {
                     client: 1,
-                    tx: 2,
-                    amount: Some(10.into()),
-                },
-                Ok((
-                    1,
-                    Transaction::Withdrawal {
-                        new_id: 2,
+                    transaction: Transaction::Deposit {
+                        new_id: 1,
                         amount: 10.into(),
                     },
-                )),
-            ),
-            (
-                Record {
-                    record_type: Withdrawal,
-                    client: 16,
-                    tx: 32,
-                    amount: None,
+                    processed: Some(ProcessedTransaction {
+                        amount: 10.into(),
+                        state: ProcessedTransactionState::Settled,
+                        disputed: None,
+                        withdrawal: false,
+                        timestamp: None,
+                        currency: None,
+                    }),
+                    timestamp: None,
+                    policy: Default::default(),
                 },
-                Err(RecordError::MissingAmount),
-            ),
-            // Deposits
-            (
-                Record {
-                    record_type: Deposit,
-                    client: 5,
-                    tx: 4,
-                    amount: Some(90.into()),
+                LedgerEvent::AccountUpdated {
+                    client: 1,
+                    available: 10.into(),
+                    held: 0.into(),
+                    total: 10.into(),
+                    locked: false,
                 },
-                Ok((
-                    5,
-                    Transaction::Deposit {
-                        new_id: 4,
-                        amount: 90.into(),
+                LedgerEvent::TransactionApplied {
+                    client: 1,
+                    transaction: Transaction::Dispute {
+                        id: 1,
+                        amount: None
                     },
-                )),
-            ),
-            (
-                Record {
-                    record_type: Deposit,
-                    client: 7,
-                    tx: 6,
-                    amount: None,
-                },
-                Err(RecordError::MissingAmount),
-            ),
-            // Disputes
-            (
-                Record {
-                    record_type: Dispute,
-                    client: 7,
-                    tx: 6,
-                    amount: None,
-                },
-                Ok((7, Transaction::Dispute { id: 6 })),
-            ),
-            (
-                Record {
-                    record_type: Dispute,
-                    client: 7,
-                    tx: 6,
-                    // Amount on a dispute is ok, it's simply ignored
-                    amount: Some(10.into()),
-                },
-                Ok((7, Transaction::Dispute { id: 6 })),
-            ),
-            // Resolve
-            (
-                Record {
-                    record_type: Resolve,
-                    client: 5,
-                    tx: 2,
-                    amount: None,
-                },
-                Ok((5, Transaction::Resolve { id: 2 })),
-            ),
-            (
-                Record {
-                    record_type: Resolve,
-                    client: 2,
-                    tx: 5,
-                    // Amount on a resolve is ok, it's simply ignored
-                    amount: Some(10.into()),
-                },
-                Ok((2, Transaction::Resolve { id: 5 })),
-            ),
-            // Chargeback
-            (
-                Record {
-                    record_type: Chargeback,
-                    client: 5,
-                    tx: 2,
-                    amount: None,
+                    processed: Some(ProcessedTransaction {
+                        amount: 10.into(),
+                        state: ProcessedTransactionState::Disputed,
+                        disputed: None,
+                        withdrawal: false,
+                        timestamp: None,
+                        currency: None,
+                    }),
+                    timestamp: None,
+                    policy: Default::default(),
                 },
-                Ok((5, Transaction::Chargeback { id: 2 })),
-            ),
-            (
-                Record {
-                    record_type: Chargeback,
-                    client: 2,
-                    tx: 5,
-                    // Amount on a chargeback is ok, it's simply ignored
-                    amount: Some(10.into()),
+                LedgerEvent::AccountUpdated {
+                    client: 1,
+                    available: 0.into(),
+                    held: 10.into(),
+                    total: 10.into(),
+                    locked: false,
                 },
-                Ok((2, Transaction::Chargeback { id: 5 })),
-            ),
-        ];
-
-        for (left, right) in tests.into_iter() {
-            assert_eq!(f(&left), right);
-        }
+            ]
+        );
     }
 
     #[test]
-    fn header_ordering_is_permissive() {
+    fn json_output() {
         let input = "\
-client,amount,type,tx
-5,10,deposit,1
+type,client,tx,amount
+deposit,2,1,10
+deposit,1,2,1.5
+dispute,1,2,
 ";
 
         let ledger = Ledger::from_csv_reader(input.as_bytes());
-        assert_eq!(ledger.accounts.len(), 1);
-        assert!(ledger.accounts.contains_key(&5));
+        let mut output = vec![];
+        ledger.accounts_to_json(&mut output, false);
+        assert_eq!(
+            String::from_utf8(output).expect("output should be UTF8"),
+            concat!(
+                r#"[{"client":1,"available":"0.0000","held":"1.5000","total":"1.5000","locked":false},"#,
+                r#"{"client":2,"available":"10.0000","held":"0.0000","total":"10.0000","locked":false}]"#,
+                "\n"
+            )
+        );
+
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+        let mut output = vec![];
+        ledger.accounts_to_json(&mut output, true);
+        assert_eq!(
+            String::from_utf8(output).expect("output should be UTF8"),
+            concat!(
+                r#"{"client":1,"available":"0.0000","held":"1.5000","total":"1.5000","locked":false}"#,
+                "\n",
+                r#"{"client":2,"available":"10.0000","held":"0.0000","total":"10.0000","locked":false}"#,
+                "\n"
+            )
+        );
     }
 
     #[test]
-    fn bad_records_are_ignored() {
+    fn client_names() {
+        use crate::policy::ClientIdPolicy;
+
+        let mut ledger = Ledger::builder()
+            .client_ids(ClientIdPolicy::Text)
+            .reorder_window(10)
+            .build();
+        ledger.read_opening_balances("client,available,held,locked\nc-2f9a,5,0,false\n".as_bytes());
+        let input = "\
+type,client,tx,amount,timestamp
+deposit, 9b1d-4e ,1,10,100
+withdrawal,c-2f9a,2,7,105
+deposit,c-2f9a,3,1,95
+dispute,9b1d-4e,1,,110
+deposit,,4,1,110
+";
+        let mut rejects = vec![];
+        ledger.read_csv_with(input.as_bytes(), Default::default(), |reject| {
+            rejects.push(reject)
+        });
+        assert_eq!(
+            rejects
+                .iter()
+                .map(|reject| reject.to_string())
+                .collect::<Vec<_>>(),
+            [
+                "line 6: The record is malformed: the client is empty",
+                "line 3: withdrawal for client c-2f9a rejected: Insufficient funds to \
+                 withdraw requested amount",
+            ]
+        );
+        assert_eq!(rejects[1].record, "withdrawal,c-2f9a,2,7,105");
+        assert_eq!(ledger.client_names().id("c-2f9a"), Some(0));
+        assert_eq!(ledger.client_names().id("9b1d-4e"), Some(1));
+
+        // Snapshots keep the names.
+        let mut snapshot = vec![];
+        ledger.save_snapshot(&mut snapshot).unwrap();
+        let mut restored = Ledger::default();
+        restored.load_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.client_names(), ledger.client_names());
+
+        let mut output = vec![];
+        ledger.closing_balances_to_csv(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client,available,held,total,locked
+c-2f9a,6,0,6,false
+9b1d-4e,0,10,10,false
+"
+        );
+    }
+
+    #[test]
+    fn recoveries() {
+        let mut ledger = Ledger::default();
+        let events = observe(&mut ledger, false);
+
         let input = "\
 type,client,tx,amount
 deposit,1,1,10
-foo,1,2,10
-withdraw,1,3,
-dispute,1,,
+withdrawal,1,2,10
+dispute,1,1,
+recovery,1,0,
+deposit,1,3,4
+deposit,2,4,4
+recovery,2,0,
+";
+        ledger.read_csv(input.as_bytes());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![LedgerEvent::DeficitRecovered {
+                client: 1,
+                amount: 4.into(),
+                outstanding: 6.into()
+            }]
+        );
+
+        let mut output = vec![];
+        ledger.recoveries_to_csv(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).expect("output should be UTF8"),
+            "\
+client,recovered,outstanding
+1,4,6
+"
+        );
+    }
+
+    #[test]
+    fn table_output() {
+        use super::AccountOrder;
+
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,1.5
+deposit,10,3,100
 ";
 
         let ledger = Ledger::from_csv_reader(input.as_bytes());
-        assert_eq!(ledger.accounts.len(), 1);
+        let mut output = vec![];
+        ledger.accounts_to_table(&mut output, AccountOrder::Client);
         assert_eq!(
-            ledger.accounts.get(&1).map(Account::available),
-            Some(10.into())
+            String::from_utf8(output).expect("output should be UTF8"),
+            "\
+client  available    held     total  locked
+------  ---------  ------  --------  ------
+     1    10.0000  0.0000   10.0000   false
+     2     1.5000  0.0000    1.5000   false
+    10   100.0000  0.0000  100.0000   false
+"
         );
+
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+        let mut output = vec![];
+        ledger.accounts_to_table(&mut output, AccountOrder::TotalDescending);
+        let output = String::from_utf8(output).expect("output should be UTF8");
+        let clients = output
+            .lines()
+            .skip(2)
+            .map(|line| line.split_whitespace().next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(clients, vec!["10", "1", "2"]);
     }
 
     #[test]
@@ -417,4 +6599,168 @@ client,available,held,total,locked
 "
         );
     }
+
+    // Property tests comparing the ledger against a simple model of it: every
+    // account's funds are its deposits, less its withdrawals and chargebacks,
+    // with the disputed part of them held.
+    mod model {
+        use std::collections::HashMap;
+
+        use proptest::{prelude::*, sample::Index};
+        use rust_decimal::Decimal;
+
+        use crate::{AccountId, Ledger, Transaction, TransactionId};
+
+        // Op is a step of a generated transaction stream. Disputes,
+        // resolutions, and chargebacks pick one of the transactions applied
+        // so far, so they're always of a transaction of the same client.
+        #[derive(Debug, Clone)]
+        enum Op {
+            Deposit(AccountId, i64),
+            Withdrawal(AccountId, i64),
+            Dispute(Index),
+            Resolve(Index),
+            Chargeback(Index),
+        }
+
+        fn op() -> impl Strategy<Value = Op> {
+            let client = 0..4 as AccountId;
+            let amount = 1..1_000_000i64;
+            prop_oneof![
+                3 => (client.clone(), amount.clone()).prop_map(|(c, a)| Op::Deposit(c, a)),
+                2 => (client, amount).prop_map(|(c, a)| Op::Withdrawal(c, a)),
+                2 => any::<Index>().prop_map(Op::Dispute),
+                1 => any::<Index>().prop_map(Op::Resolve),
+                1 => any::<Index>().prop_map(Op::Chargeback),
+            ]
+        }
+
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        enum State {
+            Settled,
+            Disputed,
+            ChargedBack,
+        }
+
+        #[derive(Default)]
+        struct Account {
+            deposited: Decimal,
+            withdrawn: Decimal,
+            charged_back: Decimal,
+            held: Decimal,
+            frozen: bool,
+        }
+
+        impl Account {
+            fn total(&self) -> Decimal {
+                self.deposited - self.withdrawn - self.charged_back
+            }
+        }
+
+        #[derive(Default)]
+        struct Model {
+            accounts: HashMap<AccountId, Account>,
+            // The transactions applied, in order: their client, ID, amount,
+            // and state. Withdrawals hold and charge back their amount like
+            // deposits do.
+            applied: Vec<(AccountId, TransactionId, Decimal, State)>,
+        }
+
+        impl Model {
+            // Apply the step to the model, returning the transaction it
+            // stands for, and whether the ledger should accept it.
+            fn apply(&mut self, op: &Op) -> Option<(AccountId, Transaction, bool)> {
+                match *op {
+                    Op::Deposit(client, amount) | Op::Withdrawal(client, amount) => {
+                        let deposit = matches!(op, Op::Deposit(..));
+                        let new_id = self.applied.len() as TransactionId;
+                        let amount = Decimal::new(amount, 2);
+                        let account = self.accounts.entry(client).or_default();
+                        let available = account.total() - account.held;
+                        let accepted = !account.frozen && (deposit || available >= amount);
+                        if accepted {
+                            if deposit {
+                                account.deposited += amount;
+                            } else {
+                                account.withdrawn += amount;
+                            }
+                            self.applied.push((client, new_id, amount, State::Settled));
+                        }
+                        let tx = if deposit {
+                            Transaction::Deposit { new_id, amount }
+                        } else {
+                            Transaction::Withdrawal { new_id, amount }
+                        };
+                        Some((client, tx, accepted))
+                    }
+                    Op::Dispute(index) | Op::Resolve(index) | Op::Chargeback(index) => {
+                        if self.applied.is_empty() {
+                            return None;
+                        }
+                        let index = index.index(self.applied.len());
+                        let (client, id, amount, state) = &mut self.applied[index];
+                        let account = self.accounts.get_mut(client).expect("account exists");
+                        let (tx, from, to) = match op {
+                            Op::Dispute(_) => (
+                                Transaction::Dispute {
+                                    id: *id,
+                                    amount: None,
+                                },
+                                State::Settled,
+                                State::Disputed,
+                            ),
+                            Op::Resolve(_) => (
+                                Transaction::Resolve { id: *id },
+                                State::Disputed,
+                                State::Settled,
+                            ),
+                            _ => (
+                                Transaction::Chargeback { id: *id },
+                                State::Disputed,
+                                State::ChargedBack,
+                            ),
+                        };
+                        let accepted = *state == from;
+                        if accepted {
+                            *state = to;
+                            match to {
+                                State::Disputed => account.held += *amount,
+                                State::Settled => account.held -= *amount,
+                                State::ChargedBack => {
+                                    account.held -= *amount;
+                                    account.charged_back += *amount;
+                                    account.frozen = true;
+                                }
+                            }
+                        }
+                        Some((*client, tx, accepted))
+                    }
+                }
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn matches_model(ops in prop::collection::vec(op(), 1..200)) {
+                let mut ledger = Ledger::default();
+                let mut model = Model::default();
+                for op in &ops {
+                    let Some((client, tx, accepted)) = model.apply(op) else {
+                        continue;
+                    };
+                    let result = ledger.apply(client, tx.clone());
+                    prop_assert_eq!(result.is_ok(), accepted, "{:?}: {:?}", tx, result);
+                }
+
+                for (client, expected) in &model.accounts {
+                    let account = ledger.account(*client).expect("account exists");
+                    prop_assert_eq!(account.total().unwrap(), expected.total());
+                    prop_assert_eq!(account.held(), expected.held);
+                    prop_assert_eq!(account.available(), expected.total() - expected.held);
+                    prop_assert_eq!(account.is_frozen(), expected.frozen);
+                }
+                prop_assert_eq!(ledger.accounts().count(), model.accounts.len());
+            }
+        }
+    }
 }
@@ -0,0 +1,359 @@
+// The ledger engine, see `Ledger`. The `ledger` binary is a thin command line
+// interface over it, but it can also be embedded directly, e.g.:
+//
+//     let mut ledger = ledger::Ledger::default();
+//     ledger.apply(1, ledger::Transaction::Deposit { new_id: 1, amount: 10.into() })?;
+//     let account = ledger.account(1);
+//
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub mod account;
+pub mod account_store;
+pub mod alloc_stats;
+pub mod anomaly;
+pub mod atomic_file;
+pub mod backfill;
+pub mod carry_forward;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod client_names;
+pub mod clock;
+pub mod compare;
+pub mod compression;
+pub mod currency;
+pub mod daemon;
+pub mod dead_letter;
+pub mod diff;
+pub mod error_code;
+pub mod error_sink;
+pub mod events;
+pub mod filter;
+mod funds;
+pub mod generate;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod journal;
+pub mod latency;
+pub mod ledger;
+pub mod limits;
+pub mod mapped;
+pub mod normalize;
+#[cfg(feature = "parquet")]
+pub mod parquet_output;
+pub mod pipeline;
+pub mod policy;
+pub mod reconcile;
+pub mod reorder;
+pub mod scaling;
+pub mod shard;
+pub mod shared;
+pub mod simulation;
+#[cfg(feature = "async")]
+pub mod source;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_output;
+pub mod statement;
+pub mod stats;
+pub mod tx_store;
+pub mod wal;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+pub use account::{Account, AccountMetadata};
+pub use currency::Currency;
+pub use ledger::Ledger;
+
+// Define some types used across the entire program
+// Client IDs fit into a u16 and transaction IDs into a u32, as per the
+// specification, unless the `wide-ids` feature makes both u64s, for feeds
+// with more clients or transactions than that.
+#[cfg(not(feature = "wide-ids"))]
+pub type TransactionId = u32;
+#[cfg(not(feature = "wide-ids"))]
+pub type AccountId = u16;
+#[cfg(feature = "wide-ids")]
+pub type TransactionId = u64;
+#[cfg(feature = "wide-ids")]
+pub type AccountId = u64;
+pub type Balance = Decimal;
+pub type TransactionAmount = Decimal;
+// Seconds since the Unix epoch.
+pub type Timestamp = u64;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Transaction {
+    Deposit {
+        new_id: TransactionId,
+        amount: TransactionAmount,
+    },
+    Withdrawal {
+        new_id: TransactionId,
+        amount: TransactionAmount,
+    },
+    // Hold the amount of a settled transaction, or only `amount` of it if
+    // given, e.g. when disputing $20 of a $100 deposit. Resolutions and
+    // chargebacks act on the disputed part only.
+    Dispute {
+        id: TransactionId,
+        amount: Option<TransactionAmount>,
+    },
+    Resolve {
+        id: TransactionId,
+    },
+    Chargeback {
+        id: TransactionId,
+    },
+    // Start recovering the account's negative balance from future deposits.
+    // The deficit is tracked separately, and the part of each deposit that
+    // goes towards it is reported as recovered.
+    Recovery,
+    // A processing fee debited from the account and credited to the fee
+    // account of the ledger's policy, see `Policy::fee_account`. Fees can't
+    // be disputed.
+    Fee {
+        new_id: TransactionId,
+        amount: TransactionAmount,
+    },
+    // Corrections made by an operator, e.g. a goodwill credit or reverting
+    // an error. They're applied even if the account is frozen, and can't be
+    // disputed. Debits still need the funds to cover them.
+    AdjustCredit {
+        new_id: TransactionId,
+        amount: TransactionAmount,
+    },
+    AdjustDebit {
+        new_id: TransactionId,
+        amount: TransactionAmount,
+    },
+    // Return the amount of a settled deposit to its sender, or only `amount`
+    // of it if given, e.g. for a returned purchase. The deposit is marked as
+    // refunded, so it can't be disputed or refunded again.
+    Refund {
+        id: TransactionId,
+        amount: Option<TransactionAmount>,
+    },
+    // Freeze or unfreeze the account, e.g. to re-enable an account frozen by
+    // a chargeback after an investigation, like `Ledger::freeze_account` and
+    // `Ledger::unfreeze_account` do.
+    Lock,
+    Unlock,
+    // Create the client's account with the given metadata, like
+    // `Ledger::create_account`. It fails if the client has an account
+    // already.
+    OpenAccount {
+        metadata: Box<AccountMetadata>,
+    },
+    // Close the client's account, like `Ledger::close_account`. A closed
+    // account can't be deposited to or withdrawn from anymore. It fails
+    // unless the account is empty, or what's left can be swept to the
+    // ledger's sweep account, see `Policy::sweep_account`.
+    CloseAccount,
+    // Hold `amount` of the available funds for a payment that isn't final
+    // yet, e.g. a card pre-authorization. Capturing the authorization settles
+    // the payment like a withdrawal, taking the held funds, while voiding it
+    // releases them. Authorizations not captured in time are voided by the
+    // ledger, see `Policy::authorization_window`.
+    Authorize {
+        new_id: TransactionId,
+        amount: TransactionAmount,
+    },
+    Capture {
+        id: TransactionId,
+    },
+    Void {
+        id: TransactionId,
+    },
+    // A transaction that takes effect at the given time, e.g. a post-dated
+    // payout. The ledger keeps it until its time comes, see
+    // `Ledger::advance_to`, and applies it then like any other.
+    Scheduled {
+        effective_at: Timestamp,
+        transaction: Box<Transaction>,
+    },
+    // A transaction that happened at the given time, which withdrawal limits
+    // and dispute windows go by, see `Policy::withdrawal_limit`, and which
+    // may be checked for order, see `Policy::out_of_order`. Transactions
+    // without a time happened at the ledger's time, see
+    // `Ledger::advance_to`.
+    Timestamped {
+        timestamp: Timestamp,
+        transaction: Box<Transaction>,
+    },
+    // A transaction in the given currency, which applies to the account's
+    // balance in that currency, see `Account::in_currency`. Transactions
+    // without one apply to the account's own balance, and those referring
+    // to a past transaction to the balance of its currency. It goes inside
+    // `Scheduled` and `Timestamped`, never around them.
+    InCurrency {
+        currency: Currency,
+        transaction: Box<Transaction>,
+    },
+}
+
+impl Transaction {
+    // The amount moved by the transaction, if it carries one. Full disputes
+    // and refunds, resolutions, and chargebacks refer to the amount of a past
+    // transaction instead.
+    pub fn amount(&self) -> Option<TransactionAmount> {
+        match self {
+            Transaction::Deposit { amount, .. }
+            | Transaction::Withdrawal { amount, .. }
+            | Transaction::Fee { amount, .. }
+            | Transaction::AdjustCredit { amount, .. }
+            | Transaction::AdjustDebit { amount, .. }
+            | Transaction::Authorize { amount, .. } => Some(*amount),
+            Transaction::Dispute { amount, .. } | Transaction::Refund { amount, .. } => *amount,
+            Transaction::Scheduled { transaction, .. }
+            | Transaction::Timestamped { transaction, .. }
+            | Transaction::InCurrency { transaction, .. } => transaction.amount(),
+            _ => None,
+        }
+    }
+
+    // The name of the transaction's type, as used for the `type` column of
+    // the CSV input.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Transaction::Deposit { .. } => "deposit",
+            Transaction::Withdrawal { .. } => "withdrawal",
+            Transaction::Dispute { .. } => "dispute",
+            Transaction::Resolve { .. } => "resolve",
+            Transaction::Chargeback { .. } => "chargeback",
+            Transaction::Recovery => "recovery",
+            Transaction::Fee { .. } => "fee",
+            Transaction::AdjustCredit { .. } => "adjust_credit",
+            Transaction::AdjustDebit { .. } => "adjust_debit",
+            Transaction::Refund { .. } => "refund",
+            Transaction::Lock => "lock",
+            Transaction::Unlock => "unlock",
+            Transaction::OpenAccount { .. } => "open_account",
+            Transaction::CloseAccount => "close_account",
+            Transaction::Authorize { .. } => "authorize",
+            Transaction::Capture { .. } => "capture",
+            Transaction::Void { .. } => "void",
+            Transaction::Scheduled { transaction, .. }
+            | Transaction::Timestamped { transaction, .. }
+            | Transaction::InCurrency { transaction, .. } => transaction.kind(),
+        }
+    }
+
+    // The ID of the transaction, or of the past transaction it refers to.
+    // Recoveries, locks, unlocks, and account openings and closings have
+    // neither.
+    pub fn id(&self) -> Option<TransactionId> {
+        match self {
+            Transaction::Deposit { new_id, .. }
+            | Transaction::Withdrawal { new_id, .. }
+            | Transaction::Fee { new_id, .. }
+            | Transaction::AdjustCredit { new_id, .. }
+            | Transaction::AdjustDebit { new_id, .. }
+            | Transaction::Authorize { new_id, .. } => Some(*new_id),
+            Transaction::Dispute { id, .. }
+            | Transaction::Resolve { id }
+            | Transaction::Chargeback { id }
+            | Transaction::Refund { id, .. }
+            | Transaction::Capture { id }
+            | Transaction::Void { id } => Some(*id),
+            Transaction::Recovery
+            | Transaction::Lock
+            | Transaction::Unlock
+            | Transaction::OpenAccount { .. }
+            | Transaction::CloseAccount => None,
+            Transaction::Scheduled { transaction, .. }
+            | Transaction::Timestamped { transaction, .. }
+            | Transaction::InCurrency { transaction, .. } => transaction.id(),
+        }
+    }
+
+    // The time the transaction happened, if it was given one.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        match self {
+            Transaction::Timestamped { timestamp, .. } => Some(*timestamp),
+            Transaction::Scheduled { transaction, .. }
+            | Transaction::InCurrency { transaction, .. } => transaction.timestamp(),
+            _ => None,
+        }
+    }
+
+    // The currency of the transaction, if it was given one.
+    pub fn currency(&self) -> Option<Currency> {
+        match self {
+            Transaction::InCurrency { currency, .. } => Some(*currency),
+            Transaction::Scheduled { transaction, .. }
+            | Transaction::Timestamped { transaction, .. } => transaction.currency(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Error, PartialEq, Eq, Debug, Clone)]
+pub enum TransactionError {
+    #[error("The account is frozen")]
+    AccountFrozen,
+    #[error("Insufficient funds to withdraw requested amount")]
+    InsufficientFunds,
+    #[error("Attempted dispute, resolution, or chargeback of a transaction that doesn't exist")]
+    NonexistentTransaction,
+    #[error("The transaction that was attempted to dispute is not currently settled")]
+    NotSettled,
+    #[error("The transaction that was attempted to resolve is not under dispute")]
+    NotDisputed,
+    #[error("The account doesn't exist")]
+    NonexistentAccount,
+    #[error("The account already exists")]
+    AccountExists,
+    #[error("Recovery was attempted for an account without a negative balance")]
+    NoDeficit,
+    #[error("Zero amounts are not allowed")]
+    ZeroAmount,
+    #[error("The transaction ID has already been used")]
+    DuplicateTransaction,
+    #[error("The dispute would hold more of the account's funds than allowed")]
+    HeldRatioExceeded,
+    #[error("The transaction couldn't be written to the write-ahead log")]
+    NotLogged,
+    #[error("Fees can't be charged without a fee account to credit them to")]
+    NoFeeAccount,
+    #[error("The refund is larger than the amount of the refunded transaction")]
+    RefundTooLarge,
+    #[error("The disputed amount is larger than the amount of the disputed transaction")]
+    DisputeTooLarge,
+    #[error("The transaction that was attempted to capture or void is not an open authorization")]
+    NotAuthorized,
+    #[error("The withdrawal would go beyond the account's withdrawal limit")]
+    LimitExceeded,
+    #[error("The amount is larger than the most a deposit or withdrawal may have")]
+    AmountTooLarge,
+    #[error("The referred transaction belongs to another client")]
+    ClientMismatch,
+    #[error("The transaction that was attempted to dispute is older than the dispute window")]
+    DisputeWindowExpired,
+    #[error("The transaction is older than the latest transaction of the account")]
+    OutOfOrder,
+    #[error("The referred transaction is in another currency")]
+    CurrencyMismatch,
+    #[error("The amount has more decimal places than its currency's minor units")]
+    TooManyDecimals,
+    #[error("The balance would be larger than the ledger can hold")]
+    Overflow,
+    #[error("The account is closed")]
+    AccountClosed,
+    #[error("The account can't be closed while it has a balance")]
+    BalanceNotZero,
+    #[error("Only deposits can be refunded")]
+    NotRefundable,
+    #[error("The amount has to be larger than zero")]
+    NonPositiveAmount,
+    #[error("The transaction store couldn't be read or written")]
+    StoreFailed,
+}
+
+impl TransactionError {
+    // A stable, machine-readable code for the error, e.g. for reports. It's
+    // the name of the error's `ErrorCode`.
+    pub fn code(&self) -> &'static str {
+        error_code::ErrorCode::from(self).name()
+    }
+}
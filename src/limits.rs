@@ -0,0 +1,213 @@
+use std::{collections::VecDeque, fmt, str::FromStr};
+
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{policy::ParsePolicyError, AccountId, Timestamp, TransactionAmount, TransactionError};
+
+// The rule accounts are frozen by for going beyond the withdrawal limit,
+// see `Policy::limit_freeze`.
+pub const LIMIT_RULE: &str = "withdrawal limit";
+
+// WithdrawalLimit caps the withdrawals of every account within a sliding
+// window of time, e.g. at most 5 withdrawals or 10,000 per day, as a basic
+// fraud control. Either cap may be left out. It's written as
+// `COUNT,AMOUNT,SECONDS` with the caps left out empty, e.g. `5,10000,86400`
+// or `,10000,86400`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WithdrawalLimit {
+    pub count: Option<usize>,
+    pub amount: Option<TransactionAmount>,
+    pub window: Timestamp,
+}
+
+impl FromStr for WithdrawalLimit {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParsePolicyError(s.to_owned());
+        let mut parts = s.split(',');
+        let (Some(count), Some(amount), Some(window), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let count = match count {
+            "" => None,
+            count => Some(count.parse().map_err(|_| invalid())?),
+        };
+        let amount = match amount {
+            "" => None,
+            amount => match amount.parse::<TransactionAmount>() {
+                Ok(amount) if !amount.is_sign_negative() => Some(amount),
+                _ => return Err(invalid()),
+            },
+        };
+        match window.parse() {
+            Ok(window) if window > 0 && (count.is_some() || amount.is_some()) => {
+                Ok(WithdrawalLimit {
+                    count,
+                    amount,
+                    window,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for WithdrawalLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(count) = self.count {
+            write!(f, "{}", count)?;
+        }
+        f.write_str(",")?;
+        if let Some(amount) = self.amount {
+            write!(f, "{}", amount.normalize())?;
+        }
+        write!(f, ",{}", self.window)
+    }
+}
+
+// Withdrawals keeps the recent withdrawals of every account, with their time
+// and amount, oldest first, to check them against a `WithdrawalLimit`.
+// Withdrawals are expected in the order of their time: those older than the
+// window of the latest one are forgotten.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Withdrawals(pub(crate) FxHashMap<AccountId, VecDeque<(Timestamp, TransactionAmount)>>);
+
+impl Withdrawals {
+    // Fail if withdrawing `amount` from the client's account at `time`
+    // would go beyond the limit.
+    pub fn check(
+        &mut self,
+        limit: &WithdrawalLimit,
+        client: AccountId,
+        time: Timestamp,
+        amount: TransactionAmount,
+    ) -> Result<(), TransactionError> {
+        let (mut count, mut total) = (1, amount);
+        if let Some(recent) = self.0.get_mut(&client) {
+            while recent
+                .front()
+                .and_then(|&(at, _)| at.checked_add(limit.window))
+                .is_some_and(|end| end <= time)
+            {
+                recent.pop_front();
+            }
+            count += recent.len();
+            // Totals too large to add up are over any cap anyway.
+            total = recent
+                .iter()
+                .fold(total, |total, &(_, amount)| total.saturating_add(amount));
+        }
+        if limit.count.is_some_and(|max| count > max) || limit.amount.is_some_and(|max| total > max)
+        {
+            return Err(TransactionError::LimitExceeded);
+        }
+        Ok(())
+    }
+
+    // Keep a withdrawal that was applied.
+    pub fn record(&mut self, client: AccountId, time: Timestamp, amount: TransactionAmount) {
+        self.0.entry(client).or_default().push_back((time, amount));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WithdrawalLimit, Withdrawals};
+    use crate::{
+        ledger::{Ledger, Reject},
+        scaling::AmountScale,
+        Timestamp, TransactionAmount, TransactionError,
+    };
+
+    #[test]
+    fn withdrawal_limits() {
+        let limit: WithdrawalLimit = "2,100,10".parse().unwrap();
+        assert_eq!(limit.to_string(), "2,100,10");
+        assert_eq!(
+            ",1.50,10".parse::<WithdrawalLimit>().unwrap().to_string(),
+            ",1.5,10"
+        );
+        for invalid in ["", ",,10", "2,100,0", "2,-1,10", "2,100", "2,100,10,1"] {
+            assert!(invalid.parse::<WithdrawalLimit>().is_err(), "{}", invalid);
+        }
+
+        let mut withdrawals = Withdrawals::default();
+        assert_eq!(
+            withdrawals.check(&limit, 1, 0, 101.into()),
+            Err(TransactionError::LimitExceeded)
+        );
+        withdrawals.record(1, 0, 60.into());
+        assert_eq!(
+            withdrawals.check(&limit, 1, 5, 50.into()),
+            Err(TransactionError::LimitExceeded)
+        );
+        withdrawals.record(1, 5, 40.into());
+        assert_eq!(
+            withdrawals.check(&limit, 1, 9, 1.into()),
+            Err(TransactionError::LimitExceeded)
+        );
+        // Other clients have limits of their own, and the first withdrawal
+        // leaves the window after 10 seconds.
+        assert_eq!(withdrawals.check(&limit, 2, 9, 1.into()), Ok(()));
+        assert_eq!(withdrawals.check(&limit, 1, 10, 60.into()), Ok(()));
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Withdrawals too large to add up are over the cap rather than
+        // overflowing, and windows running past the end of time never end.
+        let max = crate::funds::max();
+        let limit = WithdrawalLimit {
+            count: None,
+            amount: Some(max - TransactionAmount::ONE),
+            window: Timestamp::MAX,
+        };
+        let mut withdrawals = Withdrawals::default();
+        assert_eq!(withdrawals.check(&limit, 1, 0, 1.into()), Ok(()));
+        withdrawals.record(1, 0, max);
+        withdrawals.record(1, 1, max);
+        assert_eq!(
+            withdrawals.check(&limit, 1, Timestamp::MAX, 1.into()),
+            Err(TransactionError::LimitExceeded)
+        );
+
+        // Withdrawals that are rejected for other reasons, e.g. from a
+        // closed account, don't count, and refunds of withdrawals are
+        // rejected without touching the limit.
+        let input = "\
+type,client,tx,amount,timestamp
+deposit,1,1,10,0
+withdrawal,1,2,1,1
+refund,1,2,,2
+withdrawal,1,3,100,3
+withdrawal,1,4,1,4
+withdrawal,1,5,1,5
+deposit,2,6,5,0
+withdrawal,2,7,5,1
+close_account,2,0,,2
+withdrawal,2,8,1,3
+";
+        let mut ledger = Ledger::builder()
+            .withdrawal_limit("2,,100".parse().unwrap())
+            .build();
+        let mut rejects = vec![];
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+        assert_eq!(
+            rejects,
+            [
+                (4, "not_refundable"),
+                (5, "insufficient_funds"),
+                (7, "limit_exceeded"),
+                (11, "account_closed"),
+            ]
+        );
+    }
+}
@@ -1,65 +1,572 @@
-use rust_decimal::Decimal;
-use std::error::Error;
-use thiserror::Error;
-
-mod account;
-mod ledger;
-
-// Define some types used across the entire program
-type TransactionId = u32;
-type AccountId = u16;
-type Balance = Decimal;
-type TransactionAmount = Decimal;
-
-#[derive(Debug, PartialEq, Eq)]
-pub enum Transaction {
-    Deposit {
-        new_id: TransactionId,
-        amount: TransactionAmount,
-    },
-    Withdrawal {
-        new_id: TransactionId,
-        amount: TransactionAmount,
-    },
-    Dispute {
-        id: TransactionId,
-    },
-    Resolve {
-        id: TransactionId,
-    },
-    Chargeback {
-        id: TransactionId,
-    },
+use std::{collections::HashMap, error::Error};
+
+#[cfg(feature = "alloc-stats")]
+use ::ledger::alloc_stats;
+#[cfg(feature = "grpc")]
+use ::ledger::grpc;
+#[cfg(feature = "mmap")]
+use ::ledger::mapped;
+#[cfg(feature = "websocket")]
+use ::ledger::websocket;
+use ::ledger::{
+    anomaly, atomic_file, backfill, carry_forward, client_names, compare, compression, daemon,
+    dead_letter, diff, error_sink, events, generate, journal, ledger, normalize, policy, scaling,
+    shared, simulation, statement, stats, wal, AccountId,
+};
+
+mod cli;
+
+use cli::{Command, Mode, Options};
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOCATOR: alloc_stats::CountingAllocator = alloc_stats::CountingAllocator::new();
+
+fn main() -> Result<(), Box<dyn Error>> {
+    #[cfg_attr(not(feature = "alloc-stats"), allow(unused_mut))]
+    let mut phases = PhaseStats::default();
+    // The 0th argument is the program name.
+    let options = match cli::parse(std::env::args().skip(1))? {
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            return Ok(());
+        }
+        Command::Normalize { input, output } => return normalize_file(&input, &output),
+        Command::Generate { generator, output } => return generate_file(&generator, output),
+        Command::Diff { snapshots, a, b } => return diff_files(snapshots, &a, &b),
+        Command::Compare { a, b } => return compare_runs(&a, &b),
+        Command::Run(options) => *options,
+    };
+    #[cfg(feature = "alloc-stats")]
+    if options.alloc_stats {
+        phases.enable();
+    }
+    if !options.simulated_settings.is_empty() {
+        return simulate(options);
+    }
+
+    // Attempt to open the files passed on the command line.
+    let file = options
+        .filename
+        .as_ref()
+        .map(std::fs::File::open)
+        .transpose()?;
+    let mut ledger = build_ledger(&options)?;
+    let baseline = match &options.baseline {
+        Some(baseline) => Some(anomaly::read_baseline(std::fs::File::open(baseline)?)?),
+        None => None,
+    };
+    let backfill = options
+        .backfill
+        .as_ref()
+        .map(std::fs::File::open)
+        .transpose()?;
+    let dead_letters = match &options.dead_letters {
+        Some(path) => Some(dead_letter::DeadLetters::append(path.as_ref())?),
+        None => None,
+    };
+    phases.end("setup");
+
+    restore(&mut ledger, &options)?;
+    if let Some(file) = file {
+        read_input(&mut ledger, &options, file)?;
+    }
+    // The log is replayed on top of the seeded ledger, so a server restarted
+    // after a crash has to be seeded the same way.
+    let write_ahead_log = match &options.write_ahead_log {
+        Some(path) => {
+            let (log, replayed) = wal::WriteAheadLog::open(path.as_ref(), &mut ledger)?;
+            if replayed > 0 {
+                eprintln!(
+                    "replayed {} transactions from the write-ahead log",
+                    replayed
+                );
+            }
+            Some(log)
+        }
+        None => None,
+    };
+    phases.end("input");
+
+    let (check_ordering, latency_budget) = (options.check_ordering, options.latency_budget);
+    let (backfill_rate, scale) = (options.backfill_rate, options.scale);
+    match options.mode {
+        Mode::Batch => write_outputs(ledger, &options, baseline, &mut phases)?,
+        Mode::Listen(address) => {
+            let ledger = serving_ledger(ledger, check_ordering, write_ahead_log, latency_budget);
+            spawn_backfill(&ledger, backfill, backfill_rate, scale);
+            daemon::serve(address, ledger, dead_letters)?
+        }
+        #[cfg(feature = "grpc")]
+        Mode::Grpc(addr) => {
+            let ledger = serving_ledger(ledger, check_ordering, write_ahead_log, latency_budget);
+            spawn_backfill(&ledger, backfill, backfill_rate, scale);
+            grpc::serve(addr, ledger, dead_letters)?
+        }
+    }
+
+    Ok(())
 }
 
-#[derive(Error, PartialEq, Eq, Debug)]
-pub enum TransactionError {
-    #[error("The account is frozen")]
-    AccountFrozen,
-    #[error("Insufficient funds to withdraw requested amount")]
-    InsufficientFunds,
-    #[error("Attempted dispute, resolution, or chargeback of a transaction that doesn't exist")]
-    NonexistentTransaction,
-    #[error("The transaction that was attempted to dispute is not currently settled")]
-    NotSettled,
-    #[error("The transaction that was attempted to resolve is not under dispute")]
-    NotDisputed,
+// Normalize the transactions in `input`, writing them to `output`.
+fn normalize_file(input: &str, output: &str) -> Result<(), Box<dyn Error>> {
+    let input = std::fs::File::open(input)?;
+    atomic_file::write_atomically(output.as_ref(), |file| {
+        normalize::normalize(input, file, error_sink::Stderr).map_err(std::io::Error::other)
+    })?;
+    Ok(())
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    // Attempt to open the file passed on the command line.
-    let file = std::env::args()
-        // The 0th argument is the program name, the 1st should be the filename.
-        .nth(1)
-        // Error out if no filename is given
-        .ok_or("no filename given")
-        // If filename was given attempt to open it as a File.
-        .map(std::fs::File::open)??;
+// Write generated transactions to `output`, or stdout.
+fn generate_file(
+    generator: &generate::Generator,
+    output: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    match output {
+        Some(output) => atomic_file::write_atomically(output.as_ref(), |file| {
+            generator
+                .write_csv(std::io::BufWriter::new(file))
+                .map_err(std::io::Error::other)
+        })?,
+        None => generator.write_csv(std::io::stdout().lock())?,
+    }
+    Ok(())
+}
 
-    let ledger = ledger::Ledger::from_csv_reader(file);
+// Write the differences between the account summaries, or snapshots, `a`
+// and `b` to stdout.
+fn diff_files(snapshots: bool, a: &str, b: &str) -> Result<(), Box<dyn Error>> {
+    let read = |path: &str| -> Result<diff::Summaries, Box<dyn Error>> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let summaries = if snapshots {
+            diff::Summaries::from_snapshot(file)
+        } else {
+            diff::Summaries::read_csv(file)
+        };
+        summaries.map_err(|err| format!("{}: {}", path, err).into())
+    };
+    let (a, b) = (read(a)?, read(b)?);
+    let differences = diff::diff(&a, &b);
+    diff::write_csv(
+        &differences,
+        diff::has_currencies(&a, &b),
+        std::io::stdout().lock(),
+    )?;
+    // Like diff(1), differences make for a failed exit status.
+    if !differences.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-    let mut stdout = std::io::stdout();
-    ledger.accounts_to_csv(&mut stdout);
+// Write the differences between the runs described by the manifests `a` and
+// `b` to stdout.
+fn compare_runs(a: &str, b: &str) -> Result<(), Box<dyn Error>> {
+    let read = |path: &str| compare::Manifest::read(path.as_ref()).map_err(|err| err.to_string());
+    let (a, b) = (read(a)?, read(b)?);
+    let differences = compare::compare(&a, &b).map_err(|err| err.to_string())?;
+    compare::write_csv(&differences, std::io::stdout().lock())?;
+    // Like `diff`, differences make for a failed exit status.
+    if !differences.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
+// Simulations replay the file under both the actual and the simulated
+// policy and only report the differences between them.
+fn simulate(options: Options) -> Result<(), Box<dyn Error>> {
+    let mut simulated = options.policy.clone();
+    for setting in &options.simulated_settings {
+        simulated.set(setting)?;
+    }
+    let mut simulation = simulation::Simulation::new(options.policy, simulated);
+    if let Some(opening_balances) = options.opening_balances {
+        let contents = carry_forward::read_verified(opening_balances.as_ref())?;
+        simulation.read_opening_balances(&contents);
+    }
+    simulation.read_csv(&std::fs::read(
+        options.filename.ok_or("no filename given")?,
+    )?);
+    match options.output {
+        Some(output) => atomic_file::write_atomically(output.as_ref(), |file| {
+            let mut writer = options.compression.writer(file)?;
+            simulation.differences_to_csv(&mut writer)?;
+            writer.finish().map(drop)
+        })?,
+        None => simulation.differences_to_csv(std::io::stdout())?,
+    }
     Ok(())
 }
+
+// Build the ledger the options describe, with the store of its processed
+// transactions and its observers.
+fn build_ledger(options: &Options) -> Result<ledger::Ledger, Box<dyn Error>> {
+    let mut builder = ledger::Ledger::builder()
+        .policy(options.policy.clone())
+        .precision(options.precision)
+        .expected_transactions(options.expected_transactions);
+    if let Some(output_filter) = &options.filter {
+        builder = builder.filter(output_filter.clone());
+    }
+    if options.freeze_reasons {
+        builder = builder.freeze_reasons();
+    }
+    // Statements, the behavior of clients, and the stats are derived from
+    // the statements, which aren't kept otherwise.
+    if options.statement.is_some()
+        || options.behavior.is_some()
+        || options.anomalies.is_some()
+        || options.stats.is_some()
+    {
+        builder = builder.keep_statements();
+    }
+    if options.shards > 1 {
+        builder = builder.shards(options.shards);
+    }
+    #[cfg(feature = "sled")]
+    if let Some(tx_store) = &options.tx_store {
+        builder = builder.tx_store(::ledger::tx_store::SledTxStore::open(tx_store.as_ref())?);
+    }
+    if options.compact_transactions {
+        builder = builder.tx_store(::ledger::tx_store::CompactTxStore::default());
+    }
+    if let Some(max_memory) = options.max_memory {
+        builder = builder.tx_store(::ledger::tx_store::SpillTxStore::new(max_memory));
+    }
+    if let Some(cold_after) = options.cold_after {
+        // The cold tier is compressed like the outputs, or at the fastest
+        // level if they aren't compressed.
+        let compression = if options.compression == compression::Compression::None {
+            compression::Compression::zstd(1).unwrap_or_default()
+        } else {
+            options.compression
+        };
+        builder = builder.tx_store(::ledger::tx_store::ColdTxStore::new(
+            cold_after,
+            compression,
+        ));
+    }
+    let mut ledger = builder.build();
+
+    // Transactions out of order are applied, but not without a word.
+    if options.policy.out_of_order == policy::OutOfOrderPolicy::Warn {
+        ledger.add_observer(|event: &events::LedgerEvent| {
+            if let events::LedgerEvent::OutOfOrder {
+                client,
+                timestamp,
+                latest,
+                ..
+            } = event
+            {
+                eprintln!(
+                    "warning: transaction of client {} at {} is older than its latest at {}",
+                    client, timestamp, latest
+                );
+            }
+        });
+    }
+    if let Some(journal) = &options.journal {
+        ledger.add_observer(journal::Journal::append_compressed(
+            journal.as_ref(),
+            options.compression,
+        )?);
+    }
+    #[cfg(feature = "websocket")]
+    if let Some(addr) = options.websocket {
+        ledger.add_observer(websocket::listen(addr)?);
+    }
+    Ok(ledger)
+}
+
+// Put the state the run starts from in place: the snapshot being resumed,
+// or the opening balances, and the scheduled transactions whose time has
+// come.
+fn restore(ledger: &mut ledger::Ledger, options: &Options) -> Result<(), Box<dyn Error>> {
+    // Resuming picks up the state the previous run left behind. The first
+    // run has nothing to resume from and starts empty.
+    if let Some(resume) = &options.resume {
+        match std::fs::File::open(resume) {
+            Ok(file) => ledger.load_snapshot(std::io::BufReader::new(file))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    // Opening balances have to be in place before any transaction is applied.
+    if let Some(opening_balances) = &options.opening_balances {
+        let contents = carry_forward::read_verified(opening_balances.as_ref())?;
+        ledger.read_opening_balances(contents.as_slice());
+    }
+
+    // Scheduled transactions, e.g. left in the snapshot being resumed, take
+    // effect once their time has come, as do those read below.
+    let as_of = match options.as_of {
+        Some(as_of) => as_of,
+        None => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+    };
+    for (client, tx, error) in ledger.advance_to(as_of) {
+        eprintln!(
+            "scheduled {} of client {} rejected: {}",
+            tx.kind(),
+            client,
+            error
+        );
+    }
+    Ok(())
+}
+
+// Apply the transactions file to the ledger, reporting the lines it skips.
+fn read_input(
+    ledger: &mut ledger::Ledger,
+    options: &Options,
+    file: std::fs::File,
+) -> Result<(), Box<dyn Error>> {
+    #[cfg(feature = "mmap")]
+    let mapped = options.mmap.then(|| mapped::map(&file)).transpose()?;
+    #[cfg(not(feature = "mmap"))]
+    let mapped: Option<Vec<u8>> = None;
+    let (scale, strict) = (options.scale, options.strict);
+    // Strict runs and those collecting errors report the lines they skip
+    // once the file is read, strict ones by failing with the line they
+    // stopped at.
+    let collect = strict || ledger.policy().errors == policy::ErrorPolicy::Collect;
+    let mut report = error_sink::ProcessingReport::default();
+    let collect_sink = |reject| report.rejects.push(reject);
+    let check = match (&options.rejects, &mapped) {
+        (_, Some(input)) if collect => ledger.read_mapped_csv_with(input, scale, collect_sink),
+        (_, None) if collect => ledger.read_csv_with(file, scale, collect_sink),
+        (Some(rejects), Some(input)) => ledger.read_mapped_csv_with_rejects(
+            input,
+            scale,
+            std::fs::File::create(rejects)?,
+            error_sink::Stderr,
+        )?,
+        (Some(rejects), None) => ledger.read_csv_with_rejects(
+            file,
+            scale,
+            std::fs::File::create(rejects)?,
+            error_sink::Stderr,
+        )?,
+        (None, Some(input)) => ledger.read_mapped_csv_with(input, scale, error_sink::Stderr),
+        (None, None) => ledger.read_csv_with(file, scale, error_sink::Stderr),
+    };
+    if let Some(warning) = check.warning() {
+        eprintln!("warning: {}", warning);
+    }
+    if collect {
+        report.rejects.extend(ledger.take_report().rejects);
+        if let Some(rejects) = &options.rejects {
+            report.to_csv(std::fs::File::create(rejects)?)?;
+        }
+        if let (true, Some(reject)) = (strict, report.rejects.first()) {
+            return Err(reject.to_string().into());
+        }
+        report_collected(&report);
+    }
+    Ok(())
+}
+
+// Write the reports of a batch run, and then the account summaries, or the
+// statement.
+fn write_outputs(
+    ledger: ledger::Ledger,
+    options: &Options,
+    baseline: Option<HashMap<AccountId, anomaly::Behavior>>,
+    phases: &mut PhaseStats,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(carry_forward) = &options.carry_forward {
+        carry_forward::write(&ledger, carry_forward.as_ref())?;
+    }
+    if let Some(recoveries) = &options.recoveries {
+        ledger.recoveries_to_csv(std::fs::File::create(recoveries)?)?;
+    }
+    if let Some(account_metadata) = &options.account_metadata {
+        ledger.account_metadata_to_csv(std::fs::File::create(account_metadata)?)?;
+    }
+    if let (Some(expected), Some(discrepancies)) =
+        (&options.expected_balances, &options.discrepancies)
+    {
+        let expected = std::io::BufReader::new(std::fs::File::open(expected)?);
+        let reconciliation = ledger.reconcile(expected)?;
+        reconciliation.to_csv(std::fs::File::create(discrepancies)?)?;
+        if !reconciliation.is_clean() {
+            let mismatched = reconciliation.discrepancies.len();
+            eprintln!(
+                "warning: {} of {} accounts didn't match the expected balances",
+                mismatched,
+                mismatched + reconciliation.matched
+            );
+        }
+    }
+    if options.behavior.is_some() || options.anomalies.is_some() {
+        let behaviors = anomaly::behaviors(&ledger);
+        if let Some(behavior) = &options.behavior {
+            anomaly::behaviors_to_csv(&behaviors, std::fs::File::create(behavior)?)?;
+        }
+        if let (Some(baseline), Some(anomalies)) = (baseline, &options.anomalies) {
+            let flagged = anomaly::detect(&baseline, &behaviors, options.anomaly_factor);
+            anomaly::anomalies_to_csv(&flagged, std::fs::File::create(anomalies)?)?;
+        }
+    }
+    if let Some(stats) = &options.stats {
+        let buckets = options.stats_buckets.clone();
+        stats::Stats::from_ledger(&ledger, buckets).to_csv(std::fs::File::create(stats)?)?;
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(sqlite) = &options.sqlite {
+        ledger.export_sqlite(sqlite.as_ref())?;
+    }
+    if let Some(resume) = &options.resume {
+        atomic_file::write_atomically(resume.as_ref(), |file| {
+            let mut writer = std::io::BufWriter::new(file);
+            ledger
+                .save_snapshot(&mut writer)
+                .map_err(std::io::Error::other)?;
+            std::io::Write::flush(&mut writer)
+        })?;
+    }
+
+    phases.end("reports");
+
+    let statement = match &options.statement {
+        Some(client_names::ClientKey::Name(name)) => Some(
+            ledger
+                .client_names()
+                .id(name)
+                .ok_or_else(|| format!("client {} has no account", name))?,
+        ),
+        Some(client_names::ClientKey::Id(client)) => Some(*client),
+        None => None,
+    };
+    // The account summaries are written last, and consume the ledger.
+    let policy = ledger.policy().clone();
+    let (format, compression) = (options.format, options.compression);
+    match (statement, &options.output) {
+        (Some(client), Some(output)) => atomic_file::write_atomically(output.as_ref(), |file| {
+            let mut writer = compression.writer(file)?;
+            statement::write_statement(&ledger, client, format, &mut writer)
+                .map_err(std::io::Error::other)?;
+            writer.finish().map(drop)
+        })?,
+        (Some(client), None) => {
+            statement::write_statement(&ledger, client, format, std::io::stdout())?
+        }
+        (None, Some(output)) => atomic_file::write_atomically(output.as_ref(), |file| {
+            let mut writer = compression.writer(file)?;
+            ledger.write_accounts(format, options.order, &mut writer);
+            writer.finish().map(drop)
+        })?,
+        (None, None) => ledger.write_accounts(format, options.order, &mut std::io::stdout()),
+    }
+    phases.end("output");
+
+    if let (Some(manifest), Some(output)) = (&options.manifest, &options.output) {
+        compare::Manifest::new(
+            options.filename.as_deref().map(std::path::Path::new),
+            &policy,
+            output.as_ref(),
+            options.rejects.as_deref().map(std::path::Path::new),
+            options.stats.as_deref().map(std::path::Path::new),
+        )?
+        .write(manifest.as_ref())?;
+    }
+    Ok(())
+}
+
+// Print the lines skipped by `--on-error collect` on stderr, followed by how
+// many were skipped for every reason.
+fn report_collected(report: &error_sink::ProcessingReport) {
+    if report.is_empty() {
+        return;
+    }
+    for reject in &report.rejects {
+        eprintln!("{}", reject);
+    }
+    let counts = report
+        .counts()
+        .into_iter()
+        .map(|(reason, count)| format!("{} {}", count, reason))
+        .collect::<Vec<_>>();
+    eprintln!(
+        "{} lines skipped: {}",
+        report.rejects.len(),
+        counts.join(", ")
+    );
+}
+
+// Share the ledger between the connections of a server.
+fn serving_ledger(
+    ledger: ledger::Ledger,
+    check_ordering: bool,
+    write_ahead_log: Option<wal::WriteAheadLog>,
+    latency_budget: Option<std::time::Duration>,
+) -> shared::SharedLedger {
+    let mut ledger = if check_ordering {
+        shared::SharedLedger::with_ordering_checks(ledger)
+    } else {
+        ledger.into()
+    };
+    if let Some(log) = write_ahead_log {
+        ledger = ledger.with_wal(log);
+    }
+    if let Some(budget) = latency_budget {
+        ledger = ledger.with_latency_budget(budget);
+    }
+    ledger
+}
+
+// Backfill the file in the background while serving, reporting the progress
+// on stderr.
+fn spawn_backfill(
+    ledger: &shared::SharedLedger,
+    file: Option<std::fs::File>,
+    rate: u32,
+    scale: scaling::AmountScale,
+) {
+    let Some(file) = file else {
+        return;
+    };
+    let ledger = ledger.clone();
+    std::thread::spawn(move || {
+        let progress = backfill::Backfill::new(rate).run(
+            &ledger,
+            std::io::BufReader::new(file),
+            scale,
+            error_sink::Stderr,
+            |progress: &backfill::BackfillProgress| eprintln!("backfill: {}", progress),
+        );
+        eprintln!("backfill finished: {}", progress);
+    });
+}
+
+// PhaseStats reports the allocations made in each phase of a run on stderr,
+// with `--alloc-stats`. Without the `alloc-stats` feature there's nothing to
+// report.
+#[derive(Default)]
+struct PhaseStats {
+    #[cfg(feature = "alloc-stats")]
+    start: Option<alloc_stats::AllocStats>,
+}
+
+impl PhaseStats {
+    // Start counting from the beginning of the run: options are parsed
+    // before the flag is seen.
+    #[cfg(feature = "alloc-stats")]
+    fn enable(&mut self) {
+        self.start = Some(alloc_stats::AllocStats::default());
+    }
+
+    // Report the allocations since the end of the last phase.
+    #[cfg_attr(not(feature = "alloc-stats"), allow(unused_variables))]
+    fn end(&mut self, phase: &str) {
+        #[cfg(feature = "alloc-stats")]
+        if let Some(start) = self.start {
+            let now = ALLOCATOR.snapshot();
+            eprintln!("{}: {}", phase, now.since(start));
+            self.start = Some(now);
+        }
+    }
+}
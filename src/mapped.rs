@@ -0,0 +1,457 @@
+use std::thread;
+
+use crate::{
+    client_names::read_csv_named,
+    error_sink::ErrorSink,
+    ledger::{
+        csv_headers, csv_reader, offset_position, parse_csv_line, write_rejects, CsvHeaders,
+        Ledger, ParsedLine, Position, ProcessingError, Reject,
+    },
+    policy::{AmountPolicy, ClientIdPolicy, ErrorPolicy},
+    reorder::read_csv_reordered,
+    scaling::{AmountScale, ScaleCheck},
+    shard::shard_of,
+};
+
+// The number of bytes of input every thread parses at once. All threads
+// parse their chunk before the transactions are applied, so this bounds the
+// memory taken by the parsed lines.
+const CHUNK_LEN: usize = 4 * 1024 * 1024;
+
+// A line parsed on one of the parsing threads, or the reason it's skipped,
+// along with the number of its record, to order the skipped lines by. Their
+// line numbers aren't enough: the CSV reader may give several records the
+// same one, see `first_record`.
+type Parsed = (u64, Result<ParsedLine, Reject>);
+
+impl Ledger {
+    // Like `read_csv_with`, but read CSV input that's entirely in memory,
+    // e.g. a file mapped by `map`, parsing it on a thread per core: the
+    // input is split into chunks, the chunks are parsed in parallel, and
+    // then the parsed transactions are applied on the ledger's shards, see
+    // `LedgerBuilder::shards`, or in order on a single thread if it doesn't
+    // have any. This repeats until the whole input is read.
+    //
+    // Chunks are split at the end of a line, so unlike `read_csv_with`,
+    // quoted fields can't span lines. Otherwise the result is the same,
+    // except that, like with shards, skipped lines are only passed to `sink`
+    // once the chunk they're in is applied.
+    pub fn read_mapped_csv_with<S: ErrorSink>(
+        &mut self,
+        input: &[u8],
+        scale: AmountScale,
+        sink: S,
+    ) -> ScaleCheck {
+        let threads = thread::available_parallelism().map_or(1, usize::from);
+        self.with_reject_sink(sink, |ledger, sink| {
+            // Names are given account IDs in the order they're read, so the
+            // lines can't be parsed in parallel.
+            if ledger.policy().client_ids == ClientIdPolicy::Text {
+                return read_csv_named(ledger, input, scale, sink);
+            }
+            match ledger.policy().reorder_window {
+                // Lines held back to be reordered are applied one by one
+                // anyway.
+                Some(window) => read_csv_reordered(ledger, input, scale, window, sink),
+                None => ledger.read_chunks(input, scale, sink, threads, CHUNK_LEN),
+            }
+        })
+    }
+
+    // Read in-memory input like `read_mapped_csv_with`, `threads` chunks of
+    // about `chunk_len` bytes at a time.
+    fn read_chunks<S: ErrorSink>(
+        &mut self,
+        input: &[u8],
+        scale: AmountScale,
+        mut sink: S,
+        threads: usize,
+        chunk_len: usize,
+    ) -> ScaleCheck {
+        let mut check = ScaleCheck::new(scale);
+        let body = line_end(input, 1);
+        let mut reader = csv_reader(&input[..body]);
+        let Some(headers) = csv_headers(&mut reader, &mut sink) else {
+            return check;
+        };
+
+        let shards = self.parallel_shards();
+        let abort = self.policy().errors == ErrorPolicy::Abort;
+        let amounts = self.policy().amounts;
+        let mut split = if shards > 1 {
+            self.split_into_shards(shards)
+        } else {
+            vec![]
+        };
+        let mut ledgers = if split.is_empty() {
+            vec![&mut *self]
+        } else {
+            split.iter_mut().collect::<Vec<_>>()
+        };
+
+        // Where the next chunks start.
+        let mut start = csv::Position::new();
+        start.set_byte(body as u64).set_line(2).set_record(1);
+        while (start.byte() as usize) < input.len() {
+            let from = start.byte() as usize;
+            let to = line_end(input, from + threads * chunk_len);
+            let chunks = split_lines(&input[from..to], threads);
+
+            // Every chunk needs to know where it starts before it's parsed,
+            // to number its lines.
+            let starts = chunks
+                .iter()
+                .map(|chunk| {
+                    let chunk_start = start.clone();
+                    let first = first_record(input, &chunk_start);
+                    let (lines, records) = count_lines(chunk);
+                    start
+                        .set_byte(chunk_start.byte() + chunk.len() as u64)
+                        .set_line(chunk_start.line() + lines)
+                        .set_record(chunk_start.record() + records);
+                    (chunk_start, first)
+                })
+                .collect::<Vec<_>>();
+
+            let parsed = thread::scope(|scope| {
+                let handles = chunks
+                    .iter()
+                    .zip(&starts)
+                    .map(|(chunk, (start, first))| {
+                        let headers = &headers;
+                        scope.spawn(move || {
+                            parse_chunk(chunk, start, first, headers, scale, amounts, shards)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("parsing thread panicked"))
+                    .collect::<Vec<_>>()
+            });
+
+            // Every shard applies the lines of its clients, chunk by chunk.
+            let mut by_shard = (0..ledgers.len()).map(|_| vec![]).collect::<Vec<_>>();
+            for (chunk, chunk_check) in parsed {
+                check.merge(&chunk_check);
+                for (shard, lines) in chunk.into_iter().enumerate() {
+                    by_shard[shard].push(lines);
+                }
+            }
+            let applied = thread::scope(|scope| {
+                let handles = ledgers
+                    .iter_mut()
+                    .zip(by_shard)
+                    .map(|(ledger, chunks)| {
+                        scope.spawn(move || {
+                            apply_chunks(ledger, chunks.into_iter().flatten(), abort)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().expect("shard panicked"))
+                    .collect::<Vec<_>>()
+            });
+
+            let mut rejects = applied.into_iter().flatten().collect::<Vec<_>>();
+            rejects.sort_by_key(|(record, _)| *record);
+            let aborted = abort && !rejects.is_empty();
+            for (_, reject) in rejects {
+                sink.on_reject(reject);
+            }
+            if aborted {
+                break;
+            }
+        }
+
+        drop(ledgers);
+        for shard in split {
+            self.merge_shard(shard);
+        }
+        check
+    }
+
+    // Like `read_csv_with_rejects`, but read CSV input that's in memory like
+    // `read_mapped_csv_with`.
+    pub fn read_mapped_csv_with_rejects<W: std::io::Write, S: ErrorSink>(
+        &mut self,
+        input: &[u8],
+        scale: AmountScale,
+        rejects: W,
+        sink: S,
+    ) -> csv::Result<ScaleCheck> {
+        write_rejects(rejects, sink, |sink| {
+            self.read_mapped_csv_with(input, scale, sink)
+        })
+    }
+}
+
+// Map a file into memory, to read it with `Ledger::read_mapped_csv_with`.
+#[cfg(feature = "mmap")]
+pub fn map(file: &std::fs::File) -> std::io::Result<memmap2::Mmap> {
+    // Safety: the mapping is only valid as long as the file isn't changed,
+    // which input files mustn't be while they're read anyway.
+    unsafe { memmap2::Mmap::map(file) }
+}
+
+// The position right after the end of the line that `at` is in, or `at`
+// itself if it's at the start of a line.
+fn line_end(input: &[u8], at: usize) -> usize {
+    if at >= input.len() {
+        return input.len();
+    }
+    if at == 0 || input[at - 1] == b'\n' {
+        return at;
+    }
+    input[at..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(input.len(), |end| at + end + 1)
+}
+
+// Split whole lines into at most `parts` chunks of about the same size.
+fn split_lines(input: &[u8], parts: usize) -> Vec<&[u8]> {
+    let mut chunks = vec![];
+    let mut from = 0;
+    for part in 1..=parts {
+        let to = line_end(input, input.len() * part / parts);
+        if to > from {
+            chunks.push(&input[from..to]);
+            from = to;
+        }
+    }
+    chunks
+}
+
+// The number of lines a chunk ends, and the number of records in it. Like
+// the CSV reader, empty lines aren't counted as records.
+fn count_lines(chunk: &[u8]) -> (u64, u64) {
+    let mut lines = 0;
+    let mut records = 0;
+    for line in chunk.split_inclusive(|&byte| byte == b'\n') {
+        let terminated = line.ends_with(b"\n");
+        lines += u64::from(terminated);
+        let content = line.strip_suffix(b"\n").unwrap_or(line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        records += u64::from(!content.is_empty());
+    }
+    (lines, records)
+}
+
+// The position the CSV reader gives the first record at or after `start`,
+// the start of a line. The reader doesn't count the position of a record
+// from where it starts, but from the end of the one before it, without its
+// line terminator or any empty lines after it.
+fn first_record(input: &[u8], start: &csv::Position) -> csv::Position {
+    let mut end = start.byte() as usize;
+    let mut line = start.line();
+    // Skip back over empty lines.
+    while let Some(before) = input[..end].strip_suffix(b"\n") {
+        let before = before.strip_suffix(b"\r").unwrap_or(before);
+        if !before.is_empty() && !before.ends_with(b"\n") {
+            break;
+        }
+        end = before.len();
+        line -= 1;
+    }
+    let mut position = start.clone();
+    // The end of the record before is just past the first character of its
+    // line terminator.
+    if input[..end].ends_with(b"\r\n") {
+        position.set_byte(end as u64 - 1).set_line(line - 1);
+    } else {
+        position.set_byte(end as u64).set_line(line);
+    }
+    position
+}
+
+// Parse the lines of a chunk starting at `start`, split by the shard they
+// have to be applied on. Lines that can't be parsed go to the first shard.
+// `first` is the position of the first record, see `first_record`.
+fn parse_chunk(
+    chunk: &[u8],
+    start: &csv::Position,
+    first: &csv::Position,
+    headers: &CsvHeaders,
+    scale: AmountScale,
+    amounts: AmountPolicy,
+    shards: usize,
+) -> (Vec<Vec<Parsed>>, ScaleCheck) {
+    let mut check = ScaleCheck::new(scale);
+    let mut by_shard = (0..shards).map(|_| vec![]).collect::<Vec<_>>();
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(false)
+        .trim(csv::Trim::None)
+        .from_reader(chunk);
+    for (index, line) in reader.records().enumerate() {
+        let record = start.record() + index as u64;
+        let parsed = match line {
+            Ok(mut record) => {
+                if index == 0 {
+                    record.set_position(Some(first.clone()));
+                } else {
+                    offset_position(&mut record, start);
+                }
+                parse_csv_line(Ok(record), headers, scale, amounts, &mut check)
+            }
+            // The position of the error is relative to the chunk.
+            Err(err) => {
+                let position = err
+                    .position()
+                    .map_or_else(Position::default, |position| Position {
+                        line: start.line() + position.line() - 1,
+                        byte: start.byte() + position.byte(),
+                    });
+                Err(ProcessingError::malformed(position, err.to_string()).into())
+            }
+        };
+        let shard = match &parsed {
+            Ok(parsed) => shard_of(parsed.account, shards),
+            Err(_) => 0,
+        };
+        by_shard[shard].push((record, parsed));
+    }
+    (by_shard, check)
+}
+
+// Apply parsed lines in order, and return the ones that are skipped. When
+// aborting, nothing is applied after the first one.
+fn apply_chunks<I>(ledger: &mut Ledger, lines: I, abort: bool) -> Vec<(u64, Reject)>
+where
+    I: Iterator<Item = Parsed>,
+{
+    let mut rejects = vec![];
+    let mut apply = |client, tx| ledger.apply(client, tx);
+    for (record, line) in lines {
+        if let Err(reject) = line.and_then(|line| line.apply(&mut apply)) {
+            rejects.push((record, reject));
+            if abort {
+                break;
+            }
+        }
+    }
+    rejects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count_lines, split_lines};
+    use crate::{
+        ledger::{Ledger, Reject},
+        policy::ErrorPolicy,
+        scaling::AmountScale,
+    };
+
+    #[test]
+    fn same_as_sequential() {
+        let mut input = "type, client, tx, amount\n".to_owned();
+        for tx in 0..5000u32 {
+            let client = tx * 3 % 11;
+            input += &match tx % 5 {
+                0 | 1 => format!("deposit,{},{},{}.5\n", client, tx, tx % 10),
+                2 => format!("withdrawal,{},{},3\r\n", client, tx),
+                3 if tx % 100 == 3 => "\n".to_owned(),
+                3 => format!("dispute,{},{},\n", client, tx - 3),
+                _ if tx % 7 == 0 => "deposit,1,x,1\n".to_owned(),
+                _ => format!("resolve,{},{},\n", client, tx - 4),
+            };
+        }
+
+        let mut expected = vec![];
+        let sequential =
+            Ledger::from_csv_reader_with(input.as_bytes(), |reject: Reject| expected.push(reject));
+        let mut accounts = vec![];
+        sequential.accounts_to_csv(&mut accounts);
+
+        for shards in [1, 4] {
+            let mut ledger = Ledger::builder().shards(shards).build();
+            let mut rejects = vec![];
+            // Many small chunks on a few threads.
+            ledger.read_chunks(
+                input.as_bytes(),
+                AmountScale::default(),
+                |reject: Reject| rejects.push(reject),
+                3,
+                1000,
+            );
+            assert_eq!(rejects, expected);
+            let mut output = vec![];
+            ledger.accounts_to_csv(&mut output);
+            assert_eq!(
+                String::from_utf8(output),
+                String::from_utf8(accounts.clone())
+            );
+        }
+
+        // Chunks start at a line, and cover the whole input.
+        let chunks = split_lines(input.as_bytes(), 7);
+        assert_eq!(chunks.concat(), input.as_bytes());
+        assert!(chunks.iter().all(|chunk| chunk.ends_with(b"\n")));
+        assert_eq!(count_lines(b"a\n\r\n\nb\r\nc"), (4, 3));
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Deposits too large for funds to hold, deposits into closed
+        // accounts, refunds of withdrawals, and malformed lines are skipped
+        // just like when reading sequentially, in chunks as small as a line,
+        // and so are inputs with nothing but a header, or nothing at all.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,{max}
+deposit,2,3,5
+withdrawal,2,4,5
+close_account,2,0,
+deposit,2,5,1
+deposit,3,6,10
+withdrawal,3,7,4
+refund,3,7,
+deposit,3,8,x
+deposit,x,9,1
+deposit,3,10,1"
+        );
+        let header = "type,client,tx,amount";
+        for input in [input.as_str(), header, "", "\n\n"] {
+            let mut expected = vec![];
+            let sequential = Ledger::from_csv_reader_with(input.as_bytes(), |reject: Reject| {
+                expected.push(reject)
+            });
+            let mut accounts = vec![];
+            sequential.accounts_to_csv(&mut accounts);
+
+            for shards in [1, 3] {
+                let mut ledger = Ledger::builder().shards(shards).build();
+                let mut rejects = vec![];
+                ledger.read_chunks(
+                    input.as_bytes(),
+                    AmountScale::default(),
+                    |reject: Reject| rejects.push(reject),
+                    2,
+                    1,
+                );
+                assert_eq!(rejects, expected, "{:?}", input);
+                let mut output = vec![];
+                ledger.accounts_to_csv(&mut output);
+                assert_eq!(output, accounts, "{:?}", input);
+            }
+        }
+
+        // When aborting, nothing is applied after the first line skipped.
+        let mut ledger = Ledger::builder().errors(ErrorPolicy::Abort).build();
+        let mut rejects = vec![];
+        ledger.read_chunks(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+            2,
+            1,
+        );
+        assert_eq!(rejects, ["overflow"]);
+        assert!(ledger.account(2).is_none());
+    }
+}
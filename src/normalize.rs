@@ -0,0 +1,244 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+use serde::Serialize;
+
+use crate::{
+    error_sink::ErrorSink,
+    ledger::{parse_fields, Position, ProcessingError, ProcessingErrorKind},
+    policy::AmountPolicy,
+    AccountId, Currency, Transaction, TransactionAmount, TransactionId,
+};
+
+// Normalizing rewrites a messy transactions file, e.g. one received from a
+// partner, in the canonical form: fields are trimmed, only the first of
+// columns with the same name is kept, and every record is written with the
+// columns type, client, tx, and amount, followed by effective_at,
+// timestamp, and currency if the input has them. Records are parsed the same
+// way the ledger reads them, so what's left can be processed without any
+// lines being skipped as malformed.
+
+#[derive(Serialize)]
+struct NormalizedRecord<'a> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    client: AccountId,
+    tx: TransactionId,
+    amount: Option<TransactionAmount>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    effective_at: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Option<Currency>>,
+}
+
+// A parsed record with its timestamp and effective time, as written.
+type Normalized = (AccountId, Transaction, Option<String>, Option<String>);
+
+// Write the transactions read from `input` to `output` in the canonical
+// form. Lines that can't be parsed are passed to `sink` and left out.
+//
+// If the input has a `timestamp` column, every client's records are sorted
+// by it, keeping records with the same timestamp in their original order.
+// Records of different clients keep their places relative to each other.
+// Timestamps are compared as text, so they have to be in a format that sorts
+// that way, e.g. RFC 3339 in UTC.
+pub fn normalize<R, W, S>(input: R, output: W, mut sink: S) -> csv::Result<()>
+where
+    R: Read,
+    W: Write,
+    S: ErrorSink,
+{
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .has_headers(true)
+        .trim(csv::Trim::None)
+        .from_reader(input);
+    let mut all_headers = reader.headers()?.clone();
+    all_headers.trim();
+
+    // The indices of the columns that are kept.
+    let mut columns = vec![];
+    for (i, header) in all_headers.iter().enumerate() {
+        if !columns.iter().any(|&kept| &all_headers[kept] == header) {
+            columns.push(i);
+        }
+    }
+    let headers = columns
+        .iter()
+        .map(|&i| &all_headers[i])
+        .collect::<csv::StringRecord>();
+    let timestamp_column = headers.iter().position(|header| header == "timestamp");
+    let effective_at_column = headers.iter().position(|header| header == "effective_at");
+    let has_currency = headers.iter().any(|header| header == "currency");
+
+    let mut records: Vec<Normalized> = vec![];
+    for line in reader.records() {
+        let fields = match line {
+            Ok(fields) => fields,
+            Err(err) => {
+                let position = err.position().map(Position::from).unwrap_or_default();
+                sink.on_reject(ProcessingError::malformed(position, err.to_string()).into());
+                continue;
+            }
+        };
+        let mut trimmed = columns
+            .iter()
+            .map(|&i| fields.get(i).unwrap_or(""))
+            .collect::<csv::StringRecord>();
+        trimmed.trim();
+        // Amounts are left for the ledger reading the output to check.
+        match parse_fields(&trimmed, &headers, AmountPolicy::Any) {
+            Ok((client, transaction)) => {
+                let field =
+                    |column: Option<usize>| column.and_then(|i| trimmed.get(i)).map(str::to_owned);
+                let (timestamp, effective_at) =
+                    (field(timestamp_column), field(effective_at_column));
+                records.push((client, transaction, timestamp, effective_at));
+            }
+            Err(err) => {
+                let kind = ProcessingErrorKind::Record(err);
+                sink.on_reject(ProcessingError::at_line(&fields, kind).into());
+            }
+        }
+    }
+
+    if timestamp_column.is_some() {
+        sort_by_timestamp(&mut records);
+    }
+
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(true)
+        .from_writer(output);
+    for (client, transaction, timestamp, effective_at) in &records {
+        writer.serialize(NormalizedRecord {
+            record_type: transaction.kind(),
+            client: *client,
+            // Recoveries don't refer to a transaction, but the column is
+            // still required.
+            tx: transaction.id().unwrap_or(0),
+            amount: transaction.amount(),
+            effective_at: effective_at_column.map(|_| effective_at.as_deref().unwrap_or("")),
+            timestamp: timestamp_column.map(|_| timestamp.as_deref().unwrap_or("")),
+            // Currencies are written in upper case.
+            currency: has_currency.then(|| transaction.currency()),
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Sort every client's records by timestamp within the places they take up,
+// so the records of different clients stay interleaved the same way.
+fn sort_by_timestamp(records: &mut [Normalized]) {
+    let mut places = BTreeMap::<AccountId, Vec<usize>>::new();
+    for (i, (client, ..)) in records.iter().enumerate() {
+        places.entry(*client).or_default().push(i);
+    }
+    for places in places.values() {
+        let mut sorted = places
+            .iter()
+            .map(|&i| records[i].clone())
+            .collect::<Vec<_>>();
+        sorted.sort_by(|(_, _, a, _), (_, _, b, _)| a.cmp(b));
+        for (&i, record) in places.iter().zip(sorted) {
+            records[i] = record;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize;
+    use crate::{
+        ledger::{Ledger, Reject},
+        scaling::AmountScale,
+    };
+
+    #[test]
+    fn normalize_file() {
+        let input = "\
+ type , client,tx,amount,amount,timestamp
+deposit,1,1, 10.50,99,2024-01-02
+deposit , 2,2,5,,2024-01-01
+withdrawal,1,3,1,,2024-01-01
+dispute,2,4
+recovery,1,7,,,2024-01-03
+foo,1,5,1,,2024-01-01
+deposit,1,6,,,2024-01-01
+";
+        let mut output = vec![];
+        let mut rejects = vec![];
+        normalize(input.as_bytes(), &mut output, |reject: Reject| {
+            rejects.push((reject.line, reject.reason))
+        })
+        .unwrap();
+
+        // Client 1's withdrawal moves ahead of its deposit, the dispute
+        // without a timestamp goes first among client 2's records.
+        assert_eq!(rejects, [(7, "malformed"), (8, "missing_amount")]);
+        assert_eq!(
+            String::from_utf8(output).expect("output should be UTF8"),
+            "\
+type,client,tx,amount,timestamp
+withdrawal,1,3,1,2024-01-01
+dispute,2,4,,
+deposit,1,1,10.5,2024-01-02
+deposit,2,2,5,2024-01-01
+recovery,1,0,,2024-01-03
+"
+        );
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Amounts are kept exactly however long they are, and negative ones
+        // are left for the ledger to reject. Closings and refunds are kept
+        // as well, even if the ledger rejects them. An unclosed quote runs
+        // to the end of the file.
+        let input = "\
+type,client,tx,amount,currency
+deposit,1,1,79228162514264337593543950335,
+deposit,1,2,18446744073709551616.123456789,eur
+withdrawal,1,3,-1,
+refund,1,3,,
+close_account,2,9,,
+deposit,1,4,1e3,
+deposit,1,6,1.2.3,
+deposit,\"1,5,1,
+";
+        let mut output = vec![];
+        let mut rejects = vec![];
+        normalize(input.as_bytes(), &mut output, |reject: Reject| {
+            rejects.push((reject.line, reject.reason))
+        })
+        .unwrap();
+
+        assert_eq!(rejects, [(8, "malformed"), (9, "malformed")]);
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "\
+type,client,tx,amount,currency
+deposit,1,1,79228162514264337593543950335,
+deposit,1,2,18446744073709551616.123456789,EUR
+withdrawal,1,3,-1,
+refund,1,3,,
+close_account,2,0,,
+deposit,1,4,1000,
+"
+        );
+
+        // What's left is read without any lines being skipped as malformed.
+        let mut ledger = Ledger::default();
+        let mut reasons = vec![];
+        let scale = AmountScale::default();
+        ledger.read_csv_with(output.as_bytes(), scale, |reject: Reject| {
+            reasons.push(reject.reason)
+        });
+        assert!(reasons.iter().all(|reason| *reason != "malformed"));
+    }
+}
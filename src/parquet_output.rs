@@ -0,0 +1,337 @@
+use std::{io::Write, sync::Arc};
+
+use parquet::{
+    data_type::{
+        BoolType, ByteArray, ByteArrayType, DataType, FixedLenByteArray, FixedLenByteArrayType,
+    },
+    errors::{ParquetError, Result},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+
+use crate::{
+    client_names::ClientKey,
+    ledger::{AccountOrder, AccountRecord, Ledger, Precision},
+    AccountId, Balance,
+};
+
+// Clients are stored as unsigned integers as wide as their IDs. Parquet
+// only has signed physical types, so with the `wide-ids` feature the bits of
+// the IDs are stored as they are, which readers take back as unsigned.
+#[cfg(not(feature = "wide-ids"))]
+type ClientType = parquet::data_type::Int32Type;
+#[cfg(not(feature = "wide-ids"))]
+const CLIENT_COLUMN: &str = "REQUIRED INT32 client (INTEGER(16, false));";
+#[cfg(feature = "wide-ids")]
+type ClientType = parquet::data_type::Int64Type;
+#[cfg(feature = "wide-ids")]
+const CLIENT_COLUMN: &str = "REQUIRED INT64 client (INTEGER(64, false));";
+
+#[cfg(not(feature = "wide-ids"))]
+fn client_value(client: AccountId) -> i32 {
+    client.into()
+}
+
+#[cfg(feature = "wide-ids")]
+fn client_value(client: AccountId) -> i64 {
+    client as i64
+}
+
+// The most digits a balance column holds.
+const MAX_DIGITS: u32 = 38;
+
+// Balances are stored as 16 byte two's complement decimals of up to 38
+// digits, with the scale set to the output's number of decimal places, which
+// holds any balance with up to 9 of them. The currency column is only there
+// if the summaries have one, see `AccountRecord::currency`, and the closed
+// column if they have that, see `AccountRecord::closed`. Clients are stored
+// as text instead if some of them are known by name, see `ClientKey`.
+fn schema(scale: u32, currencies: bool, names: bool, closings: bool) -> String {
+    let currency = match currencies {
+        true => "OPTIONAL BYTE_ARRAY currency (UTF8);",
+        false => "",
+    };
+    let client = match names {
+        true => "REQUIRED BYTE_ARRAY client (UTF8);",
+        false => CLIENT_COLUMN,
+    };
+    let closed = match closings {
+        true => "REQUIRED BOOLEAN closed;",
+        false => "",
+    };
+    format!(
+        "
+message account {{
+    {client}
+    {currency}
+    REQUIRED FIXED_LEN_BYTE_ARRAY (16) available (DECIMAL({MAX_DIGITS}, {scale}));
+    REQUIRED FIXED_LEN_BYTE_ARRAY (16) held (DECIMAL({MAX_DIGITS}, {scale}));
+    REQUIRED FIXED_LEN_BYTE_ARRAY (16) total (DECIMAL({MAX_DIGITS}, {scale}));
+    REQUIRED BOOLEAN locked;
+    {closed}
+}}
+"
+    )
+}
+
+impl Ledger {
+    // Write the account summaries in this ledger as a Parquet file to the
+    // given writer, with the same columns as the CSV output. All accounts
+    // are written in a single row group. This consumes the ledger to prevent
+    // modification after writing.
+    pub fn accounts_to_parquet<W: Write + Send>(self, output: W) -> Result<()> {
+        let precision = self.precision();
+        write_parquet(
+            &self.account_summaries(AccountOrder::Client, precision),
+            precision,
+            output,
+        )
+    }
+}
+
+// Write the account summaries, rounded to the given precision, as a Parquet
+// file with a single row group. It fails if a balance has more digits than
+// the column can hold.
+pub(crate) fn write_parquet<W: Write + Send>(
+    summaries: &[AccountRecord],
+    precision: Precision,
+    output: W,
+) -> Result<()> {
+    let currencies = summaries.iter().any(|record| record.currency.is_some());
+    let names = summaries
+        .iter()
+        .any(|record| matches!(record.client, ClientKey::Name(_)));
+    let closings = summaries.iter().any(|record| record.closed.is_some());
+    // Balances in currencies have their minor units, the column has enough
+    // decimal places for all of them.
+    let scale = summaries
+        .iter()
+        .map(|record| record.available.scale())
+        .max()
+        .unwrap_or(precision.decimal_places);
+    let schema = Arc::new(parse_message_type(&schema(
+        scale, currencies, names, closings,
+    ))?);
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(output, schema, properties)?;
+    let mut row_group = writer.next_row_group()?;
+
+    let clients = summaries
+        .iter()
+        .filter_map(|record| match record.client {
+            ClientKey::Id(id) if !names => Some(client_value(id)),
+            _ => None,
+        })
+        .collect::<Vec<<ClientType as DataType>::T>>();
+    let client_names = summaries
+        .iter()
+        .filter(|_| names)
+        .map(|record| ByteArray::from(record.client.to_string().as_str()))
+        .collect::<Vec<_>>();
+    // Balances without a currency have it left null.
+    let currency = summaries
+        .iter()
+        .filter_map(|record| record.currency.flatten())
+        .map(|currency| ByteArray::from(currency.as_str()))
+        .collect::<Vec<_>>();
+    let currency_levels = summaries
+        .iter()
+        .map(|record| i16::from(record.currency.flatten().is_some()))
+        .collect::<Vec<_>>();
+    let available = decimal_column(summaries.iter().map(|record| record.available), scale)?;
+    let held = decimal_column(summaries.iter().map(|record| record.held), scale)?;
+    let total = decimal_column(summaries.iter().map(|record| record.total), scale)?;
+    let locked = summaries
+        .iter()
+        .map(|record| record.locked)
+        .collect::<Vec<_>>();
+    let closed = summaries
+        .iter()
+        .map(|record| record.closed.unwrap_or_default())
+        .collect::<Vec<_>>();
+
+    // Columns are returned in the order they're declared in the schema. The
+    // currency comes right after the client, if it's there.
+    let mut index = 0;
+    while let Some(mut column) = row_group.next_column()? {
+        let field = match (currencies, index) {
+            (true, 1) => None,
+            (true, index) if index > 1 => Some(index - 1),
+            (_, index) => Some(index),
+        };
+        match field {
+            Some(0) if names => {
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&client_names, None, None)?
+            }
+            Some(0) => column
+                .typed::<ClientType>()
+                .write_batch(&clients, None, None)?,
+            None => column.typed::<ByteArrayType>().write_batch(
+                &currency,
+                Some(&currency_levels),
+                None,
+            )?,
+            Some(1) => column
+                .typed::<FixedLenByteArrayType>()
+                .write_batch(&available, None, None)?,
+            Some(2) => column
+                .typed::<FixedLenByteArrayType>()
+                .write_batch(&held, None, None)?,
+            Some(3) => column
+                .typed::<FixedLenByteArrayType>()
+                .write_batch(&total, None, None)?,
+            Some(4) => column
+                .typed::<BoolType>()
+                .write_batch(&locked, None, None)?,
+            _ => column
+                .typed::<BoolType>()
+                .write_batch(&closed, None, None)?,
+        };
+        column.close()?;
+        index += 1;
+    }
+
+    row_group.close()?;
+    writer.close()?;
+    Ok(())
+}
+
+// The balances are already rounded to at most the column's number of decimal
+// places, so once they're rescaled to it their mantissa is the unscaled value
+// the schema expects. They're rescaled as `i128`s, since the unscaled value
+// of a large balance can have more digits than a `Balance` holds.
+fn decimal_column(
+    balances: impl Iterator<Item = Balance>,
+    scale: u32,
+) -> Result<Vec<FixedLenByteArray>> {
+    balances
+        .map(|balance| {
+            let unscaled = 10i128
+                .checked_pow(scale - balance.scale())
+                .and_then(|factor| balance.mantissa().checked_mul(factor))
+                .filter(|unscaled| unscaled.unsigned_abs() < 10u128.pow(MAX_DIGITS))
+                .ok_or_else(|| {
+                    ParquetError::General(format!(
+                        "balance {} has more than {} digits with {} decimal places",
+                        balance, MAX_DIGITS, scale
+                    ))
+                })?;
+            Ok(FixedLenByteArray::from(unscaled.to_be_bytes().to_vec()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    use crate::ledger::{Ledger, Precision};
+
+    #[test]
+    fn parquet_output() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+withdrawal,1,2,4
+dispute,1,2,
+deposit,2,3,15
+withdrawal,2,4,10
+dispute,2,4,
+chargeback,2,4,
+";
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+
+        let path = std::env::temp_dir().join(format!("ledger-{}.parquet", std::process::id()));
+        ledger
+            .accounts_to_parquet(fs::File::create(&path).unwrap())
+            .expect("should write Parquet output");
+
+        let reader = SerializedFileReader::new(fs::File::open(&path).unwrap()).unwrap();
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            vec![
+                "{client: 1, available: 2.0000, held: 4.0000, total: 6.0000, locked: false}",
+                "{client: 2, available: -5.0000, held: 0.0000, total: -5.0000, locked: true}",
+            ]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn edge_cases() {
+        let path = std::env::temp_dir().join(format!("ledger-edge-{}.parquet", std::process::id()));
+        let rows = |ledger: Ledger| {
+            ledger.accounts_to_parquet(fs::File::create(&path).unwrap())?;
+            let reader = SerializedFileReader::new(fs::File::open(&path).unwrap()).unwrap();
+            let rows = reader
+                .get_row_iter(None)
+                .unwrap()
+                .map(|row| row.unwrap().to_string())
+                .collect::<Vec<_>>();
+            Ok::<_, parquet::errors::ParquetError>(rows)
+        };
+
+        // Balances as large as funds can hold keep every digit they're
+        // written with, and closed accounts have a column of their own, like
+        // in the CSV output. The deposit that overflowed, the refund of a
+        // withdrawal, the deposit into the closed account, and the malformed
+        // line leave the balances as they were.
+        let max = crate::funds::max();
+        #[cfg(not(feature = "fixed-point"))]
+        let written = format!("{max}.0000");
+        #[cfg(feature = "fixed-point")]
+        let written = "792281625142643375935.4395";
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,1
+deposit,2,3,10
+withdrawal,2,4,10
+refund,2,4,
+close_account,2,0,
+deposit,2,5,1
+deposit,3,6,x
+"
+        );
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+        assert_eq!(
+            rows(ledger).unwrap(),
+            [
+                format!(
+                    "{{client: 1, available: {written}, held: 0.0000, total: {written}, \
+                     locked: false, closed: false}}"
+                ),
+                "{client: 2, available: 0.0000, held: 0.0000, total: 0.0000, locked: false, \
+                 closed: true}"
+                    .to_owned(),
+            ]
+        );
+
+        // With too many decimal places the largest balances don't fit.
+        let mut ledger = Ledger::builder()
+            .precision(Precision {
+                decimal_places: 28,
+                ..Precision::default()
+            })
+            .build();
+        let input = format!("type,client,tx,amount\ndeposit,1,1,{max}\ndeposit,2,2,1\n");
+        ledger.read_csv(input.as_bytes());
+        let error = rows(ledger).unwrap_err().to_string();
+        assert!(error.contains("has more than 38 digits with 28 decimal places"));
+
+        // No accounts is a file without rows.
+        assert_eq!(rows(Ledger::default()).unwrap(), Vec::<String>::new());
+        fs::remove_file(&path).unwrap();
+    }
+}
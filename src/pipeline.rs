@@ -0,0 +1,203 @@
+use std::{io::Read, mem, sync::mpsc, thread};
+
+use crate::{
+    error_sink::ErrorSink,
+    ledger::{csv_headers, csv_reader, parse_csv_line, Ledger, ParsedLine, Reject},
+    scaling::{AmountScale, ScaleCheck},
+};
+
+// The number of lines handed to the applying thread at once, so the channel
+// isn't paid for on every line.
+const BATCH_LEN: usize = 1024;
+
+// The number of parsed batches that may wait to be applied. Once they're
+// queued, reading waits for the ledger to catch up, which bounds the memory
+// taken by lines read ahead.
+const QUEUED_BATCHES: usize = 16;
+
+// Read transactions from the given CSV reader and apply them to the ledger
+// like `Ledger::read_csv_with`, but read and parse them on the calling thread
+// while they're applied on another one, so reading the input, parsing it,
+// and updating the ledger overlap. Transactions are applied in the order
+// they're read, and the skipped lines are passed to `sink` in order too, on
+// the calling thread, so the result is the same as reading and applying
+// them one after the other.
+pub fn read_csv_pipelined<R, S>(
+    ledger: &mut Ledger,
+    reader: R,
+    scale: AmountScale,
+    mut sink: S,
+) -> ScaleCheck
+where
+    R: Read,
+    S: ErrorSink,
+{
+    let mut check = ScaleCheck::new(scale);
+    let amounts = ledger.policy().amounts;
+    let mut reader = csv_reader(reader);
+    let Some(headers) = csv_headers(&mut reader, &mut sink) else {
+        return check;
+    };
+
+    thread::scope(|scope| {
+        let (sender, receiver) =
+            mpsc::sync_channel::<Vec<Result<ParsedLine, Reject>>>(QUEUED_BATCHES);
+        // Lines that can't be parsed are passed along with the rest, so
+        // rejects come back in the order of their lines.
+        let (rejected, rejects) = mpsc::channel();
+        let applying = scope.spawn(move || {
+            let mut apply = |client, tx| ledger.apply(client, tx);
+            for line in receiver.into_iter().flatten() {
+                if let Err(reject) = line.and_then(|line| line.apply(&mut apply)) {
+                    // The receiver is only gone once this thread is joined.
+                    let _ = rejected.send(reject);
+                }
+            }
+        });
+
+        let mut batch = Vec::with_capacity(BATCH_LEN);
+        for line in reader.records() {
+            batch.push(parse_csv_line(line, &headers, scale, amounts, &mut check));
+            if batch.len() == BATCH_LEN {
+                let batch = mem::replace(&mut batch, Vec::with_capacity(BATCH_LEN));
+                // The applying thread only hangs up if it panicked, which
+                // joining it reports below.
+                let _ = sender.send(batch);
+                rejects.try_iter().for_each(|reject| sink.on_reject(reject));
+            }
+        }
+        let _ = sender.send(batch);
+        drop(sender);
+
+        applying.join().expect("applying thread panicked");
+        rejects.try_iter().for_each(|reject| sink.on_reject(reject));
+    });
+    check
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_csv_pipelined, BATCH_LEN};
+    use crate::{
+        ledger::{read_csv_into, Ledger, Reject},
+        policy::{AmountPolicy, ErrorPolicy},
+        scaling::AmountScale,
+    };
+
+    #[test]
+    fn same_as_sequential() {
+        let mut input = "type, client, tx, amount\n".to_owned();
+        for tx in 0..5000u32 {
+            let client = tx % 7;
+            input += &match tx % 4 {
+                0 | 1 => format!("deposit,{},{},{}.25\n", client, tx, tx % 10),
+                2 => format!("withdrawal,{},{},4\n", client, tx),
+                _ if tx % 500 == 3 => "deposit,1,x,1\n".to_owned(),
+                _ => format!("dispute,{},{},\n", client, tx - 3),
+            };
+        }
+
+        let mut sequential = Ledger::builder().keep_statements().build();
+        let mut expected = vec![];
+        let expected_check = read_csv_into(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| expected.push(reject),
+            ErrorPolicy::Skip,
+            AmountPolicy::default(),
+            |client, tx| sequential.apply(client, tx),
+        );
+
+        let mut pipelined = Ledger::builder().keep_statements().build();
+        let mut rejects = vec![];
+        let check = read_csv_pipelined(
+            &mut pipelined,
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject),
+        );
+
+        assert!(rejects.iter().any(|reject| reject.reason == "malformed"));
+        assert_eq!(rejects, expected);
+        assert_eq!(check.warning(), expected_check.warning());
+        for client in 0..7 {
+            assert_eq!(
+                pipelined.statement_for(client),
+                sequential.statement_for(client)
+            );
+        }
+        let mut output = vec![];
+        let mut expected = vec![];
+        pipelined.accounts_to_csv(&mut output);
+        sequential.accounts_to_csv(&mut expected);
+        assert_eq!(String::from_utf8(output), String::from_utf8(expected));
+    }
+
+    #[test]
+    fn edge_cases() {
+        let read = |input: &[u8]| {
+            let mut ledger = Ledger::default();
+            let mut rejects = vec![];
+            read_csv_pipelined(
+                &mut ledger,
+                input,
+                AmountScale::default(),
+                |reject: Reject| rejects.push((reject.line, reject.reason)),
+            );
+            (ledger, rejects)
+        };
+
+        // Nothing is applied without a header that can be read.
+        let (ledger, rejects) = read(b"");
+        assert_eq!((ledger.accounts().count(), rejects), (0, vec![]));
+        let (ledger, rejects) = read(b"type,\xff\ndeposit,1,1,1\n");
+        assert_eq!(
+            (ledger.accounts().count(), rejects),
+            (0, vec![(1, "malformed")])
+        );
+
+        // A deposit too large for funds to hold, a refund of a withdrawal,
+        // a deposit into a closed account, and lines that can't be read,
+        // with a full batch between them, are rejected in the order of their
+        // lines.
+        let max = crate::funds::max();
+        let mut input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,1
+deposit,2,3,10
+withdrawal,2,4,4
+"
+        );
+        for tx in 0..BATCH_LEN {
+            input += &format!("deposit,3,{},1\n", 10 + tx);
+        }
+        input += "\
+refund,2,4,
+withdrawal,2,5,6
+close_account,2,0,
+deposit,2,6,1
+deposit,2,7,x
+deposit,2
+";
+        let (ledger, rejects) = read(input.as_bytes());
+        let line = BATCH_LEN as u64 + 6;
+        assert_eq!(
+            rejects,
+            [
+                (3, "overflow"),
+                (line, "not_refundable"),
+                (line + 3, "account_closed"),
+                (line + 4, "malformed"),
+                (line + 5, "malformed"),
+            ]
+        );
+        assert_eq!(ledger.account(1).unwrap().total().unwrap(), max);
+        assert!(ledger.account(2).unwrap().is_closed());
+        assert_eq!(
+            ledger.account(3).unwrap().total().unwrap(),
+            BATCH_LEN.into()
+        );
+    }
+}
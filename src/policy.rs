@@ -0,0 +1,951 @@
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    account::FreezeLevel, currency::CurrencyRegistry, ledger::MAX_DECIMAL_PLACES,
+    limits::WithdrawalLimit, AccountId, Balance, Timestamp, TransactionAmount,
+};
+
+// Policy collects the knobs that change how transactions are processed.
+// The defaults match the behavior described in the README.
+#[derive(Debug, Default, Clone)]
+pub struct Policy {
+    pub amounts: AmountPolicy,
+    pub zero_amounts: ZeroAmountPolicy,
+    pub errors: ErrorPolicy,
+    // How far withdrawals may take an account's available funds below zero,
+    // and the clients with a credit line of their own instead, e.g. for
+    // credit products.
+    pub overdraft: Balance,
+    pub account_overdrafts: BTreeMap<AccountId, Balance>,
+    pub transaction_ids: TransactionIdPolicy,
+    // The most an account's held funds may be of its total after a dispute,
+    // up to 1, e.g. 0.5 for half, and what happens to disputes that go beyond it.
+    // Disputes are never limited when it isn't set.
+    pub max_held_ratio: Option<Decimal>,
+    pub held_ratio_action: HeldRatioAction,
+    pub disputes: DisputePolicy,
+    pub withdrawal_disputes: WithdrawalDisputePolicy,
+    pub early_disputes: EarlyDisputePolicy,
+    // How many disputes may be parked for every account, see
+    // `EarlyDisputePolicy`. Early disputes beyond that are rejected. Any
+    // number may be parked when it isn't set.
+    pub max_parked_disputes: Option<usize>,
+    // The account fees are credited to, e.g. the revenue account of the
+    // operator. Fees are rejected when it isn't set.
+    pub fee_account: Option<AccountId>,
+    // The account what's left in accounts is swept to when they're closed,
+    // e.g. a suspense account. Only empty accounts can be closed when it
+    // isn't set.
+    pub sweep_account: Option<AccountId>,
+    // How many more transactions the ledger applies before an authorization
+    // that wasn't captured or voided expires, and is voided. There's no
+    // clock to go by, so the number of transactions stands in for time.
+    // Authorizations never expire when it isn't set.
+    pub authorization_window: Option<u64>,
+    // The most every account may withdraw within a window of time, see
+    // `WithdrawalLimit`. Withdrawals aren't limited when it isn't set.
+    pub withdrawal_limit: Option<WithdrawalLimit>,
+    // How strictly accounts are frozen by their chargebacks, and by going
+    // beyond the withdrawal limit, see `FreezeLevel`. Going beyond the limit
+    // only rejects the withdrawal when it isn't set.
+    pub chargeback_freeze: FreezeLevel,
+    pub limit_freeze: Option<FreezeLevel>,
+    // How many days after a transaction it may still be disputed, going by
+    // the times of the dispute and the transaction, like card schemes do.
+    // Transactions without a time can always be disputed, and so can every
+    // transaction when it isn't set.
+    pub dispute_window: Option<u64>,
+    // The largest amount a single deposit or withdrawal may have, to catch
+    // mistyped amounts. Amounts aren't limited when it isn't set.
+    pub max_amount: Option<TransactionAmount>,
+    pub out_of_order: OutOfOrderPolicy,
+    // How many seconds records are held back when reading input, so that
+    // those whose timestamps are behind by up to that much are put back in
+    // order before they're applied, e.g. when the input merges the feeds of
+    // several upstream systems. Records are applied as they're read when it
+    // isn't set.
+    pub reorder_window: Option<Timestamp>,
+    // The minor units of the currencies, which balances in them are written
+    // with, and which amounts in them may not have more decimal places than.
+    pub currencies: CurrencyRegistry,
+    pub client_ids: ClientIdPolicy,
+    pub account_creation: AccountCreationPolicy,
+}
+
+impl Policy {
+    // Change a single setting given as `name=value`, where the names match
+    // the command line options, e.g. `zero-amounts=reject`.
+    pub fn set(&mut self, setting: &str) -> Result<(), ParsePolicyError> {
+        let invalid = || ParsePolicyError(setting.to_owned());
+        let (name, value) = setting.split_once('=').ok_or_else(invalid)?;
+        match name {
+            "amounts" => self.amounts = value.parse()?,
+            "zero-amounts" => self.zero_amounts = value.parse()?,
+            "on-error" => self.errors = value.parse()?,
+            "overdraft" => match value.parse::<Balance>() {
+                Ok(overdraft) if !overdraft.is_sign_negative() => self.overdraft = overdraft,
+                _ => return Err(invalid()),
+            },
+            "account-overdraft" => {
+                let (client, limit) = value.split_once(':').ok_or_else(invalid)?;
+                let client = client.parse().map_err(|_| invalid())?;
+                match limit.parse::<Balance>() {
+                    Ok(limit) if !limit.is_sign_negative() => {
+                        self.account_overdrafts.insert(client, limit);
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+            "transaction-ids" => self.transaction_ids = value.parse()?,
+            "max-held-ratio" => match value.parse::<Decimal>() {
+                Ok(ratio)
+                    if ratio.is_sign_positive() && !ratio.is_zero() && ratio <= Decimal::ONE =>
+                {
+                    self.max_held_ratio = Some(ratio)
+                }
+                _ => return Err(invalid()),
+            },
+            "held-ratio-action" => self.held_ratio_action = value.parse()?,
+            "disputes" => self.disputes = value.parse()?,
+            "withdrawal-disputes" => self.withdrawal_disputes = value.parse()?,
+            "early-disputes" => self.early_disputes = value.parse()?,
+            "max-parked-disputes" => match value.parse::<usize>() {
+                Ok(disputes) if disputes > 0 => self.max_parked_disputes = Some(disputes),
+                _ => return Err(invalid()),
+            },
+            "client-ids" => self.client_ids = value.parse()?,
+            "account-creation" => self.account_creation = value.parse()?,
+            "fee-account" => self.fee_account = Some(value.parse().map_err(|_| invalid())?),
+            "sweep-account" => self.sweep_account = Some(value.parse().map_err(|_| invalid())?),
+            "authorization-window" => match value.parse::<u64>() {
+                Ok(window) if window > 0 => self.authorization_window = Some(window),
+                _ => return Err(invalid()),
+            },
+            "withdrawal-limit" => self.withdrawal_limit = Some(value.parse()?),
+            "chargeback-freeze" => self.chargeback_freeze = value.parse()?,
+            "limit-freeze" => {
+                self.limit_freeze = match value {
+                    "none" => None,
+                    level => Some(level.parse()?),
+                }
+            }
+            "out-of-order" => self.out_of_order = value.parse()?,
+            "reorder-window" => match value.parse::<Timestamp>() {
+                Ok(window) if window > 0 => self.reorder_window = Some(window),
+                _ => return Err(invalid()),
+            },
+            "dispute-window" => match value.parse::<u64>() {
+                Ok(days) if days > 0 => self.dispute_window = Some(days),
+                _ => return Err(invalid()),
+            },
+            "currency-units" => {
+                let (currency, digits) = value.split_once(':').ok_or_else(invalid)?;
+                let currency = currency.parse().map_err(|_| invalid())?;
+                match digits.parse() {
+                    Ok(digits) if digits <= MAX_DECIMAL_PLACES => {
+                        self.currencies.set(currency, digits)
+                    }
+                    _ => return Err(invalid()),
+                }
+            }
+            "max-amount" => match value.parse::<TransactionAmount>() {
+                Ok(amount) if amount.is_sign_positive() && !amount.is_zero() => {
+                    self.max_amount = Some(amount)
+                }
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    }
+
+    // Every setting of the policy in the form taken by `set`, in a fixed
+    // order. Amounts and ratios are normalized, so `overdraft=1.50` and
+    // `overdraft=1.5` are written the same way.
+    pub fn settings(&self) -> Vec<String> {
+        let mut settings = vec![
+            format!("zero-amounts={}", self.zero_amounts),
+            format!("on-error={}", self.errors),
+            format!("overdraft={}", self.overdraft.normalize()),
+            format!("transaction-ids={}", self.transaction_ids),
+        ];
+        if let Some(ratio) = self.max_held_ratio {
+            settings.push(format!("max-held-ratio={}", ratio.normalize()));
+        }
+        settings.push(format!("held-ratio-action={}", self.held_ratio_action));
+        // Only written when it isn't the default, so the versions of the
+        // policies from before it existed stay the same.
+        if self.disputes != DisputePolicy::All {
+            settings.push(format!("disputes={}", self.disputes));
+        }
+        if self.amounts != AmountPolicy::NonNegative {
+            settings.push(format!("amounts={}", self.amounts));
+        }
+        if self.withdrawal_disputes != WithdrawalDisputePolicy::Hold {
+            settings.push(format!("withdrawal-disputes={}", self.withdrawal_disputes));
+        }
+        if let Some(account) = self.fee_account {
+            settings.push(format!("fee-account={}", account));
+        }
+        if let Some(account) = self.sweep_account {
+            settings.push(format!("sweep-account={}", account));
+        }
+        if let Some(window) = self.authorization_window {
+            settings.push(format!("authorization-window={}", window));
+        }
+        if let Some(limit) = self.withdrawal_limit {
+            settings.push(format!("withdrawal-limit={}", limit));
+        }
+        if self.chargeback_freeze != FreezeLevel::Full {
+            settings.push(format!("chargeback-freeze={}", self.chargeback_freeze));
+        }
+        if let Some(level) = self.limit_freeze {
+            settings.push(format!("limit-freeze={}", level));
+        }
+        if let Some(amount) = self.max_amount {
+            settings.push(format!("max-amount={}", amount.normalize()));
+        }
+        if let Some(days) = self.dispute_window {
+            settings.push(format!("dispute-window={}", days));
+        }
+        if self.out_of_order != OutOfOrderPolicy::Ignore {
+            settings.push(format!("out-of-order={}", self.out_of_order));
+        }
+        if let Some(window) = self.reorder_window {
+            settings.push(format!("reorder-window={}", window));
+        }
+        if self.early_disputes != EarlyDisputePolicy::Reject {
+            settings.push(format!("early-disputes={}", self.early_disputes));
+        }
+        if let Some(disputes) = self.max_parked_disputes {
+            settings.push(format!("max-parked-disputes={}", disputes));
+        }
+        if self.client_ids != ClientIdPolicy::Numeric {
+            settings.push(format!("client-ids={}", self.client_ids));
+        }
+        if self.account_creation != AccountCreationPolicy::Implicit {
+            settings.push(format!("account-creation={}", self.account_creation));
+        }
+        for (currency, digits) in self.currencies.custom() {
+            settings.push(format!("currency-units={}:{}", currency, digits));
+        }
+        for (client, limit) in &self.account_overdrafts {
+            settings.push(format!(
+                "account-overdraft={}:{}",
+                client,
+                limit.normalize()
+            ));
+        }
+        settings
+    }
+
+    // How far withdrawals may take the given client's available funds below
+    // zero.
+    pub fn overdraft_for(&self, client: AccountId) -> Balance {
+        self.account_overdrafts
+            .get(&client)
+            .copied()
+            .unwrap_or(self.overdraft)
+    }
+
+    // Whether any account may be overdrawn.
+    pub fn allows_overdrafts(&self) -> bool {
+        !self.overdraft.is_zero()
+            || self
+                .account_overdrafts
+                .values()
+                .any(|limit| !limit.is_zero())
+    }
+
+    // The version of the policy, derived from its settings, so policies that
+    // process transactions the same way share a version no matter how they
+    // were put together.
+    pub fn version(&self) -> PolicyVersion {
+        let digest = Sha256::digest(self.settings().join("\n"));
+        let mut version = [0; 8];
+        version.copy_from_slice(&digest[..8]);
+        PolicyVersion(version)
+    }
+}
+
+// PolicyVersion identifies the policy a transaction was applied with, so
+// that audits can tell apart transactions applied under different policies,
+// e.g. in consecutive runs sharing a journal. It's written as 16 hex digits.
+// The default is the version of the default policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PolicyVersion([u8; 8]);
+
+impl Default for PolicyVersion {
+    fn default() -> Self {
+        Policy::default().version()
+    }
+}
+
+impl fmt::Display for PolicyVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl Serialize for PolicyVersion {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// AmountPolicy decides which amounts lines of input may have, which are
+// otherwise malformed with `RecordError::InvalidAmount`.
+// * Any: every amount is read as it is, including negative ones, which
+//   reverse the transaction, e.g. a negative deposit withdraws.
+// * NonNegative: negative amounts are invalid.
+// * Positive: zero is invalid too.
+// Amounts are checked when lines are read, before `ZeroAmountPolicy` is, so
+// with Positive zero amounts of the input never get as far as it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AmountPolicy {
+    Any,
+    #[default]
+    NonNegative,
+    Positive,
+}
+
+impl AmountPolicy {
+    pub fn allows(&self, amount: TransactionAmount) -> bool {
+        match self {
+            AmountPolicy::Any => true,
+            AmountPolicy::NonNegative => !amount.is_sign_negative() || amount.is_zero(),
+            AmountPolicy::Positive => amount.is_sign_positive() && !amount.is_zero(),
+        }
+    }
+}
+
+// ZeroAmountPolicy decides what happens to transactions with an amount of
+// exactly zero when they're applied, including those of embedders that
+// don't read CSV.
+// * Accept: the transaction is applied but doesn't change any balances. A
+//   deposit or withdrawal is recorded, so it can be disputed, resolved, and
+//   chargebacked later like any other transaction. Disputes and refunds of
+//   zero still fail with `TransactionError::NonPositiveAmount`.
+// * Reject: the transaction fails with `TransactionError::ZeroAmount` and is
+//   not recorded, so later references to it are treated as nonexistent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroAmountPolicy {
+    #[default]
+    Accept,
+    Reject,
+}
+
+// ErrorPolicy decides what happens when a line of the transactions file is
+// malformed or its transaction can't be applied.
+// * Skip: the line is reported and skipped, and processing goes on.
+// * Abort: the line is reported and processing stops, leaving the rest of
+//   the file unread.
+// * Collect: the line is skipped like with Skip, but kept in the ledger's
+//   `ProcessingReport` instead of being reported, see `Ledger::take_report`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    #[default]
+    Skip,
+    Abort,
+    Collect,
+}
+
+// TransactionIdPolicy decides whether the IDs of deposits and withdrawals
+// are checked for uniqueness.
+// * Unchecked: IDs are trusted to be unique, as per the specification. A
+//   repeated ID replaces the earlier transaction of the same client for
+//   later disputes.
+// * Unique: a deposit or withdrawal reusing the ID of one that was applied
+//   before, to any client, fails with
+//   `TransactionError::DuplicateTransaction`.
+// * Global: like Unique, and every ID belongs to the client whose
+//   transaction used it, for feeds whose IDs are unique across clients.
+//   Disputes and other transactions referring to the ID of another client's
+//   transaction fail with `TransactionError::ClientMismatch`, rather than as
+//   nonexistent.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionIdPolicy {
+    #[default]
+    Unchecked,
+    Unique,
+    Global,
+}
+
+// HeldRatioAction decides what happens to a dispute that would take an
+// account's held funds beyond the maximum ratio of its total. Either way the
+// ledger emits `LedgerEvent::HeldRatioExceeded` as an alert.
+// * Flag: the dispute is applied anyway.
+// * Block: the dispute fails with `TransactionError::HeldRatioExceeded`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum HeldRatioAction {
+    Flag,
+    #[default]
+    Block,
+}
+
+// DisputePolicy decides which transactions can be disputed.
+// * All: deposits and withdrawals.
+// * DepositsOnly: only deposits. Withdrawals are never recorded, which
+//   roughly halves the memory processed transactions take for
+//   withdrawal-heavy workloads, and disputes referring to them fail with
+//   `TransactionError::NonexistentTransaction`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisputePolicy {
+    #[default]
+    All,
+    DepositsOnly,
+}
+
+// WithdrawalDisputePolicy decides what disputing a withdrawal does.
+// * Hold: the same as disputing a deposit: the amount is moved from the
+//   available funds to the held funds until the dispute is resolved or
+//   charged back. Since the withdrawal already took the amount from the
+//   available funds, the client pays for it twice while the dispute is open.
+// * Reverse: like card networks do, the dispute holds nothing, and a
+//   chargeback reverses the withdrawal, crediting the disputed amount back to
+//   the available funds. Resolving the dispute leaves the withdrawal as it
+//   was.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WithdrawalDisputePolicy {
+    #[default]
+    Hold,
+    Reverse,
+}
+
+// OutOfOrderPolicy decides what happens to a transaction whose time is
+// before that of the latest transaction of the same account, e.g. one that
+// was delayed upstream. Only the order of every account's transactions is
+// checked, since accounts don't affect each other.
+// * Ignore: the transaction is applied as usual.
+// * Warn: the transaction is applied, and the ledger emits
+//   `LedgerEvent::OutOfOrder` as a warning.
+// * Reject: the ledger emits `LedgerEvent::OutOfOrder`, and the transaction
+//   fails with `TransactionError::OutOfOrder`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfOrderPolicy {
+    #[default]
+    Ignore,
+    Warn,
+    Reject,
+}
+
+// EarlyDisputePolicy decides what happens to a dispute of a transaction the
+// ledger hasn't seen yet, e.g. when the input merges several feeds and the
+// dispute overtook the deposit.
+// * Reject: the dispute fails with
+//   `TransactionError::NonexistentTransaction`.
+// * Park: the dispute is kept, and the ledger emits
+//   `LedgerEvent::DisputeParked`. It's applied right after the transaction
+//   it refers to, as of its own time. Disputes of transactions that never
+//   come stay parked, see `Ledger::parked_disputes`, up to
+//   `Policy::max_parked_disputes` of every account. Disputes of transactions
+//   that came already but weren't kept, e.g. withdrawals with
+//   `DisputePolicy::DepositsOnly`, are rejected as with `Reject`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EarlyDisputePolicy {
+    #[default]
+    Reject,
+    Park,
+}
+
+// ClientIdPolicy decides how the clients of CSV input are identified.
+// * Numeric: clients are given by their account ID.
+// * Text: clients are given by any name, e.g. a UUID, and the ledger gives
+//   every name an account ID of its own the first time it's read, see
+//   `ClientNames`. Outputs identify the clients by their names again.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ClientIdPolicy {
+    #[default]
+    Numeric,
+    Text,
+}
+
+// AccountCreationPolicy decides how accounts come to be.
+// * Implicit: a client's first transaction opens its account, if it
+//   doesn't have one yet.
+// * Explicit: accounts have to be created first, by `open_account` records,
+//   see `Ledger::create_account`, or by opening balances. Transactions of
+//   clients without an account fail with
+//   `TransactionError::NonexistentAccount`, and so do fees while the fee
+//   account doesn't exist.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AccountCreationPolicy {
+    #[default]
+    Implicit,
+    Explicit,
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("unknown policy value {0:?}")]
+pub struct ParsePolicyError(pub(crate) String);
+
+impl FromStr for AmountPolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(AmountPolicy::Any),
+            "non-negative" => Ok(AmountPolicy::NonNegative),
+            "positive" => Ok(AmountPolicy::Positive),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl FromStr for ZeroAmountPolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "accept" => Ok(ZeroAmountPolicy::Accept),
+            "reject" => Ok(ZeroAmountPolicy::Reject),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(ErrorPolicy::Skip),
+            "abort" => Ok(ErrorPolicy::Abort),
+            "collect" => Ok(ErrorPolicy::Collect),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl FromStr for HeldRatioAction {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flag" => Ok(HeldRatioAction::Flag),
+            "block" => Ok(HeldRatioAction::Block),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl FromStr for TransactionIdPolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unchecked" => Ok(TransactionIdPolicy::Unchecked),
+            "unique" => Ok(TransactionIdPolicy::Unique),
+            "global" => Ok(TransactionIdPolicy::Global),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl FromStr for DisputePolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(DisputePolicy::All),
+            "deposits-only" => Ok(DisputePolicy::DepositsOnly),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl FromStr for WithdrawalDisputePolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hold" => Ok(WithdrawalDisputePolicy::Hold),
+            "reverse" => Ok(WithdrawalDisputePolicy::Reverse),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl FromStr for OutOfOrderPolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(OutOfOrderPolicy::Ignore),
+            "warn" => Ok(OutOfOrderPolicy::Warn),
+            "reject" => Ok(OutOfOrderPolicy::Reject),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for AmountPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AmountPolicy::Any => "any",
+            AmountPolicy::NonNegative => "non-negative",
+            AmountPolicy::Positive => "positive",
+        })
+    }
+}
+
+impl fmt::Display for ZeroAmountPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ZeroAmountPolicy::Accept => "accept",
+            ZeroAmountPolicy::Reject => "reject",
+        })
+    }
+}
+
+impl fmt::Display for ErrorPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorPolicy::Skip => "skip",
+            ErrorPolicy::Abort => "abort",
+            ErrorPolicy::Collect => "collect",
+        })
+    }
+}
+
+impl fmt::Display for HeldRatioAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HeldRatioAction::Flag => "flag",
+            HeldRatioAction::Block => "block",
+        })
+    }
+}
+
+impl fmt::Display for TransactionIdPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TransactionIdPolicy::Unchecked => "unchecked",
+            TransactionIdPolicy::Unique => "unique",
+            TransactionIdPolicy::Global => "global",
+        })
+    }
+}
+
+impl fmt::Display for DisputePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DisputePolicy::All => "all",
+            DisputePolicy::DepositsOnly => "deposits-only",
+        })
+    }
+}
+
+impl fmt::Display for WithdrawalDisputePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            WithdrawalDisputePolicy::Hold => "hold",
+            WithdrawalDisputePolicy::Reverse => "reverse",
+        })
+    }
+}
+
+impl FromStr for EarlyDisputePolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(EarlyDisputePolicy::Reject),
+            "park" => Ok(EarlyDisputePolicy::Park),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for OutOfOrderPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OutOfOrderPolicy::Ignore => "ignore",
+            OutOfOrderPolicy::Warn => "warn",
+            OutOfOrderPolicy::Reject => "reject",
+        })
+    }
+}
+
+impl fmt::Display for EarlyDisputePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EarlyDisputePolicy::Reject => "reject",
+            EarlyDisputePolicy::Park => "park",
+        })
+    }
+}
+
+impl FromStr for ClientIdPolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "numeric" => Ok(ClientIdPolicy::Numeric),
+            "text" => Ok(ClientIdPolicy::Text),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for ClientIdPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ClientIdPolicy::Numeric => "numeric",
+            ClientIdPolicy::Text => "text",
+        })
+    }
+}
+
+impl FromStr for AccountCreationPolicy {
+    type Err = ParsePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "implicit" => Ok(AccountCreationPolicy::Implicit),
+            "explicit" => Ok(AccountCreationPolicy::Explicit),
+            _ => Err(ParsePolicyError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for AccountCreationPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AccountCreationPolicy::Implicit => "implicit",
+            AccountCreationPolicy::Explicit => "explicit",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AccountCreationPolicy, AmountPolicy, ClientIdPolicy, DisputePolicy, EarlyDisputePolicy,
+        FreezeLevel, OutOfOrderPolicy, Policy, TransactionIdPolicy, WithdrawalDisputePolicy,
+        ZeroAmountPolicy,
+    };
+    use crate::ledger::{Ledger, Reject};
+
+    #[test]
+    fn set() {
+        let mut policy = Policy::default();
+        policy.set("zero-amounts=reject").expect("should be valid");
+        assert_eq!(policy.zero_amounts, ZeroAmountPolicy::Reject);
+
+        assert!(policy.set("zero-amounts=maybe").is_err());
+        assert!(policy.set("zero-amounts").is_err());
+        assert!(policy.set("fees=0").is_err());
+        assert_eq!(policy.zero_amounts, ZeroAmountPolicy::Reject);
+
+        policy.set("overdraft=2.5").expect("should be valid");
+        assert_eq!(policy.overdraft, "2.5".parse().unwrap());
+        assert!(policy.set("overdraft=-1").is_err());
+        policy
+            .set("transaction-ids=unique")
+            .expect("should be valid");
+        assert_eq!(policy.transaction_ids, TransactionIdPolicy::Unique);
+        policy.set("max-held-ratio=0.5").expect("should be valid");
+        assert_eq!(policy.max_held_ratio, Some("0.5".parse().unwrap()));
+        assert!(policy.set("max-held-ratio=0").is_err());
+        assert!(policy.set("max-held-ratio=1.01").is_err());
+        policy.set("max-held-ratio=1").expect("should be valid");
+        policy.set("max-held-ratio=0.5").expect("should be valid");
+        policy
+            .set("disputes=deposits-only")
+            .expect("should be valid");
+        assert_eq!(policy.disputes, DisputePolicy::DepositsOnly);
+        policy
+            .set("withdrawal-disputes=reverse")
+            .expect("should be valid");
+        assert_eq!(policy.withdrawal_disputes, WithdrawalDisputePolicy::Reverse);
+        assert!(policy.set("withdrawal-disputes=refund").is_err());
+        policy
+            .set("authorization-window=100")
+            .expect("should be valid");
+        assert_eq!(policy.authorization_window, Some(100));
+        assert!(policy.set("authorization-window=0").is_err());
+        policy
+            .set("chargeback-freeze=withdrawals")
+            .expect("should be valid");
+        assert_eq!(policy.chargeback_freeze, FreezeLevel::Withdrawals);
+        policy.set("limit-freeze=full").expect("should be valid");
+        assert_eq!(policy.limit_freeze, Some(FreezeLevel::Full));
+        policy.set("limit-freeze=none").expect("should be valid");
+        assert_eq!(policy.limit_freeze, None);
+        assert!(policy.set("limit-freeze=partial").is_err());
+        policy.set("sweep-account=9").expect("should be valid");
+        assert_eq!(policy.sweep_account, Some(9));
+        assert!(policy.set("sweep-account=-1").is_err());
+        policy
+            .set("account-overdraft=7:100")
+            .expect("should be valid");
+        assert_eq!(policy.overdraft_for(7), 100.into());
+        assert_eq!(policy.overdraft_for(8), "2.5".parse().unwrap());
+        assert!(policy.set("account-overdraft=7").is_err());
+        assert!(policy.set("account-overdraft=7:-1").is_err());
+        policy
+            .set("withdrawal-limit=5,10000,86400")
+            .expect("should be valid");
+        assert_eq!(
+            policy.withdrawal_limit.map(|limit| limit.count),
+            Some(Some(5))
+        );
+        assert!(policy.set("withdrawal-limit=5").is_err());
+        policy.set("max-amount=1000000").expect("should be valid");
+        assert_eq!(policy.max_amount, Some(1_000_000.into()));
+        assert!(policy.set("max-amount=0").is_err());
+        policy.set("dispute-window=120").expect("should be valid");
+        assert_eq!(policy.dispute_window, Some(120));
+        assert!(policy.set("dispute-window=0").is_err());
+        policy.set("out-of-order=reject").expect("should be valid");
+        assert_eq!(policy.out_of_order, OutOfOrderPolicy::Reject);
+        assert!(policy.set("out-of-order=sort").is_err());
+        policy.set("early-disputes=park").expect("should be valid");
+        assert_eq!(policy.early_disputes, EarlyDisputePolicy::Park);
+        assert!(policy.set("early-disputes=drop").is_err());
+        policy
+            .set("max-parked-disputes=100")
+            .expect("should be valid");
+        assert_eq!(policy.max_parked_disputes, Some(100));
+        assert!(policy.set("max-parked-disputes=0").is_err());
+        policy.set("client-ids=text").expect("should be valid");
+        assert_eq!(policy.client_ids, ClientIdPolicy::Text);
+        assert!(policy.set("client-ids=uuid").is_err());
+        policy
+            .set("account-creation=explicit")
+            .expect("should be valid");
+        assert_eq!(policy.account_creation, AccountCreationPolicy::Explicit);
+        policy.set("reorder-window=60").expect("should be valid");
+        assert_eq!(policy.reorder_window, Some(60));
+        assert!(policy.set("reorder-window=0").is_err());
+        policy.set("currency-units=XAU:4").expect("should be valid");
+        assert_eq!(
+            policy.currencies.minor_units("XAU".parse().unwrap()),
+            Some(4)
+        );
+        assert!(policy.set("currency-units=XAU").is_err());
+        assert!(policy.set("currency-units=XAU:29").is_err());
+    }
+    #[test]
+    fn version() {
+        let mut policy = Policy::default();
+        assert_eq!(policy.version(), Default::default());
+        assert_eq!(policy.version().to_string().len(), 16);
+
+        // Settings read back give the same policy.
+        policy.set("overdraft=1.50").unwrap();
+        let mut same = Policy::default();
+        for setting in policy.settings() {
+            same.set(&setting).expect("should be valid");
+        }
+        assert_eq!(same.settings(), policy.settings());
+        same.set("overdraft=1.5").unwrap();
+        assert_eq!(same.version(), policy.version());
+
+        same.set("zero-amounts=reject").unwrap();
+        assert_ne!(same.version(), policy.version());
+
+        // The default dispute policy doesn't change versions.
+        let mut all = Policy::default();
+        all.set("disputes=all").unwrap();
+        assert_eq!(all.version(), Default::default());
+        all.set("disputes=deposits-only").unwrap();
+        assert_ne!(all.version(), Default::default());
+        let mut hold = Policy::default();
+        hold.set("withdrawal-disputes=hold").unwrap();
+        assert_eq!(hold.version(), Default::default());
+        hold.set("amounts=non-negative").unwrap();
+        assert_eq!(hold.version(), Default::default());
+    }
+
+    #[test]
+    fn amounts() {
+        let (negative, zero, positive) = ((-1).into(), 0.into(), 1.into());
+        let allowed = |policy: AmountPolicy| {
+            [negative, zero, positive]
+                .into_iter()
+                .filter(|&amount| policy.allows(amount))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(allowed(AmountPolicy::Any), [negative, zero, positive]);
+        assert_eq!(allowed(AmountPolicy::NonNegative), [zero, positive]);
+        assert_eq!(allowed(AmountPolicy::Positive), [positive]);
+        // Negative zero is zero.
+        assert!(AmountPolicy::NonNegative.allows("-0.0".parse().unwrap()));
+
+        let mut policy = Policy::default();
+        policy.set("amounts=positive").expect("should be valid");
+        assert_eq!(policy.amounts, AmountPolicy::Positive);
+        assert!(policy.set("amounts=negative").is_err());
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Values too large for their settings, and malformed settings, are
+        // rejected and leave the policy as it was.
+        let mut policy = Policy::default();
+        policy.set("sweep-account=9").unwrap();
+        policy.set("max-amount=100").unwrap();
+        policy.set("amounts=any").unwrap();
+        let settings = policy.settings();
+        for invalid in [
+            "overdraft=79228162514264337593543950336",
+            "max-amount=79228162514264337593543950336",
+            "max-parked-disputes=18446744073709551616000",
+            "dispute-window=18446744073709551616",
+            "sweep-account=340282366920938463463374607431768211456",
+            "account-overdraft=340282366920938463463374607431768211456:1",
+            "currency-units=XAU:4294967296",
+            "=",
+            "",
+            "overdraft==1",
+            "overdraft=1e3",
+            " overdraft=1",
+            "zero-amounts=Reject",
+            "currency-units=€:2",
+        ] {
+            assert!(policy.set(invalid).is_err(), "{:?}", invalid);
+        }
+        assert_eq!(policy.settings(), settings);
+
+        // The policy read back from its settings processes transactions the
+        // same way: client 1's deposit beyond the largest amount fails,
+        // client 2's account is swept when it's closed, and client 3 can't
+        // refund a withdrawal, even with negative amounts allowed.
+        let mut same = Policy::default();
+        for setting in &settings {
+            same.set(setting).expect("should be valid");
+        }
+        assert_eq!(same.version(), policy.version());
+        let input = "\
+type,client,tx,amount
+deposit,1,1,100.0000000000000000000000001
+deposit,2,2,5
+close_account,2,0,
+deposit,2,3,1
+deposit,3,4,10
+withdrawal,3,5,4
+refund,3,5,
+deposit,3,6,-2
+";
+        let mut ledger = Ledger::builder().policy(same).build();
+        let mut rejects = vec![];
+        ledger.read_csv_with(input.as_bytes(), Default::default(), |reject: Reject| {
+            rejects.push(reject.reason)
+        });
+        assert_eq!(
+            rejects,
+            ["amount_too_large", "account_closed", "not_refundable"]
+        );
+        assert!(ledger.account(1).is_none());
+        assert!(ledger.account(2).unwrap().is_closed());
+        assert_eq!(ledger.account(9).unwrap().total().unwrap(), 5.into());
+        assert_eq!(ledger.account(3).unwrap().total().unwrap(), 4.into());
+    }
+}
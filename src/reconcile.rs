@@ -0,0 +1,339 @@
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    client_names::ClientKey, ledger::Ledger, policy::ClientIdPolicy, AccountId, Balance, Currency,
+};
+
+// Reconciling checks the balances the ledger computed against those another
+// system expects them to be, e.g. the end of day balances of a bank or card
+// processor, see `Ledger::reconcile`. The expected balances are read as CSV
+// with the columns `client`, `available`, and `held`, and `currency` if
+// accounts have balances in currencies. Other columns are ignored. The
+// computed balances are rounded like the account summaries are, see
+// `Precision`, before they're compared.
+
+#[derive(Error, Debug)]
+pub enum ReconcileError {
+    #[error("{0}")]
+    Csv(#[from] csv::Error),
+    #[error("line {line}: {message}")]
+    Malformed { line: u64, message: String },
+}
+
+// DiscrepancyKind is how the computed balances of an account differ from the
+// expected ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscrepancyKind {
+    // The account is expected, but the ledger doesn't have it.
+    MissingAccount,
+    // The ledger has the account with funds in it, but it isn't expected.
+    UnexpectedAccount,
+    // The available funds differ, the held ones might as well.
+    AvailableMismatch,
+    // Only the held funds differ.
+    HeldMismatch,
+}
+
+// Discrepancy is an account, or its balance in a currency, whose computed
+// balances differ from the expected ones, with both, if there are any.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Discrepancy {
+    pub client: ClientKey,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Option<Currency>>,
+    pub kind: DiscrepancyKind,
+    pub expected_available: Option<Balance>,
+    pub available: Option<Balance>,
+    pub expected_held: Option<Balance>,
+    pub held: Option<Balance>,
+}
+
+// Reconciliation is the outcome of reconciling a ledger: how many accounts
+// matched, and those that didn't, in the order of their clients and
+// currencies.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Reconciliation {
+    pub matched: usize,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl Reconciliation {
+    // Whether every account matched.
+    pub fn is_clean(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+
+    // Write the discrepancies as CSV: the client, its currency if the
+    // balances have any, the kind of discrepancy, and the expected and
+    // computed available and held funds, empty where the account is missing.
+    pub fn to_csv<W: Write>(&self, output: W) -> csv::Result<()> {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(true)
+            .from_writer(output);
+        for discrepancy in &self.discrepancies {
+            writer.serialize(discrepancy)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+type Key = (ClientKey, Option<Currency>);
+
+// The available and held funds of an account, or of its balance in a
+// currency, by client and currency.
+type Balances = BTreeMap<Key, (Balance, Balance)>;
+
+pub(crate) fn reconcile<R: Read>(
+    ledger: &Ledger,
+    expected: R,
+) -> Result<Reconciliation, ReconcileError> {
+    let (expected, expected_currencies) = read_expected(ledger, expected)?;
+    let mut computed = Balances::new();
+    let mut currencies = expected_currencies;
+    for (account_id, currency, balance, _) in ledger.balance_rows() {
+        currencies |= currency.is_some();
+        let precision = ledger.precision_in(currency.flatten(), ledger.precision());
+        let key = (ledger.client_key(account_id), currency.flatten());
+        let funds = (
+            precision.apply(balance.available()),
+            precision.apply(balance.held()),
+        );
+        computed.insert(key, funds);
+    }
+
+    let mut reconciliation = Reconciliation::default();
+    let mut keys = expected.keys().chain(computed.keys()).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    for key in keys {
+        let (expected, computed) = (expected.get(key), computed.get(key));
+        let kind = match (expected, computed) {
+            (Some(expected), Some(computed)) if expected == computed => {
+                reconciliation.matched += 1;
+                continue;
+            }
+            (Some(expected), Some(computed)) if expected.0 == computed.0 => {
+                DiscrepancyKind::HeldMismatch
+            }
+            (Some(_), Some(_)) => DiscrepancyKind::AvailableMismatch,
+            (Some(_), None) => DiscrepancyKind::MissingAccount,
+            // Other systems commonly leave empty accounts out.
+            (None, Some((available, held))) if available.is_zero() && held.is_zero() => continue,
+            (None, _) => DiscrepancyKind::UnexpectedAccount,
+        };
+        reconciliation.discrepancies.push(Discrepancy {
+            client: key.0.clone(),
+            currency: currencies.then_some(key.1),
+            kind,
+            expected_available: expected.map(|expected| expected.0),
+            available: computed.map(|computed| computed.0),
+            expected_held: expected.map(|expected| expected.1),
+            held: computed.map(|computed| computed.1),
+        });
+    }
+    Ok(reconciliation)
+}
+
+// Read the expected balances, and whether they have a currency column.
+// Clients are read the way the ledger reads them, by ID, or by name if it
+// identifies them by name, see `ClientIdPolicy::Text`.
+fn read_expected<R: Read>(ledger: &Ledger, input: R) -> Result<(Balances, bool), ReconcileError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input);
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .ok_or_else(|| ReconcileError::Malformed {
+                line: 1,
+                message: format!("the {} column is missing", name),
+            })
+    };
+    let client = column("client")?;
+    let available = column("available")?;
+    let held = column("held")?;
+    let currency = headers.iter().position(|header| header == "currency");
+    let named = ledger.policy().client_ids == ClientIdPolicy::Text;
+
+    let mut balances = Balances::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |position| position.line());
+        let malformed = |message: String| ReconcileError::Malformed { line, message };
+        let field = |index: usize| record.get(index).unwrap_or_default();
+        let balance = |index: usize, name: &str| {
+            field(index)
+                .parse::<Balance>()
+                .map_err(|_| malformed(format!("invalid {} {:?}", name, field(index))))
+        };
+        let key = match field(client) {
+            name if named => ClientKey::Name(name.to_owned()),
+            id => ClientKey::Id(
+                id.parse::<AccountId>()
+                    .map_err(|_| malformed(format!("invalid client {:?}", id)))?,
+            ),
+        };
+        let currency = match currency.map(field) {
+            None | Some("") => None,
+            Some(code) => Some(code.parse().map_err(|err| malformed(format!("{}", err)))?),
+        };
+        let funds = (balance(available, "available")?, balance(held, "held")?);
+        if balances.insert((key.clone(), currency), funds).is_some() {
+            return Err(malformed(format!("client {} appears more than once", key)));
+        }
+    }
+    Ok((balances, currency.is_some()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DiscrepancyKind, ReconcileError};
+    use crate::{
+        client_names::ClientKey,
+        ledger::{Ledger, Precision},
+    };
+
+    #[test]
+    fn reconcile() {
+        let mut ledger = Ledger::default();
+        ledger.read_csv(
+            "\
+type,client,tx,amount
+deposit,1,1,10.00001
+deposit,2,2,5
+dispute,2,2,
+deposit,3,3,7
+deposit,4,4,1
+withdrawal,4,5,1
+"
+            .as_bytes(),
+        );
+        let expected = "\
+client,available,held,note
+1,10,0,rounded
+2,5,0,
+3,6,0,
+5,1,0,
+";
+        let reconciliation = ledger.reconcile(expected.as_bytes()).unwrap();
+        assert_eq!(reconciliation.matched, 1);
+        let kinds = reconciliation
+            .discrepancies
+            .iter()
+            .map(|discrepancy| (discrepancy.client.clone(), discrepancy.kind))
+            .collect::<Vec<_>>();
+        // Client 4 is empty, so it can be left out.
+        assert_eq!(
+            kinds,
+            [
+                (ClientKey::Id(2), DiscrepancyKind::AvailableMismatch),
+                (ClientKey::Id(3), DiscrepancyKind::AvailableMismatch),
+                (ClientKey::Id(5), DiscrepancyKind::MissingAccount),
+            ]
+        );
+        assert!(!reconciliation.is_clean());
+
+        let mut output = vec![];
+        reconciliation.to_csv(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client,kind,expected_available,available,expected_held,held
+2,available_mismatch,5,0.0000,0,5.0000
+3,available_mismatch,6,7.0000,0,0.0000
+5,missing_account,1,,0,
+"
+        );
+
+        let held = "client,available,held\n1,10,0\n2,0,4\n3,7,0\n";
+        let reconciliation = ledger.reconcile(held.as_bytes()).unwrap();
+        assert_eq!(
+            reconciliation.discrepancies[0].kind,
+            DiscrepancyKind::HeldMismatch
+        );
+        let unexpected = "client,available,held\n1,10,0\n2,0,5\n";
+        let reconciliation = ledger.reconcile(unexpected.as_bytes()).unwrap();
+        assert_eq!(
+            reconciliation.discrepancies[0].kind,
+            DiscrepancyKind::UnexpectedAccount
+        );
+        assert!(ledger.reconcile("client,held\n".as_bytes()).is_err());
+        assert!(ledger
+            .reconcile("client,available,held\nc-1,1,0\n".as_bytes())
+            .is_err());
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Client 1 has as much as funds can hold, client 2 closes their
+        // account, which can be left out as it's empty, and client 3's
+        // refund of a withdrawal is rejected.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,1
+deposit,2,3,5
+withdrawal,2,4,5
+close_account,2,0,
+deposit,3,5,10
+withdrawal,3,6,4
+refund,3,6,
+"
+        );
+        let mut ledger = Ledger::default();
+        ledger.read_csv(input.as_bytes());
+        let max = Precision::default().apply(max);
+        let expected = format!("client,available,held\n1,{max},0\n3,6,0\n");
+        let reconciliation = ledger.reconcile(expected.as_bytes()).unwrap();
+        assert!(reconciliation.is_clean());
+        assert_eq!(reconciliation.matched, 2);
+
+        // A balance in a currency isn't the account's own balance.
+        let currencies = format!("client,available,held,currency\n1,{max},0,\n3,6,0,EUR\n");
+        let reconciliation = ledger.reconcile(currencies.as_bytes()).unwrap();
+        let kinds = reconciliation
+            .discrepancies
+            .iter()
+            .map(|discrepancy| (discrepancy.currency.flatten(), discrepancy.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                (None, DiscrepancyKind::UnexpectedAccount),
+                ("EUR".parse().ok(), DiscrepancyKind::MissingAccount),
+            ]
+        );
+
+        // Balances larger than a decimal holds, and other malformed lines,
+        // are reported with their line.
+        for line in [
+            "3,79228162514264337593543950336,0,",
+            "3,6,,",
+            "3,6,0,EURO",
+            "1,0,0,",
+        ] {
+            let expected = format!("client,available,held,currency\n1,0,0,\n{}\n", line);
+            let reconciliation = ledger.reconcile(expected.as_bytes());
+            assert!(
+                matches!(
+                    reconciliation,
+                    Err(ReconcileError::Malformed { line: 3, .. })
+                ),
+                "{:?}",
+                line
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::{collections::BTreeMap, io::Read, mem};
+
+use crate::{
+    ledger::{csv_headers, csv_reader, parse_csv_line, Ledger, Reject},
+    policy::ErrorPolicy,
+    scaling::{AmountScale, ScaleCheck},
+    Timestamp,
+};
+
+// Reorder holds back records so that those arriving late, by up to `window`
+// seconds, can be put back in the order of their time, see
+// `Policy::reorder_window`. A record is released once one `window` seconds
+// later has come in, so nothing can overtake it anymore. Records without a
+// time are taken to happen at the latest time so far, so they stay behind
+// the records that came in before them, and records of the same time are
+// released in the order they came in. Records later than the window allows
+// are released right away, out of order, for the ledger to deal with, see
+// `OutOfOrderPolicy`.
+pub struct Reorder<T> {
+    window: Timestamp,
+    // The records held back, by their time and the order they came in.
+    pending: BTreeMap<(Timestamp, u64), T>,
+    received: u64,
+    latest: Timestamp,
+}
+
+impl<T> Reorder<T> {
+    pub fn new(window: Timestamp) -> Reorder<T> {
+        Reorder {
+            window,
+            pending: BTreeMap::new(),
+            received: 0,
+            latest: 0,
+        }
+    }
+
+    // Add a record that happened at `time`, returning the records that
+    // can't be overtaken anymore, oldest first.
+    pub fn push(&mut self, time: Option<Timestamp>, record: T) -> Vec<T> {
+        let time = time.unwrap_or(self.latest);
+        self.latest = self.latest.max(time);
+        self.pending.insert((time, self.received), record);
+        self.received += 1;
+        let Some(cutoff) = self.latest.checked_sub(self.window) else {
+            return vec![];
+        };
+        // Nothing comes after the end of time, so everything is released.
+        let held = match cutoff.checked_add(1) {
+            Some(after) => self.pending.split_off(&(after, 0)),
+            None => BTreeMap::new(),
+        };
+        mem::replace(&mut self.pending, held)
+            .into_values()
+            .collect()
+    }
+
+    // Release the records still held back, oldest first, once no more are
+    // coming.
+    pub fn finish(&mut self) -> Vec<T> {
+        mem::take(&mut self.pending).into_values().collect()
+    }
+}
+
+// Read transactions from the given CSV reader and apply them to the ledger
+// like `Ledger::read_csv_with`, putting them in the order of their time
+// within `window` seconds first. Lines that can't be read are reported as
+// they're read, and lines that can't be applied as they're applied, so
+// rejects may come out of the order of their lines.
+pub(crate) fn read_csv_reordered<R: Read>(
+    ledger: &mut Ledger,
+    reader: R,
+    scale: AmountScale,
+    window: Timestamp,
+    mut sink: &mut dyn FnMut(Reject),
+) -> ScaleCheck {
+    let mut check = ScaleCheck::new(scale);
+    let amounts = ledger.policy().amounts;
+    let abort = ledger.policy().errors == ErrorPolicy::Abort;
+    let mut reader = csv_reader(reader);
+    let Some(headers) = csv_headers(&mut reader, &mut sink) else {
+        return check;
+    };
+
+    let mut reorder = Reorder::new(window);
+    let mut apply = |client, tx| ledger.apply(client, tx);
+    for line in reader.records() {
+        let released = match parse_csv_line(line, &headers, scale, amounts, &mut check) {
+            Ok(line) => reorder.push(line.transaction.timestamp(), line),
+            Err(reject) => {
+                sink(reject);
+                if abort {
+                    return check;
+                }
+                continue;
+            }
+        };
+        for line in released {
+            if let Err(reject) = line.apply(&mut apply) {
+                sink(reject);
+                if abort {
+                    return check;
+                }
+            }
+        }
+    }
+    for line in reorder.finish() {
+        if let Err(reject) = line.apply(&mut apply) {
+            sink(reject);
+            if abort {
+                break;
+            }
+        }
+    }
+    check
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reorder;
+    use crate::{
+        ledger::{Ledger, Reject},
+        scaling::AmountScale,
+    };
+
+    #[test]
+    fn reorder() {
+        let mut reorder = Reorder::new(10);
+        assert_eq!(reorder.push(Some(100), "a"), [] as [&str; 0]);
+        assert_eq!(reorder.push(Some(95), "b"), [] as [&str; 0]);
+        // Taken to happen at 100, after "a".
+        assert_eq!(reorder.push(None, "c"), [] as [&str; 0]);
+        assert_eq!(reorder.push(Some(105), "d"), ["b"]);
+        assert_eq!(reorder.push(Some(110), "e"), ["a", "c"]);
+        // Too late to be put in order.
+        assert_eq!(reorder.push(Some(90), "f"), ["f"]);
+        assert_eq!(reorder.finish(), ["d", "e"]);
+        assert_eq!(reorder.finish(), [] as [&str; 0]);
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Records at the end of time are released like any other, and with
+        // a window as long as time only those at its start are released
+        // before the end.
+        let mut reorder = Reorder::new(0);
+        assert_eq!(reorder.push(Some(u64::MAX), "a"), ["a"]);
+        assert_eq!(reorder.push(Some(u64::MAX), "b"), ["b"]);
+        let mut reorder = Reorder::new(u64::MAX);
+        assert_eq!(reorder.push(Some(u64::MAX), "a"), [] as [&str; 0]);
+        assert_eq!(reorder.push(Some(0), "b"), ["b"]);
+        assert_eq!(reorder.finish(), ["a"]);
+
+        // The closing is taken to happen at the latest time so far, so the
+        // late withdrawal and deposit are applied before it, and it's
+        // rejected as the account isn't empty. The refund of the
+        // withdrawal, and the deposit too large for funds to hold, are
+        // rejected as well, and the line that can't be read is rejected as
+        // it's read, ahead of those held back.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount,timestamp
+deposit,1,1,10,100
+deposit,1,2,1,130
+close_account,1,0,,
+withdrawal,1,3,10,105
+refund,1,3,,
+deposit,1,4,1,110
+deposit,2,5,{max},100
+deposit,2,6,{max},101
+deposit,x,7,1,
+"
+        );
+        let mut ledger = Ledger::builder().reorder_window(30).build();
+        let mut rejects = vec![];
+        ledger.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push((reject.line, reject.reason)),
+        );
+        assert_eq!(
+            rejects,
+            [
+                (10, "malformed"),
+                (9, "overflow"),
+                (4, "balance_not_zero"),
+                (6, "not_refundable"),
+            ]
+        );
+        let account = ledger.account(1).unwrap();
+        assert!(!account.is_closed());
+        assert_eq!(account.total().unwrap(), 2.into());
+    }
+}
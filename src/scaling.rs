@@ -0,0 +1,201 @@
+use std::str::FromStr;
+
+use thiserror::Error;
+
+use crate::{ledger::MAX_DECIMAL_PLACES, TransactionAmount};
+
+// Some feeds send amounts in minor units, e.g. integer cents, rather than as
+// decimals. AmountScale is the number of decimal digits the amounts of a
+// source are shifted by: a scale of 2 reads `1050` as `10.50`. The default of
+// 0 reads amounts as they are.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AmountScale(u32);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("invalid amount scale {0:?}, expected a number of digits from 0 to {MAX_DECIMAL_PLACES}")]
+pub struct ParseScaleError(String);
+
+impl FromStr for AmountScale {
+    type Err = ParseScaleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(digits) if digits <= MAX_DECIMAL_PLACES => Ok(AmountScale(digits)),
+            _ => Err(ParseScaleError(s.to_owned())),
+        }
+    }
+}
+
+impl AmountScale {
+    // The amount shifted by the scale, or `None` if that would take it past
+    // the most decimal places an amount can have, which would round it.
+    pub fn apply(&self, amount: TransactionAmount) -> Option<TransactionAmount> {
+        match self.0 {
+            0 => Some(amount),
+            digits => (amount.normalize().scale() + digits <= MAX_DECIMAL_PLACES)
+                .then(|| amount * TransactionAmount::new(1, digits)),
+        }
+    }
+}
+
+// Whole amounts at least this large in every record of a decimal source
+// suggest that it's really in minor units.
+pub const SUSPICIOUS_MINIMUM: u32 = 1000;
+
+// ScaleCheck looks at the amounts of a source as they're read, before they're
+// scaled, to detect sources read with the wrong scale: decimal sources whose
+// amounts are all large whole numbers, which are likely in minor units, and
+// sources in minor units with fractional amounts, which are likely decimal.
+// Either only warrants a warning, since both can happen legitimately.
+#[derive(Debug, Clone)]
+pub struct ScaleCheck {
+    scale: AmountScale,
+    amounts: usize,
+    fractional: usize,
+    smallest: Option<TransactionAmount>,
+}
+
+impl ScaleCheck {
+    pub fn new(scale: AmountScale) -> ScaleCheck {
+        ScaleCheck {
+            scale,
+            amounts: 0,
+            fractional: 0,
+            smallest: None,
+        }
+    }
+
+    pub fn observe(&mut self, amount: TransactionAmount) {
+        self.amounts += 1;
+        if !amount.fract().is_zero() {
+            self.fractional += 1;
+        }
+        let amount = amount.abs();
+        self.smallest = Some(
+            self.smallest
+                .map_or(amount, |smallest| smallest.min(amount)),
+        );
+    }
+
+    // Add the amounts seen by another check of the same source, e.g. of
+    // another part of it.
+    pub fn merge(&mut self, other: &ScaleCheck) {
+        self.amounts += other.amounts;
+        self.fractional += other.fractional;
+        self.smallest = match (self.smallest, other.smallest) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    // A description of why the source looks mis-scaled, if it does.
+    pub fn warning(&self) -> Option<String> {
+        match self.scale {
+            AmountScale(0) => {
+                let large = self
+                    .smallest
+                    .is_some_and(|smallest| smallest >= SUSPICIOUS_MINIMUM.into());
+                (self.fractional == 0 && large).then(|| {
+                    format!(
+                        "all {} amounts are whole numbers of at least {}, \
+                         they may be in minor units",
+                        self.amounts, SUSPICIOUS_MINIMUM
+                    )
+                })
+            }
+            AmountScale(digits) => (self.fractional > 0).then(|| {
+                format!(
+                    "{} of {} amounts have fractions \
+                     although they're read in minor units of {} digits",
+                    self.fractional, self.amounts, digits
+                )
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AmountScale, ScaleCheck};
+    use crate::ledger::{Ledger, Reject};
+
+    #[test]
+    fn scale_check() {
+        let check = |scale: &str, amounts: &[&str]| {
+            let mut check = ScaleCheck::new(scale.parse().unwrap());
+            for amount in amounts {
+                check.observe(amount.parse().unwrap());
+            }
+            check.warning().is_some()
+        };
+
+        assert!(check("0", &["1000", "25000", "1999"]));
+        assert!(!check("0", &["1000", "25000", "19.99"]));
+        assert!(!check("0", &["1000", "250", "1999"]));
+        assert!(!check("0", &[]));
+        assert!(check("2", &["1000", "19.99"]));
+        assert!(!check("2", &["1000", "1999"]));
+
+        let scale = "2".parse::<AmountScale>().unwrap();
+        assert_eq!(
+            scale.apply("1050".parse().unwrap()).unwrap().to_string(),
+            "10.50"
+        );
+        assert!("29".parse::<AmountScale>().is_err());
+    }
+
+    #[test]
+    fn edge_cases() {
+        let parse = |amount: &str| amount.parse().unwrap();
+        let scale = |digits: &str| digits.parse::<AmountScale>().unwrap();
+
+        // The largest amount fits at the largest scale, but fractions of
+        // minor units that would be rounded away don't.
+        let max = "7.9228162514264337593543950335".parse().unwrap();
+        assert_eq!(scale("28").apply(crate::TransactionAmount::MAX), Some(max));
+        assert_eq!(scale("28").apply(parse("0.5")), None);
+        assert_eq!(
+            scale("2").apply(parse("-0.000000000000000000000000001")),
+            None
+        );
+        assert_eq!(scale("2").apply(parse("10.500")), Some(parse("0.105")));
+        for digits in ["", " 2", "-1", "2.0", "4294967296"] {
+            assert!(digits.parse::<AmountScale>().is_err());
+        }
+
+        // Amounts that can't be scaled exactly are rejected as malformed,
+        // like the rest of the lines that can't be read. A refund of a
+        // withdrawal and a deposit into a closed account are rejected as
+        // usual.
+        let input = "\
+type,client,tx,amount
+deposit,1,1,1050
+withdrawal,1,2,50
+refund,1,2,
+deposit,1,3,0.000000000000000000000000001
+close_account,1,0,
+deposit,1,4,x
+deposit,3,7,100
+withdrawal,3,8,100
+close_account,3,0,
+deposit,3,9,100
+";
+        let mut ledger = Ledger::default();
+        let mut rejects = vec![];
+        let check = ledger.read_csv_with(input.as_bytes(), scale("2"), |reject: Reject| {
+            rejects.push((reject.line, reject.reason))
+        });
+        assert_eq!(
+            rejects,
+            [
+                (4, "not_refundable"),
+                (5, "malformed"),
+                (6, "balance_not_zero"),
+                (7, "malformed"),
+                (11, "account_closed"),
+            ]
+        );
+        assert!(check.warning().is_some());
+        assert_eq!(ledger.account(1).unwrap().total().unwrap(), 10.into());
+    }
+}
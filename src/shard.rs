@@ -0,0 +1,253 @@
+use std::{io::Read, sync::mpsc, thread};
+
+use crate::{
+    error_sink::ErrorSink,
+    ledger::{apply_csv_line, csv_headers, csv_reader, Ledger, Reject},
+    scaling::{AmountScale, ScaleCheck},
+    AccountId,
+};
+
+// The number of lines handed to a shard at once, so the channels aren't
+// paid for on every line.
+const BATCH_LEN: usize = 1024;
+
+// The number of batches that may wait for a shard, which bounds the memory
+// taken by lines read ahead of a slow shard.
+const QUEUED_BATCHES: usize = 16;
+
+// The shard that owns the client's accounts, its ID modulo `shards`. With
+// the `wide-ids` feature, IDs are u64s already.
+#[cfg_attr(feature = "wide-ids", allow(clippy::useless_conversion))]
+pub(crate) fn shard_of(client: AccountId, shards: usize) -> usize {
+    // The remainder is less than `shards`, so it fits back into a usize.
+    (u64::from(client) % shards as u64) as usize
+}
+
+// Read transactions from the given CSV reader and apply them to the ledger
+// like `Ledger::read_csv_with`, but on `shards` threads. Transactions of
+// different clients are independent of each other, so every shard owns the
+// accounts, statements, and processed transactions of the clients whose ID
+// is its index modulo `shards`, and applies their transactions in the order
+// they're read. The reading thread only splits the input into lines and
+// finds their client, everything else happens on the shards. Once the input
+// is read, the shards are merged back into the ledger.
+//
+// The result is the same as applying the transactions on a single thread,
+// except that the rejected lines are only passed to `sink` at the end,
+// ordered by line. Lines whose client can't be read go to the first shard,
+// which rejects them.
+pub fn read_csv_sharded<R, S>(
+    ledger: &mut Ledger,
+    reader: R,
+    scale: AmountScale,
+    shards: usize,
+    mut sink: S,
+) -> ScaleCheck
+where
+    R: Read,
+    S: ErrorSink,
+{
+    let mut check = ScaleCheck::new(scale);
+    let mut reader = csv_reader(reader);
+    let Some(headers) = csv_headers(&mut reader, &mut sink) else {
+        return check;
+    };
+    let client_field = headers.names.iter().position(|header| header == "client");
+    let shards = shards.max(1);
+
+    let mut rejects = vec![];
+    let ledgers = ledger.split_into_shards(shards);
+    let done = thread::scope(|scope| {
+        let (senders, handles): (Vec<_>, Vec<_>) = ledgers
+            .into_iter()
+            .map(|mut shard| {
+                let (sender, receiver) = mpsc::sync_channel::<Vec<_>>(QUEUED_BATCHES);
+                let headers = &headers;
+                let handle = scope.spawn(move || {
+                    let mut check = ScaleCheck::new(scale);
+                    let mut rejects = vec![];
+                    let amounts = shard.policy().amounts;
+                    let mut apply = |client, tx| shard.apply(client, tx);
+                    for line in receiver.into_iter().flatten() {
+                        if let Err(reject) =
+                            apply_csv_line(line, headers, scale, amounts, &mut check, &mut apply)
+                        {
+                            rejects.push(reject);
+                        }
+                    }
+                    (shard, check, rejects)
+                });
+                (sender, handle)
+            })
+            .unzip();
+
+        let mut batches = (0..shards)
+            .map(|_| Vec::with_capacity(BATCH_LEN))
+            .collect::<Vec<_>>();
+        for line in reader.records() {
+            let client = match (&line, client_field) {
+                (Ok(fields), Some(field)) => fields
+                    .get(field)
+                    .and_then(|client| client.trim().parse::<AccountId>().ok()),
+                _ => None,
+            };
+            let shard = client.map_or(0, |client| shard_of(client, shards));
+            batches[shard].push(line);
+            if batches[shard].len() == BATCH_LEN {
+                let batch = std::mem::replace(&mut batches[shard], Vec::with_capacity(BATCH_LEN));
+                // A shard only hangs up if it panicked, which joining it
+                // reports below.
+                let _ = senders[shard].send(batch);
+            }
+        }
+        for (sender, batch) in senders.into_iter().zip(batches) {
+            let _ = sender.send(batch);
+        }
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("shard panicked"))
+            .collect::<Vec<_>>()
+    });
+
+    for (shard, shard_check, shard_rejects) in done {
+        ledger.merge_shard(shard);
+        check.merge(&shard_check);
+        rejects.extend(shard_rejects);
+    }
+    rejects.sort_by_key(|reject: &Reject| reject.line);
+    for reject in rejects {
+        sink.on_reject(reject);
+    }
+    check
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_csv_sharded;
+    use crate::{
+        ledger::{Ledger, Reject},
+        scaling::AmountScale,
+        AccountId,
+    };
+
+    #[test]
+    fn same_as_sequential() {
+        let mut input = "type, client, tx, amount\n".to_owned();
+        for tx in 0..5000u32 {
+            let client = tx * 7 % 13;
+            input += &match tx % 5 {
+                0 | 1 => format!("deposit,{},{},{}.5\n", client, tx, tx % 10),
+                2 => format!("withdrawal,{},{},3\n", client, tx),
+                3 => format!("dispute,{},{},\n", client, tx - 3),
+                _ if tx % 3 == 0 => format!("chargeback,{},{},\n", client, tx - 4),
+                _ => format!("resolve,{},{},\n", client, tx - 4),
+            };
+        }
+        input += "deposit,x,5000,1\n";
+
+        let mut sequential = Ledger::builder().keep_statements().build();
+        let mut expected = vec![];
+        sequential.read_csv_with(
+            input.as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| expected.push(reject),
+        );
+
+        let mut sharded = Ledger::builder().keep_statements().build();
+        let mut rejects = vec![];
+        read_csv_sharded(
+            &mut sharded,
+            input.as_bytes(),
+            AmountScale::default(),
+            4,
+            |reject: Reject| rejects.push(reject),
+        );
+
+        assert_eq!(rejects, expected);
+        for client in 0..13 {
+            assert_eq!(
+                sharded.statement_for(client),
+                sequential.statement_for(client)
+            );
+        }
+        let mut output = vec![];
+        let mut expected = vec![];
+        sharded.accounts_to_csv(&mut output);
+        sequential.accounts_to_csv(&mut expected);
+        assert_eq!(String::from_utf8(output), String::from_utf8(expected));
+    }
+
+    #[test]
+    fn edge_cases() {
+        // A deposit too large for funds to hold, a refund of a withdrawal,
+        // a deposit into a closed account, and lines that can't be read, by
+        // clients on every shard, including the largest ID there is.
+        let max = crate::funds::max();
+        let last = AccountId::MAX;
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,{last},1,{max}
+deposit,1,2,10
+deposit,{last},3,1
+withdrawal,1,4,10
+refund,1,4,
+close_account,1,0,
+deposit,1,5,1
+deposit,2,6,x
+deposit,x,7,1
+deposit,2
+"
+        );
+        let read = |mut ledger: Ledger, shards| {
+            let mut rejects = vec![];
+            read_csv_sharded(
+                &mut ledger,
+                input.as_bytes(),
+                AmountScale::default(),
+                shards,
+                |reject: Reject| rejects.push((reject.line, reject.reason)),
+            );
+            let mut output = vec![];
+            ledger.accounts_to_csv(&mut output);
+            (rejects, String::from_utf8(output).unwrap())
+        };
+
+        // No shards is a single one.
+        let (rejects, output) = read(Ledger::default(), 0);
+        assert_eq!(
+            rejects,
+            [
+                (4, "overflow"),
+                (6, "not_refundable"),
+                (8, "account_closed"),
+                (9, "malformed"),
+                (10, "malformed"),
+                (11, "malformed"),
+            ]
+        );
+        assert_eq!(
+            read(Ledger::default(), 3),
+            (rejects.clone(), output.clone())
+        );
+
+        // Closing an account that had funds swept away credits another
+        // client's account, so with a sweep account the ledger reads the
+        // input on a single thread, however many shards it's given.
+        let swept = |shards| {
+            let mut ledger = Ledger::builder().sweep_account(9).shards(shards).build();
+            let mut rejects = vec![];
+            let input = "type,client,tx,amount\ndeposit,1,1,5\nclose_account,1,0,\ndeposit,2,2,1\n";
+            ledger.read_csv_with(
+                input.as_bytes(),
+                AmountScale::default(),
+                |reject: Reject| rejects.push(reject.reason),
+            );
+            assert_eq!(rejects, Vec::<&str>::new());
+            ledger.account(9).unwrap().total().unwrap()
+        };
+        assert_eq!(swept(4), 5.into());
+        assert_eq!(swept(4), swept(1));
+    }
+}
@@ -0,0 +1,529 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    account::Account,
+    latency::{KindLatency, LatencyMonitor},
+    ledger::{BatchOutcome, Ledger},
+    wal::WriteAheadLog,
+    AccountId, Transaction, TransactionError,
+};
+
+// SharedLedger is a handle to a ledger that can be shared between threads
+// and async tasks. Clones are cheap and all refer to the same ledger.
+//
+// Every method holds the lock only while it runs, and never across an
+// `.await`, so it's safe to call from async code. Operations are applied one
+// at a time in the order they acquire the lock, which keeps the guarantees of
+// the single-threaded `Ledger`: transactions on the same account never
+// interleave.
+//
+// Transactions that come from an ordered source, e.g. a connection of the
+// line protocol, are applied with `apply_from`. Transactions of the same
+// source and client are guaranteed to be applied in the order of their
+// sequence numbers, even though transactions of different sources may be
+// interleaved in any order. Ledgers created `with_ordering_checks` assert
+// this at runtime and panic if it's ever violated.
+//
+// With a write-ahead log, see `with_wal`, every transaction is logged before
+// it's applied. Transactions that can't be logged fail with
+// `TransactionError::NotLogged` and aren't applied.
+//
+// Accounts can be paused, e.g. while they're under investigation but their
+// feed can't be stopped upstream. The transactions of a paused account are
+// queued instead of applied, and applied in order once it's resumed, see
+// `pause` and `resume`.
+//
+// Background work, like backfills, applies transactions with
+// `apply_background`, which gives way to every other operation waiting for
+// the lock, so it doesn't add to their latency more than it has to.
+//
+// With a latency budget, see `with_latency_budget`, the time every
+// transaction takes to be logged and applied is tracked by type, and those
+// over the budget are reported, see `LatencyMonitor`.
+#[derive(Clone, Default)]
+pub struct SharedLedger {
+    ledger: Arc<Mutex<Ledger>>,
+    ordering: Option<Arc<Mutex<OrderingCheck>>>,
+    next_source: Arc<AtomicU64>,
+    wal: Option<Arc<Mutex<WriteAheadLog>>>,
+    // The queued transactions of every paused account. Only locked while
+    // the ledger is.
+    paused: Arc<Mutex<HashMap<AccountId, Vec<Transaction>>>>,
+    // The number of operations waiting for the lock, other than background
+    // ones.
+    waiting: Arc<AtomicUsize>,
+    latency: Option<Arc<LatencyMonitor>>,
+}
+
+// Submitted is what happened to a transaction that was accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Submitted {
+    Applied,
+    // The account is paused, the transaction is applied once it's resumed.
+    Queued,
+}
+
+// SourceId identifies an ordered source of transactions, see `apply_from`.
+pub type SourceId = u64;
+
+// OrderingCheck remembers the sequence number of the last transaction
+// applied for every source and client.
+#[derive(Default)]
+struct OrderingCheck {
+    last: HashMap<(SourceId, AccountId), u64>,
+}
+
+impl SharedLedger {
+    pub fn new(ledger: Ledger) -> SharedLedger {
+        SharedLedger {
+            ledger: Arc::new(Mutex::new(ledger)),
+            ..SharedLedger::default()
+        }
+    }
+
+    // Like `new`, but checking that the transactions of every source and
+    // client are applied in order, see above. This costs a lookup per
+    // transaction and memory for every source and client seen.
+    pub fn with_ordering_checks(ledger: Ledger) -> SharedLedger {
+        SharedLedger {
+            ordering: Some(Arc::default()),
+            ..SharedLedger::new(ledger)
+        }
+    }
+
+    // Log every transaction to `wal` before applying it. The log should
+    // have been replayed to the ledger already, see `WriteAheadLog::open`.
+    pub fn with_wal(self, wal: WriteAheadLog) -> SharedLedger {
+        SharedLedger {
+            wal: Some(Arc::new(Mutex::new(wal))),
+            ..self
+        }
+    }
+
+    // Track how long transactions take to apply, and report those that take
+    // longer than `budget`.
+    pub fn with_latency_budget(self, budget: Duration) -> SharedLedger {
+        SharedLedger {
+            latency: Some(Arc::new(LatencyMonitor::new(budget))),
+            ..self
+        }
+    }
+
+    // The latencies of the transactions applied so far by type, if they're
+    // tracked.
+    pub fn latency(&self) -> Option<BTreeMap<&'static str, KindLatency>> {
+        self.latency.as_ref().map(|latency| latency.report())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Ledger> {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        let ledger = self.ledger.lock();
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        ledger.expect("ledger lock poisoned")
+    }
+
+    // Lock the ledger once no other operation is waiting for it. Operations
+    // that start waiting right after may still have to wait for this one.
+    fn lock_background(&self) -> MutexGuard<'_, Ledger> {
+        loop {
+            while self.waiting.load(Ordering::SeqCst) > 0 {
+                thread::yield_now();
+            }
+            let ledger = self.ledger.lock().expect("ledger lock poisoned");
+            if self.waiting.load(Ordering::SeqCst) == 0 {
+                return ledger;
+            }
+        }
+    }
+
+    // Write the transaction to the write-ahead log, if there is one. This
+    // has to happen while the ledger is locked, so that the log has the
+    // transactions in the order they're applied.
+    fn log(&self, client: AccountId, tx: &Transaction) -> Result<(), TransactionError> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let mut wal = wal.lock().expect("write-ahead log lock poisoned");
+        wal.append(client, tx).map_err(|err| {
+            eprintln!("failed to write to the write-ahead log: {}", err);
+            TransactionError::NotLogged
+        })
+    }
+
+    fn lock_paused(&self) -> MutexGuard<'_, HashMap<AccountId, Vec<Transaction>>> {
+        self.paused.lock().expect("paused accounts lock poisoned")
+    }
+
+    // Log the transaction, and apply it to the locked ledger, or queue it if
+    // its account is paused. The time this takes is its latency, which
+    // doesn't include waiting for the lock.
+    fn submit_locked(
+        &self,
+        ledger: &mut Ledger,
+        client: AccountId,
+        tx: Transaction,
+    ) -> Result<Submitted, TransactionError> {
+        let Some(latency) = &self.latency else {
+            return self.submit_untimed(ledger, client, tx);
+        };
+        let start = Instant::now();
+        let kind = tx.kind();
+        let result = self.submit_untimed(ledger, client, tx);
+        latency.record(kind, client, start.elapsed());
+        result
+    }
+
+    fn submit_untimed(
+        &self,
+        ledger: &mut Ledger,
+        client: AccountId,
+        tx: Transaction,
+    ) -> Result<Submitted, TransactionError> {
+        self.log(client, &tx)?;
+        if let Some(queue) = self.lock_paused().get_mut(&client) {
+            queue.push(tx);
+            return Ok(Submitted::Queued);
+        }
+        ledger.apply(client, tx).map(|()| Submitted::Applied)
+    }
+
+    // Register a new ordered source of transactions.
+    pub fn next_source(&self) -> SourceId {
+        self.next_source.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Forget about a source that won't send any more transactions.
+    pub fn close_source(&self, source: SourceId) {
+        if let Some(ordering) = &self.ordering {
+            let mut ordering = ordering.lock().expect("ordering lock poisoned");
+            ordering.last.retain(|&(id, _), _| id != source);
+        }
+    }
+
+    // Apply a transaction from the given source, where `sequence` is its
+    // position in the source. Sequence numbers must increase within a
+    // source, but don't have to be consecutive. Panics if ordering checks
+    // are enabled and a later transaction of the same source and client was
+    // applied already. Transactions of paused accounts are queued, which
+    // counts as applied for the ordering checks.
+    pub fn submit_from(
+        &self,
+        source: SourceId,
+        sequence: u64,
+        client: AccountId,
+        tx: Transaction,
+    ) -> Result<Submitted, TransactionError> {
+        // The ledger stays locked during the check, so the order checked is
+        // the order the transactions are applied in.
+        let mut ledger = self.lock();
+        if let Some(ordering) = &self.ordering {
+            let mut ordering = ordering.lock().expect("ordering lock poisoned");
+            let last = ordering.last.insert((source, client), sequence);
+            assert!(
+                last.is_none_or(|last| last < sequence),
+                "transaction {} of source {} for client {} was applied after transaction {}",
+                sequence,
+                source,
+                client,
+                last.unwrap_or_default(),
+            );
+        }
+        self.submit_locked(&mut ledger, client, tx)
+    }
+
+    // Like `submit_from`, for callers that don't care whether the
+    // transaction was applied or queued.
+    pub fn apply_from(
+        &self,
+        source: SourceId,
+        sequence: u64,
+        client: AccountId,
+        tx: Transaction,
+    ) -> Result<(), TransactionError> {
+        self.submit_from(source, sequence, client, tx).map(drop)
+    }
+
+    // Apply a transaction to the given account, see `Ledger::apply`, or
+    // queue it if the account is paused.
+    pub fn apply(&self, client: AccountId, tx: Transaction) -> Result<(), TransactionError> {
+        self.submit_locked(&mut self.lock(), client, tx).map(drop)
+    }
+
+    // Like `apply`, but at a lower priority than every other operation, see
+    // above.
+    pub fn apply_background(
+        &self,
+        client: AccountId,
+        tx: Transaction,
+    ) -> Result<Submitted, TransactionError> {
+        self.submit_locked(&mut self.lock_background(), client, tx)
+    }
+
+    // Apply a batch of transactions, see `Ledger::apply_batch`. The lock is
+    // held until `acknowledge` returns, so acknowledgments are delivered in
+    // the order the batches were applied. `acknowledge` must not use this
+    // ledger. With a write-ahead log, the transactions from the first one
+    // that can't be logged on are rejected without being applied.
+    // Transactions of paused accounts are queued.
+    pub fn apply_batch<I, F>(&self, batch: I, acknowledge: F) -> BatchOutcome
+    where
+        I: IntoIterator<Item = (AccountId, Transaction)>,
+        F: FnOnce(&BatchOutcome),
+    {
+        let mut ledger = self.lock();
+        let mut outcome = BatchOutcome::default();
+        let mut logged = true;
+        for (index, (client, tx)) in batch.into_iter().enumerate() {
+            let result = match logged {
+                true => self.submit_locked(&mut ledger, client, tx),
+                false => Err(TransactionError::NotLogged),
+            };
+            match result {
+                Ok(Submitted::Applied) => outcome.applied += 1,
+                Ok(Submitted::Queued) => outcome.queued += 1,
+                Err(err) => {
+                    logged = err != TransactionError::NotLogged;
+                    outcome.rejected.push((index, err));
+                }
+            }
+        }
+
+        acknowledge(&outcome);
+        outcome
+    }
+
+    // Pause the given account: its transactions are queued from now on
+    // instead of applied, until it's resumed. Returns false if it was
+    // paused already. The account doesn't have to exist yet.
+    pub fn pause(&self, client: AccountId) -> bool {
+        let _ledger = self.lock();
+        let mut paused = self.lock_paused();
+        if paused.contains_key(&client) {
+            return false;
+        }
+        paused.insert(client, vec![]);
+        true
+    }
+
+    // Resume the given account, applying the transactions queued while it
+    // was paused in the order they were received, before any later
+    // transaction. The outcome has the rejected transactions by their
+    // position in the queue. Returns `None` if the account isn't paused.
+    //
+    // Queued transactions were written to the write-ahead log when they were
+    // received, so they aren't logged again. After a restart the log is
+    // replayed as if the account had never been paused.
+    pub fn resume(&self, client: AccountId) -> Option<BatchOutcome> {
+        let mut ledger = self.lock();
+        let queue = self.lock_paused().remove(&client)?;
+        Some(ledger.apply_batch(queue.into_iter().map(|tx| (client, tx)), |_| {}))
+    }
+
+    // Whether the given account is paused.
+    pub fn is_paused(&self, client: AccountId) -> bool {
+        let _ledger = self.lock();
+        self.lock_paused().contains_key(&client)
+    }
+
+    // A snapshot of the given account. Later transactions don't change it.
+    pub fn account(&self, client: AccountId) -> Option<Account> {
+        self.lock().account(client).cloned()
+    }
+
+    // Run `f` with exclusive access to the ledger, e.g. for queries that
+    // have to see a consistent state across several accounts. Other
+    // operations wait until it returns, so it should be quick and must not
+    // block on anything that could be waiting for this ledger. Changes made
+    // through it aren't written to the write-ahead log.
+    pub fn with<R>(&self, f: impl FnOnce(&mut Ledger) -> R) -> R {
+        f(&mut self.lock())
+    }
+
+    // Take the ledger back, e.g. to write the account summaries once
+    // serving has stopped. Fails with the handle itself if other clones of
+    // it are still alive.
+    pub fn try_into_inner(self) -> Result<Ledger, SharedLedger> {
+        let SharedLedger {
+            ledger,
+            ordering,
+            next_source,
+            wal,
+            paused,
+            waiting,
+            latency,
+        } = self;
+        Arc::try_unwrap(ledger)
+            .map(|ledger| ledger.into_inner().expect("ledger lock poisoned"))
+            .map_err(|ledger| SharedLedger {
+                ledger,
+                ordering,
+                next_source,
+                wal,
+                paused,
+                waiting,
+                latency,
+            })
+    }
+}
+
+impl From<Ledger> for SharedLedger {
+    fn from(ledger: Ledger) -> SharedLedger {
+        SharedLedger::new(ledger)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::SharedLedger;
+    use crate::{Transaction, TransactionError};
+
+    #[test]
+    fn concurrent_deposits() {
+        let ledger = SharedLedger::default();
+
+        let handles = (0..4)
+            .map(|thread| {
+                let ledger = ledger.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let tx = Transaction::Deposit {
+                            new_id: thread * 100 + i,
+                            amount: 1.into(),
+                        };
+                        ledger.apply(1, tx).expect("deposits should succeed");
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let account = ledger.account(1).expect("account should exist");
+        assert_eq!(account.available(), 400.into());
+        assert_eq!(
+            ledger.with(|ledger| ledger.find_transactions(&Default::default()).count()),
+            400
+        );
+
+        let ledger = ledger.try_into_inner().ok().expect("no other handles");
+        assert!(ledger.account(2).is_none());
+    }
+
+    #[test]
+    fn ordering_checks() {
+        let ledger = SharedLedger::with_ordering_checks(Default::default());
+        let (a, b) = (ledger.next_source(), ledger.next_source());
+        let deposit = |new_id| Transaction::Deposit {
+            new_id,
+            amount: 1.into(),
+        };
+
+        // Sources are independent of each other, and so are clients.
+        ledger.apply_from(a, 1, 1, deposit(1)).unwrap();
+        ledger.apply_from(b, 1, 1, deposit(2)).unwrap();
+        ledger.apply_from(a, 3, 1, deposit(3)).unwrap();
+        ledger.apply_from(a, 2, 2, deposit(4)).unwrap();
+
+        // A closed source starts over.
+        ledger.close_source(a);
+        ledger.apply_from(a, 1, 1, deposit(5)).unwrap();
+
+        let checked = ledger.clone();
+        let result = thread::spawn(move || checked.apply_from(b, 1, 1, deposit(6))).join();
+        assert!(result.is_err(), "out of order transaction should panic");
+    }
+
+    #[test]
+    fn paused_batches() {
+        let ledger = SharedLedger::default();
+        let deposit = |new_id| Transaction::Deposit {
+            new_id,
+            amount: 1.into(),
+        };
+        assert!(ledger.pause(2));
+
+        let withdrawal = Transaction::Withdrawal {
+            new_id: 3,
+            amount: 2.into(),
+        };
+        let outcome = ledger.apply_batch(
+            vec![(1, deposit(1)), (2, deposit(2)), (2, withdrawal)],
+            |outcome| assert_eq!(outcome.queued, 2),
+        );
+        assert_eq!(outcome.applied, 1);
+        assert!(ledger.account(2).is_none());
+
+        // The withdrawal is only rejected once the queue is applied.
+        let outcome = ledger.resume(2).expect("account should be paused");
+        assert_eq!(outcome.applied, 1);
+        assert_eq!(outcome.rejected, [(1, TransactionError::InsufficientFunds)]);
+        assert_eq!(ledger.account(2).unwrap().total().unwrap(), 1.into());
+        assert_eq!(ledger.resume(2), None);
+    }
+
+    #[test]
+    fn edge_cases() {
+        let ledger = SharedLedger::default();
+        let max = crate::funds::max();
+        let deposit = |new_id, amount| Transaction::Deposit { new_id, amount };
+        let withdrawal = |new_id, amount| Transaction::Withdrawal { new_id, amount };
+
+        // While the account is paused, the queue takes anything, and the
+        // transactions are only rejected once it's applied: a deposit beyond
+        // what funds can hold, one into the account closed before it, and a
+        // refund of a withdrawal.
+        assert!(ledger.pause(1));
+        let queue = [
+            deposit(1, max),
+            deposit(2, max),
+            withdrawal(3, max),
+            Transaction::Refund {
+                id: 3,
+                amount: None,
+            },
+            Transaction::CloseAccount,
+            deposit(4, 1.into()),
+        ];
+        let outcome = ledger.apply_batch(queue.map(|tx| (1, tx)), |_| {});
+        assert_eq!((outcome.queued, outcome.rejected.len()), (6, 0));
+        let outcome = ledger.resume(1).expect("account should be paused");
+        assert_eq!(outcome.applied, 3);
+        assert_eq!(
+            outcome.rejected,
+            [
+                (1, TransactionError::Overflow),
+                (3, TransactionError::NotRefundable),
+                (5, TransactionError::AccountClosed),
+            ]
+        );
+        assert!(ledger.account(1).unwrap().is_closed());
+
+        // An account that doesn't exist can be paused, and resuming it with
+        // nothing queued applies nothing.
+        assert!(ledger.pause(2));
+        assert!(ledger.is_paused(2));
+        assert_eq!(ledger.resume(2).map(|outcome| outcome.applied), Some(0));
+        assert!(ledger.account(2).is_none());
+        assert_eq!(
+            ledger.apply(2, withdrawal(5, 1.into())),
+            Err(TransactionError::InsufficientFunds)
+        );
+
+        // The ledger can't be taken back while another handle is alive.
+        let other = ledger.clone();
+        let ledger = ledger.try_into_inner().err().expect("another handle");
+        drop(other);
+        assert!(ledger.try_into_inner().is_ok());
+    }
+}
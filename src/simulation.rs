@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{
+    client_names::ClientKey,
+    ledger::{AccountOrder, Ledger, Precision},
+    policy::Policy,
+    Balance,
+};
+
+// A Simulation replays the same history under two policies side by side: the
+// actual one, and a modified one whose effect is being evaluated. Neither
+// ledger has observers, so nothing outside the simulation sees the replay.
+pub struct Simulation {
+    actual: Ledger,
+    simulated: Ledger,
+}
+
+impl Simulation {
+    pub fn new(actual: Policy, simulated: Policy) -> Simulation {
+        Simulation {
+            actual: Ledger::with_policy(actual),
+            simulated: Ledger::with_policy(simulated),
+        }
+    }
+
+    // Open accounts in both ledgers, see `Ledger::read_opening_balances`.
+    pub fn read_opening_balances(&mut self, contents: &[u8]) {
+        self.actual.read_opening_balances(contents);
+        self.simulated.read_opening_balances(contents);
+    }
+
+    // Replay the transactions in both ledgers, see `Ledger::read_csv`.
+    pub fn read_csv(&mut self, contents: &[u8]) {
+        self.actual.read_csv(contents);
+        self.simulated.read_csv(contents);
+    }
+
+    // Write the differences between the actual and simulated totals of every
+    // account formatted as CSV to the given writer, sorted by client ID. The
+    // last row has no client and holds the aggregate over all accounts.
+    // Accounts that only exist in one of the ledgers count as having a total
+    // of zero in the other. Sums and differences too large for a balance are
+    // capped.
+    pub fn differences_to_csv<W: std::io::Write>(&self, output: W) -> csv::Result<()> {
+        #[derive(Serialize)]
+        struct DifferenceRecord {
+            client: Option<ClientKey>,
+            actual: Balance,
+            simulated: Balance,
+            difference: Balance,
+        }
+
+        // Missing totals are written like the others.
+        let zero = Precision::default().apply(Balance::ZERO);
+        let mut totals = BTreeMap::<ClientKey, (Balance, Balance)>::new();
+        for record in self
+            .actual
+            .account_summaries(AccountOrder::Client, Precision::default())
+        {
+            totals.entry(record.client).or_insert((zero, zero)).0 = record.total;
+        }
+        for record in self
+            .simulated
+            .account_summaries(AccountOrder::Client, Precision::default())
+        {
+            totals.entry(record.client).or_insert((zero, zero)).1 = record.total;
+        }
+
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(true)
+            .from_writer(output);
+
+        let (mut actual_sum, mut simulated_sum) = (Balance::ZERO, Balance::ZERO);
+        for (client, (actual, simulated)) in totals {
+            actual_sum = actual_sum.saturating_add(actual);
+            simulated_sum = simulated_sum.saturating_add(simulated);
+            writer.serialize(DifferenceRecord {
+                client: Some(client),
+                actual,
+                simulated,
+                difference: simulated.saturating_sub(actual),
+            })?;
+        }
+        writer.serialize(DifferenceRecord {
+            client: None,
+            actual: actual_sum,
+            simulated: simulated_sum,
+            difference: simulated_sum.saturating_sub(actual_sum),
+        })?;
+
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Simulation;
+    use crate::policy::{Policy, ZeroAmountPolicy};
+
+    #[test]
+    fn differences() {
+        let simulated = Policy {
+            zero_amounts: ZeroAmountPolicy::Reject,
+            ..Policy::default()
+        };
+        let mut simulation = Simulation::new(Policy::default(), simulated);
+        simulation.read_opening_balances(
+            "\
+client,available,held,locked
+3,1,0,false
+"
+            .as_bytes(),
+        );
+
+        // Rejecting the zero deposit makes the later chargeback of it fail,
+        // so the account isn't frozen and the second deposit goes through.
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,2,2,0
+dispute,2,2,
+chargeback,2,2,
+deposit,2,3,5
+";
+        simulation.read_csv(input.as_bytes());
+
+        let mut output = vec![];
+        simulation.differences_to_csv(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+client,actual,simulated,difference
+1,10.0000,10.0000,0.0000
+2,0.0000,5.0000,5.0000
+3,1.0000,1.0000,0.0000
+,11.0000,16.0000,5.0000
+"
+        );
+    }
+
+    #[test]
+    fn edge_cases() {
+        let simulated = Policy {
+            sweep_account: Some(9),
+            ..Policy::default()
+        };
+        let mut simulation = Simulation::new(Policy::default(), simulated);
+
+        // Clients 1 and 2 have more between them than a balance can hold.
+        // Client 3's account can only be closed with a sweep account, after
+        // which the deposit into it fails, and client 4's refund of a
+        // withdrawal fails either way.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,2,2,{max}
+deposit,3,3,5
+close_account,3,0,
+deposit,3,4,1
+deposit,4,5,10
+withdrawal,4,6,4
+refund,4,6,
+"
+        );
+        simulation.read_csv(input.as_bytes());
+
+        let mut output = vec![];
+        simulation.differences_to_csv(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[3..6],
+            [
+                "3,6.0000,0.0000,-6.0000",
+                "4,6.0000,6.0000,0.0000",
+                "9,0.0000,5.0000,5.0000",
+            ]
+        );
+        // The sums are capped rather than overflowing.
+        assert!(lines[6].starts_with(','));
+    }
+}
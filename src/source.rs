@@ -0,0 +1,410 @@
+use std::{collections::VecDeque, future::Future};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+use crate::{
+    client_names::ClientKey,
+    error_sink::{ErrorSink, Stderr},
+    ledger::{
+        csv_line, offset_position, parse_csv_line, CsvHeaders, Ledger, Position, ProcessingError,
+        ProcessingErrorKind, Reject,
+    },
+    policy::{AmountPolicy, ErrorPolicy},
+    reorder::Reorder,
+    scaling::{AmountScale, ScaleCheck},
+    AccountId, Transaction,
+};
+
+// TransactionSource is where the transactions of an async service come
+// from, e.g. a socket, a file in object storage, or a queue. Waiting for the
+// next transaction only suspends the task reading them, so many sources can
+// be read on a few runtime threads.
+pub trait TransactionSource {
+    // The next transaction, the reason the next record of the source has to
+    // be skipped, or `None` once the source is exhausted.
+    fn next_transaction(
+        &mut self,
+    ) -> impl Future<Output = Option<Result<SourcedTransaction, Reject>>> + Send;
+}
+
+// SourcedTransaction is a transaction read from a source, along with where
+// it was read, to report it if it can't be applied: the position it was
+// read at, and the record as it was read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourcedTransaction {
+    pub position: Position,
+    pub record: String,
+    pub client: AccountId,
+    pub transaction: Transaction,
+}
+
+// The number of bytes read before the lines read are parsed, so the CSV
+// reader isn't set up for every line.
+const CHUNK_LEN: usize = 64 * 1024;
+
+// CsvSource reads transactions from CSV like `Ledger::read_csv_with`, but
+// from an async reader. Records are split at the end of every line, so
+// unlike the sync readers, quoted fields can't span lines.
+pub struct CsvSource<R> {
+    reader: BufReader<R>,
+    scale: AmountScale,
+    amounts: AmountPolicy,
+    check: ScaleCheck,
+    headers: Option<CsvHeaders>,
+    // The lines read and parsed, but not returned yet.
+    parsed: VecDeque<csv::Result<csv::StringRecord>>,
+    // Where the chunk of the lines in `parsed` starts.
+    start: csv::Position,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin + Send> CsvSource<R> {
+    pub fn new(reader: R, scale: AmountScale) -> CsvSource<R> {
+        CsvSource {
+            reader: BufReader::new(reader),
+            scale,
+            amounts: AmountPolicy::default(),
+            check: ScaleCheck::new(scale),
+            headers: None,
+            parsed: VecDeque::new(),
+            start: csv::Position::new(),
+            done: false,
+        }
+    }
+
+    // Accept the amounts the given policy allows instead of the default
+    // ones, e.g. those of the ledger the source is applied to.
+    pub fn with_amounts(mut self, amounts: AmountPolicy) -> CsvSource<R> {
+        self.amounts = amounts;
+        self
+    }
+
+    // Whether the amounts read so far look like they have a different scale,
+    // see `ScaleCheck`.
+    pub fn check(&self) -> &ScaleCheck {
+        &self.check
+    }
+
+    // Read the next chunk of whole lines and parse them. If reading fails,
+    // the lines read before are parsed, and the error comes after them.
+    async fn read_chunk(&mut self) {
+        let mut chunk = vec![];
+        let mut failed = None;
+        while chunk.len() < CHUNK_LEN {
+            match self.reader.read_until(b'\n', &mut chunk).await {
+                Ok(0) => {
+                    self.done = true;
+                    break;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    // The rest of the line may be missing, so it's dropped.
+                    let whole = chunk.iter().rposition(|&byte| byte == b'\n');
+                    chunk.truncate(whole.map_or(0, |end| end + 1));
+                    self.done = true;
+                    failed = Some(err);
+                    break;
+                }
+            }
+        }
+
+        let start = self.start.clone();
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .trim(csv::Trim::None)
+            .from_reader(chunk.as_slice());
+        let mut records = 0;
+        self.parsed.extend(reader.records().map(|record| {
+            records += 1;
+            // Count positions from the start of the input, not the chunk.
+            record.map(|mut record| {
+                offset_position(&mut record, &start);
+                record
+            })
+        }));
+        let lines = chunk.iter().filter(|&&byte| byte == b'\n').count() as u64;
+        self.start
+            .set_byte(start.byte() + chunk.len() as u64)
+            .set_line(start.line() + lines)
+            .set_record(start.record() + records);
+        self.parsed.extend(failed.map(|err| Err(err.into())));
+    }
+
+    // The next line read, if any.
+    async fn next_line(&mut self) -> Option<csv::Result<csv::StringRecord>> {
+        while self.parsed.is_empty() && !self.done {
+            self.read_chunk().await;
+        }
+        self.parsed.pop_front()
+    }
+}
+
+impl<R: AsyncRead + Unpin + Send> TransactionSource for CsvSource<R> {
+    async fn next_transaction(&mut self) -> Option<Result<SourcedTransaction, Reject>> {
+        let headers = match self.headers.take() {
+            Some(headers) => headers,
+            None => match self.next_line().await? {
+                Ok(mut headers) => {
+                    headers.trim();
+                    CsvHeaders::new(headers)
+                }
+                Err(err) => {
+                    self.done = true;
+                    self.parsed.clear();
+                    let position = Position { line: 1, byte: 0 };
+                    let message = format!("invalid header: {}", err);
+                    return Some(Err(ProcessingError::malformed(position, message).into()));
+                }
+            },
+        };
+        let line = self.next_line().await;
+        let parsed = line.map(|line| {
+            parse_csv_line(line, &headers, self.scale, self.amounts, &mut self.check).map(
+                |parsed| SourcedTransaction {
+                    position: parsed
+                        .fields
+                        .position()
+                        .map(Position::from)
+                        .unwrap_or_default(),
+                    record: csv_line(&parsed.fields),
+                    client: parsed.account,
+                    transaction: parsed.transaction,
+                },
+            )
+        });
+        self.headers = Some(headers);
+        parsed
+    }
+}
+
+impl Ledger {
+    // Like `from_csv_reader`, but read the transactions from an async
+    // reader.
+    pub async fn from_async_reader<R: AsyncRead + Unpin + Send>(reader: R) -> Ledger {
+        let mut ledger = Ledger::default();
+        let mut source = CsvSource::new(reader, AmountScale::default());
+        ledger.apply_source(&mut source, Stderr).await;
+        if let Some(warning) = source.check().warning() {
+            Stderr.on_warning(warning);
+        }
+        ledger
+    }
+
+    // Apply every transaction read from the given source to this ledger,
+    // passing every record that's skipped to `sink`, like `read_csv_with`.
+    // Transactions are applied as they're read, so the ledger can't be used
+    // elsewhere until the source is exhausted; services that keep serving
+    // meanwhile can feed a `SharedLedger` from the source instead. With a
+    // reorder window, see `Policy::reorder_window`, transactions are held
+    // back to be put in order first.
+    pub async fn apply_source<T, S>(&mut self, source: &mut T, mut sink: S)
+    where
+        T: TransactionSource,
+        S: ErrorSink,
+    {
+        let mut reorder = self.policy().reorder_window.map(Reorder::new);
+        while let Some(next) = source.next_transaction().await {
+            let released = match (next, &mut reorder) {
+                (Ok(sourced), Some(reorder)) => {
+                    let time = sourced.transaction.timestamp();
+                    reorder.push(time, sourced).into_iter().map(Ok).collect()
+                }
+                (next, _) => vec![next],
+            };
+            for next in released {
+                if !self.apply_sourced(next, &mut sink) {
+                    return;
+                }
+            }
+        }
+        for sourced in reorder.map_or(vec![], |mut reorder| reorder.finish()) {
+            if !self.apply_sourced(Ok(sourced), &mut sink) {
+                return;
+            }
+        }
+    }
+
+    // Apply a transaction of `apply_source`, or handle the record skipped
+    // instead, returning whether to go on.
+    fn apply_sourced<S: ErrorSink>(
+        &mut self,
+        next: Result<SourcedTransaction, Reject>,
+        sink: &mut S,
+    ) -> bool {
+        let result = next.and_then(|sourced| {
+            let transaction = sourced.transaction.kind();
+            self.apply(sourced.client, sourced.transaction)
+                .map_err(|error| {
+                    ProcessingError {
+                        position: sourced.position,
+                        record: sourced.record,
+                        kind: ProcessingErrorKind::Transaction {
+                            client: ClientKey::Id(sourced.client),
+                            transaction,
+                            error,
+                        },
+                    }
+                    .into()
+                })
+        });
+        match (result, self.policy().errors) {
+            (Ok(()), _) => {}
+            (Err(reject), ErrorPolicy::Collect) => self.report.rejects.push(reject),
+            (Err(reject), ErrorPolicy::Skip) => sink.on_reject(reject),
+            (Err(reject), ErrorPolicy::Abort) => {
+                sink.on_reject(reject);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::io::{AsyncRead, ReadBuf};
+
+    use super::CsvSource;
+    use crate::{ledger::Reject, policy::ErrorPolicy, scaling::AmountScale, Ledger};
+
+    // A reader that reads `data`, and then fails, like a dropped connection.
+    struct Failing(&'static [u8]);
+
+    impl AsyncRead for Failing {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if self.0.is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            let len = self.0.len().min(buf.remaining());
+            buf.put_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn same_as_sync() {
+        let mut input = "type, client, tx, amount\n".to_owned();
+        for tx in 0..10_000u32 {
+            let client = tx % 5;
+            input += &match tx % 4 {
+                0 | 1 => format!("deposit, {}, {}, {}.75\n", client, tx, tx % 9),
+                2 => format!("withdrawal,{},{},5\n", client, tx),
+                _ if tx % 1000 == 3 => "deposit,1,x,1\n".to_owned(),
+                _ => format!("dispute,{},{},\n", client, tx - 3),
+            };
+        }
+
+        let mut expected = vec![];
+        let sync =
+            Ledger::from_csv_reader_with(input.as_bytes(), |reject: Reject| expected.push(reject));
+
+        let mut ledger = Ledger::default();
+        let mut rejects = vec![];
+        let mut source = CsvSource::new(input.as_bytes(), AmountScale::default());
+        ledger
+            .apply_source(&mut source, |reject: Reject| rejects.push(reject))
+            .await;
+
+        assert!(rejects.iter().any(|reject| reject.reason == "malformed"));
+        assert_eq!(rejects, expected);
+        let mut output = vec![];
+        let mut expected = vec![];
+        ledger.accounts_to_csv(&mut output);
+        sync.accounts_to_csv(&mut expected);
+        assert_eq!(String::from_utf8(output), String::from_utf8(expected));
+    }
+
+    #[tokio::test]
+    async fn edge_cases() {
+        async fn read<R: AsyncRead + Unpin + Send>(
+            ledger: &mut Ledger,
+            input: R,
+        ) -> Vec<(u64, &'static str, String)> {
+            let mut rejects = vec![];
+            let mut source = CsvSource::new(input, AmountScale::default());
+            ledger
+                .apply_source(&mut source, |reject: Reject| {
+                    rejects.push((reject.line, reject.reason, reject.record))
+                })
+                .await;
+            rejects
+        }
+
+        // A deposit too large for funds to hold, a refund of a withdrawal,
+        // a deposit into a closed account, and malformed lines are rejected
+        // like the sync readers reject them, quoted fields and all.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,1
+deposit,2,3,5
+withdrawal,2,4,5
+refund,2,4,
+close_account,2,0,
+deposit,2,5,1
+deposit,3,6,x
+\"deposit,3\",7,1
+"
+        );
+        let mut expected = vec![];
+        let sync = Ledger::from_csv_reader_with(input.as_bytes(), |reject: Reject| {
+            expected.push((reject.line, reject.reason, reject.record))
+        });
+        let mut ledger = Ledger::default();
+        let rejects = read(&mut ledger, input.as_bytes()).await;
+        assert_eq!(rejects, expected);
+        assert_eq!(
+            rejects.iter().map(|reject| reject.1).collect::<Vec<_>>(),
+            [
+                "overflow",
+                "not_refundable",
+                "account_closed",
+                "malformed",
+                "malformed"
+            ]
+        );
+        assert_eq!(rejects[4].2, "\"deposit,3\",7,1");
+        assert!(ledger.account(2).unwrap().is_closed());
+        assert_eq!(
+            ledger.account(1).unwrap().total(),
+            sync.account(1).unwrap().total()
+        );
+
+        // Aborting stops at the first reject.
+        let mut ledger = Ledger::builder().errors(ErrorPolicy::Abort).build();
+        let rejects = read(&mut ledger, input.as_bytes()).await;
+        assert_eq!(rejects.len(), 1);
+        assert!(ledger.account(2).is_none());
+
+        // Nothing is read from an empty source, or past a header that can't
+        // be read. A source that fails is rejected where it fails, after the
+        // lines read before, but without the line it failed in the middle
+        // of, which may be cut short.
+        let mut ledger = Ledger::default();
+        assert_eq!(read(&mut ledger, &b""[..]).await, []);
+        let rejects = read(&mut ledger, &b"type,\xff\ndeposit,1,1,1\n"[..]).await;
+        assert_eq!(rejects, [(1, "malformed", String::new())]);
+        let rejects = read(
+            &mut ledger,
+            Failing(b"type,client,tx,amount\ndeposit,1,1,1\ndeposit,1,2,10"),
+        )
+        .await;
+        assert_eq!(rejects.len(), 1);
+        assert_eq!(rejects[0].1, "malformed");
+        assert_eq!(ledger.account(1).unwrap().total().unwrap(), 1.into());
+    }
+}
@@ -0,0 +1,284 @@
+use std::path::Path;
+
+use rusqlite::{
+    params,
+    types::{ToSql, ToSqlOutput},
+    Connection, Result,
+};
+
+use crate::{
+    client_names::ClientKey,
+    ledger::{AccountOrder, Ledger, ProcessedTransactionState},
+};
+
+// Balances and amounts are stored as TEXT to keep them exact, SQLite would
+// round them to floating point otherwise. They can still be used in
+// arithmetic, e.g. `SELECT sum(CAST(total AS REAL)) FROM accounts`. The
+// currency is NULL for balances and transactions without one. Clients known
+// by name are stored as their name, which SQLite keeps as TEXT in the
+// INTEGER column. With the `wide-ids` feature, the bits of IDs too large for
+// SQLite's signed integers are stored as they are, like in the Parquet
+// output, which makes them negative.
+const SCHEMA: &str = "
+CREATE TABLE accounts (
+    client INTEGER NOT NULL,
+    currency TEXT,
+    available TEXT NOT NULL,
+    held TEXT NOT NULL,
+    total TEXT NOT NULL,
+    locked INTEGER NOT NULL,
+    closed INTEGER NOT NULL,
+    UNIQUE (client, currency)
+);
+CREATE TABLE processed_transactions (
+    client INTEGER NOT NULL,
+    tx INTEGER NOT NULL,
+    amount TEXT NOT NULL,
+    state TEXT NOT NULL,
+    currency TEXT,
+    PRIMARY KEY (client, tx)
+);
+";
+
+#[cfg(not(feature = "wide-ids"))]
+fn id_value(id: impl Into<i64>) -> i64 {
+    id.into()
+}
+
+#[cfg(feature = "wide-ids")]
+fn id_value(id: u64) -> i64 {
+    id as i64
+}
+
+impl ToSql for ClientKey {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        match self {
+            ClientKey::Id(id) => Ok(ToSqlOutput::from(id_value(*id))),
+            ClientKey::Name(name) => name.to_sql(),
+        }
+    }
+}
+
+fn state_name(state: ProcessedTransactionState) -> &'static str {
+    match state {
+        ProcessedTransactionState::Settled => "settled",
+        ProcessedTransactionState::Disputed => "disputed",
+        ProcessedTransactionState::ChargeBacked => "chargebacked",
+        ProcessedTransactionState::Adjusted => "adjusted",
+        ProcessedTransactionState::Refunded => "refunded",
+        ProcessedTransactionState::Authorized => "authorized",
+        ProcessedTransactionState::Voided => "voided",
+    }
+}
+
+impl Ledger {
+    // Export the final state of this ledger to a SQLite database at `path`:
+    // the account summaries to the `accounts` table, with the same columns
+    // as the CSV output and whether the account is closed, and every processed transaction to the
+    // `processed_transactions` table. The database is created if it doesn't
+    // exist, and it must not contain these tables yet. Everything is written
+    // in a single SQL transaction, so a failed export leaves no partial
+    // tables behind, including one that fails to read the transaction store.
+    pub fn export_sqlite(&self, path: &Path) -> Result<()> {
+        let mut connection = Connection::open(path)?;
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(SCHEMA)?;
+
+        {
+            let mut insert = transaction.prepare(
+                "INSERT INTO accounts (client, currency, available, held, total, locked, closed)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for record in self.account_summaries(AccountOrder::Client, self.precision()) {
+                insert.execute(params![
+                    record.client,
+                    record
+                        .currency
+                        .flatten()
+                        .map(|currency| currency.to_string()),
+                    record.available.to_string(),
+                    record.held.to_string(),
+                    record.total.to_string(),
+                    record.locked,
+                    record.closed.unwrap_or_default(),
+                ])?;
+            }
+
+            let mut insert = transaction.prepare(
+                "INSERT INTO processed_transactions (client, tx, amount, state, currency)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for found in self.find_transactions(&Default::default()) {
+                let (client, id, tx) =
+                    found.map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+                insert.execute(params![
+                    self.client_key(client),
+                    id_value(id),
+                    tx.amount.to_string(),
+                    state_name(tx.state),
+                    tx.currency.map(|currency| currency.to_string()),
+                ])?;
+            }
+        }
+
+        transaction.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rusqlite::Connection;
+
+    use crate::{
+        ledger::{Ledger, Precision},
+        AccountId, TransactionId,
+    };
+
+    #[test]
+    fn export_sqlite() {
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.5
+withdrawal,1,2,4
+dispute,1,2,
+deposit,2,3,15
+dispute,2,3,
+chargeback,2,3,
+";
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+
+        let path = std::env::temp_dir().join(format!("ledger-{}.sqlite", std::process::id()));
+        let _ = fs::remove_file(&path);
+        ledger.export_sqlite(&path).expect("should export");
+
+        let connection = Connection::open(&path).unwrap();
+        let accounts = connection
+            .prepare("SELECT client, available, held, total, locked FROM accounts ORDER BY client")
+            .unwrap()
+            .query_map([], |row| {
+                Ok(format!(
+                    "{} {} {} {} {}",
+                    row.get::<_, u16>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, bool>(4)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            accounts,
+            vec![
+                "1 2.5000 4.0000 6.5000 false",
+                "2 0.0000 0.0000 0.0000 true",
+            ]
+        );
+
+        let transactions = connection
+            .prepare("SELECT client, tx, amount, state FROM processed_transactions ORDER BY tx")
+            .unwrap()
+            .query_map([], |row| {
+                Ok(format!(
+                    "{} {} {} {}",
+                    row.get::<_, u16>(0)?,
+                    row.get::<_, u32>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            transactions,
+            vec!["1 1 10.5 settled", "1 2 4 disputed", "2 3 15 chargebacked"]
+        );
+
+        // Exporting over an earlier export fails instead of mixing them.
+        assert!(ledger.export_sqlite(&path).is_err());
+
+        drop(connection);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn edge_cases() {
+        let path = std::env::temp_dir().join(format!("ledger-edge-{}.sqlite", std::process::id()));
+        let _ = fs::remove_file(&path);
+        // The largest IDs and balances are exported, and so is whether
+        // accounts are closed. The deposit that overflowed, the refund of a
+        // withdrawal, the deposit into the closed account, and the malformed
+        // line aren't.
+        let (client, tx) = (AccountId::MAX, TransactionId::MAX);
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,{client},{tx},{max}
+deposit,{client},1,1
+deposit,2,2,10
+withdrawal,2,3,10
+refund,2,3,
+close_account,2,0,
+deposit,2,4,1
+deposit,3,5,x
+"
+        );
+        let ledger = Ledger::from_csv_reader(input.as_bytes());
+        ledger.export_sqlite(&path).expect("should export");
+
+        let connection = Connection::open(&path).unwrap();
+        let accounts = connection
+            .prepare("SELECT client, total, closed FROM accounts ORDER BY total")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, bool>(2)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let written = Precision::default().apply(max).to_string();
+        assert_eq!(
+            accounts,
+            [
+                (2, "0.0000".to_owned(), true),
+                (client as i64, written, false),
+            ]
+        );
+
+        let transactions = connection
+            .prepare(
+                "SELECT client, tx, state FROM processed_transactions ORDER BY client != 2, tx",
+            )
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            transactions,
+            [
+                (2, 2, "settled".to_owned()),
+                (2, 3, "settled".to_owned()),
+                (client as i64, tx as i64, "settled".to_owned()),
+            ]
+        );
+
+        drop(connection);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,374 @@
+use std::io::{self, Write};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    client_names::ClientKey,
+    ledger::{write_aligned, Ledger, OutputFormat, StatementEntry},
+    AccountId, Balance, Timestamp, Transaction, TransactionAmount, TransactionId,
+};
+
+// Statement is the data a client's statement is rendered from, taken from
+// the ledger's own record of the account so that statements always agree
+// with the balances. It's serializable, so it can be fed to a templating
+// layer as it is, e.g. as JSON; `render_text` is a plain text rendering of
+// it.
+//
+// A statement covers the period of the ledger's run: it starts from the
+// opening balance the account was carried over with, or from zero, and ends
+// with the account's current balances.
+//
+// With a filter, see `LedgerBuilder::filter`, only the lines that match it
+// are kept. The opening and closing balances are always those of the whole
+// period.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Statement {
+    pub client: ClientKey,
+    pub opening: Balances,
+    pub lines: Vec<StatementLine>,
+    pub closing: Balances,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Balances {
+    pub available: Balance,
+    pub held: Balance,
+    pub total: Balance,
+    pub locked: bool,
+}
+
+// StatementLine is a transaction applied to the account, in the order they
+// were applied, with the account's balances after it. `amount` is that of
+// the transaction, or for disputes, resolutions, and chargebacks, of the
+// transaction they refer to, and `timestamp` its time, if it had one.
+// Deposits and withdrawals that were disputed later in the period are
+// annotated with the outcome of the dispute.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StatementLine {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub tx: Option<TransactionId>,
+    pub amount: Option<TransactionAmount>,
+    pub timestamp: Option<Timestamp>,
+    pub dispute: Option<DisputeStatus>,
+    #[serde(flatten)]
+    pub balances: Balances,
+}
+
+// DisputeStatus is where the last dispute of a transaction stands at the end
+// of the period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeStatus {
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl DisputeStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            DisputeStatus::Disputed => "disputed",
+            DisputeStatus::Resolved => "resolved",
+            DisputeStatus::ChargedBack => "charged back",
+        }
+    }
+}
+
+// A row of the text rendering. Optional cells are left empty.
+fn text_row(
+    kind: &str,
+    tx: Option<TransactionId>,
+    amount: Option<TransactionAmount>,
+    dispute: Option<DisputeStatus>,
+    balances: &Balances,
+) -> Vec<String> {
+    vec![
+        kind.to_owned(),
+        tx.map(|tx| tx.to_string()).unwrap_or_default(),
+        amount.map(|amount| amount.to_string()).unwrap_or_default(),
+        balances.available.to_string(),
+        balances.held.to_string(),
+        balances.total.to_string(),
+        if balances.locked { "locked" } else { "" }.to_owned(),
+        dispute.map_or("", |dispute| dispute.name()).to_owned(),
+    ]
+}
+
+fn balances(entry: &StatementEntry) -> Balances {
+    Balances {
+        available: entry.available,
+        held: entry.held,
+        total: entry.total,
+        locked: entry.locked,
+    }
+}
+
+impl Statement {
+    // The statement of the given client, or `None` if it has no account or
+    // the ledger doesn't keep statements, see `LedgerBuilder::keep_statements`.
+    pub fn new(ledger: &Ledger, client: AccountId) -> Option<Statement> {
+        let entries = ledger.statement_for(client)?;
+        let (opening, entries) = match entries.split_first() {
+            Some((first, rest)) if first.transaction.is_none() => (balances(first), rest),
+            _ => (
+                Balances {
+                    available: Balance::ZERO,
+                    held: Balance::ZERO,
+                    total: Balance::ZERO,
+                    locked: false,
+                },
+                entries,
+            ),
+        };
+
+        let mut lines = entries
+            .iter()
+            .filter_map(|entry| {
+                let transaction = entry.transaction.as_ref()?;
+                Some(StatementLine {
+                    kind: transaction.kind(),
+                    tx: transaction.id(),
+                    amount: entry.amount,
+                    timestamp: entry.timestamp,
+                    dispute: None,
+                    balances: balances(entry),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Annotate every deposit and withdrawal with the last dispute-related
+        // transaction referring to it.
+        for (index, entry) in entries.iter().enumerate() {
+            let (id, status) = match entry.transaction {
+                Some(Transaction::Dispute { id, .. }) => (id, DisputeStatus::Disputed),
+                Some(Transaction::Resolve { id }) => (id, DisputeStatus::Resolved),
+                Some(Transaction::Chargeback { id }) => (id, DisputeStatus::ChargedBack),
+                _ => continue,
+            };
+            let disputed = lines[..index]
+                .iter_mut()
+                .rev()
+                .find(|line| matches!(line.kind, "deposit" | "withdrawal") && line.tx == Some(id));
+            if let Some(disputed) = disputed {
+                disputed.dispute = Some(status);
+            }
+        }
+
+        let closing = lines.last().map_or(opening, |line| line.balances);
+        lines.retain(|line| ledger.includes(line));
+        Some(Statement {
+            client: ledger.client_key(client),
+            opening,
+            lines,
+            closing,
+        })
+    }
+
+    // Render the statement as plain text: a title, and a table of the
+    // transactions between the opening and closing balances. The times of
+    // the transactions are only shown if some of them have one.
+    pub fn render_text<W: Write>(&self, mut output: W) -> io::Result<()> {
+        writeln!(output, "Statement for client {}", self.client)?;
+        writeln!(output)?;
+
+        let mut rows = vec![text_row("opening", None, None, None, &self.opening)];
+        for line in &self.lines {
+            rows.push(text_row(
+                line.kind,
+                line.tx,
+                line.amount,
+                line.dispute,
+                &line.balances,
+            ));
+        }
+        rows.push(text_row("closing", None, None, None, &self.closing));
+
+        let mut header = vec![
+            "type",
+            "tx",
+            "amount",
+            "available",
+            "held",
+            "total",
+            "locked",
+            "dispute",
+        ];
+        if self.lines.iter().any(|line| line.timestamp.is_some()) {
+            header.push("timestamp");
+            for (row, line) in rows.iter_mut().skip(1).zip(&self.lines) {
+                let time = line.timestamp.map(|time| time.to_string());
+                row.push(time.unwrap_or_default());
+            }
+        }
+        write_aligned(&header, &rows, &mut output)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum StatementError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("failed to write statement: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("failed to write statement: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("client {0} has no account")]
+    NoAccount(AccountId),
+    #[error("statements can't be written as {0:?}")]
+    UnsupportedFormat(OutputFormat),
+}
+
+// Write the statement of the given client in the given format: `Csv` is
+// the format of `Ledger::statement_to_csv`, `Json` the statement data, and
+// `Table` the text rendering.
+pub fn write_statement<W: Write>(
+    ledger: &Ledger,
+    client: AccountId,
+    format: OutputFormat,
+    output: W,
+) -> Result<(), StatementError> {
+    match format {
+        OutputFormat::Csv => return Ok(ledger.statement_to_csv(client, output)?),
+        OutputFormat::Json | OutputFormat::Table => {}
+        _ => return Err(StatementError::UnsupportedFormat(format)),
+    }
+
+    let statement = Statement::new(ledger, client).ok_or(StatementError::NoAccount(client))?;
+    match format {
+        OutputFormat::Json => {
+            let mut output = output;
+            serde_json::to_writer(&mut output, &statement)?;
+            writeln!(output)?;
+        }
+        _ => statement.render_text(output)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_statement, DisputeStatus, Statement, StatementError};
+    use crate::ledger::{Ledger, OutputFormat};
+
+    #[test]
+    fn statement() {
+        let mut ledger = Ledger::builder().keep_statements().build();
+        ledger.read_opening_balances("client,available,held,locked\n1,5,0,false\n".as_bytes());
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,3
+withdrawal,1,3,4
+dispute,1,1,
+dispute,1,2,
+resolve,1,2,
+";
+        ledger.read_csv(input.as_bytes());
+
+        let statement = Statement::new(&ledger, 1).expect("client should have an account");
+        let disputes = statement
+            .lines
+            .iter()
+            .map(|line| line.dispute)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            disputes,
+            [
+                Some(DisputeStatus::Disputed),
+                Some(DisputeStatus::Resolved),
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+        assert_eq!(statement.opening.total, 5.into());
+        assert_eq!(statement.closing.held, 10.into());
+        assert!(Statement::new(&ledger, 2).is_none());
+
+        let mut output = vec![];
+        statement.render_text(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+Statement for client 1
+
+      type  tx  amount  available  held  total  locked   dispute
+----------  --  ------  ---------  ----  -----  ------  --------
+   opening                      5     0      5
+   deposit   1      10         15     0     15          disputed
+   deposit   2       3         18     0     18          resolved
+withdrawal   3       4         14     0     14
+   dispute   1      10          4    10     14
+   dispute   2       3          1    13     14
+   resolve   2       3          4    10     14
+   closing                      4    10     14
+"
+        );
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Client 1's deposit too large for funds to hold, and refund of a
+        // withdrawal fail, so they aren't on the statement, and neither is
+        // the deposit after the account is closed. The withdrawal is
+        // disputed and resolved before.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,10
+deposit,1,2,{max}
+withdrawal,1,3,4
+refund,1,3,
+dispute,1,3,
+resolve,1,3,
+withdrawal,1,4,6
+close_account,1,0,
+deposit,1,5,1
+"
+        );
+        let mut ledger = Ledger::builder().keep_statements().build();
+        ledger.read_csv(input.as_bytes());
+
+        let statement = Statement::new(&ledger, 1).unwrap();
+        let lines = statement
+            .lines
+            .iter()
+            .map(|line| (line.kind, line.dispute))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                ("deposit", None),
+                ("withdrawal", Some(DisputeStatus::Resolved)),
+                ("dispute", None),
+                ("resolve", None),
+                ("withdrawal", None),
+                ("close_account", None),
+            ]
+        );
+        assert_eq!(statement.closing.total, 0.into());
+        assert!(!statement.closing.locked);
+
+        let mut json = vec![];
+        write_statement(&ledger, 1, OutputFormat::Json, &mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json["lines"][5]["type"], "close_account");
+        assert!(matches!(
+            write_statement(&ledger, 2, OutputFormat::Table, vec![]),
+            Err(StatementError::NoAccount(2))
+        ));
+        assert!(matches!(
+            write_statement(&ledger, 1, OutputFormat::JsonLines, vec![]),
+            Err(StatementError::UnsupportedFormat(_))
+        ));
+
+        // Without statements there's nothing to render.
+        let mut ledger = Ledger::default();
+        ledger.read_csv(input.as_bytes());
+        assert!(Statement::new(&ledger, 1).is_none());
+    }
+}
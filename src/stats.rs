@@ -0,0 +1,279 @@
+use std::{collections::BTreeMap, io::Write, str::FromStr};
+
+use thiserror::Error;
+
+use crate::{ledger::Ledger, Currency, Transaction, TransactionAmount};
+
+// The stats report summarises the transactions applied in a run by type, and
+// currency if they have one: how many there were, their total amount, and a
+// histogram of their amounts, e.g. for risk to spot amounts structured to
+// stay just under a reporting threshold. It's derived from the statements,
+// so the ledger has to be built with `keep_statements`. Rejected
+// transactions don't count, and neither do those without an amount, e.g.
+// locks. Fees count for the account they're charged to, not the fee account,
+// and the funds swept from closed accounts for the sweep account, which has
+// them in every currency.
+
+// The bounds of the histogram buckets when none are given.
+pub const DEFAULT_BUCKETS: &str = "10,100,1000,10000";
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("invalid buckets {0:?}, expected increasing positive amounts, e.g. 100,1000,10000")]
+pub struct ParseBucketsError(String);
+
+// Buckets are the bounds of the buckets amounts are counted in: amounts
+// below the first bound, from every bound up to the next, and from the last
+// bound up. Bounds of 1000 and 10000 count amounts below 1000, amounts from
+// 1000 up to 10000, and amounts of 10000 or more.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Buckets(Vec<TransactionAmount>);
+
+impl Default for Buckets {
+    fn default() -> Self {
+        DEFAULT_BUCKETS
+            .parse()
+            .expect("the default buckets are valid")
+    }
+}
+
+impl FromStr for Buckets {
+    type Err = ParseBucketsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseBucketsError(s.to_owned());
+        let bounds = s
+            .split(',')
+            .map(|bound| {
+                bound
+                    .trim()
+                    .parse::<TransactionAmount>()
+                    .map_err(|_| invalid())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let increasing = bounds.windows(2).all(|pair| pair[0] < pair[1]);
+        if !increasing
+            || bounds
+                .first()
+                .is_none_or(|first| *first <= TransactionAmount::ZERO)
+        {
+            return Err(invalid());
+        }
+        Ok(Buckets(bounds))
+    }
+}
+
+impl Buckets {
+    // The bucket the amount is counted in.
+    fn bucket(&self, amount: TransactionAmount) -> usize {
+        self.0.partition_point(|bound| *bound <= amount)
+    }
+
+    // The names of the buckets, used as the columns of the report, e.g.
+    // `<1000`, `1000-10000`, and `>=10000`.
+    fn names(&self) -> Vec<String> {
+        let bounds = self
+            .0
+            .iter()
+            .map(|bound| bound.normalize())
+            .collect::<Vec<_>>();
+        let mut names = vec![format!("<{}", bounds[0])];
+        names.extend(
+            bounds
+                .windows(2)
+                .map(|pair| format!("{}-{}", pair[0], pair[1])),
+        );
+        names.push(format!(">={}", bounds[bounds.len() - 1]));
+        names
+    }
+}
+
+// The transactions of a type, and currency.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypeStats {
+    pub count: u64,
+    pub total: TransactionAmount,
+    // How many of the amounts are in every bucket, see `Buckets`.
+    pub histogram: Vec<u64>,
+}
+
+// Stats are the stats of every type of transaction applied, see the top of
+// this module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    buckets: Buckets,
+    types: BTreeMap<(&'static str, Option<Currency>), TypeStats>,
+}
+
+impl Stats {
+    // The stats of the transactions in the statements of the ledger.
+    pub fn from_ledger(ledger: &Ledger, buckets: Buckets) -> Stats {
+        let mut types = BTreeMap::new();
+        let (fee_account, sweep_account) =
+            (ledger.policy().fee_account, ledger.policy().sweep_account);
+        for (client, entries) in ledger.statements() {
+            for entry in entries {
+                let (Some(transaction), Some(amount)) = (&entry.transaction, entry.amount) else {
+                    continue;
+                };
+                // The fee account's statement has the fees it was credited,
+                // and the closed account's has the funds swept from it.
+                let counted = match transaction {
+                    Transaction::Fee { .. } => fee_account != Some(client),
+                    Transaction::CloseAccount => sweep_account == Some(client),
+                    _ => true,
+                };
+                if !counted {
+                    continue;
+                }
+                let stats = types
+                    .entry((transaction.kind(), entry.currency))
+                    .or_insert_with(|| TypeStats {
+                        count: 0,
+                        total: TransactionAmount::ZERO,
+                        histogram: vec![0; buckets.0.len() + 1],
+                    });
+                stats.count += 1;
+                stats.total = stats.total.saturating_add(amount);
+                stats.histogram[buckets.bucket(amount)] += 1;
+            }
+        }
+        Stats { buckets, types }
+    }
+
+    // The stats of the given type of transaction, e.g. `deposit`, in the
+    // given currency.
+    pub fn of(&self, kind: &str, currency: Option<Currency>) -> Option<&TypeStats> {
+        self.types
+            .iter()
+            .find(|((other, in_currency), _)| *other == kind && *in_currency == currency)
+            .map(|(_, stats)| stats)
+    }
+
+    // Write the stats as CSV: the type of transaction, its currency if any
+    // transaction has one, the number of transactions, their total amount,
+    // and then how many of them are in every bucket, in a column named
+    // after it.
+    pub fn to_csv<W: Write>(&self, output: W) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(output);
+        let currencies = self.types.keys().any(|(_, currency)| currency.is_some());
+        let mut header = vec!["type".to_owned()];
+        if currencies {
+            header.push("currency".to_owned());
+        }
+        header.extend(["count".to_owned(), "total".to_owned()]);
+        header.extend(self.buckets.names());
+        writer.write_record(&header)?;
+        for ((kind, currency), stats) in &self.types {
+            let mut record = vec![kind.to_string()];
+            if currencies {
+                record.push(
+                    currency
+                        .map(|currency| currency.to_string())
+                        .unwrap_or_default(),
+                );
+            }
+            record.push(stats.count.to_string());
+            record.push(stats.total.normalize().to_string());
+            record.extend(stats.histogram.iter().map(u64::to_string));
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Buckets, Stats};
+    use crate::{ledger::Ledger, Currency};
+
+    #[test]
+    fn stats() {
+        let input = "\
+type,client,tx,amount,currency
+deposit,1,1,9500,
+deposit,1,2,9900,
+deposit,2,3,10000,
+deposit,2,4,50,
+withdrawal,1,5,20,
+withdrawal,1,6,1000000,
+dispute,2,3,,
+fee,1,7,1,
+deposit,3,8,9999,eur
+lock,1,0,,
+";
+        let mut ledger = Ledger::builder().keep_statements().fee_account(9).build();
+        ledger.read_csv(input.as_bytes());
+        let buckets = "1000,9000,10000".parse::<Buckets>().unwrap();
+        let stats = Stats::from_ledger(&ledger, buckets);
+
+        let deposits = stats.of("deposit", None).unwrap();
+        assert_eq!(deposits.count, 4);
+        assert_eq!(deposits.total, 29450.into());
+        // Two deposits are just under 10000.
+        assert_eq!(deposits.histogram, [1, 0, 2, 1]);
+        // The withdrawal that the funds don't cover isn't counted.
+        assert_eq!(stats.of("withdrawal", None).unwrap().count, 1);
+        // Nor is the fee twice, or the lock at all.
+        assert_eq!(stats.of("fee", None).unwrap().histogram, [1, 0, 0, 0]);
+        assert!(stats.of("lock", None).is_none());
+        let eur = "EUR".parse::<Currency>().ok();
+        assert_eq!(stats.of("deposit", eur).unwrap().count, 1);
+
+        let mut output = vec![];
+        stats.to_csv(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+type,currency,count,total,<1000,1000-9000,9000-10000,>=10000
+deposit,,4,29450,1,0,2,1
+deposit,EUR,1,9999,0,0,1,0
+dispute,,1,10000,0,0,0,1
+fee,,1,1,1,0,0,0
+withdrawal,,1,20,1,0,0,0
+"
+        );
+
+        for invalid in ["", "100,x", "100,10", "0,10", "-5"] {
+            assert!(invalid.parse::<Buckets>().is_err(), "{:?}", invalid);
+        }
+        assert_eq!(Buckets::default().names().len(), 5);
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Client 1 deposits more than a decimal can add up, client 2's
+        // account is closed and swept, and can't be deposited into anymore,
+        // and client 3 refunds a withdrawal, which fails.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount,currency
+deposit,1,1,{max},
+withdrawal,1,2,{max},
+deposit,1,3,{max},
+deposit,2,4,5,
+deposit,2,5,7,eur
+close_account,2,0,,
+deposit,2,6,50,
+deposit,3,7,10,
+withdrawal,3,8,4,
+refund,3,8,,
+"
+        );
+        let mut ledger = Ledger::builder().keep_statements().sweep_account(9).build();
+        ledger.read_csv(input.as_bytes());
+        let stats = Stats::from_ledger(&ledger, Buckets::default());
+
+        let deposits = stats.of("deposit", None).unwrap();
+        assert_eq!(deposits.count, 4);
+        // Totals too large for a decimal are capped rather than overflowing.
+        assert!(deposits.total >= max);
+        assert_eq!(deposits.histogram, [1, 1, 0, 0, 2]);
+        // The swept funds are counted once, in either currency.
+        assert_eq!(stats.of("close_account", None).unwrap().total, 5.into());
+        let eur = "EUR".parse::<Currency>().ok();
+        assert_eq!(stats.of("close_account", eur).unwrap().total, 7.into());
+        assert!(stats.of("refund", None).is_none());
+    }
+}
@@ -0,0 +1,1375 @@
+use std::{collections::HashMap, hash::BuildHasher};
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    compression::Compression,
+    ledger::{ProcessedTransaction, ProcessedTransactionState},
+    AccountId, TransactionError, TransactionId,
+};
+
+// TxStore keeps the deposits and withdrawals a ledger has applied, so that
+// later disputes, resolutions, and chargebacks can refer to them. A ledger
+// keeps them in memory by default, in a `HashMap` with a fast hasher for
+// integer keys, which grows with every transaction. Inputs too large for that
+// can use a store on disk instead, e.g. `SledTxStore`, see
+// `LedgerBuilder::tx_store`.
+//
+// Transactions are looked up by the client they were applied to and their
+// ID. Lookups return copies, so stores don't have to keep the transactions
+// in memory: changes are written back with `insert`. Stores that can fail to
+// read or write them, e.g. those on disk, fail with `StoreFailed`, and the
+// transaction being applied is rejected with it.
+pub trait TxStore: Send {
+    fn get(
+        &self,
+        client: AccountId,
+        id: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, TransactionError>;
+
+    // Add the transaction, or replace the one with the same client and ID.
+    // If it fails, the store is left as it was.
+    fn insert(
+        &mut self,
+        client: AccountId,
+        id: TransactionId,
+        tx: ProcessedTransaction,
+    ) -> Result<(), TransactionError>;
+
+    // Every transaction in the store as (client, ID, transaction), in no
+    // particular order. Stores that fail to read some of them yield
+    // `StoreFailed` in their place.
+    fn iter(
+        &self,
+    ) -> Box<
+        dyn Iterator<
+                Item = Result<(AccountId, TransactionId, ProcessedTransaction), TransactionError>,
+            > + '_,
+    >;
+
+    fn clear(&mut self);
+
+    // Make room for `additional` more transactions, if the store can, see
+    // `LedgerBuilder::expected_transactions`.
+    fn reserve(&mut self, _additional: usize) {}
+}
+
+impl<S: BuildHasher + Send> TxStore
+    for HashMap<(AccountId, TransactionId), ProcessedTransaction, S>
+{
+    fn get(
+        &self,
+        client: AccountId,
+        id: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, TransactionError> {
+        Ok(HashMap::get(self, &(client, id)).copied())
+    }
+
+    fn insert(
+        &mut self,
+        client: AccountId,
+        id: TransactionId,
+        tx: ProcessedTransaction,
+    ) -> Result<(), TransactionError> {
+        HashMap::insert(self, (client, id), tx);
+        Ok(())
+    }
+
+    fn iter(
+        &self,
+    ) -> Box<
+        dyn Iterator<
+                Item = Result<(AccountId, TransactionId, ProcessedTransaction), TransactionError>,
+            > + '_,
+    > {
+        Box::new(HashMap::iter(self).map(|(&(client, id), &tx)| Ok((client, id, tx))))
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        HashMap::reserve(self, additional)
+    }
+}
+
+// CompactTxStore keeps transactions in memory like the default map, packed
+// into 10 bytes each instead of the 64 of a `ProcessedTransaction`, which
+// adds up on inputs with hundreds of millions of transactions. A packed
+// transaction is the amount's mantissa as an `i64`, the minor units at the
+// amount's own scale, a byte with the state in three bits and the scale in
+// the rest, so amounts are kept exactly, down to their trailing zeros, and a
+// byte of flags telling withdrawals apart. With its key, an entry of the map
+// takes up 20 bytes, or 32 with the `wide-ids` feature. Transactions with a
+// timestamp or a currency are packed the same way, followed by the timestamp
+// and the currency code, in 21 bytes, and kept in a map of their own. The
+// rare amounts whose mantissa doesn't fit an `i64`, and partially disputed
+// transactions, are kept unpacked.
+#[derive(Default)]
+pub struct CompactTxStore {
+    packed: FxHashMap<(AccountId, TransactionId), [u8; encoding::PACKED_SIZE]>,
+    // Packed transactions with a timestamp or a currency.
+    wide: FxHashMap<(AccountId, TransactionId), [u8; encoding::WIDE_PACKED_SIZE]>,
+    unpacked: FxHashMap<(AccountId, TransactionId), ProcessedTransaction>,
+}
+
+impl TxStore for CompactTxStore {
+    fn get(
+        &self,
+        client: AccountId,
+        id: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, TransactionError> {
+        let key = (client, id);
+        if let Some(packed) = self.packed.get(&key) {
+            return Ok(Some(encoding::unpack(packed)));
+        }
+        if let Some(packed) = self.wide.get(&key) {
+            return Ok(Some(encoding::unpack_wide(packed)));
+        }
+        Ok(self.unpacked.get(&key).copied())
+    }
+
+    fn insert(
+        &mut self,
+        client: AccountId,
+        id: TransactionId,
+        tx: ProcessedTransaction,
+    ) -> Result<(), TransactionError> {
+        let key = (client, id);
+        self.packed.remove(&key);
+        self.wide.remove(&key);
+        self.unpacked.remove(&key);
+        if let Some(packed) = encoding::pack(tx) {
+            self.packed.insert(key, packed);
+        } else if let Some(packed) = encoding::pack_wide(tx) {
+            self.wide.insert(key, packed);
+        } else {
+            self.unpacked.insert(key, tx);
+        }
+        Ok(())
+    }
+
+    fn iter(
+        &self,
+    ) -> Box<
+        dyn Iterator<
+                Item = Result<(AccountId, TransactionId, ProcessedTransaction), TransactionError>,
+            > + '_,
+    > {
+        let packed = self
+            .packed
+            .iter()
+            .map(|(&(client, id), packed)| (client, id, encoding::unpack(packed)));
+        let wide = self
+            .wide
+            .iter()
+            .map(|(&(client, id), packed)| (client, id, encoding::unpack_wide(packed)));
+        let unpacked = self
+            .unpacked
+            .iter()
+            .map(|(&(client, id), &tx)| (client, id, tx));
+        Box::new(packed.chain(wide).chain(unpacked).map(Ok))
+    }
+
+    fn clear(&mut self) {
+        self.packed.clear();
+        self.wide.clear();
+        self.unpacked.clear();
+    }
+
+    // Hardly any amounts need to be kept unpacked.
+    fn reserve(&mut self, additional: usize) {
+        self.packed.reserve(additional);
+    }
+}
+
+// SpillTxStore keeps transactions in memory until they take up more than a
+// budget, and then spills the settled ones to disk, keeping memory bounded
+// while lookups keep working. Settled transactions are the bulk of them and
+// rarely looked up again, while disputed ones are about to be resolved or
+// charged back. Disputing a spilled transaction brings it back to memory.
+//
+// Every spill writes a run: a temporary file of the spilled transactions
+// sorted by client and ID, in blocks, with the first key of every block kept
+// in memory to find the block a transaction would be in. Transactions in
+// newer runs, or in memory, shadow those in older runs, which makes
+// iterating over all of them slow. Failing to read or write the runs fails
+// with `StoreFailed`.
+pub struct SpillTxStore {
+    budget: usize,
+    // The number of transactions in memory that triggers the next spill.
+    limit: usize,
+    hot: FxHashMap<(AccountId, TransactionId), ProcessedTransaction>,
+    // Oldest first.
+    runs: Vec<Run>,
+}
+
+// The memory a transaction in memory takes up, including the map's
+// overhead, roughly.
+const HOT_ENTRY_SIZE: usize = 64;
+
+// The number of transactions in a block of a run.
+const BLOCK_LEN: usize = 256;
+
+// Records are of the same size, so that a block can be searched, with values
+// padded to the size of those with a disputed amount.
+const RECORD_SIZE: usize = encoding::KEY_SIZE + encoding::MAX_VALUE_SIZE;
+
+// Run is a file of transactions sorted by client and ID, in blocks that are
+// compressed one by one, so a lookup only reads and decompresses one block.
+struct Run {
+    path: std::path::PathBuf,
+    file: std::fs::File,
+    compression: Compression,
+    // The key of the first transaction of every block, and where the block
+    // starts in the file.
+    blocks: Vec<([u8; encoding::KEY_SIZE], u64)>,
+    // The size of the file.
+    end: u64,
+}
+
+impl SpillTxStore {
+    // A store that keeps up to `budget` bytes of transactions in memory.
+    pub fn new(budget: usize) -> SpillTxStore {
+        SpillTxStore {
+            budget,
+            limit: budget / HOT_ENTRY_SIZE,
+            hot: FxHashMap::default(),
+            runs: vec![],
+        }
+    }
+
+    // Spill the settled transactions in memory. Transactions that aren't
+    // settled can't be spilled, so if there are more of them than fit the
+    // budget, the next spill waits until there are twice as many
+    // transactions in memory, rather than trying again on every insert. If
+    // writing the run fails, the transactions stay in memory.
+    fn spill(&mut self) -> std::io::Result<()> {
+        let mut settled = self
+            .hot
+            .iter()
+            .filter(|(_, tx)| tx.state == ProcessedTransactionState::Settled)
+            .map(|(&key, &tx)| (key, tx))
+            .collect::<Vec<_>>();
+        if settled.is_empty() {
+            self.limit = self.hot.len() * 2;
+            return Ok(());
+        }
+        settled.sort_unstable_by_key(|&(key, _)| key);
+        self.runs
+            .push(Run::write("spill", &settled, Compression::None)?);
+        for (key, _) in &settled {
+            self.hot.remove(key);
+        }
+        self.limit = (self.budget / HOT_ENTRY_SIZE).max(self.hot.len() * 2);
+        Ok(())
+    }
+}
+
+impl Run {
+    // Write the transactions, sorted by client and ID, to a new temporary
+    // file named after `purpose`.
+    fn write(
+        purpose: &str,
+        txs: &[((AccountId, TransactionId), ProcessedTransaction)],
+        compression: Compression,
+    ) -> std::io::Result<Run> {
+        use std::io::Write;
+
+        static NEXT: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "ledger-{}-{}-{}",
+            purpose,
+            std::process::id(),
+            NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // Remove the file if writing it fails.
+        let mut run = Run {
+            path,
+            file,
+            compression,
+            blocks: vec![],
+            end: 0,
+        };
+
+        let mut writer = std::io::BufWriter::new(&run.file);
+        let mut records = Vec::with_capacity(BLOCK_LEN * RECORD_SIZE);
+        for block in txs.chunks(BLOCK_LEN) {
+            records.clear();
+            for &((client, id), tx) in block {
+                records.extend_from_slice(&encoding::key(client, id));
+                records.extend_from_slice(&encoding::value(tx));
+                records.resize(records.len().next_multiple_of(RECORD_SIZE), 0);
+            }
+            let compressed = compression.compress(&records)?;
+            writer.write_all(&compressed)?;
+            let ((client, id), _) = block[0];
+            run.blocks.push((encoding::key(client, id), run.end));
+            run.end += compressed.len() as u64;
+        }
+        writer.flush()?;
+        drop(writer);
+        Ok(run)
+    }
+
+    fn read_block(&self, block: usize) -> std::io::Result<Vec<u8>> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let start = self.blocks[block].1;
+        let end = self
+            .blocks
+            .get(block + 1)
+            .map_or(self.end, |&(_, start)| start);
+        let mut compressed = vec![0; (end - start) as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(start))
+            .and_then(|_| file.read_exact(&mut compressed))
+            .and_then(|_| {
+                self.compression
+                    .decompress(&compressed, BLOCK_LEN * RECORD_SIZE)
+            })
+    }
+
+    fn get(&self, key: &[u8; encoding::KEY_SIZE]) -> std::io::Result<Option<ProcessedTransaction>> {
+        let block = self.blocks.partition_point(|(first, _)| first <= key);
+        let Some(block) = block.checked_sub(1) else {
+            return Ok(None);
+        };
+        let records = self.read_block(block)?;
+        let records = records.chunks_exact(RECORD_SIZE).collect::<Vec<_>>();
+        let found = records.binary_search_by(|record| record[..encoding::KEY_SIZE].cmp(key));
+        Ok(found
+            .ok()
+            .map(|i| encoding::decode_value(&records[i][encoding::KEY_SIZE..])))
+    }
+
+    // Every transaction in the run, in order, as (key, transaction). A
+    // block that can't be read yields a single error in place of its
+    // transactions.
+    fn iter(
+        &self,
+    ) -> impl Iterator<Item = std::io::Result<([u8; encoding::KEY_SIZE], ProcessedTransaction)>> + '_
+    {
+        (0..self.blocks.len()).flat_map(move |block| match self.read_block(block) {
+            Ok(records) => records
+                .chunks_exact(RECORD_SIZE)
+                .map(|record| {
+                    let (key, value) = record.split_at(encoding::KEY_SIZE);
+                    Ok((
+                        key.try_into().expect("malformed transaction key"),
+                        encoding::decode_value(value),
+                    ))
+                })
+                .collect::<Vec<_>>(),
+            Err(err) => vec![Err(err)],
+        })
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Look up a transaction in runs, newest first.
+fn get_from_runs(
+    runs: &[Run],
+    client: AccountId,
+    id: TransactionId,
+) -> Result<Option<ProcessedTransaction>, TransactionError> {
+    let key = encoding::key(client, id);
+    for run in runs.iter().rev() {
+        if let Some(tx) = run.get(&key).map_err(|_| TransactionError::StoreFailed)? {
+            return Ok(Some(tx));
+        }
+    }
+    Ok(None)
+}
+
+// Every transaction in runs, except those shadowed by a newer run or by the
+// transactions `in_memory`. Failing to read a run yields `StoreFailed`.
+fn iter_runs<'a>(
+    runs: &'a [Run],
+    in_memory: impl Fn(AccountId, TransactionId) -> bool + Copy + 'a,
+) -> impl Iterator<Item = Result<(AccountId, TransactionId, ProcessedTransaction), TransactionError>> + 'a
+{
+    runs.iter().enumerate().flat_map(move |(i, run)| {
+        run.iter().filter_map(move |entry| {
+            let entry = entry.map_err(|_| TransactionError::StoreFailed);
+            let found = entry.and_then(|(key, tx)| {
+                let (client, id) = encoding::decode_key(&key);
+                let shadowed =
+                    in_memory(client, id) || get_from_runs(&runs[i + 1..], client, id)?.is_some();
+                Ok((!shadowed).then_some((client, id, tx)))
+            });
+            found.transpose()
+        })
+    })
+}
+
+impl TxStore for SpillTxStore {
+    fn get(
+        &self,
+        client: AccountId,
+        id: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, TransactionError> {
+        if let Some(tx) = self.hot.get(&(client, id)) {
+            return Ok(Some(*tx));
+        }
+        get_from_runs(&self.runs, client, id)
+    }
+
+    fn insert(
+        &mut self,
+        client: AccountId,
+        id: TransactionId,
+        tx: ProcessedTransaction,
+    ) -> Result<(), TransactionError> {
+        let previous = self.hot.insert((client, id), tx);
+        if self.hot.len() > self.limit && self.spill().is_err() {
+            match previous {
+                Some(previous) => self.hot.insert((client, id), previous),
+                None => self.hot.remove(&(client, id)),
+            };
+            return Err(TransactionError::StoreFailed);
+        }
+        Ok(())
+    }
+
+    fn iter(
+        &self,
+    ) -> Box<
+        dyn Iterator<
+                Item = Result<(AccountId, TransactionId, ProcessedTransaction), TransactionError>,
+            > + '_,
+    > {
+        let hot = self
+            .hot
+            .iter()
+            .map(|(&(client, id), &tx)| Ok((client, id, tx)));
+        let spilled = iter_runs(&self.runs, |client, id| {
+            self.hot.contains_key(&(client, id))
+        });
+        Box::new(hot.chain(spilled))
+    }
+
+    fn clear(&mut self) {
+        self.hot.clear();
+        self.runs.clear();
+    }
+}
+
+// ColdTxStore keeps the recent transactions in memory, and moves those older
+// than a maximum age to compressed segments on disk, the cold tier. Disputes
+// mostly refer to recent transactions, so those stay fast, while older ones
+// can still be disputed, only slower: lookups fall back to the cold tier,
+// and a transaction that's changed, e.g. disputed, is recent again.
+//
+// Ages are counted in transactions: the age of a transaction is the number
+// of transactions inserted, or changed, after it. Every `max_age`
+// transactions, those at least that old are written to a new segment, a
+// temporary file like the runs of `SpillTxStore`, compressed block by block.
+// Like with `SpillTxStore`, failing to read or write the segments fails with
+// `StoreFailed`.
+pub struct ColdTxStore {
+    max_age: u64,
+    compression: Compression,
+    // The number of transactions inserted so far.
+    clock: u64,
+    // The recent transactions, with the time they were inserted.
+    hot: FxHashMap<(AccountId, TransactionId), (ProcessedTransaction, u64)>,
+    // Oldest first.
+    segments: Vec<Run>,
+}
+
+impl ColdTxStore {
+    // A store that keeps the last `max_age` transactions in memory, and
+    // compresses the older ones with `compression`.
+    pub fn new(max_age: u64, compression: Compression) -> ColdTxStore {
+        ColdTxStore {
+            max_age: max_age.max(1),
+            compression,
+            clock: 0,
+            hot: FxHashMap::default(),
+            segments: vec![],
+        }
+    }
+
+    // Move the transactions that are at least `max_age` old to a new
+    // segment. If writing it fails, they stay in memory.
+    fn move_to_cold(&mut self) -> std::io::Result<()> {
+        let mut old = self
+            .hot
+            .iter()
+            .filter(|(_, &(_, inserted))| self.clock - inserted >= self.max_age)
+            .map(|(&key, &(tx, _))| (key, tx))
+            .collect::<Vec<_>>();
+        if old.is_empty() {
+            return Ok(());
+        }
+        old.sort_unstable_by_key(|&(key, _)| key);
+        self.segments
+            .push(Run::write("cold", &old, self.compression)?);
+        for (key, _) in &old {
+            self.hot.remove(key);
+        }
+        Ok(())
+    }
+}
+
+impl TxStore for ColdTxStore {
+    fn get(
+        &self,
+        client: AccountId,
+        id: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, TransactionError> {
+        if let Some(&(tx, _)) = self.hot.get(&(client, id)) {
+            return Ok(Some(tx));
+        }
+        get_from_runs(&self.segments, client, id)
+    }
+
+    fn insert(
+        &mut self,
+        client: AccountId,
+        id: TransactionId,
+        tx: ProcessedTransaction,
+    ) -> Result<(), TransactionError> {
+        self.clock += 1;
+        let previous = self.hot.insert((client, id), (tx, self.clock));
+        if self.clock.is_multiple_of(self.max_age) && self.move_to_cold().is_err() {
+            match previous {
+                Some(previous) => self.hot.insert((client, id), previous),
+                None => self.hot.remove(&(client, id)),
+            };
+            self.clock -= 1;
+            return Err(TransactionError::StoreFailed);
+        }
+        Ok(())
+    }
+
+    fn iter(
+        &self,
+    ) -> Box<
+        dyn Iterator<
+                Item = Result<(AccountId, TransactionId, ProcessedTransaction), TransactionError>,
+            > + '_,
+    > {
+        let hot = self
+            .hot
+            .iter()
+            .map(|(&(client, id), &(tx, _))| Ok((client, id, tx)));
+        let cold = iter_runs(&self.segments, |client, id| {
+            self.hot.contains_key(&(client, id))
+        });
+        Box::new(hot.chain(cold))
+    }
+
+    fn clear(&mut self) {
+        self.hot.clear();
+        self.segments.clear();
+    }
+}
+
+// SledTxStore keeps the transactions in a sled database on disk, only
+// caching the recently used ones in memory, so memory stays bounded no
+// matter how many transactions there are. The database is scratch space for
+// a single ledger: whatever it holds is cleared when it's opened. Failing to
+// read or write the database fails with `StoreFailed`, except while
+// clearing it, which panics.
+#[cfg(feature = "sled")]
+pub struct SledTxStore {
+    db: sled::Db,
+}
+
+// How much of the database sled caches in memory, in bytes.
+#[cfg(feature = "sled")]
+const SLED_CACHE_CAPACITY: u64 = 256 * 1024 * 1024;
+
+#[cfg(feature = "sled")]
+impl SledTxStore {
+    // Open the database in the directory at `path`, creating it if it
+    // doesn't exist yet.
+    pub fn open(path: &std::path::Path) -> sled::Result<SledTxStore> {
+        let db = sled::Config::new()
+            .path(path)
+            .cache_capacity(SLED_CACHE_CAPACITY)
+            .open()?;
+        db.clear()?;
+        Ok(SledTxStore { db })
+    }
+}
+
+// How the stores on disk encode transactions. Keys are the client and ID in
+// big-endian, so the transactions of a client are kept together and keys
+// sort like (client, ID). Values are the state as a byte followed by the
+// amount as serialized by `Decimal::serialize`, see `value`.
+// `CompactTxStore` packs them tighter in memory, see `pack`.
+mod encoding {
+    use rust_decimal::Decimal;
+
+    use crate::{
+        ledger::{ProcessedTransaction, ProcessedTransactionState},
+        AccountId, Currency, Timestamp, TransactionId,
+    };
+
+    // Keys are the client and the ID, big-endian so they sort by client
+    // first.
+    const CLIENT_SIZE: usize = std::mem::size_of::<AccountId>();
+    pub(super) const KEY_SIZE: usize = CLIENT_SIZE + std::mem::size_of::<TransactionId>();
+    // The size of a value, of one with a disputed amount, and of one with a
+    // disputed amount, a timestamp, and a currency.
+    pub(super) const VALUE_SIZE: usize = 17;
+    pub(super) const DISPUTED_VALUE_SIZE: usize = 33;
+    pub(super) const MAX_VALUE_SIZE: usize = 44;
+    pub(super) const PACKED_SIZE: usize = 10;
+    // A packed transaction followed by a timestamp and a currency code.
+    pub(super) const WIDE_PACKED_SIZE: usize = PACKED_SIZE + 8 + 3;
+
+    pub(super) fn key(client: AccountId, id: TransactionId) -> [u8; KEY_SIZE] {
+        let mut key = [0; KEY_SIZE];
+        key[..CLIENT_SIZE].copy_from_slice(&client.to_be_bytes());
+        key[CLIENT_SIZE..].copy_from_slice(&id.to_be_bytes());
+        key
+    }
+
+    pub(super) fn decode_key(key: &[u8]) -> (AccountId, TransactionId) {
+        let (client, id) = key.split_at(CLIENT_SIZE);
+        (
+            AccountId::from_be_bytes(client.try_into().expect("malformed transaction key")),
+            TransactionId::from_be_bytes(id.try_into().expect("malformed transaction key")),
+        )
+    }
+
+    fn state(state: ProcessedTransactionState) -> u8 {
+        match state {
+            ProcessedTransactionState::Settled => 0,
+            ProcessedTransactionState::Disputed => 1,
+            ProcessedTransactionState::ChargeBacked => 2,
+            ProcessedTransactionState::Adjusted => 3,
+            ProcessedTransactionState::Refunded => 4,
+            ProcessedTransactionState::Authorized => 5,
+            ProcessedTransactionState::Voided => 6,
+        }
+    }
+
+    fn decode_state(state: u8) -> ProcessedTransactionState {
+        match state {
+            0 => ProcessedTransactionState::Settled,
+            1 => ProcessedTransactionState::Disputed,
+            2 => ProcessedTransactionState::ChargeBacked,
+            3 => ProcessedTransactionState::Adjusted,
+            4 => ProcessedTransactionState::Refunded,
+            5 => ProcessedTransactionState::Authorized,
+            6 => ProcessedTransactionState::Voided,
+            _ => panic!("malformed transaction state {}", state),
+        }
+    }
+
+    // The bits of a value's first byte set if a disputed amount follows the
+    // amount, if the transaction is a withdrawal, if a timestamp follows the
+    // amounts, and if a currency follows them and the timestamp.
+    const PARTIAL: u8 = 0x80;
+    const WITHDRAWAL: u8 = 0x40;
+    const TIMESTAMPED: u8 = 0x20;
+    const IN_CURRENCY: u8 = 0x10;
+
+    // Encode the transaction as a byte with its state and flags, followed by
+    // its amount, its disputed amount if only part of it was disputed, its
+    // timestamp in big-endian if it has one, and its currency code if it
+    // has one.
+    pub(super) fn value(tx: ProcessedTransaction) -> Vec<u8> {
+        let mut value = Vec::with_capacity(MAX_VALUE_SIZE);
+        value.push(state(tx.state) | if tx.withdrawal { WITHDRAWAL } else { 0 });
+        value.extend(tx.amount.serialize());
+        if let Some(disputed) = tx.disputed {
+            value[0] |= PARTIAL;
+            value.extend(disputed.serialize());
+        }
+        if let Some(timestamp) = tx.timestamp {
+            value[0] |= TIMESTAMPED;
+            value.extend(timestamp.to_be_bytes());
+        }
+        if let Some(currency) = tx.currency {
+            value[0] |= IN_CURRENCY;
+            value.extend(currency.to_bytes());
+        }
+        value
+    }
+
+    // Decode a value, ignoring any bytes after it.
+    pub(super) fn decode_value(value: &[u8]) -> ProcessedTransaction {
+        let amount = |bytes: Option<&[u8]>| {
+            let bytes = bytes.and_then(|bytes| bytes.try_into().ok());
+            Decimal::deserialize(bytes.expect("malformed transaction"))
+        };
+        let flags = value.first().expect("malformed transaction");
+        let partial = flags & PARTIAL != 0;
+        let end = if partial {
+            DISPUTED_VALUE_SIZE
+        } else {
+            VALUE_SIZE
+        };
+        let timestamp = (flags & TIMESTAMPED != 0).then(|| {
+            let bytes = value
+                .get(end..end + 8)
+                .and_then(|bytes| bytes.try_into().ok());
+            Timestamp::from_be_bytes(bytes.expect("malformed transaction"))
+        });
+        let end = end + timestamp.map_or(0, |_| 8);
+        let currency = (flags & IN_CURRENCY != 0).then(|| {
+            let bytes = value
+                .get(end..end + 3)
+                .and_then(|bytes| bytes.try_into().ok());
+            Currency::from_bytes(bytes.expect("malformed transaction"))
+                .expect("malformed transaction currency")
+        });
+        ProcessedTransaction {
+            amount: amount(value.get(1..VALUE_SIZE)),
+            state: decode_state(flags & !(PARTIAL | WITHDRAWAL | TIMESTAMPED | IN_CURRENCY)),
+            disputed: partial.then(|| amount(value.get(VALUE_SIZE..DISPUTED_VALUE_SIZE))),
+            withdrawal: flags & WITHDRAWAL != 0,
+            timestamp,
+            currency,
+        }
+    }
+
+    // The bits of a packed transaction's last byte set if the transaction is
+    // a withdrawal, and, in its wide form, if it has a timestamp and a
+    // currency.
+    const PACKED_WITHDRAWAL: u8 = 0x01;
+    const PACKED_TIMESTAMPED: u8 = 0x02;
+    const PACKED_IN_CURRENCY: u8 = 0x04;
+
+    // Pack the transaction as its amount's mantissa, in little-endian,
+    // followed by the state in the low three bits of a byte and the amount's
+    // scale, at most 28, in the bits above, and a byte of flags. Returns
+    // `None` if the mantissa doesn't fit an `i64`, if only part of the
+    // transaction was disputed, or if it has a timestamp or a currency, see
+    // `pack_wide`.
+    pub(super) fn pack(tx: ProcessedTransaction) -> Option<[u8; PACKED_SIZE]> {
+        if tx.disputed.is_some() || tx.timestamp.is_some() || tx.currency.is_some() {
+            return None;
+        }
+        let mantissa = i64::try_from(tx.amount.mantissa()).ok()?;
+        let mut packed = [0; PACKED_SIZE];
+        packed[..8].copy_from_slice(&mantissa.to_le_bytes());
+        packed[8] = state(tx.state) | (tx.amount.scale() as u8) << 3;
+        packed[9] = if tx.withdrawal { PACKED_WITHDRAWAL } else { 0 };
+        Some(packed)
+    }
+
+    pub(super) fn unpack(packed: &[u8; PACKED_SIZE]) -> ProcessedTransaction {
+        let mantissa = i64::from_le_bytes(packed[..8].try_into().expect("malformed transaction"));
+        let flags = packed[8];
+        ProcessedTransaction {
+            amount: Decimal::new(mantissa, u32::from(flags >> 3)),
+            state: decode_state(flags & 0b111),
+            disputed: None,
+            withdrawal: packed[9] & PACKED_WITHDRAWAL != 0,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    // Pack the transaction like `pack`, followed by its timestamp in
+    // little-endian and its currency code, either of them zeros if it
+    // doesn't have one.
+    pub(super) fn pack_wide(tx: ProcessedTransaction) -> Option<[u8; WIDE_PACKED_SIZE]> {
+        let narrow = pack(ProcessedTransaction {
+            timestamp: None,
+            currency: None,
+            ..tx
+        })?;
+        let mut packed = [0; WIDE_PACKED_SIZE];
+        packed[..PACKED_SIZE].copy_from_slice(&narrow);
+        if let Some(timestamp) = tx.timestamp {
+            packed[9] |= PACKED_TIMESTAMPED;
+            packed[PACKED_SIZE..PACKED_SIZE + 8].copy_from_slice(&timestamp.to_le_bytes());
+        }
+        if let Some(currency) = tx.currency {
+            packed[9] |= PACKED_IN_CURRENCY;
+            packed[PACKED_SIZE + 8..].copy_from_slice(&currency.to_bytes());
+        }
+        Some(packed)
+    }
+
+    pub(super) fn unpack_wide(packed: &[u8; WIDE_PACKED_SIZE]) -> ProcessedTransaction {
+        let narrow = packed[..PACKED_SIZE]
+            .try_into()
+            .expect("malformed transaction");
+        let flags = packed[9];
+        let timestamp = (flags & PACKED_TIMESTAMPED != 0).then(|| {
+            let bytes = packed[PACKED_SIZE..PACKED_SIZE + 8].try_into();
+            Timestamp::from_le_bytes(bytes.expect("malformed transaction"))
+        });
+        let currency = (flags & PACKED_IN_CURRENCY != 0).then(|| {
+            let bytes = packed[PACKED_SIZE + 8..].try_into();
+            Currency::from_bytes(bytes.expect("malformed transaction"))
+                .expect("malformed transaction currency")
+        });
+        ProcessedTransaction {
+            timestamp,
+            currency,
+            ..unpack(narrow)
+        }
+    }
+}
+
+#[cfg(feature = "sled")]
+impl TxStore for SledTxStore {
+    fn get(
+        &self,
+        client: AccountId,
+        id: TransactionId,
+    ) -> Result<Option<ProcessedTransaction>, TransactionError> {
+        let value = self.db.get(encoding::key(client, id));
+        let value = value.map_err(|_| TransactionError::StoreFailed)?;
+        Ok(value.map(|value| encoding::decode_value(&value)))
+    }
+
+    fn insert(
+        &mut self,
+        client: AccountId,
+        id: TransactionId,
+        tx: ProcessedTransaction,
+    ) -> Result<(), TransactionError> {
+        self.db
+            .insert(encoding::key(client, id), encoding::value(tx))
+            .map_err(|_| TransactionError::StoreFailed)?;
+        Ok(())
+    }
+
+    fn iter(
+        &self,
+    ) -> Box<
+        dyn Iterator<
+                Item = Result<(AccountId, TransactionId, ProcessedTransaction), TransactionError>,
+            > + '_,
+    > {
+        Box::new(self.db.iter().map(|entry| {
+            let (key, value) = entry.map_err(|_| TransactionError::StoreFailed)?;
+            let (client, id) = encoding::decode_key(&key);
+            Ok((client, id, encoding::decode_value(&value)))
+        }))
+    }
+
+    fn clear(&mut self) {
+        self.db
+            .clear()
+            .expect("failed to clear the transaction store");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "sled")]
+    use super::SledTxStore;
+    use rustc_hash::FxHashMap;
+
+    use super::{ColdTxStore, CompactTxStore, SpillTxStore, TxStore};
+    use crate::{
+        compression::Compression,
+        ledger::{ProcessedTransaction, ProcessedTransactionState},
+        AccountId, TransactionError, TransactionId,
+    };
+
+    #[test]
+    fn reserve() {
+        let mut store = FxHashMap::default();
+        TxStore::reserve(&mut store, 1000);
+        assert!(store.capacity() >= 1000);
+        let tx = ProcessedTransaction {
+            amount: 5.into(),
+            state: ProcessedTransactionState::Settled,
+            disputed: None,
+            withdrawal: false,
+            timestamp: None,
+            currency: None,
+        };
+        TxStore::insert(&mut store, 1, 2, tx).unwrap();
+        assert_eq!(TxStore::get(&store, 1, 2).unwrap(), Some(tx));
+    }
+
+    // The sizes `CompactTxStore` is documented with.
+    #[test]
+    fn compact_layout() {
+        use std::mem::size_of;
+
+        use super::encoding::{PACKED_SIZE, WIDE_PACKED_SIZE};
+
+        type Key = (AccountId, TransactionId);
+        assert_eq!(PACKED_SIZE, 10);
+        assert_eq!(WIDE_PACKED_SIZE, 21);
+        assert_eq!(size_of::<ProcessedTransaction>(), 64);
+        let entry = if cfg!(feature = "wide-ids") { 32 } else { 20 };
+        assert_eq!(size_of::<(Key, [u8; PACKED_SIZE])>(), entry);
+    }
+
+    #[test]
+    fn compact() {
+        let tx = |amount: &str, state| ProcessedTransaction {
+            amount: amount.parse().unwrap(),
+            state,
+            disputed: None,
+            withdrawal: false,
+            timestamp: None,
+            currency: None,
+        };
+        let mut store = CompactTxStore::default();
+        let transactions = [
+            tx("10.50", ProcessedTransactionState::Settled),
+            tx("0.0001", ProcessedTransactionState::Disputed),
+            tx(
+                "92233720368547758.07",
+                ProcessedTransactionState::ChargeBacked,
+            ),
+            // Too large to pack.
+            tx("92233720368547758.08", ProcessedTransactionState::Settled),
+            tx("0", ProcessedTransactionState::Settled),
+            tx("3", ProcessedTransactionState::Adjusted),
+            // The largest scale.
+            tx(
+                "0.0000000000000000000000000001",
+                ProcessedTransactionState::Refunded,
+            ),
+            ProcessedTransaction {
+                withdrawal: true,
+                ..tx("4", ProcessedTransactionState::Disputed)
+            },
+            // Partially disputed, which isn't packed either.
+            ProcessedTransaction {
+                disputed: Some(2.into()),
+                ..tx("5", ProcessedTransactionState::Disputed)
+            },
+            // Timestamped transactions, and those in a currency, are packed
+            // wide.
+            ProcessedTransaction {
+                timestamp: Some(1_700_000_000),
+                ..tx("6", ProcessedTransactionState::Settled)
+            },
+            ProcessedTransaction {
+                currency: Some("EUR".parse().unwrap()),
+                ..tx("7", ProcessedTransactionState::Settled)
+            },
+            ProcessedTransaction {
+                withdrawal: true,
+                timestamp: Some(u64::MAX),
+                currency: Some("JPY".parse().unwrap()),
+                ..tx("-8.25", ProcessedTransactionState::ChargeBacked)
+            },
+            // Unless they can't be packed at all.
+            ProcessedTransaction {
+                timestamp: Some(1_700_000_000),
+                disputed: Some(1.into()),
+                ..tx("9", ProcessedTransactionState::Disputed)
+            },
+        ];
+        for (id, &tx) in (0..).zip(&transactions) {
+            store.insert(1, id, tx).unwrap();
+        }
+        assert_eq!(store.packed.len(), 7);
+        assert_eq!(store.wide.len(), 3);
+        assert_eq!(store.unpacked.len(), 3);
+        for (id, &tx) in (0..).zip(&transactions) {
+            let stored = store
+                .get(1, id)
+                .unwrap()
+                .expect("transaction should be stored");
+            assert_eq!(stored, tx);
+            // Down to the trailing zeros.
+            assert_eq!(stored.amount.to_string(), tx.amount.to_string());
+        }
+
+        // Replacing a transaction moves it between the maps as needed.
+        store.insert(1, 3, transactions[0]).unwrap();
+        assert_eq!(store.get(1, 3).unwrap(), Some(transactions[0]));
+        assert_eq!(store.unpacked.len(), 2);
+        store.insert(1, 9, transactions[0]).unwrap();
+        assert_eq!(store.get(1, 9).unwrap(), Some(transactions[0]));
+        assert_eq!(store.wide.len(), 2);
+        assert_eq!(store.iter().count(), 13);
+        assert_eq!(store.get(2, 0).unwrap(), None);
+    }
+
+    #[test]
+    fn spill() {
+        let settled = |amount: TransactionId| ProcessedTransaction {
+            amount: amount.into(),
+            state: ProcessedTransactionState::Settled,
+            disputed: None,
+            withdrawal: false,
+            timestamp: None,
+            currency: None,
+        };
+        // Room for ten transactions in memory.
+        let mut store = SpillTxStore::new(10 * super::HOT_ENTRY_SIZE);
+        for id in 0..1000 {
+            store
+                .insert((id % 3) as AccountId, id, settled(id))
+                .unwrap();
+        }
+        assert!(store.runs.len() > 1);
+        assert!(store.hot.len() <= 10);
+
+        // Disputing a spilled transaction brings it back, and once it's
+        // settled again it's spilled again, along with the amount that was
+        // disputed.
+        let mut disputed = store
+            .get(1, 4)
+            .unwrap()
+            .expect("spilled transaction should be found");
+        disputed.state = ProcessedTransactionState::Disputed;
+        store.insert(1, 4, disputed).unwrap();
+        assert_eq!(store.get(1, 4).unwrap(), Some(disputed));
+        for id in 1000..1100 {
+            store.insert(0, id, settled(id)).unwrap();
+        }
+        assert_eq!(store.hot.get(&(1, 4)), Some(&disputed));
+        let resolved = ProcessedTransaction {
+            disputed: Some(2.into()),
+            timestamp: Some(1_700_000_000),
+            currency: Some("EUR".parse().unwrap()),
+            ..settled(5)
+        };
+        store.insert(1, 4, resolved).unwrap();
+        for id in 1100..1200 {
+            store.insert(0, id, settled(id)).unwrap();
+        }
+        assert!(!store.hot.contains_key(&(1, 4)));
+        assert_eq!(store.get(1, 4).unwrap(), Some(resolved));
+        assert_eq!(store.get(2, 4).unwrap(), None);
+        assert_eq!(store.get(0, 999).unwrap(), Some(settled(999)));
+
+        let mut all = store.iter().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(all.len(), 1200);
+        all.sort_by_key(|&(_, id, _)| id);
+        assert_eq!(all[4], (1, 4, resolved));
+
+        store.clear();
+        assert_eq!(store.iter().count(), 0);
+    }
+
+    #[test]
+    fn cold_tier() {
+        let settled = |amount: TransactionId| ProcessedTransaction {
+            amount: amount.into(),
+            state: ProcessedTransactionState::Settled,
+            disputed: None,
+            withdrawal: false,
+            timestamp: None,
+            currency: None,
+        };
+        #[cfg(feature = "zstd")]
+        let compression = Compression::zstd(3).unwrap();
+        #[cfg(not(feature = "zstd"))]
+        let compression = Compression::None;
+        let mut store = ColdTxStore::new(100, compression);
+        for id in 0..1050 {
+            store
+                .insert((id % 3) as AccountId, id, settled(id))
+                .unwrap();
+        }
+        assert_eq!(store.segments.len(), 9);
+        assert_eq!(store.hot.len(), 150);
+        #[cfg(feature = "zstd")]
+        assert!(
+            store
+                .segments
+                .iter()
+                .map(|segment| segment.end)
+                .sum::<u64>()
+                < 900 * 23
+        );
+
+        // Old transactions can still be disputed, which makes them recent.
+        let mut disputed = store
+            .get(1, 4)
+            .unwrap()
+            .expect("cold transaction should be found");
+        disputed.state = ProcessedTransactionState::Disputed;
+        store.insert(1, 4, disputed).unwrap();
+        for id in 1050..1120 {
+            store.insert(0, id, settled(id)).unwrap();
+        }
+        assert_eq!(store.hot.get(&(1, 4)).map(|&(tx, _)| tx), Some(disputed));
+        assert_eq!(store.get(2, 4).unwrap(), None);
+
+        let mut all = store.iter().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(all.len(), 1120);
+        all.sort_by_key(|&(_, id, _)| id);
+        assert_eq!(all[4], (1, 4, disputed));
+        assert_eq!(all[500], (2, 500, settled(500)));
+
+        store.clear();
+        assert_eq!(store.iter().count(), 0);
+    }
+
+    #[test]
+    fn unreadable_runs() {
+        use crate::ledger::Ledger;
+
+        let settled = |amount: TransactionId| ProcessedTransaction {
+            amount: amount.into(),
+            state: ProcessedTransactionState::Settled,
+            disputed: None,
+            withdrawal: false,
+            timestamp: None,
+            currency: None,
+        };
+        let mut store = SpillTxStore::new(0);
+        for id in 0..3 {
+            store.insert(1, id, settled(id)).unwrap();
+        }
+        assert!(!store.runs.is_empty());
+        // Runs can no longer be read once their file is only open for
+        // writing.
+        for run in &mut store.runs {
+            run.file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&run.path)
+                .unwrap();
+        }
+        assert_eq!(store.get(1, 0), Err(TransactionError::StoreFailed));
+        assert!(store
+            .iter()
+            .any(|found| found == Err(TransactionError::StoreFailed)));
+
+        // Looking them up through the ledger fails instead of panicking.
+        let ledger = Ledger::builder().tx_store(store).build();
+        assert!(ledger
+            .find_transactions(&Default::default())
+            .any(|found| found == Err(TransactionError::StoreFailed)));
+    }
+
+    // A store whose disk is full: it reads what it has, but can't write
+    // anything.
+    #[derive(Default)]
+    struct FullStore(FxHashMap<(AccountId, TransactionId), ProcessedTransaction>);
+
+    impl TxStore for FullStore {
+        fn get(
+            &self,
+            client: AccountId,
+            id: TransactionId,
+        ) -> Result<Option<ProcessedTransaction>, TransactionError> {
+            TxStore::get(&self.0, client, id)
+        }
+
+        fn insert(
+            &mut self,
+            _client: AccountId,
+            _id: TransactionId,
+            _tx: ProcessedTransaction,
+        ) -> Result<(), TransactionError> {
+            Err(TransactionError::StoreFailed)
+        }
+
+        fn iter(
+            &self,
+        ) -> Box<
+            dyn Iterator<
+                    Item = Result<
+                        (AccountId, TransactionId, ProcessedTransaction),
+                        TransactionError,
+                    >,
+                > + '_,
+        > {
+            TxStore::iter(&self.0)
+        }
+
+        fn clear(&mut self) {}
+    }
+
+    #[test]
+    fn failing_store() {
+        use crate::{
+            account::Account,
+            ledger::{Ledger, Reject},
+            scaling::AmountScale,
+        };
+
+        let mut ledger = Ledger::builder().tx_store(FullStore::default()).build();
+        let mut rejects = vec![];
+        ledger.read_csv_with(
+            "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,5\n".as_bytes(),
+            AmountScale::default(),
+            |reject: Reject| rejects.push(reject.reason),
+        );
+        // Transactions that can't be kept are rejected, and change nothing.
+        assert_eq!(rejects, ["store_failed", "insufficient_funds"]);
+        assert_eq!(ledger.account(1).map(Account::available), Some(0.into()));
+    }
+
+    #[test]
+    fn edge_cases() {
+        use crate::{
+            ledger::{Ledger, Reject},
+            scaling::AmountScale,
+        };
+
+        // The largest and smallest amounts, and the last client and ID, are
+        // kept exactly by every store, in memory and on disk.
+        let tx = |amount| ProcessedTransaction {
+            amount,
+            state: ProcessedTransactionState::Disputed,
+            disputed: Some(amount),
+            withdrawal: true,
+            timestamp: Some(u64::MAX),
+            currency: Some("XAU".parse().unwrap()),
+        };
+        let extremes = [
+            (
+                AccountId::MAX,
+                TransactionId::MAX,
+                tx(rust_decimal::Decimal::MAX),
+            ),
+            (0, 0, tx(rust_decimal::Decimal::MIN)),
+            (
+                AccountId::MAX,
+                0,
+                tx("-0.0000000000000000000000000001".parse().unwrap()),
+            ),
+        ];
+        let stores: [Box<dyn TxStore>; 3] = [
+            Box::new(CompactTxStore::default()),
+            Box::new(SpillTxStore::new(0)),
+            Box::new(ColdTxStore::new(1, Compression::None)),
+        ];
+        for mut store in stores {
+            for (client, id, tx) in extremes {
+                store.insert(client, id, tx).unwrap();
+            }
+            // Settled ones are spilled, and every one is old enough to be
+            // cold, once the next transaction comes.
+            for (client, id, tx) in extremes {
+                let settled = ProcessedTransaction {
+                    state: ProcessedTransactionState::Settled,
+                    ..tx
+                };
+                store.insert(client, id, settled).unwrap();
+                store.insert(1, 1, settled).unwrap();
+                assert_eq!(store.get(client, id).unwrap(), Some(settled));
+            }
+            assert_eq!(store.get(AccountId::MAX, 1).unwrap(), None);
+            assert_eq!(store.iter().count(), 4);
+        }
+
+        // Every store applies transactions the same way: client 1's deposit
+        // beyond what funds can hold fails, but the one before it can be
+        // disputed, client 2 deposits into their closed account, and client
+        // 3 refunds a withdrawal, and sends a line that can't be read.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,{max}
+dispute,1,1,
+deposit,2,3,5
+withdrawal,2,4,5
+close_account,2,0,
+deposit,2,5,1
+deposit,3,6,10
+withdrawal,3,7,4
+refund,3,7,
+deposit,3,8,x
+"
+        );
+        let run = |ledger: Ledger| {
+            let mut ledger = ledger;
+            let mut rejects = vec![];
+            ledger.read_csv_with(
+                input.as_bytes(),
+                AmountScale::default(),
+                |reject: Reject| rejects.push(reject.reason),
+            );
+            assert_eq!(ledger.account(1).unwrap().held(), max);
+            assert!(ledger.account(2).unwrap().is_closed());
+            assert_eq!(ledger.account(3).unwrap().total().unwrap(), 6.into());
+            rejects
+        };
+        for rejects in [
+            run(Ledger::default()),
+            run(Ledger::builder()
+                .tx_store(CompactTxStore::default())
+                .build()),
+            run(Ledger::builder().tx_store(SpillTxStore::new(0)).build()),
+            run(Ledger::builder()
+                .tx_store(ColdTxStore::new(1, Compression::None))
+                .build()),
+        ] {
+            assert_eq!(
+                rejects,
+                ["overflow", "account_closed", "not_refundable", "malformed"]
+            );
+        }
+    }
+
+    #[cfg(feature = "sled")]
+    #[test]
+    fn sled() {
+        use crate::{ledger::Ledger, Transaction};
+
+        let path = std::env::temp_dir().join(format!("ledger-sled-{}", std::process::id()));
+        let store = SledTxStore::open(&path).unwrap();
+        let mut ledger = Ledger::builder().tx_store(store).build();
+        let input = "\
+type,client,tx,amount
+deposit,1,1,10.5
+deposit,2,2,3
+dispute,1,1,4
+chargeback,1,1,
+dispute,2,1,
+";
+        ledger.read_csv(input.as_bytes());
+
+        let mut transactions = ledger
+            .find_transactions(&Default::default())
+            .map(|found| {
+                let (client, id, tx) = found.unwrap();
+                (client, id, tx.amount, tx.state, tx.disputed)
+            })
+            .collect::<Vec<_>>();
+        transactions.sort_by_key(|&(client, id, ..)| (client, id));
+        assert_eq!(
+            transactions,
+            [
+                (
+                    1,
+                    1,
+                    "10.5".parse().unwrap(),
+                    ProcessedTransactionState::ChargeBacked,
+                    Some(4.into())
+                ),
+                (2, 2, 3.into(), ProcessedTransactionState::Settled, None),
+            ]
+        );
+        assert_eq!(
+            ledger.apply(
+                2,
+                Transaction::Dispute {
+                    id: 2,
+                    amount: None
+                }
+            ),
+            Ok(())
+        );
+        drop(ledger);
+
+        // Reopening the store starts over.
+        let ledger = Ledger::builder()
+            .tx_store(SledTxStore::open(&path).unwrap())
+            .build();
+        assert_eq!(ledger.find_transactions(&Default::default()).count(), 0);
+        drop(ledger);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,479 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use rust_decimal::Decimal;
+
+use crate::{ledger::Ledger, AccountId, Currency, Transaction, TransactionId};
+
+// A write-ahead log keeps every transaction a server receives, written and
+// synced to disk before the transaction is applied. After a crash, replaying
+// the log brings the ledger back to the state it was in, so every record
+// that was answered is accounted for, and any that wasn't can be sent again.
+// Transactions that were rejected are logged too: the ledger rejects them
+// again when they're replayed.
+//
+// The log starts with `MAGIC`, followed by a frame for every transaction:
+// the length of the rest of the frame as a little-endian u32, the kind of
+// the transaction as a byte, and the client as a little-endian u16, or u64
+// with the `wide-ids` feature. Deposits and withdrawals go on with their ID
+// as a little-endian u32, or u64, and their amount as serialized by
+// `Decimal::serialize`, disputes, resolutions, and chargebacks with the ID
+// they refer to, and recoveries, locks, unlocks, and account closings end
+// there. Scheduled and timestamped transactions have their time as a
+// little-endian u64 after the kind, followed by the payload of the
+// transaction they wrap, and transactions in a currency its three ASCII
+// letters. Account openings have the client followed by the account's
+// metadata as JSON.
+pub struct WriteAheadLog {
+    file: File,
+}
+
+#[cfg(not(feature = "wide-ids"))]
+const MAGIC: &[u8; 8] = b"LEDGWAL1";
+// Clients and IDs are u64s in the logs of the `wide-ids` feature, which
+// have a magic of their own, so they aren't replayed by builds without it,
+// or the other way around.
+#[cfg(feature = "wide-ids")]
+const MAGIC: &[u8; 8] = b"LEDGWALW";
+
+// The size of the ID of a transaction that refers to another one, and has
+// no amount.
+const ID_SIZE: usize = std::mem::size_of::<TransactionId>();
+
+const DEPOSIT: u8 = 0;
+const WITHDRAWAL: u8 = 1;
+const DISPUTE: u8 = 2;
+const RESOLVE: u8 = 3;
+const CHARGEBACK: u8 = 4;
+const RECOVERY: u8 = 5;
+const FEE: u8 = 6;
+const ADJUST_CREDIT: u8 = 7;
+const ADJUST_DEBIT: u8 = 8;
+const LOCK: u8 = 9;
+const UNLOCK: u8 = 10;
+const REFUND: u8 = 11;
+const AUTHORIZE: u8 = 12;
+const CAPTURE: u8 = 13;
+const VOID: u8 = 14;
+const SCHEDULED: u8 = 15;
+const TIMESTAMPED: u8 = 16;
+const IN_CURRENCY: u8 = 17;
+const OPEN_ACCOUNT: u8 = 18;
+const CLOSE_ACCOUNT: u8 = 19;
+
+impl WriteAheadLog {
+    // Open the log at `path`, creating it if it doesn't exist yet, and
+    // replay every transaction in it to `ledger`, returning how many were
+    // replayed. A frame left incomplete by a crash is discarded, since its
+    // transaction was never applied.
+    pub fn open(path: &Path, ledger: &mut Ledger) -> io::Result<(WriteAheadLog, usize)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(MAGIC)?;
+            file.sync_data()?;
+            return Ok((WriteAheadLog { file }, 0));
+        }
+
+        let mut reader = BufReader::new(&file);
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a write-ahead log"));
+        }
+
+        let mut end = MAGIC.len() as u64;
+        let mut replayed = 0;
+        while let Some(frame) = read_frame(&mut reader)? {
+            let (client, tx) =
+                decode(&frame).ok_or_else(|| invalid_data("malformed write-ahead log entry"))?;
+            // Rejected transactions were rejected the first time as well.
+            let _ = ledger.apply(client, tx);
+            end += (4 + frame.len()) as u64;
+            replayed += 1;
+        }
+
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+        Ok((WriteAheadLog { file }, replayed))
+    }
+
+    // Append the transaction to the log, returning once it's on disk.
+    pub fn append(&mut self, client: AccountId, tx: &Transaction) -> io::Result<()> {
+        let payload = encode(client, tx);
+        let mut frame = Vec::with_capacity(4 + payload.len());
+        frame.extend((payload.len() as u32).to_le_bytes());
+        frame.extend(payload);
+        self.file.write_all(&frame)?;
+        self.file.sync_data()
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Read the next frame, or `None` at the end of the log, including when the
+// last frame is incomplete.
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let incomplete = |err: io::Error| match err.kind() {
+        io::ErrorKind::UnexpectedEof => Ok(None),
+        _ => Err(err),
+    };
+
+    let mut length = [0; 4];
+    if let Err(err) = reader.read_exact(&mut length) {
+        return incomplete(err);
+    }
+    let mut frame = vec![0; u32::from_le_bytes(length) as usize];
+    match reader.read_exact(&mut frame) {
+        Ok(()) => Ok(Some(frame)),
+        Err(err) => incomplete(err),
+    }
+}
+
+fn encode(client: AccountId, tx: &Transaction) -> Vec<u8> {
+    let (kind, id, amount) = match *tx {
+        Transaction::Deposit { new_id, amount } => (DEPOSIT, Some(new_id), Some(amount)),
+        Transaction::Withdrawal { new_id, amount } => (WITHDRAWAL, Some(new_id), Some(amount)),
+        Transaction::Dispute { id, amount } => (DISPUTE, Some(id), amount),
+        Transaction::Resolve { id } => (RESOLVE, Some(id), None),
+        Transaction::Chargeback { id } => (CHARGEBACK, Some(id), None),
+        Transaction::Recovery => (RECOVERY, None, None),
+        Transaction::Fee { new_id, amount } => (FEE, Some(new_id), Some(amount)),
+        Transaction::AdjustCredit { new_id, amount } => (ADJUST_CREDIT, Some(new_id), Some(amount)),
+        Transaction::AdjustDebit { new_id, amount } => (ADJUST_DEBIT, Some(new_id), Some(amount)),
+        Transaction::Refund { id, amount } => (REFUND, Some(id), amount),
+        Transaction::Lock => (LOCK, None, None),
+        Transaction::Unlock => (UNLOCK, None, None),
+        Transaction::CloseAccount => (CLOSE_ACCOUNT, None, None),
+        Transaction::Authorize { new_id, amount } => (AUTHORIZE, Some(new_id), Some(amount)),
+        Transaction::Capture { id } => (CAPTURE, Some(id), None),
+        Transaction::Void { id } => (VOID, Some(id), None),
+        Transaction::Scheduled {
+            effective_at: time,
+            ref transaction,
+        }
+        | Transaction::Timestamped {
+            timestamp: time,
+            ref transaction,
+        } => {
+            let kind = match tx {
+                Transaction::Scheduled { .. } => SCHEDULED,
+                _ => TIMESTAMPED,
+            };
+            let mut payload = vec![kind];
+            payload.extend(time.to_le_bytes());
+            payload.extend(encode(client, transaction));
+            return payload;
+        }
+        Transaction::InCurrency {
+            currency,
+            ref transaction,
+        } => {
+            let mut payload = vec![IN_CURRENCY];
+            payload.extend(currency.to_bytes());
+            payload.extend(encode(client, transaction));
+            return payload;
+        }
+        Transaction::OpenAccount { ref metadata } => {
+            let mut payload = vec![OPEN_ACCOUNT];
+            payload.extend(client.to_le_bytes());
+            payload.extend(serde_json::to_vec(metadata).expect("metadata should serialize"));
+            return payload;
+        }
+    };
+
+    let mut payload = vec![kind];
+    payload.extend(client.to_le_bytes());
+    if let Some(id) = id {
+        payload.extend(id.to_le_bytes());
+    }
+    if let Some(amount) = amount {
+        payload.extend(amount.serialize());
+    }
+    payload
+}
+
+fn decode(payload: &[u8]) -> Option<(AccountId, Transaction)> {
+    let (&kind, rest) = payload.split_first()?;
+    if kind == SCHEDULED || kind == TIMESTAMPED {
+        let (time, rest) = rest.split_first_chunk()?;
+        let time = u64::from_le_bytes(*time);
+        let (client, transaction) = decode(rest)?;
+        let transaction = Box::new(transaction);
+        let tx = match kind {
+            SCHEDULED => Transaction::Scheduled {
+                effective_at: time,
+                transaction,
+            },
+            _ => Transaction::Timestamped {
+                timestamp: time,
+                transaction,
+            },
+        };
+        return Some((client, tx));
+    }
+    if kind == IN_CURRENCY {
+        let (currency, rest) = rest.split_first_chunk()?;
+        let currency = Currency::from_bytes(*currency)?;
+        let (client, transaction) = decode(rest)?;
+        let tx = Transaction::InCurrency {
+            currency,
+            transaction: Box::new(transaction),
+        };
+        return Some((client, tx));
+    }
+    let (client, rest) = rest.split_first_chunk()?;
+    let client = AccountId::from_le_bytes(*client);
+    if kind == OPEN_ACCOUNT {
+        let metadata = Box::new(serde_json::from_slice(rest).ok()?);
+        return Some((client, Transaction::OpenAccount { metadata }));
+    }
+    let (id, amount) = match rest.split_first_chunk() {
+        Some((id, amount)) => (Some(TransactionId::from_le_bytes(*id)), amount),
+        None => (None, rest),
+    };
+    let amount = amount.try_into().ok().map(Decimal::deserialize);
+
+    let tx = match (kind, id, amount, rest.len()) {
+        (DEPOSIT, Some(new_id), Some(amount), _) => Transaction::Deposit { new_id, amount },
+        (WITHDRAWAL, Some(new_id), Some(amount), _) => Transaction::Withdrawal { new_id, amount },
+        (DISPUTE, Some(id), None, ID_SIZE) => Transaction::Dispute { id, amount: None },
+        (DISPUTE, Some(id), Some(amount), _) => Transaction::Dispute {
+            id,
+            amount: Some(amount),
+        },
+        (RESOLVE, Some(id), None, ID_SIZE) => Transaction::Resolve { id },
+        (CHARGEBACK, Some(id), None, ID_SIZE) => Transaction::Chargeback { id },
+        (RECOVERY, None, None, 0) => Transaction::Recovery,
+        (FEE, Some(new_id), Some(amount), _) => Transaction::Fee { new_id, amount },
+        (ADJUST_CREDIT, Some(new_id), Some(amount), _) => {
+            Transaction::AdjustCredit { new_id, amount }
+        }
+        (ADJUST_DEBIT, Some(new_id), Some(amount), _) => {
+            Transaction::AdjustDebit { new_id, amount }
+        }
+        (REFUND, Some(id), None, ID_SIZE) => Transaction::Refund { id, amount: None },
+        (REFUND, Some(id), Some(amount), _) => Transaction::Refund {
+            id,
+            amount: Some(amount),
+        },
+        (LOCK, None, None, 0) => Transaction::Lock,
+        (UNLOCK, None, None, 0) => Transaction::Unlock,
+        (CLOSE_ACCOUNT, None, None, 0) => Transaction::CloseAccount,
+        (AUTHORIZE, Some(new_id), Some(amount), _) => Transaction::Authorize { new_id, amount },
+        (CAPTURE, Some(id), None, ID_SIZE) => Transaction::Capture { id },
+        (VOID, Some(id), None, ID_SIZE) => Transaction::Void { id },
+        _ => return None,
+    };
+    Some((client, tx))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use super::{decode, encode, WriteAheadLog};
+    use crate::{
+        account::AccountMetadata, ledger::Ledger, shared::SharedLedger, AccountId, Currency,
+        Transaction, TransactionId,
+    };
+
+    #[test]
+    fn replay() {
+        let path = std::env::temp_dir().join(format!("ledger-wal-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let (wal, replayed) = WriteAheadLog::open(&path, &mut Ledger::default()).unwrap();
+        assert_eq!(replayed, 0);
+        let ledger = SharedLedger::default().with_wal(wal);
+        let transactions = [
+            Transaction::Deposit {
+                new_id: 1,
+                amount: "10.25".parse().unwrap(),
+            },
+            // Rejected, and rejected again when replayed.
+            Transaction::Withdrawal {
+                new_id: 2,
+                amount: 20.into(),
+            },
+            Transaction::Dispute {
+                id: 1,
+                amount: None,
+            },
+            Transaction::Recovery,
+        ];
+        for tx in transactions {
+            let _ = ledger.apply(1, tx);
+        }
+        drop(ledger);
+
+        // A crash in the middle of writing a frame leaves part of it behind.
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[7, 0, 0, 0, 0, 1]).unwrap();
+        drop(file);
+
+        let mut ledger = Ledger::default();
+        let (mut wal, replayed) = WriteAheadLog::open(&path, &mut ledger).unwrap();
+        assert_eq!(replayed, 4);
+        let account = ledger.account(1).expect("account should be replayed");
+        assert_eq!(account.held(), "10.25".parse().unwrap());
+        assert_eq!(account.available(), 0.into());
+
+        // The incomplete frame is gone, so later frames can be read.
+        wal.append(2, &Transaction::Resolve { id: 3 }).unwrap();
+        drop(wal);
+        let (_, replayed) = WriteAheadLog::open(&path, &mut Ledger::default()).unwrap();
+        assert_eq!(replayed, 5);
+
+        fs::write(&path, "type,client,tx,amount\n").unwrap();
+        assert!(WriteAheadLog::open(&path, &mut Ledger::default()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn edge_cases() {
+        // Every kind of transaction is decoded as it was encoded, however
+        // deeply it's wrapped, and with amounts as large as they get.
+        let max = crate::funds::max();
+        let eur = "EUR".parse::<Currency>().unwrap();
+        let in_eur = |transaction| Transaction::InCurrency {
+            currency: eur,
+            transaction: Box::new(transaction),
+        };
+        let transactions = [
+            Transaction::Deposit {
+                new_id: TransactionId::MAX,
+                amount: max,
+            },
+            Transaction::Dispute {
+                id: 1,
+                amount: Some(max),
+            },
+            Transaction::Refund {
+                id: 1,
+                amount: None,
+            },
+            Transaction::Fee {
+                new_id: 2,
+                amount: "0.00000001".parse().unwrap(),
+            },
+            Transaction::Scheduled {
+                effective_at: u64::MAX,
+                transaction: Box::new(Transaction::Timestamped {
+                    timestamp: 0,
+                    transaction: Box::new(in_eur(Transaction::Void { id: 3 })),
+                }),
+            },
+            Transaction::OpenAccount {
+                metadata: Box::new(AccountMetadata {
+                    reference: Some("c,\"1\"".to_owned()),
+                    currency: Some(eur),
+                    risk_tier: None,
+                }),
+            },
+            Transaction::CloseAccount,
+        ];
+        for tx in transactions {
+            assert_eq!(
+                decode(&encode(AccountId::MAX, &tx)),
+                Some((AccountId::MAX, tx))
+            );
+        }
+
+        // Frames cut short or with trailing bytes are malformed, and so are
+        // unknown kinds.
+        let deposit = encode(
+            1,
+            &Transaction::Deposit {
+                new_id: 1,
+                amount: 1.into(),
+            },
+        );
+        assert!(decode(&deposit[..deposit.len() - 1]).is_none());
+        let mut lock = encode(1, &Transaction::Lock);
+        lock.push(0);
+        assert!(decode(&lock).is_none());
+        assert!(decode(&[99, 1, 0]).is_none());
+        assert!(decode(&[]).is_none());
+
+        // Transactions that were rejected, e.g. a deposit into a closed
+        // account, or a refund of a withdrawal, are rejected again when
+        // they're replayed, and a malformed frame stops the replay.
+        let path = std::env::temp_dir().join(format!("ledger-wal-edges-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let (mut wal, _) = WriteAheadLog::open(&path, &mut Ledger::default()).unwrap();
+        let transactions = [
+            (
+                1,
+                Transaction::Deposit {
+                    new_id: 1,
+                    amount: 5.into(),
+                },
+            ),
+            (
+                1,
+                Transaction::Withdrawal {
+                    new_id: 2,
+                    amount: 5.into(),
+                },
+            ),
+            (1, Transaction::CloseAccount),
+            (
+                1,
+                Transaction::Deposit {
+                    new_id: 3,
+                    amount: 1.into(),
+                },
+            ),
+            (
+                2,
+                Transaction::Deposit {
+                    new_id: 4,
+                    amount: 10.into(),
+                },
+            ),
+            (
+                2,
+                Transaction::Withdrawal {
+                    new_id: 5,
+                    amount: 4.into(),
+                },
+            ),
+            (
+                2,
+                Transaction::Refund {
+                    id: 5,
+                    amount: None,
+                },
+            ),
+        ];
+        for (client, tx) in &transactions {
+            wal.append(*client, tx).unwrap();
+        }
+        drop(wal);
+        let mut ledger = Ledger::default();
+        let (_, replayed) = WriteAheadLog::open(&path, &mut ledger).unwrap();
+        assert_eq!(replayed, transactions.len());
+        assert!(ledger.account(1).unwrap().is_closed());
+        assert_eq!(ledger.account(1).unwrap().total().unwrap(), 0.into());
+        assert_eq!(ledger.account(2).unwrap().total().unwrap(), 6.into());
+
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 0, 0, 0, 99]).unwrap();
+        drop(file);
+        let err = WriteAheadLog::open(&path, &mut Ledger::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use serde::Serialize;
+use tungstenite::{Message, WebSocket};
+
+use crate::{
+    events::{LedgerEvent, Observer},
+    AccountId, Balance,
+};
+
+// Update is the message pushed to subscribers, serialized as JSON, e.g.
+// {"type":"AccountUpdated","client":1,"available":"10.5",...}
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum Update {
+    AccountUpdated {
+        client: AccountId,
+        available: Balance,
+        held: Balance,
+        total: Balance,
+        locked: bool,
+    },
+}
+
+type Subscribers = Arc<Mutex<Vec<WebSocket<TcpStream>>>>;
+
+// UpdateFeed is an observer that pushes an update to every WebSocket
+// subscriber whenever an account changes. Updates are sent from a separate
+// thread so slow subscribers don't hold up the ledger, but they may delay
+// each other.
+pub struct UpdateFeed {
+    local_addr: SocketAddr,
+    updates: mpsc::Sender<String>,
+    subscribers: Subscribers,
+}
+
+impl UpdateFeed {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // The number of currently connected subscribers. Subscribers that
+    // disconnected are only noticed when the next update is sent.
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().expect("lock poisoned").len()
+    }
+}
+
+impl Observer for UpdateFeed {
+    fn on_event(&mut self, event: &LedgerEvent) {
+        if let LedgerEvent::AccountUpdated {
+            client,
+            available,
+            held,
+            total,
+            locked,
+        } = *event
+        {
+            let update = Update::AccountUpdated {
+                client,
+                available,
+                held,
+                total,
+                locked,
+            };
+            let message = serde_json::to_string(&update).expect("updates are serializable");
+            // The broadcasting thread only stops if this feed is dropped.
+            let _ = self.updates.send(message);
+        }
+    }
+}
+
+// Start accepting WebSocket subscribers on the given address. The returned
+// feed has to be registered as an observer of the ledger to publish its
+// updates.
+pub fn listen(addr: SocketAddr) -> io::Result<UpdateFeed> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    let subscribers = Subscribers::default();
+
+    let accepted = subscribers.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            // Handshakes happen on their own threads so a stalled client
+            // can't prevent others from subscribing.
+            let accepted = accepted.clone();
+            thread::spawn(move || match tungstenite::accept(stream) {
+                Ok(socket) => accepted.lock().expect("lock poisoned").push(socket),
+                Err(err) => eprintln!("WebSocket handshake failed: {}", err),
+            });
+        }
+    });
+
+    let (updates, received) = mpsc::channel::<String>();
+    let broadcast = subscribers.clone();
+    thread::spawn(move || {
+        for message in received {
+            // Subscribers that can't be sent to have disconnected.
+            broadcast
+                .lock()
+                .expect("lock poisoned")
+                .retain_mut(|socket| socket.send(Message::text(message.clone())).is_ok());
+        }
+    });
+
+    Ok(UpdateFeed {
+        local_addr,
+        updates,
+        subscribers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::TcpStream, thread, time::Duration};
+
+    use super::listen;
+    use crate::{
+        events::{LedgerEvent, Observer},
+        ledger::Ledger,
+        AccountId,
+    };
+
+    #[test]
+    fn pushes_account_updates() {
+        let mut feed = listen(([127, 0, 0, 1], 0).into()).expect("should bind");
+        let url = format!("ws://{}", feed.local_addr());
+        let (mut client, _) = tungstenite::connect(url).expect("should connect");
+
+        // The subscriber is registered after the handshake completes.
+        while feed.subscriber_count() == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let client_id: AccountId = 1;
+        feed.on_event(&LedgerEvent::AccountUnfrozen {
+            client: client_id,
+            reason: "ignored".to_owned(),
+            at: 0,
+        });
+        feed.on_event(&LedgerEvent::AccountUpdated {
+            client: client_id,
+            available: "10.5".parse().unwrap(),
+            held: 2.into(),
+            total: "12.5".parse().unwrap(),
+            locked: true,
+        });
+
+        let message = client.read().expect("should receive an update");
+        assert_eq!(
+            message.into_text().unwrap().as_str(),
+            r#"{"type":"AccountUpdated","client":1,"available":"10.5","held":"2","total":"12.5","locked":true}"#
+        );
+    }
+
+    #[test]
+    fn edge_cases() {
+        let feed = listen(([127, 0, 0, 1], 0).into()).expect("should bind");
+        let addr = feed.local_addr();
+
+        // A client that never completes the handshake doesn't keep others
+        // from subscribing.
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        stalled.write_all(b"not a handshake\r\n\r\n").unwrap();
+        let (mut client, _) = tungstenite::connect(format!("ws://{}", addr)).unwrap();
+        while feed.subscriber_count() == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Transactions that fail, like a deposit too large for funds to
+        // hold, a deposit into a closed account, or a refund of a
+        // withdrawal, don't change the account, so nothing is pushed.
+        let max = crate::funds::max();
+        let input = format!(
+            "\
+type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,{max}
+deposit,2,3,5
+withdrawal,2,4,5
+close_account,2,0,
+deposit,2,5,1
+deposit,3,6,10
+withdrawal,3,7,4
+refund,3,7,
+deposit,9,8,1
+"
+        );
+        let mut ledger = Ledger::default();
+        ledger.add_observer(feed);
+        ledger.read_csv(input.as_bytes());
+
+        let mut updates = vec![];
+        loop {
+            let message = client.read().expect("should receive an update");
+            let update: serde_json::Value =
+                serde_json::from_str(message.to_text().unwrap()).unwrap();
+            let client = update["client"].as_u64().unwrap();
+            if client == 9 {
+                break;
+            }
+            updates.push((client, update["total"].as_str().unwrap().to_owned()));
+        }
+        let total = |client, total: &str| (client, total.to_owned());
+        assert_eq!(
+            updates,
+            [
+                total(1, &max.to_string()),
+                total(2, "5"),
+                total(2, "0"),
+                total(2, "0"),
+                total(3, "10"),
+                total(3, "6"),
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_disconnected_subscribers() {
+        let mut feed = listen(([127, 0, 0, 1], 0).into()).expect("should bind");
+        let url = format!("ws://{}", feed.local_addr());
+        let (client, _) = tungstenite::connect(url).expect("should connect");
+        while feed.subscriber_count() == 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(client);
+
+        // Sending to a disconnected subscriber may only fail once the
+        // connection is found to be closed.
+        let update = LedgerEvent::AccountUpdated {
+            client: 1,
+            available: 1.into(),
+            held: 0.into(),
+            total: 1.into(),
+            locked: false,
+        };
+        while feed.subscriber_count() > 0 {
+            feed.on_event(&update);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}